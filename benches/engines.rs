@@ -0,0 +1,45 @@
+// runs each program in benches/programs under both engines, output discarded.
+//
+// to add a benchmark, drop a .lox file into benches/programs, add it to
+// PROGRAMS below and give it an expected output in tests/programs.rs so it
+// is checked on every `cargo test`. keep programs deterministic and under a
+// second or so in a release build, criterion runs each one many times.
+//
+//     cargo bench --bench engines            # everything
+//     cargo bench --bench engines -- fib     # only the groups matching "fib"
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use crafting_interpreters::runtime::{Runtime, VMRuntime};
+use crafting_interpreters::types::output::Output;
+
+const PROGRAMS: &[(&str, &str)] = &[
+    ("fib", include_str!("programs/fib.lox")),
+    ("arithmetic", include_str!("programs/arithmetic.lox")),
+    ("strings", include_str!("programs/strings.lox")),
+    ("methods", include_str!("programs/methods.lox")),
+    ("closures", include_str!("programs/closures.lox")),
+    ("global_reads", include_str!("programs/global_reads.lox")),
+    ("local_reads", include_str!("programs/local_reads.lox")),
+    ("global_increment", include_str!("programs/global_increment.lox")),
+];
+
+fn engines(c: &mut Criterion) {
+    for (name, source) in PROGRAMS {
+        let mut group = c.benchmark_group(*name);
+        group.sample_size(10);
+        group.bench_function("interpreter", |b| b.iter(|| {
+            let mut runtime = Runtime::default();
+            runtime.output = Output::discard();
+            runtime.run_source(source.to_string()).unwrap();
+        }));
+        group.bench_function("vm", |b| b.iter(|| {
+            let mut vm_runtime = VMRuntime::default();
+            vm_runtime.output = Output::discard();
+            vm_runtime.run_source(source.to_string()).unwrap();
+        }));
+        group.finish();
+    }
+}
+
+criterion_group!(benches, engines);
+criterion_main!(benches);
@@ -0,0 +1,106 @@
+use crate::types::err::LoxError;
+use crate::types::span::Span;
+
+// renders one error as the message followed by the offending source line:
+//
+// error: <message>
+//  --> line 1, column 9
+//   |
+// 1 | var a = ;
+//   |         ^
+pub fn render(source: &str, span: Option<Span>, message: &str) -> String {
+    let mut out = format!("error: {}", message);
+    let span = match span {
+        None => return out,
+        Some(span) => span,
+    };
+    let text = match source.lines().nth(span.line.wrapping_sub(1)) {
+        None => return out,
+        Some(text) => text,
+    };
+
+    // no column known, underline the whole line
+    let (col, len) = if span.col == 0 {
+        let indent = text.len() - text.trim_start().len();
+        (indent + 1, text.trim().len())
+    } else {
+        let col = span.col.min(text.len() + 1);
+        (col, span.len.min(text.len() + 1 - col))
+    };
+
+    let gutter = " ".repeat(span.line.to_string().len());
+    out.push_str(&format!("\n{}--> line {}, column {}", gutter, span.line, col));
+    out.push_str(&format!("\n{} |", gutter));
+    out.push_str(&format!("\n{} | {}", span.line, text));
+    out.push_str(&format!("\n{} | {}{}", gutter, " ".repeat(col - 1), "^".repeat(len.max(1))));
+    return out;
+}
+
+// renders every error carried by `err`, one block each
+pub fn render_error(source: &str, err: &LoxError) -> String {
+    return match err {
+        LoxError::Scan(e) => render(source, Some(e.span), &e.message),
+        LoxError::Parse(errors) | LoxError::Compile(errors) => {
+            errors.iter()
+                .map(|e| render(source, e.span(), &e.to_string()))
+                .collect::<Vec<String>>()
+                .join("\n")
+        }
+        LoxError::Runtime(e) => render(source, e.span(), &e.to_string()),
+        LoxError::Io(e) => render(source, None, &e.to_string()),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::diagnostics::render_error;
+    use crate::runtime::{Runtime, VMRuntime};
+
+    #[test]
+    fn parse_error_points_at_token() {
+        let source = "var a = 1;\nvar b = ;";
+        let err = Runtime::default().run_source(source.to_string()).unwrap_err();
+        assert_eq!(render_error(source, &err), "\
+error: ExpectedExpression line=2,token_type=Semicolon
+ --> line 2, column 9
+  |
+2 | var b = ;
+  |         ^");
+    }
+
+    #[test]
+    fn compile_error_points_at_name() {
+        let source = "{\n  var count = 1;\n  var count = 2;\n}";
+        let err = VMRuntime::default().run_source(source.to_string()).unwrap_err();
+        assert_eq!(render_error(source, &err), "\
+error: count, Variable repeat def, first declared at line=2.
+ --> line 3, column 7
+  |
+3 |   var count = 2;
+  |       ^^^^^");
+    }
+
+    #[test]
+    fn runtime_error_points_at_operator() {
+        let source = "var a = 1;\nprint a + \"b\";";
+        let err = Runtime::default().run_source(source.to_string()).unwrap_err();
+        assert_eq!(render_error(source, &err), "\
+error: Left Int(1) Right String(\"b\") Operator Plus, not match [line 2]
+ --> line 2, column 9
+  |
+2 | print a + \"b\";
+  |         ^");
+    }
+
+    #[test]
+    fn vm_runtime_error_underlines_line() {
+        let source = "var a = 1;\n  a();";
+        let err = VMRuntime::default().run_source(source.to_string()).unwrap_err();
+        assert_eq!(render_error(source, &err), "\
+error: Simple Error: Can only call functions and classes; tried to call a (int). [line 2]
+ --> line 2, column 3
+  |
+2 |   a();
+  |   ^^^^");
+    }
+}
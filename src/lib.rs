@@ -1,9 +1,11 @@
 extern crate core;
 
 
+pub mod diagnostics;
 pub mod types;
 pub mod process;
 pub mod runtime;
+pub mod stdlib;
 pub mod vm;
 
 
@@ -1,6 +1,9 @@
-use clap::{Parser, ValueEnum};
+use std::time::Duration;
 
-use crafting_interpreters::runtime::{Runtime, VMRuntime};
+use clap::{Parser, Subcommand, ValueEnum};
+
+use crafting_interpreters::runtime::{Runtime, VMRuntime, Watcher};
+use crafting_interpreters::types::err::LoxError;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 pub enum RuntimeType {
@@ -8,9 +11,26 @@ pub enum RuntimeType {
     Interpreter,
 }
 
-
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// run a script
+    Run(Args),
+    /// run each line typed in, until an empty one
+    Repl(ReplArgs),
+    /// print the bytecode the virtual machine would run for a script
+    Disasm(DisasmArgs),
+    /// report the errors in a script without running it
+    Compile(CompileArgs),
+}
+
+#[derive(clap::Args, Debug)]
 struct Args {
     #[arg(short, long, default_value_t = false)]
     disassemble: bool,
@@ -20,20 +40,201 @@ struct Args {
 
     #[arg(short, long)]
     file: String,
+
+    /// stop the script after this many seconds
+    #[arg(short, long)]
+    timeout: Option<f64>,
+
+    /// compile assert statements out (virtual machine only)
+    #[arg(long, default_value_t = false)]
+    strip_asserts: bool,
+
+    /// fold constant expressions before running (interpreter only)
+    #[arg(long, default_value_t = false)]
+    optimize: bool,
+
+    /// disable natives that change the host, like setenv()
+    #[arg(long, default_value_t = false)]
+    sandbox: bool,
+
+    /// seed random() so runs repeat, entropy otherwise
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// fix the seed and fake the clock so every run prints the same
+    #[arg(long, default_value_t = false)]
+    deterministic: bool,
+
+    /// re-run the file whenever it changes
+    #[arg(short, long, default_value_t = false)]
+    watch: bool,
 }
 
-fn main() {
-    env_logger::init();
-    let args = Args::parse() as Args;
+#[derive(clap::Args, Debug)]
+struct ReplArgs {
+    #[arg(short, long, value_enum, default_value_t = RuntimeType::VirtualMachine)]
+    model: RuntimeType,
+
+    /// disable natives that change the host, like setenv()
+    #[arg(long, default_value_t = false)]
+    sandbox: bool,
+
+    /// seed random() so runs repeat, entropy otherwise
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+#[derive(clap::Args, Debug)]
+struct DisasmArgs {
+    #[arg(short, long)]
+    file: String,
+
+    /// compile assert statements out
+    #[arg(long, default_value_t = false)]
+    strip_asserts: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct CompileArgs {
+    #[arg(short, long, value_enum, default_value_t = RuntimeType::VirtualMachine)]
+    model: RuntimeType,
+
+    #[arg(short, long)]
+    file: String,
+}
 
+// every run starts from a fresh runtime, so watch mode doesn't leak state between runs
+fn run(args: &Args) -> Result<(), LoxError> {
+    let timeout = args.timeout.map(Duration::from_secs_f64);
     match args.model {
         RuntimeType::VirtualMachine => {
             let mut vm_runtime = VMRuntime::default();
             vm_runtime.disassemble = args.disassemble;
-            vm_runtime.run_file(args.file)
+            vm_runtime.timeout = timeout;
+            vm_runtime.strip_asserts = args.strip_asserts;
+            vm_runtime.sandbox = args.sandbox;
+            if args.deterministic {
+                vm_runtime.deterministic();
+            }
+            if let Some(seed) = args.seed {
+                vm_runtime.seed(seed);
+            }
+            vm_runtime.run_file(args.file.clone())
         }
         RuntimeType::Interpreter => {
-            Runtime::default().run_file(args.file)
+            let mut runtime = Runtime::default();
+            runtime.timeout = timeout;
+            runtime.sandbox = args.sandbox;
+            runtime.optimize = args.optimize;
+            if args.deterministic {
+                runtime.deterministic();
+            }
+            if let Some(seed) = args.seed {
+                runtime.seed(seed);
+            }
+            runtime.run_file(args.file.clone())
+        }
+    }
+}
+
+fn repl(args: &ReplArgs) {
+    match args.model {
+        RuntimeType::VirtualMachine => {
+            let mut vm_runtime = VMRuntime::default();
+            vm_runtime.sandbox = args.sandbox;
+            if let Some(seed) = args.seed {
+                vm_runtime.seed(seed);
+            }
+            vm_runtime.run_prompt();
+        }
+        RuntimeType::Interpreter => {
+            let mut runtime = Runtime::default();
+            runtime.sandbox = args.sandbox;
+            if let Some(seed) = args.seed {
+                runtime.seed(seed);
+            }
+            runtime.run_prompt();
+        }
+    }
+}
+
+// the exit code of a failed command
+fn start(cli: &Cli) -> Option<i32> {
+    let result = match &cli.command {
+        Command::Run(args) => {
+            if args.watch {
+                Watcher::new(args.file.clone(), Duration::from_millis(500)).watch(|| run(args));
+                return None;
+            }
+            run(args)
+        }
+        Command::Repl(args) => {
+            repl(args);
+            Ok(())
+        }
+        Command::Disasm(args) => {
+            let mut vm_runtime = VMRuntime::default();
+            vm_runtime.disassemble = true;
+            vm_runtime.strip_asserts = args.strip_asserts;
+            vm_runtime.run_file(args.file.clone())
+        }
+        Command::Compile(args) => match args.model {
+            RuntimeType::VirtualMachine => VMRuntime::default().check_file(args.file.clone()),
+            RuntimeType::Interpreter => Runtime::default().check_file(args.file.clone()),
+        },
+    };
+    match result {
+        Ok(()) => None,
+        Err(e) => Some(e.exit_code()),
+    }
+}
+
+fn main() {
+    env_logger::init();
+    let cli = Cli::parse();
+
+    match start(&cli) {
+        None => {}
+        Some(code) => std::process::exit(code),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use crate::{Cli, Command, RuntimeType};
+
+    fn parse(args: &[&str]) -> Command {
+        let args = ["crafting-interpreters"].iter().chain(args);
+        Cli::try_parse_from(args).unwrap().command
+    }
+
+    #[test]
+    fn every_subcommand_parses() {
+        match parse(&["run", "-m", "interpreter", "-f", "a.lox", "--sandbox", "--seed", "3", "-t", "1.5"]) {
+            Command::Run(args) => {
+                assert_eq!(args.model, RuntimeType::Interpreter);
+                assert_eq!(args.file, "a.lox");
+                assert!(args.sandbox && !args.watch);
+                assert_eq!(args.seed, Some(3));
+                assert_eq!(args.timeout, Some(1.5));
+            }
+            other => panic!("{:?}", other),
+        }
+        match parse(&["repl"]) {
+            Command::Repl(args) => assert_eq!(args.model, RuntimeType::VirtualMachine),
+            other => panic!("{:?}", other),
+        }
+        match parse(&["disasm", "-f", "a.lox", "--strip-asserts"]) {
+            Command::Disasm(args) => assert!(args.strip_asserts),
+            other => panic!("{:?}", other),
+        }
+        match parse(&["compile", "--model", "interpreter", "--file", "a.lox"]) {
+            Command::Compile(args) => assert_eq!(args.model, RuntimeType::Interpreter),
+            other => panic!("{:?}", other),
         }
+        assert!(Cli::try_parse_from(["crafting-interpreters", "run", "-f", "a.lox"]).is_err());
+        assert!(Cli::try_parse_from(["crafting-interpreters", "-m", "interpreter", "-f", "a.lox"]).is_err());
     }
 }
@@ -1,7 +1,7 @@
 use std::ops::Add;
 
 use crate::types::expr;
-use crate::types::expr::{Expression, Literal};
+use crate::types::expr::{Expression, ExpressionKind, Literal};
 
 pub trait Accept {
     fn accept(&self, printer: &dyn Printer) -> String;
@@ -31,22 +31,25 @@ impl AstPrinter {
 
 impl Printer for AstPrinter {
     fn visit_expr(&self, group: &Expression) -> String {
-        match group {
-            Expression::Grouping(g) => {
+        match &group.kind {
+            ExpressionKind::Grouping(g) => {
                 return self.parenthesize("group", vec![g.as_ref()]);
             }
 
-            Expression::Binary(l, op, r) => {
+            ExpressionKind::Binary(l, op, r) => {
                 return self.parenthesize(op.token_type.to_string().as_str(), vec![l, r]);
             }
-            Expression::Unary(op, exp) => {
+            ExpressionKind::Unary(op, exp) => {
                 return self.parenthesize(op.token_type.to_string().as_str(), vec![exp]);
             }
-            Expression::Literal(l) => {
+            ExpressionKind::Literal(l) => {
                 return match l {
                     Literal::String(s) => {
                         s.to_string()
                     }
+                    Literal::Int(n) => {
+                        n.to_string()
+                    }
                     Literal::Number(n) => {
                         n.to_string()
                     }
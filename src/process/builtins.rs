@@ -0,0 +1,32 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::process::interpreter::Interpreter;
+use crate::types::class::LoxInstance;
+use crate::types::val::{InterpreterError, Value};
+
+// shallow copy: a new instance whose fields still point at the same values
+pub fn clone(
+    interpreter: &mut Interpreter,
+    args: &[Value],
+) -> Result<Value, InterpreterError> {
+    return match &args[0] {
+        Value::LoxInstance { id, class, .. } => {
+            let id = copy_instance(interpreter, *id);
+            let parent = interpreter.lox_instances.get(&id).expect("just copied").parent;
+            Ok(Value::LoxInstance { id, parent, class: class.clone() })
+        }
+        Value::Array(values) => Ok(Value::Array(Rc::new(RefCell::new(values.borrow().clone())))),
+        Value::Map(map) => Ok(Value::Map(Rc::new(RefCell::new(map.borrow().clone())))),
+        other => Ok(other.clone()),
+    };
+}
+
+// the superclass parts live in instances of their own, each gets copied as well
+fn copy_instance(interpreter: &mut Interpreter, id: usize) -> usize {
+    let mut copy: LoxInstance = interpreter.lox_instances.get(&id).expect("should be exist").clone();
+    copy.parent = copy.parent.map(|parent| copy_instance(interpreter, parent));
+    let new_id = interpreter.next_id();
+    interpreter.lox_instances.insert(new_id, copy);
+    return new_id;
+}
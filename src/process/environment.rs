@@ -1,19 +1,23 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::rc::Rc;
 
 use crate::types::{env, val};
 
+// scopes are shared, not copied: a block, a closure and the code that
+// created them all see the same bindings
 #[derive(Default, Clone, Debug)]
 pub struct Environment {
     pub values: HashMap<String, val::Value>,
-    pub enclosing: Option<Box<Environment>>,
+    pub enclosing: Option<Rc<RefCell<Environment>>>,
 }
 
 
 impl Environment {
-    pub fn with_enclosing(env: Environment) -> Self {
+    pub fn with_enclosing(env: Rc<RefCell<Environment>>) -> Self {
         return Self {
             values: Default::default(),
-            enclosing: Some(Box::new(env)),
+            enclosing: Some(env),
         };
     }
 
@@ -21,7 +25,7 @@ impl Environment {
         self.values.insert(name.clone(), var.clone());
     }
 
-    pub fn get(&self, name: &str) -> Option<&val::Value> {
+    pub fn get(&self, name: &str) -> Option<val::Value> {
         return match self.values.get(name) {
             None => {
                 match &self.enclosing {
@@ -29,12 +33,12 @@ impl Environment {
                         None
                     }
                     Some(parent) => {
-                        return parent.get(name);
+                        return parent.borrow().get(name);
                     }
                 }
             }
             Some(val) => {
-                Some(val)
+                Some(val.clone())
             }
         };
     }
@@ -45,13 +49,13 @@ impl Environment {
             return Ok(());
         }
 
-        return match &mut self.enclosing {
+        return match &self.enclosing {
             None => {
                 Err(env::EnvError::UnknownParam(name.clone()))
             }
             Some(parent) => {
-                parent.assign(name, var)
+                parent.borrow_mut().assign(name, var)
             }
         };
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,142 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::mem::size_of;
+use std::rc::Rc;
+
+use crate::process::environment::Environment;
+use crate::process::interpreter::Interpreter;
+use crate::stdlib::heap::HeapStats;
+use crate::types::{class, func, val};
+
+// instances and functions live in the interpreter's tables by id, so nothing
+// frees them on its own. a collection marks every id reachable from the global
+// scope and drops the rest. it only runs between top level statements, where no
+// half evaluated expression can hold an id the scopes don't know about
+#[derive(Default)]
+struct Marker {
+    instances: HashSet<usize>,
+    functions: HashSet<usize>,
+    // by address, scopes and containers can point back at themselves
+    seen: HashSet<usize>,
+}
+
+impl Marker {
+    fn first_visit<T>(&mut self, rc: &Rc<T>) -> bool {
+        return self.seen.insert(Rc::as_ptr(rc) as *const u8 as usize);
+    }
+
+    fn environment(&mut self, interpreter: &Interpreter, env: &Rc<RefCell<Environment>>) {
+        if !self.first_visit(env) {
+            return;
+        }
+        let env = env.borrow();
+        for value in env.values.values() {
+            self.value(interpreter, value);
+        }
+        match &env.enclosing {
+            None => {}
+            Some(enclosing) => self.environment(interpreter, enclosing),
+        }
+    }
+
+    fn class(&mut self, interpreter: &Interpreter, class: &class::LoxClass) {
+        for method in &class.methods {
+            self.value(interpreter, method);
+        }
+        for value in class.statics.borrow().values() {
+            self.value(interpreter, value);
+        }
+        match &class.super_class {
+            None => {}
+            Some(super_class) => self.class(interpreter, super_class),
+        }
+    }
+
+    fn instance(&mut self, interpreter: &Interpreter, id: usize) {
+        if !self.instances.insert(id) {
+            return;
+        }
+        match interpreter.lox_instances.get(&id) {
+            None => {}
+            Some(instance) => {
+                self.class(interpreter, &instance.class);
+                for (_, value) in instance.fields() {
+                    self.value(interpreter, value);
+                }
+                match instance.parent {
+                    None => {}
+                    Some(parent) => self.instance(interpreter, parent),
+                }
+            }
+        }
+    }
+
+    fn function(&mut self, interpreter: &Interpreter, id: usize) {
+        if !self.functions.insert(id) {
+            return;
+        }
+        match interpreter.lox_functions.get(&id) {
+            None => {}
+            Some(function) => {
+                self.environment(interpreter, &function.closure);
+                match &function.bind {
+                    None => {}
+                    Some(bind) => self.value(interpreter, bind),
+                }
+            }
+        }
+    }
+
+    fn value(&mut self, interpreter: &Interpreter, value: &val::Value) {
+        match value {
+            val::Value::LoxInstance { id, parent, .. } => {
+                self.instance(interpreter, *id);
+                match parent {
+                    None => {}
+                    Some(parent) => self.instance(interpreter, *parent),
+                }
+            }
+            val::Value::LoxFunc(_, id) => self.function(interpreter, *id),
+            val::Value::LoxBoundMethod { receiver, id, .. } => {
+                self.value(interpreter, receiver);
+                self.function(interpreter, *id);
+            }
+            val::Value::LoxClass(class) => self.class(interpreter, class),
+            val::Value::Array(array) if self.first_visit(array) => {
+                for value in array.borrow().iter() {
+                    self.value(interpreter, value);
+                }
+            }
+            val::Value::Map(map) if self.first_visit(map) => {
+                for (key, value) in map.borrow().iter() {
+                    self.value(interpreter, key);
+                    self.value(interpreter, value);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+pub fn collect(interpreter: &mut Interpreter) -> usize {
+    let mut marker = Marker::default();
+    marker.environment(interpreter, &interpreter.global);
+    marker.environment(interpreter, &interpreter.environment);
+
+    let before = interpreter.lox_instances.len() + interpreter.lox_functions.len();
+    interpreter.lox_instances.retain(|id, _| marker.instances.contains(id));
+    interpreter.lox_functions.retain(|id, _| marker.functions.contains(id));
+    return before - interpreter.lox_instances.len() - interpreter.lox_functions.len();
+}
+
+pub fn stats(interpreter: &Interpreter, collections: usize) -> HeapStats {
+    let instances = interpreter.lox_instances.len();
+    let functions = interpreter.lox_functions.len();
+    return HeapStats {
+        instances: Some(instances),
+        functions: Some(functions),
+        strings: None,
+        bytes: instances * size_of::<class::LoxInstance>() + functions * size_of::<func::LoxFunction>(),
+        collections,
+    };
+}
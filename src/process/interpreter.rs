@@ -1,64 +1,179 @@
-use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
-use crate::process::environment;
+use crate::process::{builtins, environment, gc};
+use crate::stdlib;
+use crate::stdlib::debug::Shape;
+use crate::stdlib::determinism::Determinism;
+use crate::stdlib::heap::HeapStats;
+use crate::stdlib::random::Rng;
+use crate::stdlib::testing::Tally;
 use crate::types::{class, expr, func, val};
+use crate::types::map::LoxMap;
+use crate::types::output::Output;
+
+#[derive(Debug)]
+pub enum ControlFlow {
+    Normal,
+    Return(val::Value),
+    // `return f(x);`, the function returning runs the call in its own place
+    TailCall(val::Value, Vec<val::Value>),
+    Break(Option<String>),
+    Continue(Option<String>),
+}
+
+enum Call {
+    Function(val::Value, Vec<val::Value>),
+    Handled(val::Value),
+}
+
+// nested calls deeper than this are a runtime error rather than a host stack overflow
+pub const MAX_CALL_DEPTH: usize = 1000;
+// a call that finds less host stack than this left runs on a new STACK_SEGMENT, so
+// MAX_CALL_DEPTH is reachable on any thread, even in a debug build
+pub const STACK_RED_ZONE: usize = 1024 * 1024;
+pub const STACK_SEGMENT: usize = 16 * 1024 * 1024;
 
-#[derive(Default)]
 pub struct Interpreter {
-    pub environment: environment::Environment,
-    pub global: environment::Environment,
+    pub environment: Rc<RefCell<environment::Environment>>,
+    pub global: Rc<RefCell<environment::Environment>>,
     pub lox_functions: HashMap<usize, func::LoxFunction>,
     pub lox_instances: HashMap<usize, class::LoxInstance>,
     counter: usize,
-    pub ret: Option<val::Value>,
+    // lox functions running, the ones entered by a tail call don't count
+    pub depth: usize,
+    missing: Vec<usize>,
+    pub timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    pub output: Output,
+    pub sandbox: bool,
+    pub rng: Rng,
+    pub determinism: Option<Determinism>,
+    pub tally: Tally,
+    gc_requested: bool,
+    collections: usize,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        let global = Rc::new(RefCell::new(environment::Environment::default()));
+        global.borrow_mut().define("clone".to_string(), &val::Value::LoxNative(func::LoxNative {
+            name: "clone".to_string(),
+            arity: 1,
+            func: builtins::clone,
+        }));
+        for native in stdlib::natives() {
+            global.borrow_mut().define(native.name.to_string(), &val::Value::Native(native));
+        }
+        return Interpreter {
+            environment: global.clone(),
+            global,
+            lox_functions: Default::default(),
+            lox_instances: Default::default(),
+            counter: 0,
+            depth: 0,
+            missing: vec![],
+            timeout: None,
+            deadline: None,
+            output: Output::default(),
+            sandbox: false,
+            rng: Rng::default(),
+            determinism: None,
+            tally: Tally::default(),
+            gc_requested: false,
+            collections: 0,
+        };
+    }
 }
 
 impl Interpreter {
-    pub fn execute(&mut self, expr: &expr::Statement) -> Result<(), val::InterpreterError> {
-        log::debug!("execute expr: {:?}",expr);
-        self.interpret_statement(expr)?;
+    pub fn interpret(&mut self, statements: &[expr::Statement]) -> Result<(), val::InterpreterError> {
+        self.deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        for statement in statements {
+            // a `return` outside of any function ends the script
+            if let ControlFlow::Return(_) = self.interpret_statement(statement)? {
+                break;
+            }
+            if self.gc_requested {
+                self.gc_requested = false;
+                self.collections += 1;
+                gc::collect(self);
+            }
+        }
         Ok(())
     }
 
-    pub fn execute_block(&mut self, sts: &Vec<expr::Statement>) -> Result<(), val::InterpreterError> {
-        log::debug!("execute_block expr: {:?}",sts);
-        // everytime execute, should be new env for block
-        self.environment = environment::Environment::with_enclosing(self.environment.clone());
-        for st in sts {
-            match self.execute(st) {
-                Ok(_) => {
-                    match self.ret {
-                        None => {}
-                        Some(_) => {
-                            // fast return
-                            break;
-                        }
-                    }
-                }
-                Err(e) => {
-                    return Err(val::InterpreterError::ExecuteError(Box::new(e)));
-                }
-            }
+    pub fn echo(&mut self, expr: &expr::Expression) -> Result<(), val::InterpreterError> {
+        let value = self.interpret_expression(expr)?;
+        match &value {
+            val::Value::Nil => {}
+            value => self.output.print(&format!("> {}", value))?,
         }
+        self.global.borrow_mut().define("_".to_string(), &value);
+        return Ok(());
+    }
 
-        match &self.environment.enclosing {
-            None => {
-                panic!("impossible, always has previous");
-            }
-            Some(previous) => {
-                self.environment = *previous.clone()
+    pub fn reset_transient(&mut self) {
+        self.depth = 0;
+        self.missing.clear();
+        self.environment = self.global.clone();
+    }
+
+    fn check_deadline(&self) -> Result<(), val::InterpreterError> {
+        match (self.timeout, self.deadline) {
+            (Some(after), Some(deadline)) if Instant::now() >= deadline => {
+                return Err(val::InterpreterError::Timeout { after, trace: vec![] });
             }
+            _ => {}
         }
         Ok(())
     }
 
+    pub fn execute(&mut self, expr: &expr::Statement) -> Result<ControlFlow, val::InterpreterError> {
+        log::debug!("execute expr: {:?}",expr);
+        return self.interpret_statement(expr);
+    }
+
+    pub fn execute_block(&mut self, sts: &Vec<expr::Statement>) -> Result<ControlFlow, val::InterpreterError> {
+        log::debug!("execute_block expr: {:?}",sts);
+        // everytime execute, should be new env for block
+        let previous = self.environment.clone();
+        self.environment = Rc::new(RefCell::new(environment::Environment::with_enclosing(previous.clone())));
+        let result = self.execute_statements(sts);
+        // back to the enclosing scope on every path, an error must not leave us in the dead block
+        self.environment = previous;
+        return result.map_err(|e| val::InterpreterError::ExecuteError(Box::new(e)));
+    }
+
+    fn execute_statements(&mut self, sts: &Vec<expr::Statement>) -> Result<ControlFlow, val::InterpreterError> {
+        for st in sts {
+            match self.execute(st)? {
+                ControlFlow::Normal => {}
+                other => return Ok(other),
+            }
+        }
+        return Ok(ControlFlow::Normal);
+    }
+
     fn cast_callable(interpreter: &mut Self, value: &val::Value) -> Option<Box<dyn func::Callable>> {
         match value {
             val::Value::LoxFunc(_, id) => {
                 let f = interpreter.get_lox_function(*id);
                 Some(Box::new(f.clone()))
             }
+            val::Value::LoxBoundMethod { receiver, id, .. } => {
+                let mut f = interpreter.get_lox_function(*id).clone();
+                f.bind = Some(*receiver.clone());
+                Some(Box::new(f))
+            }
+            val::Value::LoxNative(native) => {
+                Some(Box::new(native.clone()))
+            }
+            val::Value::Native(native) => {
+                Some(Box::new(native.clone()))
+            }
             val::Value::LoxClass(class) => {
                 Some(Box::new(class.clone()))
             }
@@ -89,44 +204,69 @@ impl Interpreter {
         }
     }
 
-    pub fn interpret_statement(&mut self, expr: &expr::Statement) -> Result<(), val::InterpreterError> {
+    pub fn interpret_statement(&mut self, expr: &expr::Statement) -> Result<ControlFlow, val::InterpreterError> {
         log::debug!("interpreter statement: {:?}",expr);
-        return match expr {
-            expr::Statement::Class {
-                name, methods, super_class
+        self.check_deadline()?;
+        return match &expr.kind {
+            expr::StatementKind::Enum(name, members) => {
+                let lox_class = class::LoxClass {
+                    name: name.to_string(),
+                    ..class::LoxClass::default()
+                };
+                for (i, member) in members.iter().enumerate() {
+                    lox_class.statics.borrow_mut().insert(member.to_string(), val::Value::Int(i as i64));
+                }
+                self.environment.borrow_mut().define(name.to_string(), &val::Value::LoxClass(lox_class));
+                Ok(ControlFlow::Normal)
+            }
+            expr::StatementKind::Class {
+                name, methods, super_class, statics
             } => {
                 let mut super_lox_class = None;
 
                 match super_class {
                     None => {}
                     Some(super_class) => {
-                        match self.environment.get(super_class).unwrap() {
+                        match self.lookup(super_class.to_string())? {
                             val::Value::LoxClass(clazz) => {
                                 super_lox_class = Some(Box::new(clazz.clone()))
                             }
-                            _ => {}
+                            _ => {
+                                return Err(val::InterpreterError::SimpleError("Superclass must be a class.".to_string()).at(expr.span));
+                            }
                         }
                     }
                 }
 
 
-                self.environment.define(name.to_string(), &val::Value::Nil);
-                let mut lox_class = class::LoxClass::default();
-                lox_class.name = name.to_string();
-                lox_class.super_class = super_lox_class;
+                self.environment.borrow_mut().define(name.to_string(), &val::Value::Nil);
+                let mut lox_class = class::LoxClass {
+                    name: name.to_string(),
+                    super_class: super_lox_class,
+                    ..class::LoxClass::default()
+                };
                 let mut lox_class_methods = vec![];
+                // `super` is the superclass of the class a method is declared in, whatever
+                // the class of the instance it runs on
+                let methods_env = match &lox_class.super_class {
+                    None => self.environment.clone(),
+                    Some(super_class) => {
+                        let mut env = environment::Environment::with_enclosing(self.environment.clone());
+                        env.define("super".to_string(), &val::Value::LoxClass(*super_class.clone()));
+                        Rc::new(RefCell::new(env))
+                    }
+                };
                 // init methods
                 for method in methods {
-                    match method {
-                        expr::Statement::Function(name, params, body) => {
-                            let func_name = name.as_str();
+                    match &method.kind {
+                        expr::StatementKind::Function(name, params, body) => {
                             let func_id = self.next_id();
                             let lox_function = func::LoxFunction {
                                 id: func_id,
                                 name: name.to_string(),
                                 parameters: params.clone(),
                                 body: *body.clone(),
-                                closure: self.environment.clone(),
+                                closure: methods_env.clone(),
                                 bind: None,
                                 is_initializer: name.as_str().eq("init"),
                             };
@@ -137,23 +277,36 @@ impl Interpreter {
                     }
                 }
                 lox_class.methods = lox_class_methods;
-                self.environment.assign(name.to_string(), &val::Value::LoxClass(lox_class)).expect("failed");
-                Ok(())
+                let table = lox_class.statics.clone();
+                self.environment.borrow_mut().assign(name.to_string(), &val::Value::LoxClass(lox_class)).expect("failed");
+                // the class is already bound, a later static may read an earlier one
+                for (name, initializer) in statics {
+                    let value = self.interpret_expression(initializer)?;
+                    table.borrow_mut().insert(name.to_string(), value);
+                }
+                Ok(ControlFlow::Normal)
             }
-            expr::Statement::Return(_, expr) => {
+            expr::StatementKind::Return(_, expr) => {
                 match expr {
-                    Some(expr) => {
-                        self.ret = Some(self.interpret_expression(expr)?);
+                    Some(expr::Expression { kind: expr::ExpressionKind::Call(callee, _, args), .. }) if self.depth > 0 => {
+                        return match self.prepare_call(callee, args)? {
+                            None => Ok(ControlFlow::Return(val::Value::Nil)),
+                            Some(Call::Function(callee, arguments)) => Ok(ControlFlow::TailCall(callee, arguments)),
+                            Some(Call::Handled(value)) => Ok(ControlFlow::Return(value)),
+                        };
                     }
                     _ => {}
                 }
-                Ok(())
+                return match expr {
+                    Some(expr) => Ok(ControlFlow::Return(self.interpret_expression(expr)?)),
+                    None => Ok(ControlFlow::Return(val::Value::Nil)),
+                };
             }
-            expr::Statement::Function(name, params, body) => {
+            expr::StatementKind::Function(name, params, body) => {
                 let func_id = self.next_id();
 
                 // env 里面要放入这个函数，不然后面找不到
-                self.environment.define(name.to_string(), &val::Value::LoxFunc(name.to_string(), func_id));
+                self.environment.borrow_mut().define(name.to_string(), &val::Value::LoxFunc(name.to_string(), func_id));
 
                 let lox_function = func::LoxFunction {
                     id: func_id,
@@ -167,60 +320,96 @@ impl Interpreter {
 
                 self.lox_functions.insert(func_id, lox_function);
 
-                Ok(())
+                Ok(ControlFlow::Normal)
             }
-            expr::Statement::Expression(exp) => {
+            expr::StatementKind::Expression(exp) => {
                 self.interpret_expression(exp)?;
-                Ok(())
+                Ok(ControlFlow::Normal)
             }
-            expr::Statement::Print(exp) => {
-                let print_result = self.interpret_expression(exp)?;
-                println!("{:?}", print_result);
-                Ok(())
+            expr::StatementKind::Print(values) => {
+                // formatted only once all of them ran, like the vm does
+                let mut evaluated = vec![];
+                for value in values {
+                    evaluated.push(self.interpret_expression(value)?);
+                }
+                let printed: Vec<String> = evaluated.iter().map(|v| v.to_string()).collect();
+                self.output.print(&printed.join(" "))?;
+                Ok(ControlFlow::Normal)
             }
-            expr::Statement::Var(name, var) => {
+            expr::StatementKind::Var(name, var) => {
                 let value = self.interpret_expression(var)?;
-                self.environment.define(name.to_string(), &value);
-                Ok(())
+                self.environment.borrow_mut().define(name.to_string(), &value);
+                Ok(ControlFlow::Normal)
             }
-            expr::Statement::Block(sts) => {
-                self.execute_block(sts)?;
-                Ok(())
+            expr::StatementKind::Block(sts) => {
+                self.execute_block(sts)
             }
-            expr::Statement::If(condition, then, els) => {
-                let condition = self.interpret_expression(condition)?;
-                return match condition {
-                    val::Value::Bool(b) => {
-                        if b {
-                            return self.interpret_statement(then);
-                        }
-                        match els {
-                            None => {
-                                Ok(())
-                            }
-                            Some(sts) => {
-                                self.interpret_statement(sts)
-                            }
-                        }
+            expr::StatementKind::If(condition, then, els) => {
+                if self.interpret_expression(condition)?.is_truthy() {
+                    return self.interpret_statement(then);
+                }
+                match els {
+                    None => {
+                        Ok(ControlFlow::Normal)
                     }
-                    _ => {
-                        panic!("should be bool")
+                    Some(sts) => {
+                        self.interpret_statement(sts)
                     }
+                }
+            }
+            expr::StatementKind::Assert { condition, text, message } => {
+                if self.interpret_expression(condition)?.is_truthy() {
+                    return Ok(ControlFlow::Normal);
+                }
+                let message = match message {
+                    None => val::Value::Nil,
+                    Some(message) => self.interpret_expression(message)?,
                 };
+                Err(val::assertion_failed(text, &message).at(condition.span))
+            }
+            expr::StatementKind::Break(label) => {
+                Ok(ControlFlow::Break(label.clone()))
             }
-            expr::Statement::While(condition, sts) => {
+            expr::StatementKind::Continue(label) => {
+                Ok(ControlFlow::Continue(label.clone()))
+            }
+            expr::StatementKind::ForIn { name, iterable, body, label } => {
+                let iterable = self.interpret_expression(iterable)?;
+                let mut iter = stdlib::iter::LoxIter::new(&iterable, &mut |obj, name| self.call_method(obj, name))?;
                 loop {
-                    let condition = self.interpret_expression(condition)?;
-                    match condition {
-                        val::Value::Bool(b) => {
-                            if b {
-                                self.interpret_statement(sts)?;
-                            } else {
-                                return Ok(());
-                            }
-                        }
-                        _ => {
-                            panic!("should be bool")
+                    let value = match iter.next_value(&mut |obj, name| self.call_method(obj, name))? {
+                        None => break,
+                        Some(value) => value,
+                    };
+                    // every pass gets its own variable, closures keep the value they saw
+                    let previous = self.environment.clone();
+                    let mut env = environment::Environment::with_enclosing(previous.clone());
+                    env.define(name.to_string(), &value);
+                    self.environment = Rc::new(RefCell::new(env));
+                    let result = self.interpret_statement(body);
+                    self.environment = previous;
+
+                    match Self::loop_exit(result?, label) {
+                        None => {}
+                        Some(flow) => return Ok(flow),
+                    }
+                }
+                Ok(ControlFlow::Normal)
+            }
+            expr::StatementKind::While { condition, body, increment, label } => {
+                loop {
+                    if !self.interpret_expression(condition)?.is_truthy() {
+                        return Ok(ControlFlow::Normal);
+                    }
+                    let flow = self.interpret_statement(body)?;
+                    match Self::loop_exit(flow, label) {
+                        None => {}
+                        Some(flow) => return Ok(flow),
+                    }
+                    match increment {
+                        None => {}
+                        Some(increment) => {
+                            self.interpret_expression(increment)?;
                         }
                     }
                 }
@@ -228,10 +417,19 @@ impl Interpreter {
         };
     }
 
+    fn loop_exit(flow: ControlFlow, label: &Option<String>) -> Option<ControlFlow> {
+        return match flow {
+            ControlFlow::Normal => None,
+            ControlFlow::Continue(target) if target.is_none() || target == *label => None,
+            ControlFlow::Break(target) if target.is_none() || target == *label => Some(ControlFlow::Normal),
+            other => Some(other),
+        };
+    }
+
     fn lookup(&self, name: String) -> Result<val::Value, val::InterpreterError> {
-        return match self.environment.get(name.as_str()) {
+        return match self.environment.borrow().get(name.as_str()) {
             None => {
-                match self.global.get(name.as_str()) {
+                match self.global.borrow().get(name.as_str()) {
                     None => {
                         Err(val::InterpreterError::MissVariable {
                             name
@@ -248,110 +446,56 @@ impl Interpreter {
         };
     }
 
-    fn interpret_expression(&mut self, expr: &expr::Expression) -> Result<val::Value, val::InterpreterError> {
+    pub fn interpret_expression(&mut self, expr: &expr::Expression) -> Result<val::Value, val::InterpreterError> {
         log::debug!("interpreter expr: {:?}",expr);
-        match expr {
-            expr::Expression::This(this) => {
+        match &expr.kind {
+            expr::ExpressionKind::This(this) => {
                 let result = self.lookup(this.to_string())?;
                 return Ok(result);
             }
-            expr::Expression::Super {
+            expr::ExpressionKind::Super {
                 keyword, method
             } => {
                 let super_class = self.lookup(keyword.to_string())?;
                 return match super_class {
-                    val::Value::LoxInstance {
-                        id, ..
-                    } => {
-                        let instance = self.lox_instances.get(&id).expect("should be exist");
-
-                        let mut func = instance.class.find_method(method.clone()).expect("should contains function: ");
-                        match func {
-                            val::Value::LoxFunc(_, id) => {
-                                let func = self.lox_functions.get_mut(&id).expect("should exist func");
-                                func.bind = Some(super_class)
-                            }
-                            _ => {
-                                panic!("not here")
+                    val::Value::LoxClass(super_class) => {
+                        // only methods, the superclass has no fields of its own to read
+                        let func = match super_class.find_method(method.clone()) {
+                            None => {
+                                return Err(val::InterpreterError::SimpleError(
+                                    format!("Undefined superclass method '{}'.", method)).at(expr.span));
                             }
-                        }
-
-                        return Ok(func);
+                            Some(func) => func,
+                        };
+                        // the superclass's method still runs on the instance the call came from
+                        let this = self.lookup("this".to_string())?;
+                        return Ok(self.bind_method(&this, func));
                     }
                     other => {
                         Err(val::InterpreterError::TypeNotMatch {
                             expected: "LoxClass".to_string(),
-                            found: other,
+                            found: Box::new(other),
                         })
                     }
                 };
             }
-            expr::Expression::Set { object, variable, value } => {
+            expr::ExpressionKind::Set { object, variable, value } => {
                 let obj = self.interpret_expression(object)?;
                 let val = self.interpret_expression(value)?;
-                return match obj {
-                    val::Value::LoxInstance {
-                        id, ..
-                    } => {
-                        return match self.lox_instances.get_mut(&id) {
-                            None => {
-                                Err(val::InterpreterError::SimpleError(format!("miss instance: {:?}", id)))
-                            }
-                            Some(mut instance) => {
-                                instance.set(variable, val);
-                                Ok(val::Value::Nil)
-                            }
-                        };
-                    }
-                    _ => {
-                        Err(val::InterpreterError::SimpleError("should be call in instance".to_string()))
-                    }
-                };
+                self.set_property(obj, variable, val)?;
+                return Ok(val::Value::Nil);
             }
-            expr::Expression::Get { object, variable } => {
-                let obj = self.interpret_expression(object)?;
-                let variable = variable.as_str();
-                let result = match obj {
-                    val::Value::LoxInstance {
-                        id, ..
-                    } => {
-                        return match self.lox_instances.get(&id) {
-                            None => {
-                                Err(val::InterpreterError::SimpleError(format!("miss instance: {:?}", id)))
-                            }
-                            Some(instance) => {
-                                match instance.get(variable) {
-                                    None => {
-                                        Err(val::InterpreterError::SimpleError(format!("miss variable: {} in {:?}", variable, instance)))
-                                    }
-                                    Some(val) => {
-                                        return match val {
-                                            // bind instance
-                                            val::Value::LoxFunc(_, ref func_id) => {
-                                                let lox_func = self.lox_functions.get_mut(func_id).unwrap();
-                                                lox_func.bind = Some(obj.clone());
-                                                Ok(val.clone())
-                                            }
-                                            _ => {
-                                                Ok(val.clone())
-                                            }
-                                        };
-                                    }
-                                }
-                            }
-                        };
-                    }
-                    _ => {
-                        Err(val::InterpreterError::SimpleError("should be call in instance".to_string()))
-                    }
-                }?;
-                Ok(result)
+            expr::ExpressionKind::Get { .. } => {
+                return Ok(self.interpret_chain(expr)?.unwrap_or(val::Value::Nil));
             }
-            expr::Expression::Literal(l) => {
+            expr::ExpressionKind::Literal(l) => {
                 return match l {
                     expr::Literal::String(s) => {
                         Ok(val::Value::String(s.to_string()))
                     }
+                    expr::Literal::Int(n) => {
+                        Ok(val::Value::Int(*n))
+                    }
                     expr::Literal::Number(n) => {
                         Ok(val::Value::Number(*n))
                     }
@@ -366,208 +510,68 @@ impl Interpreter {
                     }
                 };
             }
-            expr::Expression::Grouping(expr) => {
+            expr::ExpressionKind::Grouping(expr) => {
                 self.interpret_expression(expr)
             }
 
-            expr::Expression::Binary(left, op, right) => {
+            expr::ExpressionKind::Binary(left, op, right) => {
                 let left = self.interpret_expression(left)?;
                 let right = self.interpret_expression(right)?;
-                return match op.token_type {
+                let result = match op.token_type {
                     expr::BinaryOperatorType::EqualEqual => {
                         Ok(val::Value::Bool(left.eq(&right)))
                     }
                     expr::BinaryOperatorType::NotEqual => {
                         Ok(val::Value::Bool(!left.eq(&right)))
                     }
+                    // the same operators the vm uses, so NaN compares false every way
                     expr::BinaryOperatorType::Less => {
-                        match left.partial_cmp(&right) {
-                            None => {
-                                Err(val::InterpreterError::OperatorNotMatch {
-                                    left,
-                                    right,
-                                    opt: expr::BinaryOperatorType::Less,
-                                })
-                            }
-                            Some(ord) => {
-                                return Ok(val::Value::Bool(ord == Ordering::Less));
-                            }
-                        }
+                        val::check_comparable(expr::BinaryOperatorType::Less, &left, &right)
+                            .map(|_| val::Value::Bool(left < right))
                     }
                     expr::BinaryOperatorType::LessEqual => {
-                        match left.partial_cmp(&right) {
-                            None => {
-                                Err(val::InterpreterError::OperatorNotMatch {
-                                    left,
-                                    right,
-                                    opt: expr::BinaryOperatorType::LessEqual,
-                                })
-                            }
-                            Some(ord) => {
-                                return Ok(val::Value::Bool(ord == Ordering::Less || left.eq(&right)));
-                            }
-                        }
+                        val::check_comparable(expr::BinaryOperatorType::LessEqual, &left, &right)
+                            .map(|_| val::Value::Bool(left <= right))
                     }
                     expr::BinaryOperatorType::Greater => {
-                        match left.partial_cmp(&right) {
-                            None => {
-                                Err(val::InterpreterError::OperatorNotMatch {
-                                    left,
-                                    right,
-                                    opt: expr::BinaryOperatorType::Greater,
-                                })
-                            }
-                            Some(ord) => {
-                                return Ok(val::Value::Bool(ord == Ordering::Greater));
-                            }
-                        }
+                        val::check_comparable(expr::BinaryOperatorType::Greater, &left, &right)
+                            .map(|_| val::Value::Bool(left > right))
                     }
                     expr::BinaryOperatorType::GreaterEqual => {
-                        return match left.partial_cmp(&right) {
-                            None => {
-                                Err(val::InterpreterError::OperatorNotMatch {
-                                    left,
-                                    right,
-                                    opt: expr::BinaryOperatorType::GreaterEqual,
-                                })
-                            }
-                            Some(ord) => {
-                                Ok(val::Value::Bool(ord == Ordering::Greater || left.eq(&right)))
-                            }
-                        };
+                        val::check_comparable(expr::BinaryOperatorType::GreaterEqual, &left, &right)
+                            .map(|_| val::Value::Bool(left >= right))
                     }
-                    expr::BinaryOperatorType::Plus => {
-                        match left {
-                            val::Value::Number(x) => {
-                                match right {
-                                    val::Value::Number(y) => {
-                                        Ok(val::Value::Number(x + y))
-                                    }
-                                    _ => {
-                                        Err(val::InterpreterError::OperatorNotMatch {
-                                            left,
-                                            right,
-                                            opt: expr::BinaryOperatorType::Plus,
-                                        })
-                                    }
-                                }
-                            }
-                            val::Value::String(x) => {
-                                match right {
-                                    val::Value::String(y) => {
-                                        Ok(val::Value::String((x.to_owned() + y.as_str()).to_string()))
-                                    }
-                                    _ => {
-                                        Err(val::InterpreterError::OperatorNotMatch {
-                                            left: val::Value::String(x),
-                                            right,
-                                            opt: expr::BinaryOperatorType::Plus,
-                                        })
-                                    }
-                                }
-                            }
-                            _ => {
-                                Err(val::InterpreterError::OperatorNotMatch {
-                                    left,
-                                    right,
-                                    opt: expr::BinaryOperatorType::Plus,
-                                })
-                            }
-                        }
+                    expr::BinaryOperatorType::In => {
+                        val::contains(&right, &left).map(val::Value::Bool)
                     }
-                    expr::BinaryOperatorType::Minus => {
-                        match left {
-                            val::Value::Number(x) => {
-                                match right {
-                                    val::Value::Number(y) => {
-                                        Ok(val::Value::Number(x - y))
-                                    }
-                                    _ => {
-                                        Err(val::InterpreterError::OperatorNotMatch {
-                                            left,
-                                            right,
-                                            opt: expr::BinaryOperatorType::Minus,
-                                        })
-                                    }
-                                }
-                            }
-                            _ => {
-                                Err(val::InterpreterError::OperatorNotMatch {
-                                    left,
-                                    right,
-                                    opt: expr::BinaryOperatorType::Minus,
-                                })
+                    expr::BinaryOperatorType::Is => {
+                        match &right {
+                            val::Value::LoxClass(class) => {
+                                let is = match &left {
+                                    val::Value::LoxInstance { id, .. } => self.lox_instances.get(id)
+                                        .is_some_and(|instance| instance.class.inherits(class)),
+                                    _ => false,
+                                };
+                                Ok(val::Value::Bool(is))
                             }
+                            other => Err(val::is_not_a_class(other)),
                         }
                     }
-                    expr::BinaryOperatorType::Star => {
-                        match left {
-                            val::Value::Number(x) => {
-                                match right {
-                                    val::Value::Number(y) => {
-                                        Ok(val::Value::Number(x * y))
-                                    }
-                                    _ => {
-                                        Err(val::InterpreterError::OperatorNotMatch {
-                                            left,
-                                            right,
-                                            opt: expr::BinaryOperatorType::Minus,
-                                        })
-                                    }
-                                }
-                            }
-                            _ => {
-                                Err(val::InterpreterError::OperatorNotMatch {
-                                    left,
-                                    right,
-                                    opt: expr::BinaryOperatorType::Star,
-                                })
-                            }
-                        }
-                    }
-                    expr::BinaryOperatorType::Slash => {
-                        match left {
-                            val::Value::Number(x) => {
-                                match right {
-                                    val::Value::Number(y) => {
-                                        Ok(val::Value::Number(x / y))
-                                    }
-                                    _ => {
-                                        Err(val::InterpreterError::OperatorNotMatch {
-                                            left,
-                                            right,
-                                            opt: expr::BinaryOperatorType::Slash,
-                                        })
-                                    }
-                                }
-                            }
-                            _ => {
-                                Err(val::InterpreterError::OperatorNotMatch {
-                                    left,
-                                    right,
-                                    opt: expr::BinaryOperatorType::Slash,
-                                })
-                            }
-                        }
+                    expr::BinaryOperatorType::Plus
+                    | expr::BinaryOperatorType::Minus
+                    | expr::BinaryOperatorType::Star
+                    | expr::BinaryOperatorType::Slash => {
+                        val::arithmetic(op.token_type, left, right)
                     }
                 };
+                return result.map_err(|e| e.at(op.span));
             }
 
-            expr::Expression::Unary(opt, expr) => {
+            expr::ExpressionKind::Unary(opt, expr) => {
                 let value = self.interpret_expression(expr)?;
-                return match opt.token_type {
+                let result = match opt.token_type {
                     expr::UnaryOperatorType::Minus => {
-                        match value {
-                            val::Value::Number(n) => {
-                                Ok(val::Value::Number(-n))
-                            }
-                            other => {
-                                Err(val::InterpreterError::TypeNotMatch {
-                                    expected: "want val::Value::Number".to_string(),
-                                    found: other,
-                                })
-                            }
-                        }
+                        val::negate(value)
                     }
                     expr::UnaryOperatorType::Bang => {
                         match value {
@@ -580,20 +584,21 @@ impl Interpreter {
                             other => {
                                 Err(val::InterpreterError::TypeNotMatch {
                                     expected: "want val::Value::Bool".to_string(),
-                                    found: other,
+                                    found: Box::new(other),
                                 })
                             }
                         }
                     }
                 };
+                return result.map_err(|e| e.at(opt.span));
             }
 
-            expr::Expression::Variable(name) => {
-                match self.environment.get(name) {
+            expr::ExpressionKind::Variable(name) => {
+                match self.environment.borrow().get(name) {
                     None => {
                         Err(val::InterpreterError::MissVariable {
                             name: name.to_string()
-                        })
+                        }.at(expr.span))
                     }
                     Some(val) => {
                         Ok(val.clone())
@@ -601,62 +606,1391 @@ impl Interpreter {
                 }
             }
 
-            expr::Expression::Assign(name, expr) => {
-                let val = self.interpret_expression(expr)?;
-                return match self.environment.assign(name.to_string(), &val) {
+            expr::ExpressionKind::Assign(name, value) => {
+                let val = self.interpret_expression(value)?;
+                return match self.environment.borrow_mut().assign(name.to_string(), &val) {
                     Ok(_) => {
                         Ok(val)
                     }
                     Err(_) => {
-                        Err(val::InterpreterError::MissVariable {
+                        Err(val::InterpreterError::UndeclaredAssign {
                             name: name.to_string()
-                        })
+                        }.at(expr.span))
                     }
                 };
             }
-            expr::Expression::Logical(left, opt, right) => {
+            expr::ExpressionKind::Logical(left, opt, right) => {
                 let l = self.interpret_expression(left)?;
                 match opt {
                     expr::LogicalOperatorType::And => {
-                        match l {
-                            val::Value::Bool(b) => {
-                                if !b {
-                                    return Ok(l);
-                                }
-                            }
-                            _ => {}
+                        if !l.is_truthy() {
+                            return Ok(l);
                         }
                     }
                     expr::LogicalOperatorType::Or => {
-                        match l {
-                            val::Value::Bool(b) => {
-                                if b {
-                                    return Ok(l);
-                                }
-                            }
-                            _ => {}
+                        if l.is_truthy() {
+                            return Ok(l);
                         }
                     }
                 };
 
                 return self.interpret_expression(right);
             }
-            expr::Expression::Call(callee, name, args) => {
-                let callee = self.interpret_expression(callee)?;
-                let mut arguments = vec![];
-                for a in args {
-                    arguments.push(self.interpret_expression(a)?);
+            expr::ExpressionKind::Call(..) | expr::ExpressionKind::Index { .. } => {
+                return Ok(self.interpret_chain(expr)?.unwrap_or(val::Value::Nil));
+            }
+            expr::ExpressionKind::Array(elements) => {
+                let mut values = vec![];
+                for element in elements {
+                    values.push(self.interpret_expression(element)?);
+                }
+                return Ok(val::Value::Array(Rc::new(RefCell::new(values))));
+            }
+            expr::ExpressionKind::Map(entries) => {
+                let mut map = LoxMap::new();
+                for (key, value) in entries {
+                    let key = self.interpret_expression(key)?;
+                    let value = self.interpret_expression(value)?;
+                    map.insert(key, value)?;
                 }
+                return Ok(val::Value::Map(Rc::new(RefCell::new(map))));
+            }
+            expr::ExpressionKind::SetIndex { object, index, value } => {
+                let object = self.interpret_expression(object)?;
+                let index = self.interpret_expression(index)?;
+                let value = self.interpret_expression(value)?;
+                match object {
+                    val::Value::LoxInstance { .. } => self.set_property(object, val::property_name(&index)?, value.clone())?,
+                    _ => val::index_set(&object, &index, value.clone())?,
+                }
+                return Ok(value);
+            }
+            expr::ExpressionKind::Spread(_) => {
+                return Err(val::InterpreterError::SimpleError("Can only spread call arguments.".to_string()));
+            }
+        }
+    }
+
+    fn interpret_chain(&mut self, expr: &expr::Expression) -> Result<Option<val::Value>, val::InterpreterError> {
+        return match &expr.kind {
+            expr::ExpressionKind::Get { object, variable, optional } => {
+                let obj = match self.interpret_chain(object)? {
+                    None => return Ok(None),
+                    Some(obj) => obj,
+                };
+                if *optional && matches!(obj, val::Value::Nil) {
+                    return Ok(None);
+                }
+                Ok(Some(self.get_property(obj, variable)?))
+            }
+            expr::ExpressionKind::Call(callee, _, args) => {
+                return match self.prepare_call(callee, args)? {
+                    None => Ok(None),
+                    Some(Call::Function(callee, arguments)) => Ok(Some(self.call_with(callee, arguments)?)),
+                    Some(Call::Handled(value)) => Ok(Some(value)),
+                };
+            }
+            expr::ExpressionKind::Index { object, index } => {
+                let object = match self.interpret_chain(object)? {
+                    None => return Ok(None),
+                    Some(object) => object,
+                };
+                let index = self.interpret_expression(index)?;
+                match object {
+                    val::Value::LoxInstance { .. } => Ok(Some(self.get_property(object, val::property_name(&index)?)?)),
+                    _ => Ok(Some(val::index_get(&object, &index)?)),
+                }
+            }
+            _ => Ok(Some(self.interpret_expression(expr)?)),
+        };
+    }
+
+    fn prepare_call(&mut self, callee: &expr::Expression, args: &[expr::Expression]) -> Result<Option<Call>, val::InterpreterError> {
+        if let expr::ExpressionKind::Get { object, variable, optional } = &callee.kind {
+            let obj = match self.interpret_chain(object)? {
+                None => return Ok(None),
+                Some(obj) => obj,
+            };
+            if *optional && matches!(obj, val::Value::Nil) {
+                return Ok(None);
+            }
+            match self.lookup_property(&obj, variable)? {
+                None => {}
+                Some(method) => return Ok(Some(Call::Function(method, self.arguments(args)?))),
+            }
+            let arguments = self.arguments(args)?;
+            return Ok(Some(Call::Handled(self.method_missing(obj, variable, arguments)?)));
+        }
+        return match self.interpret_chain(callee)? {
+            None => Ok(None),
+            Some(callee) => Ok(Some(Call::Function(callee, self.arguments(args)?))),
+        };
+    }
+
+    fn get_property(&mut self, obj: val::Value, variable: &str) -> Result<val::Value, val::InterpreterError> {
+        return match self.lookup_property(&obj, variable)? {
+            None => self.method_missing(obj, variable, vec![]),
+            Some(value) => Ok(value),
+        };
+    }
+
+    fn method_missing(&mut self, obj: val::Value, variable: &str, arguments: Vec<val::Value>) -> Result<val::Value, val::InterpreterError> {
+        let id = match obj {
+            val::Value::LoxInstance { id, .. } => id,
+            _ => return Err(val::InterpreterError::SimpleError("should be call in instance".to_string())),
+        };
+        let instance = match self.lox_instances.get(&id) {
+            None => return Err(val::InterpreterError::SimpleError(format!("miss instance: {:?}", id))),
+            Some(instance) => instance,
+        };
+        let func_id = match instance.class.find_method("methodMissing".to_string()) {
+            Some(val::Value::LoxFunc(_, func_id)) => func_id,
+            _ => return Err(val::InterpreterError::SimpleError(format!("miss variable: {} in {:?}", variable, instance))),
+        };
+        // a miss on the instance whose methodMissing is running would go round forever
+        if self.missing.contains(&id) {
+            return Err(val::InterpreterError::SimpleError(format!("Undefined property '{}' inside methodMissing.", variable)));
+        }
+        self.lox_functions.get_mut(&func_id).unwrap().bind = Some(obj);
+        let hook = val::Value::LoxFunc("methodMissing".to_string(), func_id);
+        let arguments = vec![val::Value::String(variable.to_string()), val::Value::Array(Rc::new(RefCell::new(arguments)))];
+        self.missing.push(id);
+        let result = self.call_with(hook, arguments);
+        self.missing.pop();
+        return result;
+    }
+
+    fn lookup_property(&mut self, obj: &val::Value, variable: &str) -> Result<Option<val::Value>, val::InterpreterError> {
+        let result = match obj {
+            val::Value::LoxInstance {
+                id, ..
+            } => {
+                // fields set by a superclass init live in the parent instances
+                let id = self.field_owner(*id, variable).unwrap_or(*id);
+                return match self.lox_instances.get(&id) {
+                    None => {
+                        Err(val::InterpreterError::SimpleError(format!("miss instance: {:?}", id)))
+                    }
+                    Some(instance) => {
+                        match instance.get(variable) {
+                            None => Ok(None),
+                            // a function stored in a field is called as it is
+                            Some(val) if instance.has_field(variable) => Ok(Some(val)),
+                            Some(method) => Ok(Some(self.bind_method(obj, method))),
+                        }
+                    }
+                };
+            }
+            val::Value::LoxClass(class) => {
+                return match class.find_static(variable) {
+                    None => Err(val::InterpreterError::SimpleError(
+                        format!("Undefined property '{}' on class {}.", variable, class.name))),
+                    Some(val) => Ok(Some(val)),
+                };
+            }
+            _ => {
+                Err(val::InterpreterError::SimpleError("should be call in instance".to_string()))
+            }
+        }?;
+        Ok(result)
+    }
+
+    fn bind_method(&self, receiver: &val::Value, method: val::Value) -> val::Value {
+        let class = match receiver {
+            val::Value::LoxInstance { id, .. } => match self.lox_instances.get(id) {
+                None => return method,
+                Some(instance) => instance.class.name.clone(),
+            },
+            _ => return method,
+        };
+        return match method {
+            val::Value::LoxFunc(name, id) => {
+                val::Value::LoxBoundMethod { receiver: Box::new(receiver.clone()), class, name, id }
+            }
+            other => other,
+        };
+    }
 
-                return match Self::cast_callable(self, &callee) {
+    fn set_property(&mut self, obj: val::Value, variable: &str, val: val::Value) -> Result<(), val::InterpreterError> {
+        return match obj {
+            val::Value::LoxInstance {
+                id, ..
+            } => {
+                let id = self.field_owner(id, variable).unwrap_or(id);
+                return match self.lox_instances.get_mut(&id) {
                     None => {
-                        panic!("should be callable")
+                        Err(val::InterpreterError::SimpleError(format!("miss instance: {:?}", id)))
                     }
-                    Some(callable) => {
-                        callable.call(self, arguments)
+                    Some(instance) => {
+                        instance.set(variable, val);
+                        Ok(())
                     }
                 };
             }
+            // a subclass gets its own, leaving the superclass's alone
+            val::Value::LoxClass(class) => {
+                class.statics.borrow_mut().insert(variable.to_string(), val);
+                Ok(())
+            }
+            _ => {
+                Err(val::InterpreterError::SimpleError("should be call in instance".to_string()))
+            }
+        };
+    }
+
+    fn field_owner(&self, id: usize, name: &str) -> Option<usize> {
+        let mut part = Some(id);
+        while let Some(current) = part {
+            let instance = self.lox_instances.get(&current)?;
+            if instance.has_field(name) {
+                return Some(current);
+            }
+            part = instance.parent;
+        }
+        None
+    }
+
+    fn arguments(&mut self, args: &[expr::Expression]) -> Result<Vec<val::Value>, val::InterpreterError> {
+        let mut arguments = vec![];
+        for a in args {
+            match &a.kind {
+                expr::ExpressionKind::Spread(spread) => {
+                    match self.interpret_expression(spread)? {
+                        val::Value::Array(items) => arguments.extend(items.borrow().iter().cloned()),
+                        other => {
+                            return Err(val::InterpreterError::SimpleError(
+                                format!("Can only spread an array, found {}.", other.type_name())).at(a.span));
+                        }
+                    }
+                }
+                _ => arguments.push(self.interpret_expression(a)?),
+            }
+        }
+        return Ok(arguments);
+    }
+
+    pub fn call_method(&mut self, obj: &val::Value, name: &str) -> Result<val::Value, val::InterpreterError> {
+        let method = self.get_property(obj.clone(), name)?;
+        return self.call_with(method, vec![]);
+    }
+
+    pub fn call_with(&mut self, callee: val::Value, arguments: Vec<val::Value>) -> Result<val::Value, val::InterpreterError> {
+        return match Self::cast_callable(self, &callee) {
+            None => {
+                Err(val::InterpreterError::SimpleError("Can only call functions and classes.".to_string()))
+            }
+            Some(callable) => {
+                match callee {
+                    // natives check their own arity, some take optional arguments
+                    val::Value::LoxFunc(..) | val::Value::LoxBoundMethod { .. } if callable.arity(self) != arguments.len() => {
+                        return Err(val::InterpreterError::SimpleError(
+                            format!("Expected {} arguments but got {}.", callable.arity(self), arguments.len())));
+                    }
+                    _ => {}
+                }
+                callable.call(self, arguments)
+            }
+        };
+    }
+}
+
+impl stdlib::Caller for Interpreter {
+    fn call(&mut self, callee: val::Value, args: Vec<val::Value>) -> Result<val::Value, val::InterpreterError> {
+        return self.call_with(callee, args);
+    }
+
+    // a failed call already puts back the scope, call depth and methodMissing guard it changed
+    fn try_call(&mut self, callee: val::Value, args: Vec<val::Value>) -> Result<val::Value, val::InterpreterError> {
+        return self.call_with(callee, args);
+    }
+
+    fn output(&self) -> Output {
+        return self.output.clone();
+    }
+
+    fn sandboxed(&self) -> bool {
+        return self.sandbox;
+    }
+
+    fn rng(&mut self) -> &mut Rng {
+        return &mut self.rng;
+    }
+
+    fn tally(&mut self) -> &mut Tally {
+        return &mut self.tally;
+    }
+
+    fn determinism(&mut self) -> Option<&mut Determinism> {
+        return self.determinism.as_mut();
+    }
+
+    fn heap_stats(&self) -> HeapStats {
+        return gc::stats(self, self.collections);
+    }
+
+    fn collect_garbage(&mut self) {
+        self.gc_requested = true;
+    }
+
+    fn globals(&self) -> Vec<(String, val::Value)> {
+        return self.global.borrow().values.iter().map(|(name, value)| (name.clone(), value.clone())).collect();
+    }
+
+    fn shape(&self, value: &val::Value) -> Option<Shape> {
+        return match value {
+            val::Value::LoxInstance { id, .. } => {
+                let instance = self.lox_instances.get(id)?;
+                // the superclass parts keep their fields in instances of their own,
+                // the nearest part wins
+                let mut fields = BTreeMap::new();
+                let mut part = Some(*id);
+                while let Some(current) = part {
+                    let instance = self.lox_instances.get(&current)?;
+                    for (name, value) in instance.fields() {
+                        fields.entry(name.clone()).or_insert_with(|| value.clone());
+                    }
+                    part = instance.parent;
+                }
+                Some(Shape::Instance { id: *id, class: instance.class.name.clone(), fields: fields.into_iter().collect() })
+            }
+            val::Value::LoxFunc(name, id) | val::Value::LoxBoundMethod { name, id, .. } => {
+                let arity = self.lox_functions.get(id)?.parameters.len();
+                Some(Shape::Function { name: name.clone(), arity })
+            }
+            val::Value::LoxNative(native) => Some(Shape::Function { name: native.name.clone(), arity: native.arity }),
+            val::Value::LoxClass(class) => Some(Shape::Class { name: class.name.clone() }),
+            _ => None,
+        };
+    }
+
+    fn remove_field(&mut self, instance: &val::Value, name: &str) -> Option<val::Value> {
+        return match instance {
+            val::Value::LoxInstance { id, .. } => {
+                let owner = self.field_owner(*id, name)?;
+                self.lox_instances.get_mut(&owner)?.remove(name)
+            }
+            _ => None,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use std::time::{Duration, Instant};
+
+    use crate::process::interpreter::Interpreter;
+    use crate::process::parser::Parser;
+    use crate::process::scanner;
+    use crate::runtime::support::{both_engines, runtime_error};
+    use crate::runtime::{Runtime, VMRuntime};
+    use crate::types::val::Value;
+
+    fn run(source: &str) -> Interpreter {
+        let tokens = scanner::scan_tokens(source.to_string()).unwrap();
+        let statements = Parser::new(tokens).parse().unwrap();
+        let mut interpreter = Interpreter::default();
+        for statement in statements {
+            interpreter.interpret_statement(&statement).unwrap();
         }
+        interpreter
+    }
+
+    fn global(interpreter: &Interpreter, name: &str) -> Value {
+        interpreter.global.borrow().get(name).expect("should be defined")
+    }
+
+    #[test]
+    fn block_variable_shadows_outer() {
+        let interpreter = run("var a = 1; var inner; { var a = 2; inner = a; } var outer = a;");
+        assert_eq!(global(&interpreter, "inner"), Value::Number(2.0));
+        assert_eq!(global(&interpreter, "outer"), Value::Number(1.0));
+    }
+
+    #[test]
+    fn block_assignment_updates_outer() {
+        let interpreter = run("var a = 1; { a = 3; } var b = 1; fun f() { b = 5; } { f(); }");
+        assert_eq!(global(&interpreter, "a"), Value::Number(3.0));
+        assert_eq!(global(&interpreter, "b"), Value::Number(5.0));
+    }
+
+    #[test]
+    fn arithmetic_follows_numeric_tower() {
+        let interpreter = run(r#"
+            var a = 1 + 2;
+            var b = 7 / 2;
+            var c = 1 + 0.5;
+            var d = 2 * 3 - 10;
+            var e = -1 + 2;
+            var f = 2.0 * 3;
+            var g = 1 == 1.0;
+            var h = 2 < 2.5;
+            var i = 10 / 4 * 2;
+            var j = 9223372036854775807;
+        "#);
+        for (name, expected) in [
+            ("a", "Int(3)"),
+            ("b", "Number(3.5)"),
+            ("c", "Number(1.5)"),
+            ("d", "Int(-4)"),
+            ("e", "Int(1)"),
+            ("f", "Number(6.0)"),
+            ("g", "Bool(true)"),
+            ("h", "Bool(true)"),
+            ("i", "Number(5.0)"),
+            ("j", "Int(9223372036854775807)"),
+        ] {
+            assert_eq!(format!("{:?}", global(&interpreter, name)), expected, "{}", name);
+        }
+
+        let tokens = scanner::scan_tokens("9223372036854775807 + 1;".to_string()).unwrap();
+        let statements = Parser::new(tokens).parse().unwrap();
+        let err = Interpreter::default().interpret(&statements).unwrap_err();
+        assert!(err.to_string().starts_with("Simple Error: Integer overflow"));
+    }
+
+    #[test]
+    fn optional_get_short_circuits_chain() {
+        let interpreter = run(r#"
+            class Node {
+                get() { return this; }
+            }
+            var n = Node();
+            n.value = 1;
+            n.next = nil;
+            var a = nil;
+            var r1 = a?.b;
+            var r2 = a?.b.c;
+            var r3 = n?.value;
+            var r4 = n.next?.value;
+            var r5 = a?.m();
+            var r6 = n?.get().value;
+            var r7 = n.next?.value == nil;
+        "#);
+        for (name, expected) in [
+            ("r1", Value::Nil),
+            ("r2", Value::Nil),
+            ("r3", Value::Int(1)),
+            ("r4", Value::Nil),
+            ("r5", Value::Nil),
+            ("r6", Value::Int(1)),
+            ("r7", Value::Bool(true)),
+        ] {
+            assert_eq!(global(&interpreter, name), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn clone_copies_instance_fields() {
+        let interpreter = run(r#"
+            class Point {}
+            var a = Point();
+            a.x = 1;
+            var b = clone(a);
+            b.x = 2;
+            b.y = 3;
+            var ax = a.x;
+            var bx = b.x;
+            var by = b.y;
+            var n = clone(5);
+            var s = clone("lox");
+        "#);
+        for (name, expected) in [
+            ("ax", Value::Int(1)),
+            ("bx", Value::Int(2)),
+            ("by", Value::Int(3)),
+            ("n", Value::Int(5)),
+            ("s", Value::String("lox".to_string())),
+        ] {
+            assert_eq!(global(&interpreter, name), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn clones_own_the_fields_superclasses_set() {
+        let source = r#"
+            class A { init() { this.x = 1; } }
+            class B < A { init() { super.init(); } }
+            class C < B { init() { super.init(); this.y = 1; } }
+            var b = B();
+            var k = clone(b);
+            k.x = 2;
+            print b.x, k.x;
+            var c = C();
+            var d = clone(c);
+            d.x = 3;
+            d.y = 4;
+            print c.x, c.y, d.x, d.y, d is A;
+        "#;
+        let (out, vm_out) = both_engines(source);
+        assert_eq!(out, "1 2\n1 1 3 4 true\n");
+        assert_eq!(vm_out, "1 2\n1 1 3 4 true\n");
+    }
+
+    #[test]
+    fn in_checks_membership() {
+        let interpreter = run(r#"
+            var a = "ell" in "hello";
+            var b = "z" in "hello";
+            var c = !("z" in "hello");
+            var d = "he" + "l" in "hello";
+        "#);
+        for (name, expected) in [
+            ("a", Value::Bool(true)),
+            ("b", Value::Bool(false)),
+            ("c", Value::Bool(true)),
+            ("d", Value::Bool(true)),
+        ] {
+            assert_eq!(global(&interpreter, name), expected, "{}", name);
+        }
+
+        let tokens = scanner::scan_tokens("1 in 2;".to_string()).unwrap();
+        let statements = Parser::new(tokens).parse().unwrap();
+        let err = Interpreter::default().interpret(&statements).unwrap_err();
+        assert!(err.to_string().contains("must be a container, found int"));
+    }
+
+    #[test]
+    fn arrays_are_shared_and_mutable() {
+        let interpreter = run(r#"
+            var xs = [3, 1, 2];
+            var ys = xs;
+            push(ys, 0);
+            sort(ys);
+            var first = xs[0];
+            xs[1] = 10;
+            var second = ys[1];
+            var removed = removeAt(xs, 3);
+            var at = indexOf(xs, 10);
+            var has = 2 in xs;
+            var copy = clone(xs);
+            push(copy, 5);
+            var len = push(xs, 4);
+        "#);
+        for (name, expected) in [
+            ("first", Value::Int(0)),
+            ("second", Value::Int(10)),
+            ("removed", Value::Int(3)),
+            ("at", Value::Int(1)),
+            ("has", Value::Bool(true)),
+            ("len", Value::Int(4)),
+        ] {
+            assert_eq!(global(&interpreter, name), expected, "{}", name);
+        }
+        assert_eq!(global(&interpreter, "xs").to_string(), "[0, 10, 2, 4]");
+    }
+
+    #[test]
+    fn maps_keep_insertion_order() {
+        let interpreter = run(r#"
+            var m = {"b": 1, "a": 2};
+            m["c"] = 3;
+            m["b"] = 10;
+            var b = m["b"];
+            var missing = m["z"];
+            var found = "a" in m;
+            var removed = remove(m, "a");
+            var gone = remove(m, "a");
+            var still = has(m, "a");
+            var merged = merge(m, {"c": 30, "d": 4});
+            var order = keys(merged);
+            var vals = values(m);
+        "#);
+        for (name, expected) in [
+            ("b", Value::Int(10)),
+            ("missing", Value::Nil),
+            ("found", Value::Bool(true)),
+            ("removed", Value::Int(2)),
+            ("gone", Value::Nil),
+            ("still", Value::Bool(false)),
+        ] {
+            assert_eq!(global(&interpreter, name), expected, "{}", name);
+        }
+        assert_eq!(global(&interpreter, "order").to_string(), "[b, c, d]");
+        assert_eq!(global(&interpreter, "vals").to_string(), "[10, 3]");
+        assert_eq!(global(&interpreter, "m").to_string(), "{b: 10, c: 3}");
+    }
+
+    #[test]
+    fn init_returns_this_after_early_return() {
+        let interpreter = run(r#"
+            class Point {
+                init(x) {
+                    this.x = x;
+                    if (x > 0) return;
+                    this.x = 0;
+                }
+            }
+            fun make() {
+                var p = Point(1);
+                while (true) {
+                    return p;
+                }
+            }
+            var p = make();
+            var x = p.x;
+            var again = p.init(-1);
+            var reset = p.x;
+            var same = again.x;
+        "#);
+        for (name, expected) in [
+            ("x", Value::Int(1)),
+            ("reset", Value::Int(0)),
+            ("same", Value::Int(0)),
+        ] {
+            assert_eq!(global(&interpreter, name), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn negative_indexes_count_from_the_end() {
+        let interpreter = run(r#"
+            var xs = [1, 2, 3];
+            var last = xs[-1];
+            xs[-3] = 0;
+            var first = xs[0];
+            var ch = "héllo"[-4];
+            var tail = slice(xs, -2, nil);
+        "#);
+        for (name, expected) in [
+            ("last", Value::Int(3)),
+            ("first", Value::Int(0)),
+            ("ch", Value::String("é".to_string())),
+        ] {
+            assert_eq!(global(&interpreter, name), expected, "{}", name);
+        }
+        assert_eq!(global(&interpreter, "tail").to_string(), "[2, 3]");
+    }
+
+    #[test]
+    fn arrays_concat_and_compare_by_value() {
+        let interpreter = run(r#"
+            var a = [1, 2];
+            var b = [3];
+            var c = a + b;
+            var same = [1, [2]] == [1, [2]];
+            var differ = [1, [2]] != [1, [3]];
+        "#);
+        assert_eq!(global(&interpreter, "c").to_string(), "[1, 2, 3]");
+        assert_eq!(global(&interpreter, "a").to_string(), "[1, 2]");
+        assert_eq!(global(&interpreter, "same"), Value::Bool(true));
+        assert_eq!(global(&interpreter, "differ"), Value::Bool(true));
+    }
+
+    #[test]
+    fn strings_repeat_with_star() {
+        let interpreter = run(r#"
+            var a = "ab" * 3;
+            var b = 3 * "x";
+            var c = 2 * 3;
+        "#);
+        assert_eq!(global(&interpreter, "a"), Value::String("ababab".to_string()));
+        assert_eq!(global(&interpreter, "b"), Value::String("xxx".to_string()));
+        assert_eq!(global(&interpreter, "c"), Value::Int(6));
+    }
+
+    #[test]
+    fn for_in_walks_ranges_and_collections() {
+        let interpreter = run(r#"
+            var sum = 0;
+            for (var i in range(1, 5)) sum = sum + i;
+            var down = [];
+            for (var i in range(5, 0, -2)) push(down, i);
+            var seen = 0;
+            for (var i in range(0, 1000000000000)) {
+                var doubled = i * 2;
+                if (i == 3) break;
+                seen = seen + 1;
+            }
+            var chars = "";
+            for (var c in "héllo") chars = c + chars;
+            var keys = "";
+            for (var k in {"a": 1, "b": 2}) keys = keys + k;
+            var n = 0;
+            while (true) {
+                n = n + 1;
+                if (n == 4) break;
+            }
+            var size = len(range(0, 10, 3));
+        "#);
+        for (name, expected) in [
+            ("sum", Value::Int(10)),
+            ("seen", Value::Int(3)),
+            ("chars", Value::String("olléh".to_string())),
+            ("keys", Value::String("ab".to_string())),
+            ("n", Value::Int(4)),
+            ("size", Value::Int(4)),
+        ] {
+            assert_eq!(global(&interpreter, name), expected, "{}", name);
+        }
+        assert_eq!(global(&interpreter, "down").to_string(), "[5, 3, 1]");
+    }
+
+    #[test]
+    fn optional_chain_stops_at_first_nil_link() {
+        let interpreter = run(r#"
+            class Node {}
+            var a = Node();
+            a.next = Node();
+            a.next.value = 7;
+            var present = a?.next?.value;
+            a.next.next = nil;
+            var cut = a?.next?.next?.value;
+            var missing = nil;
+            var root = missing?.next?.value;
+        "#);
+        for (name, expected) in [
+            ("present", Value::Int(7)),
+            ("cut", Value::Nil),
+            ("root", Value::Nil),
+        ] {
+            assert_eq!(global(&interpreter, name), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn for_in_uses_iterate_and_next() {
+        let interpreter = run(r#"
+            class Cursor {
+                next() {
+                    if (this.node == nil) return nil;
+                    var value = this.node.value;
+                    this.node = this.node.rest;
+                    return value;
+                }
+            }
+            class LinkedList {
+                add(value) {
+                    var node = Cursor();
+                    node.value = value;
+                    node.rest = this.head;
+                    this.head = node;
+                }
+                iterate() {
+                    var cursor = Cursor();
+                    cursor.node = this.head;
+                    return cursor;
+                }
+            }
+            var list = LinkedList();
+            list.head = nil;
+            list.add(3);
+            list.add(2);
+            list.add(1);
+
+            var all = [];
+            for (var x in list) push(all, x);
+            var firstTwo = [];
+            for (var x in list) {
+                if (x == 3) break;
+                push(firstTwo, x);
+            }
+            var pairs = 0;
+            for (var x in list) for (var y in list) pairs = pairs + 1;
+
+            class Wrapper {
+                iterate() { return [4, 5]; }
+            }
+            var wrapped = [];
+            for (var x in Wrapper()) push(wrapped, x);
+        "#);
+        assert_eq!(global(&interpreter, "all").to_string(), "[1, 2, 3]");
+        assert_eq!(global(&interpreter, "firstTwo").to_string(), "[1, 2]");
+        assert_eq!(global(&interpreter, "pairs"), Value::Int(9));
+        assert_eq!(global(&interpreter, "wrapped").to_string(), "[4, 5]");
+    }
+
+    #[test]
+    fn higher_order_natives_call_back() {
+        let interpreter = run(r#"
+            fun double(x) { return x * 2; }
+            fun gtOne(x) { return x > 1; }
+            fun add(a, b) { return a + b; }
+            class Scale {
+                apply(x) { return x * this.by; }
+            }
+            var s = Scale();
+            s.by = 10;
+            var xs = [1, 2, 3];
+            var doubled = map(xs, double);
+            var scaled = map(xs, s.apply);
+            var lengths = map([[1], [], [1, 2]], len);
+            var big = filter(xs, gtOne);
+            var total = reduce(xs, add, 0);
+            var seen = [];
+            fun remember(x) { push(seen, x); }
+            var nothing = forEach(xs, remember);
+        "#);
+        assert_eq!(global(&interpreter, "doubled").to_string(), "[2, 4, 6]");
+        assert_eq!(global(&interpreter, "scaled").to_string(), "[10, 20, 30]");
+        assert_eq!(global(&interpreter, "lengths").to_string(), "[1, 0, 2]");
+        assert_eq!(global(&interpreter, "big").to_string(), "[2, 3]");
+        assert_eq!(global(&interpreter, "total"), Value::Int(6));
+        assert_eq!(global(&interpreter, "seen").to_string(), "[1, 2, 3]");
+        assert_eq!(global(&interpreter, "nothing"), Value::Nil);
+    }
+
+    #[test]
+    fn format_fills_placeholders() {
+        let interpreter = run(r#"
+            var s = format("{} of {} is {{{}}}", [1, 2], 3, "x");
+        "#);
+        assert_eq!(global(&interpreter, "s"), Value::String("[1, 2] of 3 is {x}".to_string()));
+    }
+
+    #[test]
+    fn integral_number_keys_match_ints() {
+        let interpreter = run(r#"
+            var m = {};
+            m[3] = 1;
+            var found = m[3.0];
+            m[2.0] = "two";
+        "#);
+        assert_eq!(global(&interpreter, "found"), Value::Int(1));
+        assert_eq!(global(&interpreter, "m").to_string(), "{3: 1, 2: two}");
+    }
+
+    #[test]
+    fn reset_keeps_globals_after_an_error() {
+        let mut interpreter = Interpreter::default();
+        let lines = ["fun twice(x) { return x * 2; }", "{ var local = 1; print local + nil; }", "var after = twice(21);"];
+        let mut results = vec![];
+        for line in lines {
+            let tokens = scanner::scan_tokens(line.to_string()).unwrap();
+            let statements = Parser::new(tokens).parse().unwrap();
+            results.push(interpreter.interpret(&statements).is_ok());
+            interpreter.reset_transient();
+        }
+        assert_eq!(results, vec![true, false, true]);
+        assert_eq!(global(&interpreter, "after"), Value::Int(42));
+        assert!(interpreter.global.borrow().get("local").is_none());
+    }
+
+    #[test]
+    fn errors_leave_the_environment_where_they_found_it() {
+        let mut interpreter = Interpreter::default();
+        let lines = [
+            "{ var outer = 1; { var inner = 2; print inner + nil; } }",
+            "var first = 1; print first;",
+            "fun fails(x) { { var local = x; return local + nil; } }",
+            "{ var y = 3; print fails(y); }",
+            "var second = first + 1; print second;",
+        ];
+        let mut results = vec![];
+        for line in lines {
+            let tokens = scanner::scan_tokens(line.to_string()).unwrap();
+            let statements = Parser::new(tokens).parse().unwrap();
+            results.push(interpreter.interpret(&statements).is_ok());
+            assert!(Rc::ptr_eq(&interpreter.environment, &interpreter.global), "{}", line);
+        }
+        assert_eq!(results, vec![false, true, true, false, true]);
+        assert_eq!(global(&interpreter, "second"), Value::Int(2));
+        for name in ["outer", "inner", "local", "y"] {
+            assert!(interpreter.global.borrow().get(name).is_none(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn labeled_break_and_continue() {
+        let interpreter = run(r#"
+            var pairs = [];
+            outer: for (var i = 0; i < 3; i = i + 1) {
+                for (var j = 0; j < 3; j = j + 1) {
+                    if (j == 1) continue;
+                    if (i == 1) continue outer;
+                    if (i == 2) break outer;
+                    push(pairs, [i, j]);
+                }
+            }
+            var found = nil;
+            rows: for (var row in [[1, 2], [3, 4], [5, 6]]) {
+                var seen = 0;
+                while (true) {
+                    for (var x in row) {
+                        seen = seen + 1;
+                        if (x == 4) {
+                            found = x;
+                            break rows;
+                        }
+                    }
+                    break;
+                }
+            }
+            var n = 0;
+            var odd = 0;
+            while (n < 6) {
+                n = n + 1;
+                if (n == 2 or n == 4 or n == 6) continue;
+                odd = odd + n;
+            }
+        "#);
+        assert_eq!(global(&interpreter, "pairs").to_string(), "[[0, 0], [0, 2]]");
+        assert_eq!(global(&interpreter, "found"), Value::Int(4));
+        assert_eq!(global(&interpreter, "odd"), Value::Int(9));
+    }
+
+    #[test]
+    fn loop_runs_until_break_or_return() {
+        let interpreter = run(r#"
+            var count = 0;
+            loop {
+                count = count + 1;
+                if (count == 5) break;
+            }
+            fun firstOver(limit) {
+                var n = 1;
+                loop {
+                    n = n * 2;
+                    if (n > limit) return n;
+                }
+            }
+            var over = firstOver(100);
+            var steps = 0;
+            outer: loop {
+                var i = 0;
+                while (true) {
+                    i = i + 1;
+                    steps = steps + 1;
+                    if (i == 3) break outer;
+                }
+            }
+            var skipped = [];
+            var k = 0;
+            loop {
+                k = k + 1;
+                if (k > 4) break;
+                if (k == 2) continue;
+                push(skipped, k);
+            }
+        "#);
+        assert_eq!(global(&interpreter, "count"), Value::Int(5));
+        assert_eq!(global(&interpreter, "over"), Value::Int(128));
+        assert_eq!(global(&interpreter, "steps"), Value::Int(3));
+        assert_eq!(global(&interpreter, "skipped").to_string(), "[1, 3, 4]");
+    }
+
+    #[test]
+    fn spread_flattens_call_arguments() {
+        let interpreter = run(r#"
+            fun add3(a, b, c) { return a * 100 + b * 10 + c; }
+            var all = add3(...[1, 2, 3]);
+            var rest = [2, 3];
+            var mixed = add3(1, ...rest);
+            var around = add3(...[1], 2, ...[3]);
+            var none = add3(...[], 4, 5, 6);
+        "#);
+        assert_eq!(global(&interpreter, "all"), Value::Int(123));
+        assert_eq!(global(&interpreter, "mixed"), Value::Int(123));
+        assert_eq!(global(&interpreter, "around"), Value::Int(123));
+        assert_eq!(global(&interpreter, "none"), Value::Int(456));
+
+        for (source, expected) in [
+            ("fun f(a, b, c) {} f(...[1, 2]);", "Expected 3 arguments but got 2"),
+            ("fun f(a) {} f(...1);", "Can only spread an array, found int."),
+        ] {
+            let tokens = scanner::scan_tokens(source.to_string()).unwrap();
+            let statements = Parser::new(tokens).parse().unwrap();
+            let err = Interpreter::default().interpret(&statements).unwrap_err();
+            assert!(err.to_string().contains(expected), "{}", err);
+        }
+
+        let err = VMRuntime::default().run_source("fun f(a) {} f(...[1]);".to_string()).unwrap_err();
+        assert!(err.to_string().contains("Spread arguments are only supported by the interpreter."), "{}", err);
+    }
+
+    #[test]
+    fn approx_eq_allows_a_tolerance() {
+        let interpreter = run(r#"
+            var exact = 0.1 + 0.2 == 0.3;
+            var close = approxEq(0.1 + 0.2, 0.3, 0.000000001);
+            var strict = approxEq(0.1 + 0.2, 0.3, 0);
+            var fallback = approxEq(0.1 + 0.2, 0.3);
+        "#);
+        assert_eq!(global(&interpreter, "exact"), Value::Bool(false));
+        assert_eq!(global(&interpreter, "close"), Value::Bool(true));
+        assert_eq!(global(&interpreter, "strict"), Value::Bool(false));
+        assert_eq!(global(&interpreter, "fallback"), Value::Bool(true));
+    }
+
+    #[test]
+    fn enum_members_count_up_from_zero() {
+        let interpreter = run(r#"
+            enum Color { Red, Green, Blue, }
+            enum Empty {}
+            var red = Color.Red;
+            var blue = Color.Blue;
+            var same = Color.Green == Color.Green;
+            var differ = Color.Red == Color.Green;
+            var ordered = Color.Red < Color.Blue;
+            fun name(color) {
+                if (color == Color.Red) return "red";
+                return "other";
+            }
+            var named = name(Color.Red);
+        "#);
+        assert_eq!(global(&interpreter, "red"), Value::Int(0));
+        assert_eq!(global(&interpreter, "blue"), Value::Int(2));
+        assert_eq!(global(&interpreter, "same"), Value::Bool(true));
+        assert_eq!(global(&interpreter, "differ"), Value::Bool(false));
+        assert_eq!(global(&interpreter, "ordered"), Value::Bool(true));
+        assert_eq!(global(&interpreter, "named").to_string(), "red");
+
+        let tokens = scanner::scan_tokens("enum A { X, X }".to_string()).unwrap();
+        assert!(Parser::new(tokens).parse().is_err());
+        let tokens = scanner::scan_tokens("enum A { X } A.Y;".to_string()).unwrap();
+        let statements = Parser::new(tokens).parse().unwrap();
+        let err = Interpreter::default().interpret(&statements).unwrap_err();
+        assert!(err.to_string().contains("Undefined property 'Y' on class A."), "{}", err);
+    }
+
+    #[test]
+    fn collect_garbage_frees_unreachable_instances() {
+        // collections happen between top level statements, which run() skips
+        let source = r#"
+            class Node {
+                link(other) { this.next = other; }
+            }
+            var kept = Node();
+            var nodes = [];
+            for (var i = 0; i < 10; i = i + 1) {
+                var node = Node();
+                node.n = i;
+                node.link(node);
+                push(nodes, node);
+            }
+            kept.link(nodes[0]);
+            var before = heapStats()["instances"];
+            nodes = nil;
+            collectGarbage();
+            var after = heapStats()["instances"];
+            var still = kept.next.next.n;
+        "#;
+        let tokens = scanner::scan_tokens(source.to_string()).unwrap();
+        let statements = Parser::new(tokens).parse().unwrap();
+        let mut interpreter = Interpreter::default();
+        interpreter.interpret(&statements).unwrap();
+        assert_eq!(global(&interpreter, "before"), Value::Int(11));
+        assert_eq!(global(&interpreter, "after"), Value::Int(2));
+        assert_eq!(global(&interpreter, "still"), Value::Int(0));
+    }
+
+    #[test]
+    fn instances_equal_only_themselves() {
+        let interpreter = run(r#"
+            class Point {}
+            var a = Point();
+            var b = a;
+            var aliased = a == b;
+            var separate = Point() == Point();
+            var copied = clone(a) == a;
+        "#);
+        assert_eq!(global(&interpreter, "aliased"), Value::Bool(true));
+        assert_eq!(global(&interpreter, "separate"), Value::Bool(false));
+        assert_eq!(global(&interpreter, "copied"), Value::Bool(false));
+    }
+
+    #[test]
+    fn super_methods_run_on_the_calling_instance() {
+        let interpreter = run(r#"
+            class Animal {
+                describe() { return this.name + " says " + this.sound(); }
+                sound() { return "..."; }
+            }
+            class Dog < Animal {
+                init(name) { this.name = name; }
+                describe() { return super.describe() + "!"; }
+                sound() { return "woof"; }
+            }
+            var said = Dog("rex").describe();
+        "#);
+        assert_eq!(global(&interpreter, "said").to_string(), "rex says woof!");
+    }
+
+    #[test]
+    fn super_resolves_from_the_declaring_class() {
+        let interpreter = run(r#"
+            class A { name() { return "A"; } }
+            class B < A { name() { return "B>" + super.name(); } }
+            class C < B { name() { return "C>" + super.name(); } }
+            var chain = C().name();
+            var middle = B().name();
+        "#);
+        assert_eq!(global(&interpreter, "chain").to_string(), "C>B>A");
+        assert_eq!(global(&interpreter, "middle").to_string(), "B>A");
+
+        let source = "class A {} class B < A { m() { return super.m(); } } B().m();";
+        let tokens = scanner::scan_tokens(source.to_string()).unwrap();
+        let statements = Parser::new(tokens).parse().unwrap();
+        let err = Interpreter::default().interpret(&statements).unwrap_err();
+        assert!(err.to_string().contains("Undefined superclass method 'm'."), "{}", err);
+    }
+
+    #[test]
+    fn fields_from_a_superclass_init_are_reachable() {
+        let interpreter = run(r#"
+            class Base { init() { this.size = 1; } }
+            class Middle < Base { grow() { this.size = this.size + 1; return this.size; } }
+            class Leaf < Middle { describe() { return this.size * 10; } }
+            var leaf = Leaf();
+            var grown = leaf.grow();
+            var described = leaf.describe();
+            var read = leaf.size;
+        "#);
+        assert_eq!(global(&interpreter, "grown"), Value::Int(2));
+        assert_eq!(global(&interpreter, "described"), Value::Int(20));
+        assert_eq!(global(&interpreter, "read"), Value::Int(2));
+    }
+
+    #[test]
+    fn tail_calls_run_in_place() {
+        let interpreter = run(r#"
+            fun countdown(n) {
+                if (n == 0) return "done";
+                return countdown(n - 1);
+            }
+            var result = countdown(500000);
+            fun isEven(n) { if (n == 0) return true; return isOdd(n - 1); }
+            fun isOdd(n) { if (n == 0) return false; return isEven(n - 1); }
+            var even = isEven(10001);
+            class Box { init(v) { this.v = v; } }
+            fun wrap(v) { return Box(v); }
+            var boxed = wrap(3).v;
+            fun size(xs) { return len(xs); }
+            var counted = size([1, 2]);
+            fun adder(x) { fun add(y) { return x + y; } return add; }
+            fun nested() { return adder(1)(size([1, 2])); }
+            var sum = nested();
+        "#);
+        assert_eq!(global(&interpreter, "result").to_string(), "done");
+        assert_eq!(global(&interpreter, "even"), Value::Bool(false));
+        assert_eq!(global(&interpreter, "boxed"), Value::Int(3));
+        assert_eq!(global(&interpreter, "counted"), Value::Int(2));
+        // calls in the callee and arguments of a tail call run before it returns
+        assert_eq!(global(&interpreter, "sum"), Value::Int(3));
+        assert_eq!(interpreter.depth, 0);
+    }
+
+    #[test]
+    fn deep_recursion_is_an_error() {
+        // on the test thread's own small stack
+        let source = "fun sum(n) { if (n == 0) return 0; return n + sum(n - 1); } var a = sum(900); sum(100000);";
+        let tokens = scanner::scan_tokens(source.to_string()).unwrap();
+        let statements = Parser::new(tokens).parse().unwrap();
+        let mut interpreter = Interpreter::default();
+        let err = interpreter.interpret(&statements).unwrap_err();
+        assert_eq!(global(&interpreter, "a"), Value::Int(405450));
+        assert!(err.to_string().contains("Stack overflow."), "{}", err);
+    }
+
+    #[test]
+    fn static_fields_are_shared_by_the_class() {
+        let interpreter = run(r#"
+            class Counter {
+                class var count = 0;
+                class var step;
+                tick() { Counter.count = Counter.count + 1; }
+            }
+            class Sub < Counter {}
+            Counter().tick(); Counter().tick(); Sub().tick();
+            var counted = Counter.count;
+            var through_sub = Sub.count;
+            var step = Counter.step;
+            Sub.count = 10;
+            var shadowed = Sub.count;
+            var untouched = Counter.count;
+            Sub().tick();
+            var after = Counter.count;
+        "#);
+        assert_eq!(global(&interpreter, "counted"), Value::Int(3));
+        assert_eq!(global(&interpreter, "through_sub"), Value::Int(3));
+        assert_eq!(global(&interpreter, "step"), Value::Nil);
+        assert_eq!(global(&interpreter, "shadowed"), Value::Int(10));
+        assert_eq!(global(&interpreter, "untouched"), Value::Int(3));
+        // the inherited method still counts on Counter
+        assert_eq!(global(&interpreter, "after"), Value::Int(4));
+    }
+
+    #[test]
+    fn closures_share_their_scope_instead_of_copying_it() {
+        // deep scopes full of variables, a copy per closure would be quadratic
+        let locals: String = (0..200).map(|i| format!("var v{} = {};", i, i)).collect();
+        let source = format!(r#"
+            var inc; var read; var made = 0;
+            {}
+            {{ {} var count = 0;
+                for (var i = 0; i < 20000; i = i + 1) {{
+                    fun f() {{ return count + v199; }}
+                    made = made + 1;
+                }}
+                fun bump() {{ count = count + 1; }}
+                fun get() {{ return count; }}
+                inc = bump; read = get;
+            {} }}
+            inc(); inc();
+            var shared = read();
+        "#, "{".repeat(50), locals, "}".repeat(50));
+        let start = Instant::now();
+        let interpreter = run(&source);
+        assert!(start.elapsed() < Duration::from_secs(10), "took {:?}", start.elapsed());
+        assert_eq!(global(&interpreter, "made"), Value::Int(20000));
+        assert_eq!(global(&interpreter, "shared"), Value::Int(2));
+    }
+
+    #[test]
+    fn returns_only_leave_their_own_function() {
+        let interpreter = run(r#"
+            fun inner(x) { if (x > 1) { return "big"; } return "small"; }
+            fun outer() {
+                var seen = inner(5) + inner(0);
+                { var again = inner(2); seen = seen + again; }
+                return seen + "!";
+            }
+            var joined = outer();
+
+            class Point {
+                init(x) { this.x = x; if (x < 0) { this.x = 0; return; } this.x = x * 2; }
+            }
+            var clamped = Point(-3).x;
+            var doubled = Point(4).x;
+            var early = Point(-1).init(5).x;
+
+            fun find(xs, wanted) {
+                for (var x in xs) {
+                    var i = 0;
+                    while (true) {
+                        i = i + 1;
+                        if (i > 3) break;
+                        if (x == wanted) return i * 10 + x;
+                    }
+                }
+                return -1;
+            }
+            var found = find([1, 2, 3], 2);
+            var missing = find([1], 5);
+            var after = 0;
+            for (var i = 0; i < 3; i = i + 1) { after = after + find([i], i); }
+        "#);
+        assert_eq!(global(&interpreter, "joined").to_string(), "bigsmallbig!");
+        assert_eq!(global(&interpreter, "clamped"), Value::Int(0));
+        assert_eq!(global(&interpreter, "doubled"), Value::Int(8));
+        assert_eq!(global(&interpreter, "early"), Value::Int(10));
+        assert_eq!(global(&interpreter, "found"), Value::Int(12));
+        assert_eq!(global(&interpreter, "missing"), Value::Int(-1));
+        assert_eq!(global(&interpreter, "after"), Value::Int(33));
+    }
+
+    #[test]
+    fn assignment_needs_a_declared_variable() {
+        let mut runtime = Runtime::default();
+        let mut vm_runtime = VMRuntime::default();
+        let expected = "Undefined variable 'x', declare it with 'var' before assigning to it. [line 2]";
+        for source in ["var y = 1;\nx = 5;", "fun f() {\n  x = 5;\n}\nf();", "{\n  x = 5;\n}"] {
+            assert!(runtime_error(runtime.run_source(source.to_string())).contains(expected), "{}", source);
+            assert!(runtime_error(vm_runtime.run_source(source.to_string())).contains(expected), "{}", source);
+        }
+        assert!(runtime_error(runtime.run_source("print\n  missing;".to_string())).contains("Undefined variable 'missing'. [line 2]"));
+        assert!(runtime_error(vm_runtime.run_source("print\n  missing;".to_string())).contains("Undefined variable 'missing'. [line 2]"));
+
+        runtime.run_source("var x; x = 5;".to_string()).unwrap();
+        vm_runtime.run_source("var x; x = 5;".to_string()).unwrap();
+    }
+
+    #[test]
+    fn instances_take_computed_property_names() {
+        let class = "class Point { init(x) { this.x = x; } twice() { return this.x * 2; } } var p = Point(1);";
+        let source = format!("{} var key = \"x\"; print p[key]; p[key] = 3; p[\"y\"] = p.x + 1; print p.x, p.y, p[\"twice\"]();", class);
+        let (out, vm_out) = both_engines(&source);
+        assert_eq!(out, "1\n3 4 6\n");
+        assert_eq!(vm_out, "1\n3 4 6\n");
+
+        for (rest, expected) in [("p[\"z\"];", "z"), ("p[1];", "Instance keys must be strings"), ("p[nil] = 2;", "Instance keys must be strings")] {
+            let source = format!("{} {}", class, rest);
+            assert!(runtime_error(Runtime::default().run_source(source.clone())).contains(expected), "{}", rest);
+            assert!(runtime_error(VMRuntime::default().run_source(source)).contains(expected), "{}", rest);
+        }
+    }
+
+    #[test]
+    fn method_missing_takes_unknown_properties() {
+        let proxy = r#"
+            class Target { greet(name) { return "hi " + name; } }
+            class Proxy {
+                init(target) { this.target = target; this.log = []; }
+                methodMissing(name, args) {
+                    push(this.log, name);
+                    if (name == "greet") return this.target.greet(args[0]);
+                    return format("{}?{}", name, len(args));
+                }
+            }
+            var p = Proxy(Target());
+        "#;
+        let source = format!("{} fun greet(p) {{ return p.greet(\"ann\"); }} print p.greet(\"bob\"); print greet(p), p.color, p[\"size\"]; print p.log;", proxy);
+        let expected = "hi bob\nhi ann color?0 size?0\n[greet, greet, color, size]\n";
+        let (out, vm_out) = both_engines(&source);
+        assert_eq!(out, expected);
+        assert_eq!(vm_out, expected);
+
+        let looping = "class Loop { methodMissing(name, args) { return this.other; } } Loop().x;";
+        let plain = "class Plain {} Plain().x;";
+        for (source, expected) in [(looping, "Undefined property 'other' inside methodMissing."), (plain, "x")] {
+            assert!(runtime_error(Runtime::default().run_source(source.to_string())).contains(expected), "{}", source);
+            assert!(runtime_error(VMRuntime::default().run_source(source.to_string())).contains(expected), "{}", source);
+        }
+    }
+
+    #[test]
+    fn is_walks_the_class_chain() {
+        let classes = "class A {} class B < A {} class C {} var b = B(); fun make() { class K {} return K; } var K1 = make(); var K2 = make();";
+        let source = format!("{} print b is B, b is A, b is C, A() is B, 1 is A, nil is A, K1() is K1, K1() is K2;", classes);
+        let expected = "true true false false false false true false\n";
+        let (out, vm_out) = both_engines(&source);
+        assert_eq!(out, expected);
+        assert_eq!(vm_out, expected);
+
+        let source = format!("{} var a = A; print A == A, a == A, A != B, B == A, K1 == K2, K1 == K1;", classes);
+        let expected = "true true true false false true\n";
+        let (out, vm_out) = both_engines(&source);
+        assert_eq!(out, expected);
+        assert_eq!(vm_out, expected);
+
+        for rest in ["print 1 is 2;", "print b is b;", "print b is \"B\";"] {
+            let source = format!("{} {}", classes, rest);
+            let expected = "Right operand of 'is' must be a class";
+            assert!(runtime_error(Runtime::default().run_source(source.clone())).contains(expected), "{}", rest);
+            assert!(runtime_error(VMRuntime::default().run_source(source)).contains(expected), "{}", rest);
+        }
+    }
+
+    #[test]
+    fn conditions_go_by_truthiness() {
+        let source = r#"
+            print 1 and 2, nil and 2, 0 and "", 1 or 2, nil or 2, false or nil;
+            if (0) print "zero"; else print "no";
+            if (nil) print "nil"; else print "no";
+            var x = 3;
+            while (x) { print x; x = nil; }
+        "#;
+        let expected = "2 nil  1 2 nil\nzero\nno\n3\n";
+        let (out, vm_out) = both_engines(source);
+        assert_eq!(out, expected);
+        assert_eq!(vm_out, expected);
+    }
+
+    // returns travel as control flow, not as values, so nothing wrapped can reach print
+    #[test]
+    fn returned_values_print_as_themselves() {
+        let source = r#"
+            fun one() { return 1; }
+            fun outer() { for (var x in [1, 2]) { while (true) { return one() + x; } } }
+            fun list() { return [one(), "a", nil]; }
+            fun nothing() { return; }
+            fun maker() { fun inner() { return "inner"; } return inner; }
+            print one(), outer(), list(), nothing(), maker()();
+            print format("{}{}", one(), outer()), len(list());
+        "#;
+        let expected = "1 2 [1, a, nil] nil inner\n12 3\n";
+        let (out, vm_out) = both_engines(source);
+        assert_eq!(out, expected);
+        assert_eq!(vm_out, expected);
     }
 }
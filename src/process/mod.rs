@@ -2,4 +2,7 @@ pub mod scanner;
 pub mod parser;
 pub mod ast;
 pub mod interpreter;
-pub mod environment;
\ No newline at end of file
+pub mod environment;
+pub mod builtins;
+pub mod gc;
+pub mod optimizer;
@@ -0,0 +1,217 @@
+use crate::process::interpreter::Interpreter;
+use crate::types::expr::{BinaryOperatorType, Expression, ExpressionKind, Literal, Statement, StatementKind};
+use crate::types::val::Value;
+
+// folds unary and binary expressions over literals into the literal they come to,
+// so a loop doesn't work out `2 + 3` on every turn. the interpreter does the
+// folding itself, a folded expression can't give anything but what it would have.
+// what it leaves alone:
+// - expressions that fail, like `1 + "a"` or an int overflow, so the error still
+//   happens when and only if the code runs. `1 / 0` doesn't fail, it folds to inf
+// - string repetition, `"ab" * 1e9` in a branch never taken shouldn't build anything
+pub fn fold_constants(statements: &mut [Statement]) {
+    let mut folder = Folder { interpreter: Interpreter::default() };
+    for statement in statements {
+        folder.statement(statement);
+    }
+}
+
+struct Folder {
+    interpreter: Interpreter,
+}
+
+impl Folder {
+    fn statement(&mut self, statement: &mut Statement) {
+        match &mut statement.kind {
+            StatementKind::Expression(expr) | StatementKind::Var(_, expr) => self.expression(expr),
+            StatementKind::Function(_, _, body) => self.statement(body),
+            StatementKind::Print(exprs) => {
+                for expr in exprs {
+                    self.expression(expr);
+                }
+            }
+            StatementKind::Return(_, expr) => {
+                match expr {
+                    None => {}
+                    Some(expr) => self.expression(expr),
+                }
+            }
+            StatementKind::Block(statements) => {
+                for statement in statements {
+                    self.statement(statement);
+                }
+            }
+            StatementKind::Class { methods, statics, .. } => {
+                for method in methods {
+                    self.statement(method);
+                }
+                for (_, initializer) in statics {
+                    self.expression(initializer);
+                }
+            }
+            StatementKind::If(condition, then_branch, else_branch) => {
+                self.expression(condition);
+                self.statement(then_branch);
+                match else_branch {
+                    None => {}
+                    Some(else_branch) => self.statement(else_branch),
+                }
+            }
+            StatementKind::While { condition, body, increment, .. } => {
+                self.expression(condition);
+                self.statement(body);
+                match increment {
+                    None => {}
+                    Some(increment) => self.expression(increment),
+                }
+            }
+            StatementKind::ForIn { iterable, body, .. } => {
+                self.expression(iterable);
+                self.statement(body);
+            }
+            // the condition's text was kept when it was parsed, folding doesn't change it
+            StatementKind::Assert { condition, message, .. } => {
+                self.expression(condition);
+                match message {
+                    None => {}
+                    Some(message) => self.expression(message),
+                }
+            }
+            StatementKind::Enum(..) | StatementKind::Break(_) | StatementKind::Continue(_) => {}
+        }
+    }
+
+    fn expression(&mut self, expr: &mut Expression) {
+        match &mut expr.kind {
+            ExpressionKind::Literal(_) | ExpressionKind::Super { .. } | ExpressionKind::This(_)
+            | ExpressionKind::Variable(_) => {}
+            ExpressionKind::Unary(_, operand) | ExpressionKind::Grouping(operand) | ExpressionKind::Spread(operand) => {
+                self.expression(operand)
+            }
+            ExpressionKind::Binary(left, _, right) | ExpressionKind::Logical(left, _, right) => {
+                self.expression(left);
+                self.expression(right);
+            }
+            ExpressionKind::Call(callee, _, args) => {
+                self.expression(callee);
+                for arg in args {
+                    self.expression(arg);
+                }
+            }
+            ExpressionKind::Get { object, .. } => self.expression(object),
+            ExpressionKind::Set { object, value, .. } => {
+                self.expression(object);
+                self.expression(value);
+            }
+            ExpressionKind::Assign(_, value) => self.expression(value),
+            ExpressionKind::Array(elements) => {
+                for element in elements {
+                    self.expression(element);
+                }
+            }
+            ExpressionKind::Map(entries) => {
+                for (key, value) in entries {
+                    self.expression(key);
+                    self.expression(value);
+                }
+            }
+            ExpressionKind::Index { object, index } => {
+                self.expression(object);
+                self.expression(index);
+            }
+            ExpressionKind::SetIndex { object, index, value } => {
+                self.expression(object);
+                self.expression(index);
+                self.expression(value);
+            }
+        }
+
+        if !foldable(expr) {
+            return;
+        }
+        let literal = match self.interpreter.interpret_expression(expr) {
+            Ok(value) => literal(value),
+            Err(_) => None,
+        };
+        match literal {
+            None => {}
+            Some(literal) => expr.kind = ExpressionKind::Literal(literal),
+        }
+    }
+}
+
+fn foldable(expr: &Expression) -> bool {
+    let is_literal = |expr: &Expression| matches!(expr.kind, ExpressionKind::Literal(_));
+    return match &expr.kind {
+        ExpressionKind::Unary(_, operand) | ExpressionKind::Grouping(operand) => is_literal(operand),
+        ExpressionKind::Binary(left, op, right) => {
+            let repeats = matches!(op.token_type, BinaryOperatorType::Star)
+                && [left, right].iter().any(|side| matches!(side.kind, ExpressionKind::Literal(Literal::String(_))));
+            is_literal(left) && is_literal(right) && !repeats
+        }
+        _ => false,
+    };
+}
+
+fn literal(value: Value) -> Option<Literal> {
+    return match value {
+        Value::Int(int) => Some(Literal::Int(int)),
+        Value::Number(number) => Some(Literal::Number(number)),
+        Value::String(string) => Some(Literal::String(string)),
+        Value::Bool(true) => Some(Literal::True),
+        Value::Bool(false) => Some(Literal::False),
+        Value::Nil => Some(Literal::Nil),
+        _ => None,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::process::optimizer::fold_constants;
+    use crate::process::parser::Parser;
+    use crate::process::scanner;
+    use crate::runtime::Runtime;
+    use crate::types::expr::{ExpressionKind, Literal, StatementKind};
+    use crate::types::output::Output;
+
+    fn output(source: &str, optimize: bool) -> String {
+        let (output, out, _) = Output::capture();
+        let mut runtime = Runtime { output, optimize, ..Runtime::default() };
+        match runtime.run_source(source.to_string()) {
+            Ok(_) => {}
+            Err(e) => return format!("error: {:?}", e),
+        }
+        return String::from_utf8(out.borrow().clone()).unwrap();
+    }
+
+    #[test]
+    fn folded_expressions_print_the_same() {
+        let sources = [
+            "print 2 + 3 * 4, (1 + 2) / 4, -(-7), !nil, !true == false;",
+            "print 1 / 0, -1 / 0, 0.0 / 0 == 0.0 / 0, \"a\" + \"b\", 2 < 3, 1 == 1.0;",
+            "var total = 0; for (var i = 0; i < 3; i = i + 1) { total = total + (10 - 4); } print total;",
+            "print \"ab\" * 2;",
+            "print 1 + \"a\";",
+            "print 9223372036854775807 + 1;",
+            "if (false) { print 1 + nil; } print 1;",
+        ];
+        for source in sources {
+            assert_eq!(output(source, true), output(source, false), "{}", source);
+        }
+    }
+
+    #[test]
+    fn only_literal_operations_are_folded() {
+        let tokens = scanner::scan_tokens("var a = (2 + 3) * -4; var b = a + 1; var c = 1 + \"x\"; var d = 2 + 3 == 5;".to_string()).unwrap();
+        let mut statements = Parser::new(tokens).parse().unwrap();
+        fold_constants(&mut statements);
+        let initializers: Vec<&ExpressionKind> = statements.iter().map(|statement| match &statement.kind {
+            StatementKind::Var(_, initializer) => &initializer.kind,
+            other => panic!("should be var, got {:?}", other),
+        }).collect();
+        assert!(matches!(initializers[0], ExpressionKind::Literal(Literal::Int(-20))));
+        assert!(matches!(initializers[1], ExpressionKind::Binary(..)));
+        assert!(matches!(initializers[2], ExpressionKind::Binary(..)));
+        assert!(matches!(initializers[3], ExpressionKind::Literal(Literal::True)));
+    }
+}
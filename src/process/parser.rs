@@ -1,23 +1,59 @@
 use crate::types::{expr, token};
+use crate::types::span::Span;
 
 pub struct Parser {
     tokens: Vec<token::Token>,
     current: usize,
+    // labels of the loops enclosing the current statement, `break` needs at least one
+    loops: Vec<Option<String>>,
+    classes: Vec<bool>,
+    // names declared directly in each block enclosing the current statement. there's none
+    // at the top level, where declaring a name again is fine
+    scopes: Vec<Vec<(String, Span)>>,
+    errors: Vec<expr::ExpError>,
+    newline_before: Vec<bool>,
+    pub newlines: bool,
 }
 
 impl Parser {
     pub fn new(tokens: Vec<token::Token>) -> Self {
-        Self { tokens, current: 0 }
+        // comments only matter to tools working on the tokens themselves, newlines are kept
+        // as a flag on the token after them
+        let mut kept = vec![];
+        let mut newline_before = vec![];
+        let mut after_newline = false;
+        for token in tokens {
+            match token.token_type {
+                token::TokenType::Comment => {}
+                token::TokenType::Newline => after_newline = true,
+                _ => {
+                    kept.push(token);
+                    newline_before.push(after_newline);
+                    after_newline = false;
+                }
+            }
+        }
+        Self { tokens: kept, current: 0, loops: vec![], classes: vec![], scopes: vec![], errors: vec![], newline_before, newlines: false }
     }
 
     // http://www.craftinginterpreters.com/appendix-i.html
-    pub fn parse(&mut self) -> Result<Vec<expr::Statement>, expr::ExpError> {
+    pub fn parse(&mut self) -> Result<Vec<expr::Statement>, Vec<expr::ExpError>> {
         let mut statements = vec![];
         while !self.at_end() {
-            let statement = self.declaration()?;
-            statements.push(statement)
+            match self.declaration() {
+                Ok(statement) => {
+                    statements.push(statement)
+                }
+                Err(e) => {
+                    self.errors.push(e);
+                    self.synchronize();
+                }
+            }
         }
 
+        if !self.errors.is_empty() {
+            return Err(std::mem::take(&mut self.errors));
+        }
         return Ok(statements);
     }
 
@@ -25,8 +61,18 @@ impl Parser {
         if self.match_token(vec![token::TokenType::Class]) {
             return self.class();
         }
+        if self.match_token(vec![token::TokenType::Enum]) {
+            return self.enum_declaration();
+        }
         if self.match_token(vec![token::TokenType::Fun]) {
-            return self.function("function");
+            let start = self.previous().span;
+            if self.check(token::TokenType::Identifier) {
+                let name = self.peek().clone();
+                self.declare(&name);
+            }
+            let mut function = self.function("function")?;
+            function.span = start.to(function.span);
+            return Ok(function);
         }
         if self.match_token(vec![token::TokenType::Var]) {
             return self.var_declaration();
@@ -35,39 +81,107 @@ impl Parser {
     }
 
     pub fn class(&mut self) -> Result<expr::Statement, expr::ExpError> {
+        let start = self.previous().span;
         let name = self.consume(token::TokenType::Identifier, "Expect class name.")?.clone();
+        self.declare(&name);
 
         let mut super_class = None;
         if self.match_token(vec![token::TokenType::Less]) {
             self.consume(token::TokenType::Identifier, "Expect superclass name.")?;
+            if self.previous().lexeme == name.lexeme {
+                return Err(expr::ExpError::Common("A class can't inherit from itself.".to_string()));
+            }
             super_class = Some(self.previous().clone().lexeme)
         }
 
+        // popped even on error, parsing carries on after the class
+        self.classes.push(super_class.is_some());
+        let mut statics = vec![];
+        let methods = self.class_body(&mut statics);
+        self.classes.pop();
+        let methods = methods?;
+        return Ok(expr::Statement::new(expr::StatementKind::Class {
+            name: name.lexeme,
+            methods,
+            super_class,
+            statics,
+        }, self.since(start)));
+    }
+
+    fn parameter(&mut self, seen: &[String]) -> Result<String, expr::ExpError> {
+        let token = self.peek().clone();
+        if token::parse_keyword(&token.lexeme).is_some() {
+            return Err(expr::ExpError::ReservedParameter(token.lexeme, token.span));
+        }
+        let name = self.consume(token::TokenType::Identifier, "Expect parameter name.")?.lexeme.clone();
+        if seen.contains(&name) {
+            return Err(expr::ExpError::DuplicateParameter(name, token.span));
+        }
+        return Ok(name);
+    }
+
+    fn enum_declaration(&mut self) -> Result<expr::Statement, expr::ExpError> {
+        let start = self.previous().span;
+        let name = self.consume(token::TokenType::Identifier, "Expect enum name.")?.clone();
+        self.declare(&name);
+        self.consume(token::TokenType::LeftBrace, "Expect '{' before enum body.")?;
+        let mut members: Vec<token::Token> = vec![];
+        while !self.check(token::TokenType::RightBrace) {
+            let member = self.consume(token::TokenType::Identifier, "Expect enum member name.")?.clone();
+            match members.iter().find(|m| m.lexeme == member.lexeme) {
+                None => {}
+                Some(first) => {
+                    return Err(expr::ExpError::VariableRepeatDef { name: member.lexeme, span: member.span, first: first.span });
+                }
+            }
+            members.push(member);
+            if !self.match_token(vec![token::TokenType::Comma]) {
+                break;
+            }
+        }
+        self.consume(token::TokenType::RightBrace, "Expect '}' after enum body.")?;
+        let members = members.into_iter().map(|member| member.lexeme).collect();
+        return Ok(expr::Statement::new(expr::StatementKind::Enum(name.lexeme, members), self.since(start)));
+    }
+
+    fn class_body(&mut self, statics: &mut Vec<(String, expr::Expression)>) -> Result<Vec<expr::Statement>, expr::ExpError> {
         self.consume(token::TokenType::LeftBrace, "Expect '{' before class body.")?;
         let mut methods = vec![];
         while !self.check(token::TokenType::RightBrace) && !self.at_end() {
+            if self.match_token(vec![token::TokenType::Class]) {
+                self.consume(token::TokenType::Var, "Expect 'var' after 'class' in a class body.")?;
+                // a static belongs to the class, not to the block the class is declared in
+                let enclosing_scopes = std::mem::take(&mut self.scopes);
+                let field = self.var_declaration();
+                self.scopes = enclosing_scopes;
+                match field?.kind {
+                    expr::StatementKind::Var(name, initializer) => statics.push((name, initializer)),
+                    _ => panic!("should be var"),
+                }
+                continue;
+            }
             methods.push(self.function("method")?);
         }
         self.consume(token::TokenType::RightBrace, "Expect '}' before class body.")?;
-        return Ok(expr::Statement::Class {
-            name: name.lexeme,
-            methods,
-            super_class,
-        });
+        return Ok(methods);
     }
 
     pub fn function(&mut self, kind: &str) -> Result<expr::Statement, expr::ExpError> {
+        let start = self.peek().span;
         let name = self.consume(token::TokenType::Identifier, format!("{} {} {}", "Expect", kind, "name").as_str())?.clone();
         self.consume(token::TokenType::LeftParen, format!("{} {} {}", "Expect '{' before", kind, "name").as_str())?;
         let mut parameters = vec![];
+        let mut parameter_spans = vec![];
 
         if !self.check(token::TokenType::RightParen) {
             loop {
-                if parameters.len() >= 255 {
-                    return Err(expr::ExpError::TooManyArgs);
+                if parameters.len() >= expr::MAX_ARGS {
+                    return Err(expr::ExpError::TooManyArgs("parameters", self.peek().span));
                 }
-                parameters.push(self.consume(token::TokenType::Identifier, "Expect parameter name.")?.clone().lexeme);
-                if !self.match_token(vec![token::TokenType::Comma]) {
+                let parameter = self.parameter(&parameters)?;
+                parameter_spans.push(self.previous().span);
+                parameters.push(parameter);
+                if !self.match_token(vec![token::TokenType::Comma]) || self.check(token::TokenType::RightParen) {
                     break;
                 }
             }
@@ -76,18 +190,27 @@ impl Parser {
         self.consume(token::TokenType::RightParen, "Expect ')' after parameters.")?;
         self.consume(token::TokenType::LeftBrace, format!("{} {} {}", "Expect '{' before", kind, "name").as_str())?;
 
-        let body = self.block()?;
-        return Ok(expr::Statement::Function(name.lexeme.clone(), parameters, Box::new(body)));
+        // a function body starts outside of any loop, and shares its scope with the parameters
+        let enclosing_loops = std::mem::take(&mut self.loops);
+        self.scopes.push(parameters.iter().cloned().zip(parameter_spans).collect());
+        let body = self.block_statements();
+        self.scopes.pop();
+        self.loops = enclosing_loops;
+        let body = body?;
+        return Ok(expr::Statement::new(expr::StatementKind::Function(name.lexeme.clone(), parameters, Box::new(body)),
+                                       self.since(start)));
     }
 
     pub fn var_declaration(&mut self) -> Result<expr::Statement, expr::ExpError> {
+        let start = self.previous().span;
         let name = self.consume(token::TokenType::Identifier, "Expect variable name.")?.clone();
-        let mut initializer = expr::Expression::Literal(expr::Literal::Nil);
+        self.declare(&name);
+        let mut initializer = expr::Expression::new(expr::ExpressionKind::Literal(expr::Literal::Nil), name.span);
         if self.match_token(vec![token::TokenType::Equal]) {
             initializer = self.expression()?;
         }
-        self.consume(token::TokenType::Semicolon, "Expect ';' after expression.")?;
-        return Ok(expr::Statement::Var(name.lexeme.to_string(), initializer));
+        self.terminator("Expect ';' after expression.")?;
+        return Ok(expr::Statement::new(expr::StatementKind::Var(name.lexeme.to_string(), initializer), self.since(start)));
     }
 
 
@@ -98,11 +221,23 @@ impl Parser {
         if self.match_token(vec![token::TokenType::Return]) {
             return self.return_statement();
         }
+        if self.match_token(vec![token::TokenType::Assert]) {
+            return self.assert_statement();
+        }
         if self.match_token(vec![token::TokenType::While]) {
-            return self.while_statement();
+            return self.while_statement(None);
         }
         if self.match_token(vec![token::TokenType::For]) {
-            return self.for_statement();
+            return self.for_statement(None);
+        }
+        if self.match_token(vec![token::TokenType::Loop]) {
+            return self.loop_statement(None);
+        }
+        if self.match_token(vec![token::TokenType::Break, token::TokenType::Continue]) {
+            return self.jump_statement();
+        }
+        if self.is_label() {
+            return self.labeled_statement();
         }
         if self.match_token(vec![token::TokenType::LeftBrace]) {
             return self.block();
@@ -117,29 +252,96 @@ impl Parser {
         let token = self.previous().clone();
         let mut expr = None;
 
-        if !self.check(token::TokenType::Semicolon) {
+        if !self.check(token::TokenType::Semicolon) && !self.ends_line() {
             expr = Some(self.expression()?)
         }
 
-        self.consume(token::TokenType::Semicolon, "Expect ';' after return expression.")?;
+        self.terminator("Expect ';' after return expression.")?;
 
-        Ok(expr::Statement::Return(token.lexeme.to_string(), expr))
+        Ok(expr::Statement::new(expr::StatementKind::Return(token.lexeme.to_string(), expr), self.since(token.span)))
     }
 
-    pub fn for_statement(&mut self) -> Result<expr::Statement, expr::ExpError> {
+    pub fn jump_statement(&mut self) -> Result<expr::Statement, expr::ExpError> {
+        let keyword = self.previous().clone();
+        if self.loops.is_empty() {
+            return Err(expr::ExpError::Common(format!("Can't use '{}' outside of a loop.", keyword.lexeme)));
+        }
+        let mut label = None;
+        if !self.ends_line() && self.match_token(vec![token::TokenType::Identifier]) {
+            let name = self.previous().lexeme.clone();
+            if !self.loops.contains(&Some(name.clone())) {
+                return Err(expr::ExpError::Common(format!("No enclosing loop labeled '{}'.", name)));
+            }
+            label = Some(name);
+        }
+        self.terminator(format!("Expect ';' after '{}'.", keyword.lexeme).as_str())?;
+        let kind = match keyword.token_type {
+            token::TokenType::Break => expr::StatementKind::Break(label),
+            _ => expr::StatementKind::Continue(label),
+        };
+        return Ok(expr::Statement::new(kind, self.since(keyword.span)));
+    }
+
+    pub fn labeled_statement(&mut self) -> Result<expr::Statement, expr::ExpError> {
+        let start = self.peek().span;
+        let label = self.advance().lexeme.clone();
+        self.advance();
+        if self.loops.contains(&Some(label.clone())) {
+            return Err(expr::ExpError::Common(format!("Label '{}' is already used by an enclosing loop.", label)));
+        }
+        let mut statement = if self.match_token(vec![token::TokenType::While]) {
+            self.while_statement(Some(label))?
+        } else if self.match_token(vec![token::TokenType::For]) {
+            self.for_statement(Some(label))?
+        } else if self.match_token(vec![token::TokenType::Loop]) {
+            self.loop_statement(Some(label))?
+        } else {
+            return Err(expr::ExpError::Common(format!("Expect a loop after label '{}'.", label)));
+        };
+        statement.span = start.to(statement.span);
+        return Ok(statement);
+    }
+
+    fn loop_body(&mut self, label: &Option<String>) -> Result<expr::Statement, expr::ExpError> {
+        self.loops.push(label.clone());
+        let body = self.statement();
+        self.loops.pop();
+        return body;
+    }
+
+    pub fn for_in_statement(&mut self, start: Span, label: Option<String>) -> Result<expr::Statement, expr::ExpError> {
+        self.consume(token::TokenType::Var, "Expect 'var' in for-in loop.")?;
+        let name = self.consume(token::TokenType::Identifier, "Expect variable name.")?.lexeme.clone();
+        self.consume(token::TokenType::In, "Expect 'in' after loop variable.")?;
+        let iterable = self.expression()?;
+        self.consume(token::TokenType::RightParen, "Expect ')' after for-in expression.")?;
+        let body = self.loop_body(&label)?;
+        return Ok(expr::Statement::new(expr::StatementKind::ForIn { name, iterable, body: Box::new(body), label },
+                                       self.since(start)));
+    }
+
+    pub fn for_statement(&mut self, label: Option<String>) -> Result<expr::Statement, expr::ExpError> {
+        let start = self.previous().span;
         self.consume(token::TokenType::LeftParen, "Expect '(' after for expression.")?;
+        if self.is_for_in() {
+            return self.for_in_statement(start, label);
+        }
 
         // initializer
         let mut initializer = None;
         if self.match_token(vec![token::TokenType::Semicolon])
         {} else if self.match_token(vec![token::TokenType::Var]) {
-            initializer = Some(self.var_declaration()?);
+            // the loop variable gets a scope of its own, the body can declare the name again
+            self.scopes.push(vec![]);
+            let declaration = self.var_declaration();
+            self.scopes.pop();
+            initializer = Some(declaration?);
         } else {
             initializer = Some(self.expression_statement()?);
         }
 
         // condition
-        let mut condition = expr::Expression::Literal(expr::Literal::True);
+        let mut condition = expr::Expression::new(expr::ExpressionKind::Literal(expr::Literal::True), self.peek().span);
         if !self.check(token::TokenType::Semicolon) {
             condition = self.expression()?
         }
@@ -151,35 +353,49 @@ impl Parser {
         }
         self.consume(token::TokenType::RightParen, "Expect ')' after for expression.")?;
 
-        let mut body = self.statement()?;
-        match increment {
-            None => {}
-            Some(inc) => {
-                body = expr::Statement::Block(vec![body, expr::Statement::Expression(inc)])
-            }
-        }
-
-        body = expr::Statement::While(condition, Box::new(body));
+        let body = self.loop_body(&label)?;
+        // the desugared statements all point at the whole loop
+        let span = self.since(start);
+        let mut body = expr::Statement::new(expr::StatementKind::While {
+            condition,
+            body: Box::new(body),
+            increment,
+            label,
+        }, span);
 
         match initializer {
             None => {}
             Some(init) => {
-                body = expr::Statement::Block(vec![init, body])
+                body = expr::Statement::new(expr::StatementKind::Block(vec![init, body]), span)
             }
         }
 
         return Ok(body);
     }
 
-    pub fn while_statement(&mut self) -> Result<expr::Statement, expr::ExpError> {
+    pub fn loop_statement(&mut self, label: Option<String>) -> Result<expr::Statement, expr::ExpError> {
+        let start = self.previous().span;
+        self.consume(token::TokenType::LeftBrace, "Expect '{' after 'loop'.")?;
+        self.loops.push(label.clone());
+        let body = self.block();
+        self.loops.pop();
+        let condition = expr::Expression::new(expr::ExpressionKind::Literal(expr::Literal::True), start);
+        let kind = expr::StatementKind::While { condition, body: Box::new(body?), increment: None, label };
+        return Ok(expr::Statement::new(kind, self.since(start)));
+    }
+
+    pub fn while_statement(&mut self, label: Option<String>) -> Result<expr::Statement, expr::ExpError> {
+        let start = self.previous().span;
         self.consume(token::TokenType::LeftParen, "Expect '(' after while expression.")?;
         let condition = self.expression()?;
         self.consume(token::TokenType::RightParen, "Expect ')' after while expression.")?;
-        let body = self.statement()?;
-        Ok(expr::Statement::While(condition, Box::new(body)))
+        let body = self.loop_body(&label)?;
+        let kind = expr::StatementKind::While { condition, body: Box::new(body), increment: None, label };
+        Ok(expr::Statement::new(kind, self.since(start)))
     }
 
     pub fn if_statement(&mut self) -> Result<expr::Statement, expr::ExpError> {
+        let start = self.previous().span;
         self.consume(token::TokenType::LeftParen, "Expect '(' after if expression.")?;
         let condition = self.expression()?;
         self.consume(token::TokenType::RightParen, "Expect ')' after if expression.")?;
@@ -191,40 +407,99 @@ impl Parser {
             else_branch = Some(Box::new(else_statement))
         }
 
-        return Ok(expr::Statement::If(condition, Box::new(then_branch), else_branch));
+        return Ok(expr::Statement::new(expr::StatementKind::If(condition, Box::new(then_branch), else_branch),
+                                       self.since(start)));
     }
 
     pub fn block(&mut self) -> Result<expr::Statement, expr::ExpError> {
+        self.scopes.push(vec![]);
+        let block = self.block_statements();
+        self.scopes.pop();
+        return block;
+    }
+
+    fn block_statements(&mut self) -> Result<expr::Statement, expr::ExpError> {
+        let start = self.previous().span;
         let mut statements = vec![];
         while !self.check(token::TokenType::RightBrace) && !self.at_end() {
             let statement = self.declaration()?;
             statements.push(statement)
         }
         self.consume(token::TokenType::RightBrace, "Expect '}' after expression.")?;
-        return Ok(expr::Statement::Block(statements));
+        return Ok(expr::Statement::new(expr::StatementKind::Block(statements), self.since(start)));
+    }
+
+    pub fn assert_statement(&mut self) -> Result<expr::Statement, expr::ExpError> {
+        let start = self.previous().span;
+        let first = self.current;
+        let condition = self.expression()?;
+        let text = token::source_text(&self.tokens[first..self.current]);
+        let mut message = None;
+        if self.match_token(vec![token::TokenType::Comma]) {
+            message = Some(self.expression()?);
+        }
+        self.terminator("Expect ';' after assertion.")?;
+        let kind = expr::StatementKind::Assert { condition, text, message };
+        return Ok(expr::Statement::new(kind, self.since(start)));
     }
 
     pub fn print_statement(&mut self) -> Result<expr::Statement, expr::ExpError> {
-        let expr = self.expression()?;
-        self.consume(token::TokenType::Semicolon, "Expect ';' after expression.")?;
+        let start = self.previous().span;
+        let mut values = vec![self.expression()?];
+        while self.match_token(vec![token::TokenType::Comma]) {
+            values.push(self.expression()?);
+        }
+        self.terminator("Expect ';' after expression.")?;
 
-        return Ok(expr::Statement::Print(expr));
+        return Ok(expr::Statement::new(expr::StatementKind::Print(values), self.since(start)));
     }
 
     pub fn expression_statement(&mut self) -> Result<expr::Statement, expr::ExpError> {
+        let start = self.peek().span;
         let expr = self.expression()?;
-        self.consume(token::TokenType::Semicolon, "Expect ';' after expression.")?;
-        return Ok(expr::Statement::Expression(expr));
+        self.terminator("Expect ';' after expression.")?;
+        return Ok(expr::Statement::new(expr::StatementKind::Expression(expr), self.since(start)));
+    }
+
+    // a name declared twice in the same block is an error, like the vm's compiler makes it.
+    // parsing carries on, the declaration itself is fine
+    fn declare(&mut self, name: &token::Token) {
+        let scope = match self.scopes.last_mut() {
+            None => return,
+            Some(scope) => scope,
+        };
+        match scope.iter().find(|(declared, _)| *declared == name.lexeme) {
+            None => scope.push((name.lexeme.clone(), name.span)),
+            Some((_, first)) => {
+                let first = *first;
+                self.errors.push(expr::ExpError::VariableRepeatDef { name: name.lexeme.clone(), span: name.span, first });
+            }
+        }
+    }
+
+    fn terminator(&mut self, message: &str) -> Result<(), expr::ExpError> {
+        if self.match_token(vec![token::TokenType::Semicolon]) || self.ends_line() {
+            return Ok(());
+        }
+        self.consume(token::TokenType::Semicolon, message)?;
+        return Ok(());
+    }
+
+    fn ends_line(&mut self) -> bool {
+        if !self.newlines {
+            return false;
+        }
+        return self.newline_before[self.current] || self.check(token::TokenType::RightBrace) || self.at_end();
     }
 
     fn synchronize(&mut self) {
         self.advance();
         while !self.at_end() {
-            if self.previous().token_type == token::TokenType::Semicolon {
+            if self.previous().token_type == token::TokenType::Semicolon || (self.newlines && self.newline_before[self.current]) {
                 return;
             }
             match self.peek().token_type {
-                token::TokenType::Class | token::TokenType::Fun | token::TokenType::Var | token::TokenType::For |
+                token::TokenType::Class | token::TokenType::Enum | token::TokenType::Fun | token::TokenType::Var | token::TokenType::For |
                 token::TokenType::If | token::TokenType::While | token::TokenType::Print | token::TokenType::Return => {
                     return;
                 }
@@ -245,19 +520,29 @@ impl Parser {
         if self.match_token(vec![token::TokenType::Equal]) {
             let equals = self.previous().clone();
             let value = self.assignment()?;
+            let span = expr.span.to(value.span);
 
-            return match expr {
-                expr::Expression::Variable(token) => {
-                    Ok(expr::Expression::Assign(token, Box::new(value)))
+            return match expr.kind {
+                expr::ExpressionKind::Variable(token) => {
+                    Ok(expr::Expression::new(expr::ExpressionKind::Assign(token, Box::new(value)), span))
                 }
-                expr::Expression::Get {
-                    object, variable
+                expr::ExpressionKind::Get {
+                    object, variable, optional: false
                 } => {
-                    Ok(expr::Expression::Set {
+                    Ok(expr::Expression::new(expr::ExpressionKind::Set {
                         object,
                         variable,
                         value: Box::new(value),
-                    })
+                    }, span))
+                }
+                expr::ExpressionKind::Index {
+                    object, index
+                } => {
+                    Ok(expr::Expression::new(expr::ExpressionKind::SetIndex {
+                        object,
+                        index,
+                        value: Box::new(value),
+                    }, span))
                 }
                 _ => {
                     Err(expr::ExpError::AssignmentFailed {
@@ -274,7 +559,9 @@ impl Parser {
         let mut expr = self.and()?;
         while self.match_token(vec![token::TokenType::Or]) {
             let right = self.and()?;
-            expr = expr::Expression::Logical(Box::new(expr), expr::LogicalOperatorType::Or, Box::new(right))
+            let span = expr.span.to(right.span);
+            expr = expr::Expression::new(
+                expr::ExpressionKind::Logical(Box::new(expr), expr::LogicalOperatorType::Or, Box::new(right)), span)
         }
         return Ok(expr);
     }
@@ -283,7 +570,9 @@ impl Parser {
         let mut expr = self.equality()?;
         while self.match_token(vec![token::TokenType::And]) {
             let right = self.equality()?;
-            expr = expr::Expression::Logical(Box::new(expr), expr::LogicalOperatorType::And, Box::new(right))
+            let span = expr.span.to(right.span);
+            expr = expr::Expression::new(
+                expr::ExpressionKind::Logical(Box::new(expr), expr::LogicalOperatorType::And, Box::new(right)), span)
         }
         return Ok(expr);
     }
@@ -294,9 +583,11 @@ impl Parser {
         while self.match_token(vec![token::TokenType::BangEqual, token::TokenType::EqualEqual]) {
             let operator = self.previous().clone();
             let right = self.comparison()?;
-            expr = expr::Expression::Binary(Box::new(expr), expr::BinaryOp {
+            let span = expr.span.to(right.span);
+            expr = expr::Expression::new(expr::ExpressionKind::Binary(Box::new(expr), expr::BinaryOp {
                 token_type: Self::token_to_binary_token_type(&operator)?,
-            }, Box::new(right))
+                span: operator.span,
+            }, Box::new(right)), span)
         }
         return Ok(expr);
     }
@@ -304,13 +595,16 @@ impl Parser {
     fn comparison(&mut self) -> Result<expr::Expression, expr::ExpError> {
         let mut expr = self.term()?;
         while self.match_token(vec![token::TokenType::Greater, token::TokenType::GreaterEqual,
-                                    token::TokenType::Less, token::TokenType::LessEqual]) {
+                                    token::TokenType::Less, token::TokenType::LessEqual, token::TokenType::In,
+                                    token::TokenType::Is]) {
             let operator = self.previous().clone();
             let right = self.term()?;
 
-            expr = expr::Expression::Binary(Box::new(expr), expr::BinaryOp {
+            let span = expr.span.to(right.span);
+            expr = expr::Expression::new(expr::ExpressionKind::Binary(Box::new(expr), expr::BinaryOp {
                 token_type: Self::token_to_binary_token_type(&operator)?,
-            }, Box::new(right))
+                span: operator.span,
+            }, Box::new(right)), span)
         }
         return Ok(expr);
     }
@@ -320,9 +614,11 @@ impl Parser {
         while self.match_token(vec![token::TokenType::Minus, token::TokenType::Plus]) {
             let operator = self.previous().clone();
             let right = self.factor()?;
-            expr = expr::Expression::Binary(Box::new(expr), expr::BinaryOp {
+            let span = expr.span.to(right.span);
+            expr = expr::Expression::new(expr::ExpressionKind::Binary(Box::new(expr), expr::BinaryOp {
                 token_type: Self::token_to_binary_token_type(&operator)?,
-            }, Box::new(right))
+                span: operator.span,
+            }, Box::new(right)), span)
         }
         return Ok(expr);
     }
@@ -332,20 +628,24 @@ impl Parser {
         while self.match_token(vec![token::TokenType::Slash, token::TokenType::Star]) {
             let operator = self.previous().clone();
             let right = self.unary()?;
-            expr = expr::Expression::Binary(Box::new(expr), expr::BinaryOp {
+            let span = expr.span.to(right.span);
+            expr = expr::Expression::new(expr::ExpressionKind::Binary(Box::new(expr), expr::BinaryOp {
                 token_type: Self::token_to_binary_token_type(&operator)?,
-            }, Box::new(right))
+                span: operator.span,
+            }, Box::new(right)), span)
         }
         return Ok(expr);
     }
 
     fn unary(&mut self) -> Result<expr::Expression, expr::ExpError> {
-        while self.match_token(vec![token::TokenType::Bang, token::TokenType::Minus]) {
+        if self.match_token(vec![token::TokenType::Bang, token::TokenType::Minus]) {
             let operator = self.previous().clone();
             let right = self.unary()?;
-            return Ok(expr::Expression::Unary(expr::UnaryOp {
-                token_type: Self::token_to_unary_token_type(&operator)?
-            }, Box::new(right)));
+            let span = operator.span.to(right.span);
+            return Ok(expr::Expression::new(expr::ExpressionKind::Unary(expr::UnaryOp {
+                token_type: Self::token_to_unary_token_type(&operator)?,
+                span: operator.span,
+            }, Box::new(right)), span));
         }
         return self.call();
     }
@@ -359,10 +659,28 @@ impl Parser {
                 expr = self.finish_call(expr)?;
             } else if self.match_token(vec![token::TokenType::Dot]) {
                 let variable = self.consume(token::TokenType::Identifier, "Expect property name after '.'.")?.clone();
-                expr = expr::Expression::Get {
+                let span = expr.span.to(variable.span);
+                expr = expr::Expression::new(expr::ExpressionKind::Get {
                     object: Box::new(expr),
                     variable: variable.lexeme.to_string(),
-                }
+                    optional: false,
+                }, span)
+            } else if self.match_token(vec![token::TokenType::LeftBracket]) {
+                let index = self.expression()?;
+                self.consume(token::TokenType::RightBracket, "Expect ']' after index.")?;
+                let span = self.since(expr.span);
+                expr = expr::Expression::new(expr::ExpressionKind::Index {
+                    object: Box::new(expr),
+                    index: Box::new(index),
+                }, span)
+            } else if self.match_token(vec![token::TokenType::QuestionDot]) {
+                let variable = self.consume(token::TokenType::Identifier, "Expect property name after '?.'.")?.clone();
+                let span = expr.span.to(variable.span);
+                expr = expr::Expression::new(expr::ExpressionKind::Get {
+                    object: Box::new(expr),
+                    variable: variable.lexeme.to_string(),
+                    optional: true,
+                }, span)
             } else {
                 break;
             }
@@ -375,38 +693,49 @@ impl Parser {
         let mut arguments = vec![];
         if !self.check(token::TokenType::RightParen) {
             loop {
-                if arguments.len() >= 255 {
-                    return Err(expr::ExpError::TooManyArgs);
+                if arguments.len() >= expr::MAX_ARGS {
+                    return Err(expr::ExpError::TooManyArgs("arguments", self.peek().span));
                 }
 
-                arguments.push(self.expression()?);
-                if !self.match_token(vec![token::TokenType::Comma]) {
+                if self.match_token(vec![token::TokenType::Ellipsis]) {
+                    let start = self.previous().span;
+                    let spread = self.expression()?;
+                    arguments.push(expr::Expression::new(expr::ExpressionKind::Spread(Box::new(spread)), self.since(start)));
+                } else {
+                    arguments.push(self.expression()?);
+                }
+                // a trailing comma is fine, a missing argument after it isn't
+                if !self.match_token(vec![token::TokenType::Comma]) || self.check(token::TokenType::RightParen) {
                     break;
                 }
             }
         }
-        let paren = self.consume(token::TokenType::RightParen, "Expect ')' after arguments.")?;
-        return Ok(expr::Expression::Call(Box::new(callee), paren.lexeme.to_string(), arguments));
+        let paren = self.consume(token::TokenType::RightParen, "Expect ')' after arguments.")?.lexeme.to_string();
+        let span = self.since(callee.span);
+        return Ok(expr::Expression::new(expr::ExpressionKind::Call(Box::new(callee), paren, arguments), span));
     }
 
 
     fn primary(&mut self) -> Result<expr::Expression, expr::ExpError> {
         if self.match_token(vec![token::TokenType::False]) {
-            return Ok(expr::Expression::Literal(expr::Literal::False));
+            return Ok(self.token_expression(expr::ExpressionKind::Literal(expr::Literal::False)));
         }
 
         if self.match_token(vec![token::TokenType::True]) {
-            return Ok(expr::Expression::Literal(expr::Literal::True));
+            return Ok(self.token_expression(expr::ExpressionKind::Literal(expr::Literal::True)));
         }
 
         if self.match_token(vec![token::TokenType::Nil]) {
-            return Ok(expr::Expression::Literal(expr::Literal::Nil));
+            return Ok(self.token_expression(expr::ExpressionKind::Literal(expr::Literal::Nil)));
         }
 
         if self.match_token(vec![token::TokenType::Number]) {
             match &self.previous().literal {
+                Some(token::Literal::Int(n)) => {
+                    return Ok(self.token_expression(expr::ExpressionKind::Literal(expr::Literal::Int(*n))));
+                }
                 Some(token::Literal::Number(n)) => {
-                    return Ok(expr::Expression::Literal(expr::Literal::Number(*n)));
+                    return Ok(self.token_expression(expr::ExpressionKind::Literal(expr::Literal::Number(*n))));
                 }
                 Some(l) => panic!(
                     "internal error in parser: when parsing number, found  expr::Literal {:?}",
@@ -419,7 +748,7 @@ impl Parser {
         if self.match_token(vec![token::TokenType::String]) {
             match &self.previous().literal {
                 Some(token::Literal::Str(str)) => {
-                    return Ok(expr::Expression::Literal(expr::Literal::String(str.to_string())));
+                    return Ok(self.token_expression(expr::ExpressionKind::Literal(expr::Literal::String(str.to_string()))));
                 }
                 Some(l) => panic!(
                     "internal error in parser: when parsing string, found  expr::Literal {:?}",
@@ -430,44 +759,105 @@ impl Parser {
         }
 
         if self.match_token(vec![token::TokenType::Identifier]) {
-            return Ok(expr::Expression::Variable(self.previous().lexeme.to_string()));
+            return Ok(self.token_expression(expr::ExpressionKind::Variable(self.previous().lexeme.to_string())));
         }
 
         if self.match_token(vec![token::TokenType::Super]) {
+            let start = self.previous().span;
             let keyword = self.previous().lexeme.to_string();
+            match self.classes.last() {
+                None => return Err(expr::ExpError::Common("Can't use 'super' outside of a class.".to_string())),
+                Some(false) => return Err(expr::ExpError::Common("Can't use 'super' in a class with no superclass.".to_string())),
+                Some(true) => {}
+            }
             self.consume(token::TokenType::Dot, "Expect '.' after 'super'.")?;
             let method = self.consume(token::TokenType::Identifier, "Expect 'method' after 'super.'.")?.lexeme.clone();
-            return Ok(expr::Expression::Super { keyword, method });
+            return Ok(expr::Expression::new(expr::ExpressionKind::Super { keyword, method }, self.since(start)));
         }
 
         if self.match_token(vec![token::TokenType::This]) {
-            return Ok(expr::Expression::This(self.previous().lexeme.to_string()));
+            return Ok(self.token_expression(expr::ExpressionKind::This(self.previous().lexeme.to_string())));
         }
 
         if self.match_token(vec![token::TokenType::LeftParen]) {
+            let start = self.previous().span;
             let expr = self.expression()?;
             self.consume(token::TokenType::RightParen, "Expect ')' after expression.")?;
-            return Ok(expr::Expression::Grouping(Box::new(expr)));
+            return Ok(expr::Expression::new(expr::ExpressionKind::Grouping(Box::new(expr)), self.since(start)));
+        }
+
+        if self.match_token(vec![token::TokenType::LeftBracket]) {
+            let start = self.previous().span;
+            let mut elements = vec![];
+            if !self.check(token::TokenType::RightBracket) {
+                loop {
+                    elements.push(self.expression()?);
+                    if !self.match_token(vec![token::TokenType::Comma]) || self.check(token::TokenType::RightBracket) {
+                        break;
+                    }
+                }
+            }
+            self.consume(token::TokenType::RightBracket, "Expect ']' after array elements.")?;
+            return Ok(expr::Expression::new(expr::ExpressionKind::Array(elements), self.since(start)));
+        }
+
+        // a brace only starts a map in expression position, statements still see a block
+        if self.match_token(vec![token::TokenType::LeftBrace]) {
+            let start = self.previous().span;
+            let mut entries = vec![];
+            if !self.check(token::TokenType::RightBrace) {
+                loop {
+                    let key = self.expression()?;
+                    self.consume(token::TokenType::Colon, "Expect ':' after map key.")?;
+                    let value = self.expression()?;
+                    entries.push((key, value));
+                    if !self.match_token(vec![token::TokenType::Comma]) || self.check(token::TokenType::RightBrace) {
+                        break;
+                    }
+                }
+            }
+            self.consume(token::TokenType::RightBrace, "Expect '}' after map entries.")?;
+            return Ok(expr::Expression::new(expr::ExpressionKind::Map(entries), self.since(start)));
         }
 
         return Err(expr::ExpError::ExpectedExpression {
             token_type: self.peek().token_type,
-            line: self.peek().line,
+            span: self.peek().span,
         });
     }
 
+    fn since(&self, start: Span) -> Span {
+        return start.to(self.previous().span);
+    }
+
+    fn token_expression(&self, kind: expr::ExpressionKind) -> expr::Expression {
+        return expr::Expression::new(kind, self.previous().span);
+    }
+
     fn consume(&mut self, ty: token::TokenType, message: &str) -> Result<&token::Token, expr::ExpError> {
         if self.check(ty) {
             return Ok(self.advance());
         }
         return Err(expr::ExpError::TokenMismatch {
-            expected: ty.clone(),
-            found: self.previous().clone(),
+            expected: ty,
+            found: Box::new(self.previous().clone()),
             err_string: Some(message.to_string()),
         });
     }
 
 
+    fn is_label(&self) -> bool {
+        let ahead = |n: usize| self.tokens.get(self.current + n).map(|t| t.token_type);
+        return ahead(0) == Some(token::TokenType::Identifier) && ahead(1) == Some(token::TokenType::Colon);
+    }
+
+    fn is_for_in(&self) -> bool {
+        let ahead = |n: usize| self.tokens.get(self.current + n).map(|t| t.token_type);
+        return ahead(0) == Some(token::TokenType::Var)
+            && ahead(1) == Some(token::TokenType::Identifier)
+            && ahead(2) == Some(token::TokenType::In);
+    }
+
     fn match_token(&mut self, token_types: Vec<token::TokenType>) -> bool {
         for token_type in token_types {
             if self.check(token_type) {
@@ -500,7 +890,7 @@ impl Parser {
         return &self.tokens[self.current];
     }
 
-    fn previous(&mut self) -> &token::Token {
+    fn previous(&self) -> &token::Token {
         return &self.tokens[self.current - 1];
     }
 
@@ -512,6 +902,8 @@ impl Parser {
             token::TokenType::LessEqual => Ok(expr::BinaryOperatorType::LessEqual),
             token::TokenType::Greater => Ok(expr::BinaryOperatorType::Greater),
             token::TokenType::GreaterEqual => Ok(expr::BinaryOperatorType::GreaterEqual),
+            token::TokenType::In => Ok(expr::BinaryOperatorType::In),
+            token::TokenType::Is => Ok(expr::BinaryOperatorType::Is),
             token::TokenType::Plus => Ok(expr::BinaryOperatorType::Plus),
             token::TokenType::Minus => Ok(expr::BinaryOperatorType::Minus),
             token::TokenType::Star => Ok(expr::BinaryOperatorType::Star),
@@ -519,8 +911,8 @@ impl Parser {
             _ => Err(expr::ExpError::ConvertFailed {
                 expected: vec![token::TokenType::BangEqual, token::TokenType::EqualEqual, token::TokenType::Less,
                                token::TokenType::LessEqual, token::TokenType::Greater, token::TokenType::GreaterEqual,
-                               token::TokenType::Plus, token::TokenType::Minus, token::TokenType::Star, token::TokenType::Slash],
-                found: token.clone(),
+                               token::TokenType::In, token::TokenType::Is, token::TokenType::Plus, token::TokenType::Minus, token::TokenType::Star, token::TokenType::Slash],
+                found: Box::new(token.clone()),
             }),
         }
     }
@@ -531,8 +923,294 @@ impl Parser {
             token::TokenType::Bang => Ok(expr::UnaryOperatorType::Bang),
             _ => Err(expr::ExpError::ConvertFailed {
                 expected: vec![token::TokenType::Minus, token::TokenType::Bang],
-                found: token.clone(),
+                found: Box::new(token.clone()),
             }),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::process::parser::Parser;
+    use crate::process::scanner;
+    use crate::runtime::support::{both_engines, runtime_error};
+    use crate::runtime::{Runtime, VMRuntime};
+    use crate::types::err::LoxError;
+    use crate::types::expr;
+    use crate::types::span::Span;
+
+    #[test]
+    fn reports_every_syntax_error() {
+        let tokens = scanner::scan_tokens("var a = ;\nvar b = 2;\nprint );\nprint b;".to_string()).unwrap();
+        let errors = Parser::new(tokens).parse().unwrap_err();
+        let lines: Vec<usize> = errors.iter().map(|e| e.span().expect("should have span").line).collect();
+        assert_eq!(lines, vec![1, 3]);
+    }
+
+    #[test]
+    fn spans_cover_the_whole_node() {
+        let tokens = scanner::scan_tokens("print 1;\n  1 + 2 * 3;".to_string()).unwrap();
+        let statements = Parser::new(tokens).parse().unwrap();
+        let statement = &statements[1];
+        assert_eq!(statement.span, Span { line: 2, col: 3, len: 10, end_line: 2, end_col: 13 });
+        match &statement.kind {
+            expr::StatementKind::Expression(binary) => {
+                assert!(matches!(binary.kind, expr::ExpressionKind::Binary(..)));
+                assert_eq!(binary.span, Span { line: 2, col: 3, len: 9, end_line: 2, end_col: 12 });
+            }
+            other => panic!("not an expression statement {:?}", other),
+        }
+    }
+
+    #[test]
+    fn newlines_can_end_statements() {
+        let source = "var a = 1 +\n  2\nprint a, \"b\"\nfun f() { return }\nwhile (a < 3) { a = a + 1; break }\nfun g() {\n  return\n  a\n}";
+        let parse = |newlines: bool| {
+            let mut parser = Parser::new(scanner::scan_tokens_with_newlines(source.to_string()).unwrap());
+            parser.newlines = newlines;
+            parser.parse()
+        };
+        let statements = parse(true).unwrap();
+        let kinds: Vec<String> = statements.iter().map(|s| format!("{:?}", s.kind).split(['(', ' ']).next().unwrap().to_string()).collect();
+        assert_eq!(kinds, vec!["Var", "Print", "Function", "While", "Function"]);
+        match &statements[0].kind {
+            expr::StatementKind::Var(_, initializer) => assert!(matches!(initializer.kind, expr::ExpressionKind::Binary(..))),
+            other => panic!("not a var {:?}", other),
+        }
+        // a return at the end of its line returns nothing, the next line is a statement of its own
+        match &statements[4].kind {
+            expr::StatementKind::Function(_, _, body) => match &body.kind {
+                expr::StatementKind::Block(body) => {
+                    assert!(matches!(body[0].kind, expr::StatementKind::Return(_, None)));
+                    assert!(matches!(body[1].kind, expr::StatementKind::Expression(_)));
+                }
+                other => panic!("not a block {:?}", other),
+            },
+            other => panic!("not a function {:?}", other),
+        }
+
+        assert!(parse(false).is_err());
+        let same_line = scanner::scan_tokens_with_newlines("var a = 1 var b = 2".to_string()).unwrap();
+        let mut parser = Parser::new(same_line);
+        parser.newlines = true;
+        assert!(parser.parse().is_err());
+    }
+
+    #[test]
+    fn print_takes_several_values() {
+        let source = r#"
+            var log = [];
+            fun note(x) { push(log, x); return x; }
+            print 1, "two", [3.5, nil];
+            print note("a"), note("b") + note("c");
+            print log;
+            print 7;
+        "#;
+        let (out, vm_out) = both_engines(source);
+        let expected = "1 two [3.5, nil]\na bc\n[a, b, c]\n7\n";
+        assert_eq!(out, expected);
+        assert_eq!(vm_out, expected);
+
+        // a later value changing an earlier one shows in both
+        let (out, vm_out) = both_engines("var xs = [3, 1, 2]; print xs, pop(xs), xs;");
+        assert_eq!(out, "[3, 1] 2 [3, 1]\n");
+        assert_eq!(vm_out, out);
+
+        assert!(matches!(Runtime::default().run_source("print 1, 2,;".to_string()), Err(LoxError::Parse(_))));
+        assert!(matches!(VMRuntime::default().run_source("print 1, 2,;".to_string()), Err(LoxError::Compile(_))));
+    }
+
+    #[test]
+    fn assert_reports_its_source() {
+        let mut runtime = Runtime::default();
+        let mut vm_runtime = VMRuntime::default();
+        let pass = "var x = 2; assert x > 0; assert x, \"unused\" + nil;";
+        runtime.run_source(pass.to_string()).unwrap();
+        vm_runtime.run_source(pass.to_string()).unwrap();
+
+        let fail = "var y = -1;\nassert y  >  0 and [y][0] != nil;";
+        let expected = "Assertion failed: y  >  0 and [y][0] != nil [line 2]";
+        assert!(runtime_error(runtime.run_source(fail.to_string())).contains(expected));
+        assert!(runtime_error(vm_runtime.run_source(fail.to_string())).contains(expected));
+
+        let message = "var z = nil;\nassert z,\n  format(\"z is {}\", z);";
+        let expected = "Assertion failed: z: z is nil [line 2]";
+        assert!(runtime_error(runtime.run_source(message.to_string())).contains(expected));
+        assert!(runtime_error(vm_runtime.run_source(message.to_string())).contains(expected));
+
+        let mut stripped = VMRuntime { strip_asserts: true, ..VMRuntime::default() };
+        stripped.run_source("assert false; var after = 1;".to_string()).unwrap();
+        assert!(matches!(stripped.run_source("assert ;".to_string()), Err(LoxError::Compile(_))));
+    }
+
+    #[test]
+    fn break_needs_an_enclosing_loop() {
+        let mut runtime = Runtime::default();
+        let mut vm_runtime = VMRuntime::default();
+        let sources = [
+            "break;",
+            "while (true) { fun f() { break; } }",
+            "continue;",
+            "a: while (true) { break b; }",
+            "a: while (true) { fun f() { while (true) { continue a; } } }",
+            "a: while (true) { a: while (true) {} }",
+            "a: print 1;",
+            "loop print 1;",
+        ];
+        for source in sources {
+            assert!(matches!(runtime.run_source(source.to_string()), Err(LoxError::Parse(_))), "{}", source);
+            assert!(matches!(vm_runtime.run_source(source.to_string()), Err(LoxError::Compile(_))), "{}", source);
+        }
+        assert!(matches!(runtime.run_source("for (var x in 1) {}".to_string()), Err(LoxError::Runtime(_))));
+        assert!(matches!(vm_runtime.run_source("for (var x in range(0, 1, 0)) {}".to_string()), Err(LoxError::Runtime(_))));
+    }
+
+    #[test]
+    fn superclass_must_be_another_class() {
+        let mut runtime = Runtime::default();
+        let mut vm_runtime = VMRuntime::default();
+        assert!(matches!(runtime.run_source("class A < A {}".to_string()), Err(LoxError::Parse(_))));
+        assert!(matches!(vm_runtime.run_source("class A < A {}".to_string()), Err(LoxError::Compile(_))));
+
+        let source = "var n = 1;\nclass A < n {}";
+        let expected = "Superclass must be a class. [line 2]";
+        assert!(runtime_error(runtime.run_source(source.to_string())).contains(expected));
+        assert!(runtime_error(vm_runtime.run_source(source.to_string())).contains(expected));
+    }
+
+    #[test]
+    fn super_needs_a_superclass() {
+        let mut runtime = Runtime::default();
+        let mut vm_runtime = VMRuntime::default();
+        let sources = [
+            ("super.m();", "Can't use 'super' outside of a class."),
+            ("fun f() { return super.m(); }", "Can't use 'super' outside of a class."),
+            ("class A { m() { return super.m(); } }", "Can't use 'super' in a class with no superclass."),
+            ("class A {} class B < A { m() { class C { n() { return super.m; } } } }",
+             "Can't use 'super' in a class with no superclass."),
+        ];
+        for (source, expected) in sources {
+            match runtime.run_source(source.to_string()) {
+                Err(LoxError::Parse(e)) => assert!(format!("{:?}", e).contains(expected), "{}: {:?}", source, e),
+                other => panic!("{} should fail to parse, got {:?}", source, other),
+            }
+            match vm_runtime.run_source(source.to_string()) {
+                Err(LoxError::Compile(e)) => assert!(format!("{:?}", e).contains(expected), "{}: {:?}", source, e),
+                other => panic!("{} should fail to compile, got {:?}", source, other),
+            }
+        }
+    }
+
+    #[test]
+    fn parameters_must_be_distinct_names() {
+        let mut runtime = Runtime::default();
+        let mut vm_runtime = VMRuntime::default();
+        let sources = [
+            ("fun f(a, b,\n  a) {}", "Duplicate parameter 'a' at line=2."),
+            ("class A { m(x, x) {} }", "Duplicate parameter 'x' at line=1."),
+            ("fun f(this) {}", "Can't use reserved word 'this' as a parameter name at line=1."),
+            ("class A {\n m(a, class) {} }", "Can't use reserved word 'class' as a parameter name at line=2."),
+        ];
+        for (source, expected) in sources {
+            match runtime.run_source(source.to_string()) {
+                Err(LoxError::Parse(e)) => assert_eq!(e[0].to_string(), expected, "{}", source),
+                other => panic!("{} should fail to parse, got {:?}", source, other),
+            }
+            match vm_runtime.run_source(source.to_string()) {
+                Err(LoxError::Compile(e)) => assert_eq!(e[0].to_string(), expected, "{}", source),
+                other => panic!("{} should fail to compile, got {:?}", source, other),
+            }
+        }
+        runtime.run_source("fun f(a, b) { var c = a; } class A { m(a) { fun g(a) {} } }".to_string()).unwrap();
+        vm_runtime.run_source("fun f(a, b) { var c = a; } class A { m(a) { fun g(a) {} } }".to_string()).unwrap();
+    }
+
+    #[test]
+    fn calls_take_at_most_255_arguments() {
+        let names: Vec<String> = (0..300).map(|i| format!("p{}", i)).collect();
+        let values: Vec<String> = (0..300).map(|i| i.to_string()).collect();
+        let sources = [
+            (format!("fun f({}) {{}}", names.join(", ")), "Can't have more than 255 parameters at line=1."),
+            (format!("fun f() {{}}\nf({});", values.join(", ")), "Can't have more than 255 arguments at line=2."),
+        ];
+        for (source, expected) in sources {
+            match Runtime::default().run_source(source.clone()) {
+                Err(LoxError::Parse(e)) => assert_eq!(e[0].to_string(), expected),
+                other => panic!("should fail to parse, got {:?}", other),
+            }
+            match VMRuntime::default().run_source(source.clone()) {
+                Err(LoxError::Compile(e)) => assert_eq!(e[0].to_string(), expected),
+                other => panic!("should fail to compile, got {:?}", other),
+            }
+        }
+
+        // a trailing comma doesn't count against the limit
+        let source = format!("fun f({},) {{ return p254; }}\nvar last = f({},);", names[..255].join(", "), values[..255].join(", "));
+        Runtime::default().run_source(source.clone()).unwrap();
+        VMRuntime::default().run_source(source).unwrap();
+    }
+
+    #[test]
+    fn lists_may_end_with_a_comma() {
+        let source = r#"
+            fun add(a, b,) { return a + b; }
+            var xs = [1, 2, 3,];
+            var m = {"a": 1, "b": 2,};
+            print add(
+                1,
+                2,
+            ), xs, m, [], {};
+        "#;
+        let (out, vm_out) = both_engines(source);
+        let expected = "3 [1, 2, 3] {a: 1, b: 2} [] {}\n";
+        assert_eq!(out, expected);
+        assert_eq!(vm_out, expected);
+
+        let rejected = [
+            "fun f() {} f(,);",
+            "fun f(a) {} f(1,,);",
+            "fun f(,) {}",
+            "fun f(a,,) {}",
+            "var xs = [,];",
+            "var xs = [1,,];",
+            "var m = {,};",
+            "var m = {\"a\": 1,,};",
+        ];
+        for source in rejected {
+            assert!(matches!(Runtime::default().run_source(source.to_string()), Err(LoxError::Parse(_))), "{}", source);
+            assert!(matches!(VMRuntime::default().run_source(source.to_string()), Err(LoxError::Compile(_))), "{}", source);
+        }
+    }
+
+    fn declaration_error(result: Result<(), LoxError>) -> (String, usize) {
+        return match result {
+            Err(LoxError::Parse(errors)) | Err(LoxError::Compile(errors)) => {
+                // the declaration parses fine, nothing after it should be reported
+                assert_eq!(errors.len(), 1, "{:?}", errors);
+                (errors[0].to_string(), errors[0].span().expect("should have span").line)
+            }
+            other => panic!("should fail to compile, got {:?}", other),
+        };
+    }
+
+    #[test]
+    fn locals_are_declared_once_per_scope() {
+        let repeats = [
+            ("{\n  var a = 1;\n  var a = 2;\n}", "a, Variable repeat def, first declared at line=2.", 3),
+            ("fun f(a) {\n  var a = 1;\n}", "a, Variable repeat def, first declared at line=1.", 2),
+            ("{\n  fun g() {}\n  class g {}\n}", "g, Variable repeat def, first declared at line=2.", 3),
+        ];
+        for (source, message, line) in repeats {
+            let expected = (message.to_string(), line);
+            assert_eq!(declaration_error(Runtime::default().run_source(source.to_string())), expected, "{}", source);
+            assert_eq!(declaration_error(VMRuntime::default().run_source(source.to_string())), expected, "{}", source);
+        }
+
+        let source = "var a = 1; var a = 2; { var b = a; { var b = 1; print a + b; } } \
+            for (var i = 0; i < 1; i = i + 1) { var i = 5; print i; } for (var x in [1]) { var x = 7; print x; }";
+        let expected = "3\n5\n7\n";
+        let (out, vm_out) = both_engines(source);
+        assert_eq!(out, expected);
+        assert_eq!(vm_out, expected);
+    }
+}
@@ -1,6 +1,5 @@
-use std::error::Error;
-
-use crate::types::err::new_error;
+use crate::types::err::{new_error, ScanError};
+use crate::types::span::Span;
 use crate::types::token;
 
 pub struct Scanner {
@@ -9,12 +8,27 @@ pub struct Scanner {
     start: usize,
     current: usize,
     line: usize,
+    line_start: usize,
+    start_line: usize,
+    start_col: usize,
+    pub keep_comments: bool,
+    pub significant_newlines: bool,
 }
 
 pub fn scan_tokens(source: String) -> token::TokenResult {
+    return Scanner::new(source).tokens();
+}
+
+pub fn scan_tokens_with_comments(source: String) -> token::TokenResult {
     let mut scanner = Scanner::new(source);
-    scanner.scan_tokens();
-    return Ok(scanner.tokens);
+    scanner.keep_comments = true;
+    return scanner.tokens();
+}
+
+pub fn scan_tokens_with_newlines(source: String) -> token::TokenResult {
+    let mut scanner = Scanner::new(source);
+    scanner.significant_newlines = true;
+    return scanner.tokens();
 }
 
 impl Scanner {
@@ -24,13 +38,30 @@ impl Scanner {
             tokens: vec![],
             start: 0,
             current: 0,
-            line: 1 as usize,
+            line: 1,
+            line_start: 0,
+            start_line: 1,
+            start_col: 1,
+            keep_comments: false,
+            significant_newlines: false,
         };
     }
 
-    pub fn scan_tokens(&mut self) -> Option<Box<dyn Error>> {
+    pub fn tokens(mut self) -> token::TokenResult {
+        match self.scan_tokens() {
+            None => {}
+            Some(e) => {
+                return Err(e);
+            }
+        }
+        return Ok(self.tokens);
+    }
+
+    pub fn scan_tokens(&mut self) -> Option<ScanError> {
         while !self.is_at_end() {
             self.start = self.current;
+            self.start_line = self.line;
+            self.start_col = self.current - self.line_start + 1;
             match self.scan_token() {
                 None => {}
                 Some(e) => {
@@ -43,14 +74,14 @@ impl Scanner {
             token_type: token::TokenType::Eof,
             lexeme: "".to_string(),
             literal: None,
-            line: self.line,
+            span: Span::new(self.line, self.current - self.line_start + 1, 0),
         });
 
         return None;
     }
 
 
-    fn scan_token(&mut self) -> Option<Box<dyn Error>> {
+    fn scan_token(&mut self) -> Option<ScanError> {
         let c = self.advance();
         match c {
             "(" => {
@@ -65,11 +96,23 @@ impl Scanner {
             "}" => {
                 self.add_token_type(token::TokenType::RightBrace)
             }
+            "[" => {
+                self.add_token_type(token::TokenType::LeftBracket)
+            }
+            "]" => {
+                self.add_token_type(token::TokenType::RightBracket)
+            }
             "," => {
                 self.add_token_type(token::TokenType::Comma)
             }
             "." => {
-                self.add_token_type(token::TokenType::Dot)
+                if self.peek() == Some(".") && self.peek_next() == Some(".") {
+                    self.advance();
+                    self.advance();
+                    self.add_token_type(token::TokenType::Ellipsis)
+                } else {
+                    self.add_token_type(token::TokenType::Dot)
+                }
             }
             "-" => {
                 self.add_token_type(token::TokenType::Minus)
@@ -83,6 +126,17 @@ impl Scanner {
             "*" => {
                 self.add_token_type(token::TokenType::Star)
             }
+            "?" => {
+                let next_token = if self.match_next(".") {
+                    token::TokenType::QuestionDot
+                } else {
+                    token::TokenType::Question
+                };
+                self.add_token_type(next_token)
+            }
+            ":" => {
+                self.add_token_type(token::TokenType::Colon)
+            }
             "!" => {
                 let next_token = if self.match_next("=") {
                     token::TokenType::BangEqual
@@ -121,11 +175,24 @@ impl Scanner {
                     while self.peek().is_some() && self.peek().unwrap() != "\n" && !self.is_at_end() {
                         self.advance();
                     }
+                    self.comment();
+                } else if self.match_next("*") {
+                    match self.block_comment() {
+                        None => {}
+                        Some(e) => {
+                            return Some(e);
+                        }
+                    }
+                } else {
+                    self.add_token_type(token::TokenType::Slash)
                 }
             }
             " " | "\r" | "\t" => {}
             "\n" => {
-                self.line += 1;
+                if self.significant_newlines {
+                    self.add_token_type(token::TokenType::Newline);
+                }
+                self.new_line();
             }
             "\"" => {
                 match self.string() {
@@ -141,7 +208,7 @@ impl Scanner {
                 } else if Self::is_alpha(c) {
                     self.identifier()
                 } else {
-                    return Some(new_error(self.line, "Unexpected character.".to_string()));
+                    return Some(new_error(self.token_span(), "Unexpected character.".to_string()));
                 }
             }
         }
@@ -149,6 +216,30 @@ impl Scanner {
         return None;
     }
 
+    // does not nest, the first `*/` closes it
+    fn block_comment(&mut self) -> Option<ScanError> {
+        loop {
+            if self.is_at_end() {
+                return Some(new_error(self.token_span(), "Unterminated block comment.".to_string()));
+            }
+            if self.match_next("*") && self.match_next("/") {
+                break;
+            }
+            let new_line = self.advance() == "\n";
+            if new_line {
+                self.new_line();
+            }
+        }
+        self.comment();
+        return None;
+    }
+
+    fn comment(&mut self) {
+        if self.keep_comments {
+            self.add_token_type(token::TokenType::Comment);
+        }
+    }
+
     fn number(&mut self) {
         while self.peek().is_some() && Self::is_digit(self.peek().unwrap()) {
             self.advance();
@@ -161,13 +252,18 @@ impl Scanner {
                 self.advance();
             }
         }
-        let x = self.source[self.start..self.current].parse::<f64>().unwrap();
-        self.add_token(token::TokenType::Number, Some(token::Literal::Number(x)));
+        // no decimal point means an int, unless it doesn't fit in one
+        let text = &self.source[self.start..self.current];
+        let literal = match text.parse::<i64>() {
+            Ok(int) => token::Literal::Int(int),
+            Err(_) => token::Literal::Number(text.parse::<f64>().unwrap()),
+        };
+        self.add_token(token::TokenType::Number, Some(literal));
     }
 
     fn is_alpha(input: &str) -> bool {
         let c = input.chars().nth(0).unwrap();
-        return (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || c == '_';
+        return c.is_ascii_alphabetic() || c == '_';
     }
 
     fn is_alpha_numeric(input: &str) -> bool {
@@ -193,19 +289,20 @@ impl Scanner {
 
     fn is_digit(input: &str) -> bool {
         let c = input.chars().nth(0).unwrap();
-        return c >= '0' && c <= '9';
+        return c.is_ascii_digit();
     }
 
-    fn string(&mut self) -> Option<Box<dyn Error>> {
+    fn string(&mut self) -> Option<ScanError> {
         while self.peek().is_some() && self.peek().unwrap() != "\"" && !self.is_at_end() {
-            if self.peek()? == "\n" {
-                self.line += 1
-            }
+            let new_line = self.peek()? == "\n";
             self.advance();
+            if new_line {
+                self.new_line();
+            }
         }
 
         if self.is_at_end() {
-            return Some(new_error(self.line, "Untermianted string.".to_string()));
+            return Some(new_error(self.token_span(), "Untermianted string.".to_string()));
         }
 
         self.advance();
@@ -221,10 +318,14 @@ impl Scanner {
     }
 
     fn peek_next(&mut self) -> Option<&str> {
-        if self.current + 1 >= self.source.len() {
+        if self.is_at_end() {
             return None;
         }
-        return Some(&self.source[self.current + 1..(self.current + 2)]);
+        let next = self.current + self.char_len(self.current);
+        if next >= self.source.len() {
+            return None;
+        }
+        return Some(&self.source[next..(next + self.char_len(next))]);
     }
 
     fn match_next(&mut self, expect: &str) -> bool {
@@ -234,7 +335,7 @@ impl Scanner {
         if self.current() != expect {
             return false;
         }
-        self.current += 1;
+        self.current += expect.len();
         return true;
     }
 
@@ -249,22 +350,180 @@ impl Scanner {
             token_type,
             lexeme: text,
             literal,
-            line: self.line,
+            span: self.token_span(),
         })
     }
 
+    // a token spanning several lines is underlined up to the end of its first line
+    fn token_span(&self) -> Span {
+        let text = &self.source[self.start..self.current];
+        let len = match text.find('\n') {
+            None => text.len(),
+            Some(end) => end,
+        };
+        return Span {
+            line: self.start_line,
+            col: self.start_col,
+            len,
+            end_line: self.line,
+            end_col: self.current - self.line_start + 1,
+        };
+    }
+
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.line_start = self.current;
+    }
+
 
     fn advance(&mut self) -> &str {
-        let advance = &self.source[self.current..(self.current + 1)];
-        self.current += 1;
-        return advance;
+        let start = self.current;
+        self.current += self.char_len(start);
+        return &self.source[start..self.current];
     }
 
     fn current(&mut self) -> &str {
-        return &self.source[self.current..(self.current + 1)];
+        return &self.source[self.current..(self.current + self.char_len(self.current))];
+    }
+
+    // source is utf-8, so one character may take several bytes
+    fn char_len(&self, at: usize) -> usize {
+        return self.source[at..].chars().next().map_or(1, |c| c.len_utf8());
     }
 
     fn is_at_end(&self) -> bool {
         return self.current >= self.source.len();
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::process::scanner::{scan_tokens, scan_tokens_with_comments, scan_tokens_with_newlines};
+    use crate::types::token::{Token, TokenType};
+
+    fn token_types(source: &str) -> Vec<TokenType> {
+        return scan_tokens(source.to_string()).unwrap().iter().map(|t| t.token_type).collect();
+    }
+
+    #[test]
+    fn scans_question_and_colon() {
+        assert_eq!(token_types("a ? b : c"), vec![
+            TokenType::Identifier,
+            TokenType::Question,
+            TokenType::Identifier,
+            TokenType::Colon,
+            TokenType::Identifier,
+            TokenType::Eof,
+        ]);
+        assert_eq!(token_types("a?.b ? c : d"), vec![
+            TokenType::Identifier,
+            TokenType::QuestionDot,
+            TokenType::Identifier,
+            TokenType::Question,
+            TokenType::Identifier,
+            TokenType::Colon,
+            TokenType::Identifier,
+            TokenType::Eof,
+        ]);
+        assert_eq!(token_types("{a: 1}"), vec![
+            TokenType::LeftBrace,
+            TokenType::Identifier,
+            TokenType::Colon,
+            TokenType::Number,
+            TokenType::RightBrace,
+            TokenType::Eof,
+        ]);
+    }
+
+    #[test]
+    fn scans_ellipsis_apart_from_dots() {
+        assert_eq!(token_types("f(...a.b)"), vec![
+            TokenType::Identifier,
+            TokenType::LeftParen,
+            TokenType::Ellipsis,
+            TokenType::Identifier,
+            TokenType::Dot,
+            TokenType::Identifier,
+            TokenType::RightParen,
+            TokenType::Eof,
+        ]);
+    }
+
+    #[test]
+    fn scans_brackets() {
+        assert_eq!(token_types("[1, 2]"), vec![
+            TokenType::LeftBracket,
+            TokenType::Number,
+            TokenType::Comma,
+            TokenType::Number,
+            TokenType::RightBracket,
+            TokenType::Eof,
+        ]);
+        assert_eq!(token_types("xs[[0][0]]"), vec![
+            TokenType::Identifier,
+            TokenType::LeftBracket,
+            TokenType::LeftBracket,
+            TokenType::Number,
+            TokenType::RightBracket,
+            TokenType::LeftBracket,
+            TokenType::Number,
+            TokenType::RightBracket,
+            TokenType::RightBracket,
+            TokenType::Eof,
+        ]);
+    }
+
+    #[test]
+    fn tokens_compare_without_position() {
+        let tokens = scan_tokens("var name =\n  \"lox\" + 1.5;".to_string()).unwrap();
+        assert_eq!(tokens, vec![
+            Token::simple(TokenType::Var, "var"),
+            Token::simple(TokenType::Identifier, "name"),
+            Token::simple(TokenType::Equal, "="),
+            Token::simple(TokenType::String, "\"lox\""),
+            Token::simple(TokenType::Plus, "+"),
+            Token::simple(TokenType::Number, "1.5"),
+            Token::simple(TokenType::Semicolon, ";"),
+            Token::simple(TokenType::Eof, ""),
+        ]);
+    }
+
+    #[test]
+    fn keeps_comments_only_when_asked() {
+        let source = "// leading\nvar a = 1; /* block\n spanning lines */ print a;";
+        let kept: Vec<(TokenType, String)> = scan_tokens_with_comments(source.to_string()).unwrap()
+            .into_iter()
+            .filter(|t| t.token_type == TokenType::Comment)
+            .map(|t| (t.token_type, t.lexeme))
+            .collect();
+        assert_eq!(kept, vec![
+            (TokenType::Comment, "// leading".to_string()),
+            (TokenType::Comment, "/* block\n spanning lines */".to_string()),
+        ]);
+
+        let tokens = scan_tokens(source.to_string()).unwrap();
+        assert!(tokens.iter().all(|t| t.token_type != TokenType::Comment));
+        // the line count carries on past the block comment
+        assert_eq!(tokens.iter().find(|t| t.token_type == TokenType::Print).unwrap().span.line, 3);
+        assert!(scan_tokens("/* open".to_string()).is_err());
+    }
+
+    #[test]
+    fn newlines_are_tokens_only_when_asked() {
+        let source = "var a = 1\n// note\nprint a /* two\nlines */\n";
+        let types: Vec<TokenType> = scan_tokens_with_newlines(source.to_string()).unwrap().into_iter().map(|t| t.token_type).collect();
+        assert_eq!(types, vec![
+            TokenType::Var,
+            TokenType::Identifier,
+            TokenType::Equal,
+            TokenType::Number,
+            TokenType::Newline,
+            TokenType::Newline,
+            TokenType::Print,
+            TokenType::Identifier,
+            TokenType::Newline,
+            TokenType::Eof,
+        ]);
+        assert!(!token_types(source).contains(&TokenType::Newline));
+    }
+}
@@ -1,19 +1,29 @@
-use std::{fs, io};
-use std::error::Error;
+use std::{fs, io, thread};
 use std::io::BufRead;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::process::{interpreter, parser, scanner};
+use crate::diagnostics;
+use crate::process::{optimizer, parser, scanner};
 use crate::process::interpreter::Interpreter;
-use crate::types::expr::ExpError;
-use crate::types::val::{InterpreterError, Value};
+use crate::stdlib::determinism;
+use crate::stdlib::determinism::Determinism;
+use crate::stdlib::random::Rng;
+use crate::types::err::LoxError;
+use crate::types::expr::{ExpError, Expression, Statement, StatementKind};
+use crate::types::output::Output;
+use crate::types::token::TokenType;
 use crate::vm::{compiler, vm};
-use crate::vm::chunk::Constant;
+use crate::vm::chunk::Function;
 use crate::vm::vm::FunctionType;
 
 pub struct VMRuntime {
-    had_error: bool,
-    vm: vm::VirtualMachine,
+    pub(crate) vm: vm::VirtualMachine,
     pub disassemble: bool,
+    pub timeout: Option<Duration>,
+    pub output: Output,
+    pub repl: bool,
+    pub strip_asserts: bool,
+    pub sandbox: bool,
 }
 
 
@@ -22,122 +32,542 @@ impl Default for VMRuntime {
         let mut machine = vm::VirtualMachine::default();
         machine.init();
         return VMRuntime {
-            had_error: false,
             vm: machine,
             disassemble: false,
+            timeout: None,
+            output: Output::default(),
+            repl: false,
+            strip_asserts: false,
+            sandbox: false,
         };
     }
 }
 
 impl VMRuntime {
-    pub fn run_file(&mut self, file_name: String) {
-        let all_file = fs::read_to_string(file_name).expect("read file error");
-        self.run(all_file);
-        if self.had_error {
-            std::process::exit(65);
-        }
-    }
-
-    fn run(&mut self, file: String) {
-        let tokens = scanner::scan_tokens(file);
-        let mut compiler = compiler::Compiler::new(tokens.unwrap(), FunctionType::Script);
-        match compiler.compile() {
-            Ok(func) => {
-                if self.disassemble {
-                    func.chunk.disassemble("main");
-                    return;
-                }
-                match self.vm.interpret(func) {
-                    Ok(_) => {
-                        let mut i = self.vm.stack.len();
-                        loop {
-                            let v = self.vm.stack.pop();
-                            if v.is_none() {
-                                break;
-                            }
-                            i -= 1;
-                            println!("stack #{}: value {:?}", i, v)
-                        }
-                    }
-                    Err(e) => {
-                        self.report(Box::new(e))
-                    }
+    pub fn seed(&mut self, seed: u64) {
+        self.vm.rng = Rng::seeded(seed);
+    }
+
+    // a fixed seed and a fake clock, sleep() only moves the clock
+    pub fn deterministic(&mut self) {
+        self.seed(determinism::SEED);
+        self.vm.determinism = Some(Determinism::default());
+    }
+
+    pub fn run_file(&mut self, file_name: String) -> Result<(), LoxError> {
+        let all_file = fs::read_to_string(file_name)?;
+        self.run_source(all_file)
+    }
+
+    pub fn run_source(&mut self, file: String) -> Result<(), LoxError> {
+        let result = self.run(file.clone());
+        if let Err(e) = &result {
+            self.report(&file, e);
+        }
+        result
+    }
+
+    pub fn check_file(&mut self, file_name: String) -> Result<(), LoxError> {
+        let source = fs::read_to_string(file_name)?;
+        let result = self.compile(source.clone()).map(|_| ());
+        if let Err(e) = &result {
+            self.report(&source, e);
+        }
+        result
+    }
+
+    fn compile(&self, file: String) -> Result<Function, LoxError> {
+        let result = self.compile_source(file.clone());
+        // a bare expression at the prompt reads as if it ended with `;`
+        match &result {
+            Err(err) if self.repl && misses_final_semicolon(&file, err) => {
+                if let Ok(func) = self.compile_source(format!("{};", file)) {
+                    return Ok(func);
                 }
             }
-            Err(e) => {
-                self.report(Box::new(e))
-            }
+            _ => {}
+        }
+        return result;
+    }
+
+    fn compile_source(&self, file: String) -> Result<Function, LoxError> {
+        let tokens = scanner::scan_tokens(file)?;
+        let mut compiler = compiler::Compiler::new(tokens, FunctionType::Script);
+        compiler.repl = self.repl;
+        compiler.strip_asserts = self.strip_asserts;
+        return compiler.compile().map_err(|e| LoxError::Compile(vec![e]));
+    }
+
+    fn run(&mut self, file: String) -> Result<(), LoxError> {
+        let func = self.compile(file)?;
+        if self.disassemble {
+            func.chunk.disassemble("main");
+            return Ok(());
+        }
+
+        self.vm.timeout = self.timeout;
+        self.vm.output = self.output.clone();
+        self.vm.sandbox = self.sandbox;
+        self.vm.interpret(func)?;
+        // whatever the script left behind is only of interest when debugging the vm
+        let mut i = self.vm.stack.len();
+        while let Some(v) = self.vm.stack.pop() {
+            i -= 1;
+            log::trace!("stack #{}: value {:?}", i, v);
         }
+        Ok(())
     }
 
-    fn report(&mut self, err: Box<dyn Error>) {
-        println!("{}", err);
-        self.had_error = true;
+    fn report(&mut self, source: &str, err: &LoxError) {
+        println!("{}", diagnostics::render_error(source, err));
+    }
+
+    pub fn run_prompt(&mut self) {
+        self.repl = true;
+        let stdin = io::stdin();
+        println!("input: ");
+        for line in stdin.lock().lines() {
+            let readed = line.unwrap();
+            if readed.is_empty() {
+                break;
+            }
+            // errors are already reported, the session keeps going
+            let _ = self.run_source(readed);
+        }
     }
 }
 
 
-pub struct Runtime {
-    had_error: bool,
-    interpreter: Interpreter,
+// the first error is the `;` expected after the last token of `source`
+fn misses_final_semicolon(source: &str, err: &LoxError) -> bool {
+    let found = match err {
+        LoxError::Parse(errors) | LoxError::Compile(errors) => match errors.first() {
+            Some(ExpError::TokenMismatch { expected: TokenType::Semicolon, found, .. }) => found,
+            _ => return false,
+        },
+        _ => return false,
+    };
+    return match scanner::scan_tokens(source.to_string()) {
+        Ok(tokens) => tokens.iter().rev().find(|token| token.token_type != TokenType::Eof)
+            .is_some_and(|last| last.span == found.span),
+        Err(_) => false,
+    };
+}
 
+pub struct Runtime {
+    pub(crate) interpreter: Interpreter,
+    pub timeout: Option<Duration>,
+    pub output: Output,
+    pub sandbox: bool,
+    pub optimize: bool,
+    pub repl: bool,
 }
 
 impl Default for Runtime {
     fn default() -> Self {
         return Runtime {
-            had_error: false,
             interpreter: Interpreter::default(),
+            timeout: None,
+            output: Output::default(),
+            sandbox: false,
+            optimize: false,
+            repl: false,
         };
     }
 }
 
 impl Runtime {
-    fn error(line: usize, message: String) {}
+    pub fn seed(&mut self, seed: u64) {
+        self.interpreter.rng = Rng::seeded(seed);
+    }
+
+    pub fn deterministic(&mut self) {
+        self.seed(determinism::SEED);
+        self.interpreter.determinism = Some(Determinism::default());
+    }
+
+    fn report(&mut self, source: &str, err: &LoxError) {
+        println!("{}", diagnostics::render_error(source, err));
+    }
 
-    fn report(&mut self, err: Box<dyn Error>) {
-        println!("{}", err);
-        self.had_error = true;
+    pub fn run_file(&mut self, file_name: String) -> Result<(), LoxError> {
+        let all_file = fs::read_to_string(file_name)?;
+        self.run_source(all_file)
     }
 
-    pub fn run_file(&mut self, file_name: String) {
-        let all_file = fs::read_to_string(file_name).expect("read file error");
-        self.run(all_file);
-        if self.had_error {
-            std::process::exit(65);
+    pub fn run_source(&mut self, file: String) -> Result<(), LoxError> {
+        let result = self.run(file.clone());
+        if let Err(e) = &result {
+            self.report(&file, e);
         }
+        result
     }
 
-    fn run(&mut self, file: String) {
-        let tokens = scanner::scan_tokens(file);
-        let expression = parser::Parser::new(tokens.unwrap()).parse();
-        match expression {
-            Ok(exp) => {
-                for ex in exp {
-                    match self.interpreter.interpret_statement(&ex) {
-                        Ok(result) => {}
-                        Err(e) => {
-                            self.report(Box::new(e))
-                        }
-                    }
-                }
-            }
-            Err(e) => {
-                self.report(Box::new(e))
-            }
+    pub fn check_file(&mut self, file_name: String) -> Result<(), LoxError> {
+        let source = fs::read_to_string(file_name)?;
+        let result = Self::parse(source.clone()).map(|_| ());
+        if let Err(e) = &result {
+            self.report(&source, e);
         }
+        result
+    }
+
+    fn parse(file: String) -> Result<Vec<Statement>, LoxError> {
+        let tokens = scanner::scan_tokens(file)?;
+        return parser::Parser::new(tokens).parse().map_err(LoxError::Parse);
+    }
+
+    fn run(&mut self, file: String) -> Result<(), LoxError> {
+        let (mut statements, echo) = self.parse_line(file)?;
+        if self.optimize {
+            optimizer::fold_constants(&mut statements);
+        }
+        self.interpreter.timeout = self.timeout;
+        self.interpreter.output = self.output.clone();
+        self.interpreter.sandbox = self.sandbox;
+        self.interpreter.interpret(&statements)?;
+        match echo {
+            None => {}
+            Some(expr) => self.interpreter.echo(&expr)?,
+        }
+        Ok(())
+    }
+
+    // at the prompt a line missing its last `;` is retried with one, and if it then ends
+    // in an expression that expression is handed back to be echoed
+    fn parse_line(&self, file: String) -> Result<(Vec<Statement>, Option<Expression>), LoxError> {
+        let err = match Self::parse(file.clone()) {
+            Ok(statements) => return Ok((statements, None)),
+            Err(e) if self.repl && misses_final_semicolon(&file, &e) => e,
+            Err(e) => return Err(e),
+        };
+        let mut statements = match Self::parse(format!("{};", file)) {
+            Ok(statements) => statements,
+            Err(_) => return Err(err),
+        };
+        let echo = match statements.last().map(|statement| &statement.kind) {
+            Some(StatementKind::Expression(expr)) => Some(expr.clone()),
+            _ => None,
+        };
+        if echo.is_some() {
+            statements.pop();
+        }
+        return Ok((statements, echo));
     }
 
     pub fn run_prompt(&mut self) {
+        self.repl = true;
         let stdin = io::stdin();
         println!("input: ");
         for line in stdin.lock().lines() {
             let readed = line.unwrap();
-            if readed.len() == 0 {
+            if readed.is_empty() {
                 break;
             }
-            self.run(readed);
-            self.had_error = false;
+            let _ = self.run_source(readed);
+            self.interpreter.reset_transient();
+        }
+    }
+}
+
+// re-runs a script every time its file is modified, by polling its mtime
+pub struct Watcher {
+    file_name: String,
+    interval: Duration,
+    last_modified: Option<SystemTime>,
+}
+
+impl Watcher {
+    pub fn new(file_name: String, interval: Duration) -> Self {
+        return Watcher {
+            file_name,
+            interval,
+            last_modified: None,
+        };
+    }
+
+    pub fn watch<F: FnMut() -> Result<(), LoxError>>(&mut self, mut run: F) {
+        loop {
+            self.poll(&mut run);
+            thread::sleep(self.interval);
+        }
+    }
+
+    pub fn poll<F: FnMut() -> Result<(), LoxError>>(&mut self, run: &mut F) -> bool {
+        // a file being saved may briefly be missing, try again next poll
+        let modified = match fs::metadata(&self.file_name).and_then(|m| m.modified()) {
+            Ok(modified) => modified,
+            Err(_) => return false,
+        };
+        if self.last_modified == Some(modified) {
+            return false;
+        }
+
+        if self.last_modified.is_some() {
+            println!("---------- {} changed, re-running at {} ----------", self.file_name, timestamp());
+        }
+        self.last_modified = Some(modified);
+        let _ = run();
+        return true;
+    }
+}
+
+fn timestamp() -> String {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    return format!("{:02}:{:02}:{:02}", secs / 3600 % 24, secs / 60 % 60, secs % 60);
+}
+
+// helpers for the tests of every module that runs scripts in both engines
+#[cfg(test)]
+pub mod support {
+    use crate::runtime::{Runtime, VMRuntime};
+    use crate::types::err::LoxError;
+    use crate::types::output::{Buffer, Output};
+
+    pub fn text(buffer: &Buffer) -> String {
+        return String::from_utf8(buffer.borrow().clone()).unwrap();
+    }
+
+    pub fn both_engines(source: &str) -> (String, String) {
+        let ((out, _), (vm_out, _)) = both_engines_streams(source);
+        return (out, vm_out);
+    }
+
+    pub fn both_engines_streams(source: &str) -> ((String, String), (String, String)) {
+        let (output, out, err) = Output::capture();
+        Runtime { output, ..Runtime::default() }.run_source(source.to_string()).unwrap();
+        let (vm_output, vm_out, vm_err) = Output::capture();
+        VMRuntime { output: vm_output, ..VMRuntime::default() }.run_source(source.to_string()).unwrap();
+        return ((text(&out), text(&err)), (text(&vm_out), text(&vm_err)));
+    }
+
+    pub fn runtime_error(result: Result<(), LoxError>) -> String {
+        return match result {
+            Err(LoxError::Runtime(e)) => e.to_string(),
+            other => panic!("should fail at runtime, got {:?}", other),
+        };
+    }
+
+    pub fn timed_out(result: Result<(), LoxError>) -> bool {
+        return match result {
+            Err(LoxError::Runtime(e)) => e.to_string().starts_with("execution timed out after 0.2s"),
+            _ => false,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::{Duration, Instant, SystemTime};
+
+    use crate::runtime::support::timed_out;
+    use crate::runtime::{misses_final_semicolon, Runtime, VMRuntime, Watcher};
+    use crate::types::err::LoxError;
+    use crate::types::output::Output;
+    use crate::types::val::InterpreterError;
+
+    #[test]
+    fn failures_report_their_phase() {
+        let mut runtime = Runtime::default();
+        assert!(matches!(runtime.run_source("var a = @;".to_string()), Err(LoxError::Scan(_))));
+        assert!(matches!(runtime.run_source("var a = ;".to_string()), Err(LoxError::Parse(_))));
+        assert!(matches!(runtime.run_source("print -nil;".to_string()), Err(LoxError::Runtime(_))));
+        assert!(matches!(runtime.run_file("missing.lox".to_string()), Err(LoxError::Io(_))));
+
+        let mut vm_runtime = VMRuntime::default();
+        assert!(matches!(vm_runtime.run_source("var a = @;".to_string()), Err(LoxError::Scan(_))));
+        assert!(matches!(vm_runtime.run_source("{ var a = 1; var a = 2; }".to_string()), Err(LoxError::Compile(_))));
+        assert!(matches!(vm_runtime.run_source("var a = 1; a();".to_string()), Err(LoxError::Runtime(_))));
+        assert!(matches!(vm_runtime.run_file("missing.lox".to_string()), Err(LoxError::Io(_))));
+    }
+
+    #[test]
+    fn both_engines_reject_bad_operands() {
+        let mut runtime = Runtime::default();
+        let mut vm_runtime = VMRuntime::default();
+        for source in ["print [1] < [2];", "print [1] >= [1];", "print nil > 1;", "print \"a\" * -1;"] {
+            assert!(matches!(runtime.run_source(source.to_string()), Err(LoxError::Runtime(_))), "{}", source);
+            assert!(matches!(vm_runtime.run_source(source.to_string()), Err(LoxError::Runtime(_))), "{}", source);
+        }
+    }
+
+    #[test]
+    fn prompt_echoes_trailing_expressions() {
+        let (output, out, _) = Output::capture();
+        let mut vm_runtime = VMRuntime { output, repl: true, ..VMRuntime::default() };
+        for line in ["var a = 1;", "print a;", "a + 2;", "a; print a;", "{ a + 3; }", "clock; nil;"] {
+            vm_runtime.run_source(line.to_string()).unwrap();
+        }
+        let echoed = String::from_utf8(out.borrow().clone()).unwrap();
+        assert_eq!(echoed, "1\n> 3\n1\n");
+    }
+
+    #[test]
+    fn prompt_echoes_bare_expressions() {
+        let lines = ["var a = 1", "a + 2", "print a;", "_ * 10", "a = _", "print 1, 2,", "print a;", "nil", "_"];
+        let (output, out, _) = Output::capture();
+        let mut runtime = Runtime { output, repl: true, ..Runtime::default() };
+        let (vm_output, vm_out, _) = Output::capture();
+        let mut vm_runtime = VMRuntime { output: vm_output, repl: true, ..VMRuntime::default() };
+        let mut failed = vec![];
+        for line in lines {
+            if runtime.run_source(line.to_string()).is_err() {
+                failed.push(line);
+            }
+            vm_runtime.run_source(line.to_string()).unwrap_or(());
         }
+        // the bad line is reported and the session carries on with `a` still set
+        assert_eq!(failed, vec!["print 1, 2,"]);
+        assert_eq!(String::from_utf8(out.borrow().clone()).unwrap(), "> 3\n1\n> 30\n> 30\n30\n");
+        assert_eq!(String::from_utf8(vm_out.borrow().clone()).unwrap(), "> 3\n1\n> 30\n> 30\n30\n");
+
+        // outside the prompt a missing `;` is still an error
+        assert!(matches!(Runtime::default().run_source("1 + 2".to_string()), Err(LoxError::Parse(_))));
+        assert!(matches!(VMRuntime::default().run_source("1 + 2".to_string()), Err(LoxError::Compile(_))));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn prompt_retries_only_a_missing_final_semicolon() {
+        let error = |repl: bool, line: &str| {
+            let mut vm_runtime = VMRuntime { output: Output::discard(), repl, ..VMRuntime::default() };
+            return vm_runtime.run_source(line.to_string()).unwrap_err().to_string();
+        };
+        for line in ["var a = 1 print a", "print (1", "print 1 +", "var = 2"] {
+            assert_eq!(error(true, line), error(false, line), "{}", line);
+        }
+        let compile = |line: &str| VMRuntime::default().compile_source(line.to_string()).unwrap_err();
+        assert!(misses_final_semicolon("a + 2", &compile("a + 2")));
+        assert!(misses_final_semicolon("var a = 1 // one", &compile("var a = 1 // one")));
+        assert!(!misses_final_semicolon("var a = 1 print a", &compile("var a = 1 print a")));
+        assert!(!misses_final_semicolon("print (1", &compile("print (1")));
+    }
+
+    #[test]
+    fn callback_errors_point_at_the_callback() {
+        let source = "fun bad(x) {\n  return x + nil;\n}\nmap([1], bad);";
+        let mut runtime = Runtime::default();
+        let mut vm_runtime = VMRuntime::default();
+        for result in [runtime.run_source(source.to_string()), vm_runtime.run_source(source.to_string())] {
+            match result {
+                Err(LoxError::Runtime(e)) => assert_eq!(e.span().map(|span| span.line), Some(2)),
+                other => panic!("expected a runtime error, got {:?}", other),
+            }
+        }
+    }
+
+    fn exit_code(result: Result<(), LoxError>) -> i32 {
+        return match result {
+            Ok(_) => 0,
+            Err(e) => e.exit_code(),
+        };
+    }
+
+    #[test]
+    fn exit_code_follows_failed_phase() {
+        let mut vm_runtime = VMRuntime::default();
+        assert_eq!(exit_code(vm_runtime.run_source("print 1;".to_string())), 0);
+        assert_eq!(exit_code(vm_runtime.run_source("print @;".to_string())), 65);
+        assert_eq!(exit_code(vm_runtime.run_source("print ;".to_string())), 65);
+        assert_eq!(exit_code(vm_runtime.run_source("print -\"a\";".to_string())), 70);
+        assert_eq!(exit_code(vm_runtime.run_file("missing.lox".to_string())), 74);
+
+        let mut runtime = Runtime::default();
+        assert_eq!(exit_code(runtime.run_source("print ;".to_string())), 65);
+        assert_eq!(exit_code(runtime.run_source("print -\"a\";".to_string())), 70);
+    }
+
+    #[test]
+    fn watcher_reruns_only_on_change() {
+        let path = std::env::temp_dir().join(format!("lox-watch-{}.lox", std::process::id()));
+        fs::write(&path, "var a = 1;").unwrap();
+        let mut watcher = Watcher::new(path.to_str().unwrap().to_string(), Duration::from_millis(1));
+
+        let mut runs = 0;
+        let mut run = || {
+            runs += 1;
+            Runtime::default().run_file(path.to_str().unwrap().to_string())
+        };
+        assert!(watcher.poll(&mut run));
+        assert!(!watcher.poll(&mut run));
+
+        // a failing script must not stop the watcher
+        fs::write(&path, "var a = ;").unwrap();
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(5)).unwrap();
+        assert!(watcher.poll(&mut run));
+        assert!(!watcher.poll(&mut run));
+
+        fs::remove_file(&path).unwrap();
+        assert!(!watcher.poll(&mut run));
+        assert_eq!(runs, 2);
+    }
+
+    #[test]
+    fn timeout_stops_long_running_scripts() {
+        let timeout = Some(Duration::from_millis(200));
+
+        let mut runtime = Runtime { timeout, ..Runtime::default() };
+        let start = Instant::now();
+        assert!(timed_out(runtime.run_source("while (true) {}".to_string())));
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        let mut vm_runtime = VMRuntime { timeout, ..VMRuntime::default() };
+        let start = Instant::now();
+        assert!(timed_out(vm_runtime.run_source("while (true) {}".to_string())));
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        // the interpreter has no sleep(), a busy loop inside a call stands in for it
+        let start = Instant::now();
+        assert!(timed_out(runtime.run_source("fun spin() { while (true) {} }\nspin();".to_string())));
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        let start = Instant::now();
+        let result = vm_runtime.run_source("fun nap() { sleep(60); }\nnap();".to_string());
+        assert!(start.elapsed() < Duration::from_secs(2));
+        match result {
+            Err(LoxError::Runtime(InterpreterError::Spanned { error, .. })) => {
+                match *error {
+                    InterpreterError::Timeout { trace, .. } => {
+                        assert_eq!(trace, vec!["nap() [line 1]", "script [line 2]"]);
+                    }
+                    other => panic!("unexpected error {}", other),
+                }
+            }
+            _ => panic!("should time out"),
+        }
+    }
+
+    fn vm_output(deterministic: bool, source: &str) -> String {
+        let (output, out, _) = Output::capture();
+        let mut vm_runtime = VMRuntime { output, ..VMRuntime::default() };
+        if deterministic {
+            vm_runtime.deterministic();
+        }
+        vm_runtime.run_source(source.to_string()).unwrap();
+        return String::from_utf8(out.borrow().clone()).unwrap();
+    }
+
+    #[test]
+    fn deterministic_runs_print_the_same() {
+        let source = r#"
+            var start = clock();
+            sleep(5);
+            print random(), randomInt(1, 1000000), clock() - start, now();
+        "#;
+        let started = Instant::now();
+        let first = vm_output(true, source);
+        assert_eq!(first, vm_output(true, source));
+        // sleep() only moved the fake clock, by its seconds plus one tick
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(first.contains(" 5001.0 "), "{}", first);
+        assert_ne!(vm_output(false, "print random();"), vm_output(false, "print random();"));
+
+        let (output, out, _) = Output::capture();
+        let mut runtime = Runtime { output, ..Runtime::default() };
+        runtime.deterministic();
+        runtime.run_source("print random(), now(), now();".to_string()).unwrap();
+        let vm_out = vm_output(true, "print random(), now(), now();");
+        assert_eq!(String::from_utf8(out.borrow().clone()).unwrap(), vm_out);
+    }
+}
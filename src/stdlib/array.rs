@@ -0,0 +1,217 @@
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::rc::Rc;
+
+use crate::stdlib::{array_arg, int_arg, Caller};
+use crate::types::val::{InterpreterError, Value};
+
+fn out_of_bounds(index: i64, len: usize) -> InterpreterError {
+    return InterpreterError::SimpleError(format!("Index {} out of bounds for array of length {}.", index, len));
+}
+
+// appends and returns the new length
+pub fn push(args: &[Value]) -> Result<Value, InterpreterError> {
+    let array = array_arg("push", args, 0)?;
+    array.borrow_mut().push(args[1].clone());
+    let len = array.borrow().len();
+    return Ok(Value::Int(len as i64));
+}
+
+pub fn pop(args: &[Value]) -> Result<Value, InterpreterError> {
+    let array = array_arg("pop", args, 0)?;
+    let last = array.borrow_mut().pop();
+    return match last {
+        None => Err(InterpreterError::SimpleError("Can't pop from an empty array.".to_string())),
+        Some(value) => Ok(value),
+    };
+}
+
+// `i` may equal the length, which appends
+pub fn insert(args: &[Value]) -> Result<Value, InterpreterError> {
+    let array = array_arg("insert", args, 0)?;
+    let index = int_arg("insert", args, 1)?;
+    let len = array.borrow().len();
+    if index < 0 || index as usize > len {
+        return Err(out_of_bounds(index, len));
+    }
+    array.borrow_mut().insert(index as usize, args[2].clone());
+    return Ok(Value::Nil);
+}
+
+// returns the removed element
+pub fn remove_at(args: &[Value]) -> Result<Value, InterpreterError> {
+    let array = array_arg("removeAt", args, 0)?;
+    let index = int_arg("removeAt", args, 1)?;
+    let len = array.borrow().len();
+    if index < 0 || index as usize >= len {
+        return Err(out_of_bounds(index, len));
+    }
+    return Ok(array.borrow_mut().remove(index as usize));
+}
+
+// first position of the value, -1 when missing
+pub fn index_of(args: &[Value]) -> Result<Value, InterpreterError> {
+    let array = array_arg("indexOf", args, 0)?;
+    let position = array.borrow().iter().position(|v| v.eq(&args[1]));
+    return match position {
+        None => Ok(Value::Int(-1)),
+        Some(i) => Ok(Value::Int(i as i64)),
+    };
+}
+
+// stable, ascending, all numbers or all strings
+pub fn sort(args: &[Value]) -> Result<Value, InterpreterError> {
+    let array = array_arg("sort", args, 0)?;
+    let mut values = array.borrow_mut();
+    let numbers = values.iter().all(|v| v.as_f64().is_some());
+    let strings = values.iter().all(|v| matches!(v, Value::String(_)));
+    if !numbers && !strings {
+        return Err(InterpreterError::SimpleError("sort() needs an array of only numbers or only strings.".to_string()));
+    }
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    return Ok(Value::Nil);
+}
+
+// the callbacks below see a snapshot, so they may change the array they walk
+
+pub fn map(caller: &mut dyn Caller, args: &[Value]) -> Result<Value, InterpreterError> {
+    let values = array_arg("map", args, 0)?.borrow().clone();
+    let mut mapped = vec![];
+    for value in values {
+        mapped.push(caller.call(args[1].clone(), vec![value])?);
+    }
+    return Ok(Value::Array(Rc::new(RefCell::new(mapped))));
+}
+
+// keeps the elements the predicate isn't false or nil for
+pub fn filter(caller: &mut dyn Caller, args: &[Value]) -> Result<Value, InterpreterError> {
+    let values = array_arg("filter", args, 0)?.borrow().clone();
+    let mut kept = vec![];
+    for value in values {
+        if caller.call(args[1].clone(), vec![value.clone()])?.is_truthy() {
+            kept.push(value);
+        }
+    }
+    return Ok(Value::Array(Rc::new(RefCell::new(kept))));
+}
+
+// reduce(xs, f, init) calls f(accumulator, element) left to right
+pub fn reduce(caller: &mut dyn Caller, args: &[Value]) -> Result<Value, InterpreterError> {
+    let values = array_arg("reduce", args, 0)?.borrow().clone();
+    let mut accumulator = args[2].clone();
+    for value in values {
+        accumulator = caller.call(args[1].clone(), vec![accumulator, value])?;
+    }
+    return Ok(accumulator);
+}
+
+pub fn for_each(caller: &mut dyn Caller, args: &[Value]) -> Result<Value, InterpreterError> {
+    let values = array_arg("forEach", args, 0)?.borrow().clone();
+    for value in values {
+        caller.call(args[1].clone(), vec![value])?;
+    }
+    return Ok(Value::Nil);
+}
+
+// a slice bound, negative counts from the end and anything out of range is clamped
+fn slice_bound(name: &str, args: &[Value], i: usize, len: usize, default: usize) -> Result<usize, InterpreterError> {
+    if let Value::Nil = args[i] {
+        return Ok(default);
+    }
+    let bound = int_arg(name, args, i)?;
+    if bound < 0 {
+        return Ok(len.saturating_sub(bound.unsigned_abs() as usize));
+    }
+    return Ok((bound as usize).min(len));
+}
+
+// a new array or string from `start` up to but not including `end`, a nil `end` means to the end
+pub fn slice(args: &[Value]) -> Result<Value, InterpreterError> {
+    return match &args[0] {
+        Value::Array(values) => {
+            let values = values.borrow();
+            let start = slice_bound("slice", args, 1, values.len(), 0)?;
+            let end = slice_bound("slice", args, 2, values.len(), values.len())?;
+            let sliced = if start < end { values[start..end].to_vec() } else { vec![] };
+            Ok(Value::Array(Rc::new(RefCell::new(sliced))))
+        }
+        // by character, not byte
+        Value::String(s) => {
+            let len = s.chars().count();
+            let start = slice_bound("slice", args, 1, len, 0)?;
+            let end = slice_bound("slice", args, 2, len, len)?;
+            Ok(Value::String(s.chars().skip(start).take(end.saturating_sub(start)).collect()))
+        }
+        other => Err(InterpreterError::SimpleError(
+            format!("slice() expects an array or a string as argument 1, found {}.", other.type_name()))),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::stdlib::array::{index_of, insert, pop, push, remove_at, slice, sort};
+    use crate::types::val::Value;
+
+    fn array(values: Vec<Value>) -> Value {
+        return Value::Array(Rc::new(RefCell::new(values)));
+    }
+
+    #[test]
+    fn mutates_in_place() {
+        let xs = array(vec![Value::Int(1)]);
+        assert_eq!(push(&[xs.clone(), Value::Int(3)]).unwrap(), Value::Int(2));
+        insert(&[xs.clone(), Value::Int(1), Value::Int(2)]).unwrap();
+        assert_eq!(index_of(&[xs.clone(), Value::Int(2)]).unwrap(), Value::Int(1));
+        assert_eq!(remove_at(&[xs.clone(), Value::Int(0)]).unwrap(), Value::Int(1));
+        assert_eq!(pop(std::slice::from_ref(&xs)).unwrap(), Value::Int(3));
+        assert_eq!(xs.to_string(), "[2]");
+        assert_eq!(index_of(&[xs, Value::Int(9)]).unwrap(), Value::Int(-1));
+    }
+
+    #[test]
+    fn reports_bad_indexes() {
+        let xs = array(vec![Value::Int(1)]);
+        let err = remove_at(&[xs.clone(), Value::Int(3)]).unwrap_err();
+        assert!(err.to_string().contains("Index 3 out of bounds for array of length 1"));
+        let err = insert(&[xs.clone(), Value::Int(-1), Value::Nil]).unwrap_err();
+        assert!(err.to_string().contains("Index -1 out of bounds"));
+        pop(std::slice::from_ref(&xs)).unwrap();
+        assert!(pop(&[xs]).unwrap_err().to_string().contains("empty array"));
+    }
+
+    #[test]
+    fn sort_is_stable_and_rejects_mixed() {
+        // 1.0 and 1 compare equal, so they must keep their order
+        let xs = array(vec![Value::Int(2), Value::Number(1.0), Value::Int(1), Value::Int(0)]);
+        sort(std::slice::from_ref(&xs)).unwrap();
+        match xs {
+            Value::Array(values) => {
+                let shown: Vec<String> = values.borrow().iter().map(|v| format!("{:?}", v)).collect();
+                assert_eq!(shown, vec!["Int(0)", "Number(1.0)", "Int(1)", "Int(2)"]);
+            }
+            _ => panic!("should be array"),
+        }
+
+        let mixed = array(vec![Value::Int(1), Value::String("a".to_string())]);
+        assert!(sort(&[mixed]).is_err());
+    }
+
+    #[test]
+    fn slices_clamp_and_count_from_the_end() {
+        let xs = array(vec![Value::Int(1), Value::Int(2), Value::Int(3)]);
+        let slice_of = |value: &Value, start: Value, end: Value| slice(&[value.clone(), start, end]).unwrap().to_string();
+        assert_eq!(slice_of(&xs, Value::Int(-2), Value::Nil), "[2, 3]");
+        assert_eq!(slice_of(&xs, Value::Int(0), Value::Int(-1)), "[1, 2]");
+        assert_eq!(slice_of(&xs, Value::Int(2), Value::Int(1)), "[]");
+        assert_eq!(slice_of(&xs, Value::Int(-10), Value::Int(10)), "[1, 2, 3]");
+
+        let s = Value::String("héllo wörld".to_string());
+        assert_eq!(slice_of(&s, Value::Int(1), Value::Int(4)), "éll");
+        assert_eq!(slice_of(&s, Value::Int(-5), Value::Nil), "wörld");
+        assert_eq!(slice_of(&s, Value::Int(5), Value::Int(5)), "");
+        assert!(slice(&[Value::Int(1), Value::Int(0), Value::Nil]).is_err());
+    }
+}
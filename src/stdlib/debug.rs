@@ -0,0 +1,170 @@
+use std::rc::Rc;
+
+use crate::stdlib::{int_arg, Caller};
+use crate::types::val::{InterpreterError, Value};
+
+const DEFAULT_DEPTH: i64 = 6;
+const INDENT: &str = "  ";
+
+// what debug() needs to know about a value only its engine can look into
+pub enum Shape {
+    // `id` tells instances apart, fields are sorted by name
+    Instance { id: usize, class: String, fields: Vec<(String, Value)> },
+    Function { name: String, arity: usize },
+    Class { name: String },
+}
+
+struct Printer<'a> {
+    caller: &'a dyn Caller,
+    depth: usize,
+    // what encloses the value being printed, reaching one of them again is a cycle
+    path: Vec<usize>,
+    out: String,
+}
+
+impl Printer<'_> {
+    fn line(&mut self, level: usize, text: &str) {
+        self.out.push_str(&INDENT.repeat(level));
+        self.out.push_str(text);
+        self.out.push('\n');
+    }
+
+    // `prefix` is what leads the value on its line, a field name or map key
+    fn value(&mut self, value: &Value, prefix: &str, level: usize) {
+        match value {
+            Value::String(s) => self.line(level, &format!("{}{:?}", prefix, s)),
+            Value::Array(array) => {
+                let id = Rc::as_ptr(array) as *const u8 as usize;
+                let items: Vec<(String, Value)> = array.borrow().iter().map(|item| (String::new(), item.clone())).collect();
+                self.nested(id, prefix, ("[", "]"), items, level);
+            }
+            Value::Map(map) => {
+                let id = Rc::as_ptr(map) as *const u8 as usize;
+                let entries: Vec<(String, Value)> = map.borrow().iter()
+                    .map(|(key, value)| (format!("{}: ", debug_key(key)), value.clone()))
+                    .collect();
+                self.nested(id, prefix, ("{", "}"), entries, level);
+            }
+            Value::Native(native) => {
+                let arity = match native.optional {
+                    0 => native.arity.to_string(),
+                    optional => format!("{}..{}", native.arity, native.arity + optional),
+                };
+                self.line(level, &format!("{}<native fn {}/{}>", prefix, native.name, arity));
+            }
+            other => match self.caller.shape(other) {
+                None => self.line(level, &format!("{}{}", prefix, other)),
+                Some(Shape::Function { name, arity }) => self.line(level, &format!("{}<fn {}/{}>", prefix, name, arity)),
+                Some(Shape::Class { name }) => self.line(level, &format!("{}<class {}>", prefix, name)),
+                Some(Shape::Instance { id, class, fields }) => {
+                    let fields = fields.into_iter().map(|(name, value)| (format!("{}: ", name), value)).collect();
+                    let open = format!("{} {{", class);
+                    self.nested(id, prefix, (&open, "}"), fields, level);
+                }
+            },
+        }
+    }
+
+    fn nested(&mut self, id: usize, prefix: &str, (open, close): (&str, &str), items: Vec<(String, Value)>, level: usize) {
+        if self.path.contains(&id) {
+            self.line(level, &format!("{}<cycle>", prefix));
+            return;
+        }
+        if items.is_empty() {
+            self.line(level, &format!("{}{}{}", prefix, open, close));
+            return;
+        }
+        if self.path.len() >= self.depth {
+            self.line(level, &format!("{}{}...{}", prefix, open, close));
+            return;
+        }
+        self.line(level, &format!("{}{}", prefix, open));
+        self.path.push(id);
+        for (item_prefix, item) in items {
+            self.value(&item, &item_prefix, level + 1);
+        }
+        self.path.pop();
+        self.line(level, close);
+    }
+}
+
+fn debug_key(key: &Value) -> String {
+    return match key {
+        Value::String(s) => format!("{:?}", s),
+        other => other.to_string(),
+    };
+}
+
+// the structure of a value, one field or element per line, nested containers are
+// cut off below `depth` levels
+pub fn render(caller: &dyn Caller, value: &Value, depth: usize) -> String {
+    let mut printer = Printer { caller, depth, path: vec![], out: String::new() };
+    printer.value(value, "", 0);
+    return printer.out;
+}
+
+pub fn debug(caller: &mut dyn Caller, args: &[Value]) -> Result<Value, InterpreterError> {
+    let depth = match args.get(1) {
+        None => DEFAULT_DEPTH,
+        Some(_) => int_arg("debug", args, 1)?,
+    };
+    if depth < 0 {
+        return Err(InterpreterError::SimpleError(format!("debug() expects a depth of at least 0, found {}.", depth)));
+    }
+    let text = render(caller, &args[0], depth as usize);
+    caller.output().eprint(text.trim_end_matches('\n'))?;
+    return Ok(Value::Nil);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::runtime::support::both_engines_streams;
+
+    fn stderr_of_both_engines(source: &str) -> (String, String) {
+        let ((out, err), (vm_out, vm_err)) = both_engines_streams(source);
+        assert!(out.is_empty() && vm_out.is_empty());
+        return (err, vm_err);
+    }
+
+    #[test]
+    fn debug_prints_nested_structure() {
+        let source = r#"
+            class Point { norm() { return 0; } }
+            var p = Point();
+            p.y = [2.5, "two", {"k": nil}, []];
+            p.x = 1;
+            debug(p);
+            debug(p.norm);
+            debug(Point);
+        "#;
+        let expected = "Point {\n  x: 1\n  y: [\n    2.5\n    \"two\"\n    {\n      \"k\": nil\n    }\n    []\n  ]\n}\n\
+            <fn norm/0>\n<class Point>\n";
+        let (err, vm_err) = stderr_of_both_engines(source);
+        assert_eq!(err, expected);
+        assert_eq!(vm_err, expected);
+    }
+
+    #[test]
+    fn debug_stops_at_cycles_and_depth() {
+        let source = r#"
+            class Node {}
+            var a = Node();
+            var b = Node();
+            a.other = b;
+            b.other = a;
+            debug(a);
+            var xs = [1];
+            push(xs, xs);
+            debug(xs);
+            debug([[[1]], []], 1);
+            debug([1], 0);
+        "#;
+        let expected = "Node {\n  other: Node {\n    other: <cycle>\n  }\n}\n\
+            [\n  1\n  <cycle>\n]\n\
+            [\n  [...]\n  []\n]\n\
+            [...]\n";
+        let (err, vm_err) = stderr_of_both_engines(source);
+        assert_eq!(err, expected);
+        assert_eq!(vm_err, expected);
+    }
+}
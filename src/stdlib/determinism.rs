@@ -0,0 +1,26 @@
+use std::time::Duration;
+
+// what --deterministic swaps in for the outside world, so a script prints the
+// same thing on every run and every machine
+pub const SEED: u64 = 0;
+
+// how far the fake clock moves each time it's read
+pub const TICK: Duration = Duration::from_millis(1);
+
+// a clock that starts at the epoch and only moves when read or slept on
+#[derive(Clone, Debug, Default)]
+pub struct Determinism {
+    elapsed: Duration,
+}
+
+impl Determinism {
+    // time since the epoch
+    pub fn now(&mut self) -> Duration {
+        self.elapsed += TICK;
+        return self.elapsed;
+    }
+
+    pub fn sleep(&mut self, duration: Duration) {
+        self.elapsed += duration;
+    }
+}
@@ -0,0 +1,64 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::stdlib::Caller;
+use crate::types::map::LoxMap;
+use crate::types::val::{InterpreterError, Value};
+
+// what an engine's heap holds, counts it doesn't keep are None and read as nil
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct HeapStats {
+    pub instances: Option<usize>,
+    pub functions: Option<usize>,
+    pub strings: Option<usize>,
+    // a rough size of what is counted above, not what the process uses
+    pub bytes: usize,
+    pub collections: usize,
+}
+
+fn count(n: Option<usize>) -> Value {
+    return match n {
+        None => Value::Nil,
+        Some(n) => Value::Int(n as i64),
+    };
+}
+
+// always the same keys, whichever engine runs the script
+pub fn heap_stats(caller: &mut dyn Caller, _args: &[Value]) -> Result<Value, InterpreterError> {
+    let stats = caller.heap_stats();
+    let mut map = LoxMap::new();
+    map.insert(Value::String("instances".to_string()), count(stats.instances))?;
+    map.insert(Value::String("functions".to_string()), count(stats.functions))?;
+    map.insert(Value::String("strings".to_string()), count(stats.strings))?;
+    map.insert(Value::String("bytes".to_string()), Value::Int(stats.bytes as i64))?;
+    map.insert(Value::String("collections".to_string()), Value::Int(stats.collections as i64))?;
+    return Ok(Value::Map(Rc::new(RefCell::new(map))));
+}
+
+pub fn collect_garbage(caller: &mut dyn Caller, _args: &[Value]) -> Result<Value, InterpreterError> {
+    caller.collect_garbage();
+    return Ok(Value::Nil);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::runtime::support::both_engines;
+
+    #[test]
+    fn heap_stats_see_dropped_instances_in_both_engines() {
+        let source = r#"
+            class Node {}
+            var nodes = [];
+            for (var i = 0; i < 10; i = i + 1) push(nodes, Node());
+            var before = heapStats();
+            nodes = nil;
+            collectGarbage();
+            var after = heapStats();
+            print keys(after), before["instances"] - after["instances"], after["collections"];
+        "#;
+        let (out, vm_out) = both_engines(source);
+        let expected = "[instances, functions, strings, bytes, collections] 10 1\n";
+        assert_eq!(out, expected);
+        assert_eq!(vm_out, expected);
+    }
+}
@@ -0,0 +1,195 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::stdlib::int_arg;
+use crate::types::val::{InterpreterError, Value};
+
+// a lazy run of ints, nothing is allocated however long it is
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Range {
+    pub start: i64,
+    pub end: i64,
+    pub step: i64,
+}
+
+impl Range {
+    pub fn len(&self) -> usize {
+        let span = if self.step > 0 {
+            self.end as i128 - self.start as i128
+        } else {
+            self.start as i128 - self.end as i128
+        };
+        if span <= 0 {
+            return 0;
+        }
+        let step = (self.step as i128).abs();
+        return ((span + step - 1) / step) as usize;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+}
+
+// calls a method with no arguments on an instance, each engine brings its own
+pub type CallMethod<'a> = dyn FnMut(&Value, &str) -> Result<Value, InterpreterError> + 'a;
+
+// what for-in walks over, both engines drive it the same way.
+//
+// an instance takes part through a small protocol: for-in calls its `iterate()`
+// once, which returns an iterator object, then calls `next()` on that object
+// until it returns nil. nil ends the loop, so it can't be an element itself.
+// `iterate()` may also return anything for-in walks natively, like an array.
+#[derive(Debug)]
+pub enum LoxIter {
+    // by index, so elements pushed while looping are still visited
+    Array {
+        values: Rc<RefCell<Vec<Value>>>,
+        next: usize,
+    },
+    // map keys and string characters, taken when the loop starts
+    Items {
+        values: Vec<Value>,
+        next: usize,
+    },
+    Range {
+        next: i64,
+        range: Range,
+    },
+    // the object `iterate()` returned
+    Object(Value),
+}
+
+impl LoxIter {
+    pub fn new(value: &Value, call: &mut CallMethod) -> Result<LoxIter, InterpreterError> {
+        return match value {
+            Value::LoxInstance { .. } | Value::Instance(_) => {
+                let iterator = call(value, "iterate")?;
+                match iterator {
+                    Value::LoxInstance { .. } | Value::Instance(_) => Ok(LoxIter::Object(iterator)),
+                    other => LoxIter::new(&other, call),
+                }
+            }
+            Value::Array(values) => Ok(LoxIter::Array { values: values.clone(), next: 0 }),
+            Value::Map(map) => Ok(LoxIter::Items { values: map.borrow().keys(), next: 0 }),
+            Value::String(s) => Ok(LoxIter::Items {
+                values: s.chars().map(|c| Value::String(c.to_string())).collect(),
+                next: 0,
+            }),
+            Value::Range(range) => Ok(LoxIter::Range { next: range.start, range: *range }),
+            other => Err(InterpreterError::SimpleError(
+                format!("Can't iterate over {}.", other.type_name()))),
+        };
+    }
+
+    pub fn next_value(&mut self, call: &mut CallMethod) -> Result<Option<Value>, InterpreterError> {
+        return match self {
+            LoxIter::Object(iterator) => {
+                match call(iterator, "next")? {
+                    Value::Nil => Ok(None),
+                    value => Ok(Some(value)),
+                }
+            }
+            _ => Ok(self.next_native()),
+        };
+    }
+
+    fn next_native(&mut self) -> Option<Value> {
+        return match self {
+            LoxIter::Array { values, next } => {
+                let value = values.borrow().get(*next).cloned();
+                *next += 1;
+                value
+            }
+            LoxIter::Items { values, next } => {
+                let value = values.get(*next).cloned();
+                *next += 1;
+                value
+            }
+            LoxIter::Range { next, range } => {
+                let more = if range.step > 0 { *next < range.end } else { *next > range.end };
+                if !more {
+                    return None;
+                }
+                let value = *next;
+                // past the end of i64 means we're done anyway
+                *next = next.checked_add(range.step).unwrap_or(range.end);
+                Some(Value::Int(value))
+            }
+            LoxIter::Object(_) => None,
+        };
+    }
+}
+
+// range(end), range(start, end) or range(start, end, step), end is never included
+pub fn range(args: &[Value]) -> Result<Value, InterpreterError> {
+    let (start, end) = match args.len() {
+        1 => (0, int_arg("range", args, 0)?),
+        _ => (int_arg("range", args, 0)?, int_arg("range", args, 1)?),
+    };
+    let step = match args.len() {
+        3 => int_arg("range", args, 2)?,
+        _ => 1,
+    };
+    if step == 0 {
+        return Err(InterpreterError::SimpleError("range() step can't be zero.".to_string()));
+    }
+    return Ok(Value::Range(Range { start, end, step }));
+}
+
+pub fn len(args: &[Value]) -> Result<Value, InterpreterError> {
+    let len = match &args[0] {
+        Value::Array(values) => values.borrow().len(),
+        Value::Map(map) => map.borrow().len(),
+        Value::String(s) => s.chars().count(),
+        Value::Range(range) => range.len(),
+        other => {
+            return Err(InterpreterError::SimpleError(
+                format!("len() expects an array, map, string or range, found {}.", other.type_name())));
+        }
+    };
+    return Ok(Value::Int(len as i64));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stdlib::iter::{len, range, LoxIter};
+    use crate::types::val::{InterpreterError, Value};
+
+    fn no_methods(_: &Value, name: &str) -> Result<Value, InterpreterError> {
+        panic!("{}() should not be called", name)
+    }
+
+    fn ints(args: &[Value]) -> Vec<i64> {
+        let range = range(args).unwrap();
+        let mut iter = LoxIter::new(&range, &mut no_methods).unwrap();
+        let mut ints = vec![];
+        while let Some(value) = iter.next_value(&mut no_methods).unwrap() {
+            match value {
+                Value::Int(i) => ints.push(i),
+                other => panic!("not an int {:?}", other),
+            }
+        }
+        return ints;
+    }
+
+    #[test]
+    fn ranges_count_up_and_down() {
+        assert_eq!(ints(&[Value::Int(3)]), vec![0, 1, 2]);
+        assert_eq!(ints(&[Value::Int(2), Value::Int(5)]), vec![2, 3, 4]);
+        assert_eq!(ints(&[Value::Int(0), Value::Int(7), Value::Int(3)]), vec![0, 3, 6]);
+        assert_eq!(ints(&[Value::Int(5), Value::Int(0), Value::Int(-2)]), vec![5, 3, 1]);
+        assert_eq!(ints(&[Value::Int(5), Value::Int(0)]), Vec::<i64>::new());
+        assert!(range(&[Value::Int(0), Value::Int(1), Value::Int(0)]).is_err());
+    }
+
+    #[test]
+    fn len_does_not_walk_ranges() {
+        let huge = range(&[Value::Int(0), Value::Int(i64::MAX), Value::Int(2)]).unwrap();
+        assert_eq!(len(&[huge]).unwrap(), Value::Int(i64::MAX / 2 + 1));
+        let down = range(&[Value::Int(5), Value::Int(0), Value::Int(-2)]).unwrap();
+        assert_eq!(len(&[down]).unwrap(), Value::Int(3));
+        assert_eq!(len(&[Value::String("héllo".to_string())]).unwrap(), Value::Int(5));
+        assert!(len(&[Value::Nil]).is_err());
+    }
+}
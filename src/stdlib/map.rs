@@ -0,0 +1,103 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::stdlib::map_arg;
+use crate::types::val::{InterpreterError, Value};
+
+fn array(values: Vec<Value>) -> Value {
+    return Value::Array(Rc::new(RefCell::new(values)));
+}
+
+// in insertion order
+pub fn keys(args: &[Value]) -> Result<Value, InterpreterError> {
+    let map = map_arg("keys", args, 0)?;
+    let keys = map.borrow().keys();
+    return Ok(array(keys));
+}
+
+// in the same order as keys()
+pub fn values(args: &[Value]) -> Result<Value, InterpreterError> {
+    let map = map_arg("values", args, 0)?;
+    let values = map.borrow().values();
+    return Ok(array(values));
+}
+
+pub fn has(args: &[Value]) -> Result<Value, InterpreterError> {
+    let map = map_arg("has", args, 0)?;
+    let found = map.borrow().contains_key(&args[1])?;
+    return Ok(Value::Bool(found));
+}
+
+// returns the removed value, nil when the key was missing
+pub fn remove(args: &[Value]) -> Result<Value, InterpreterError> {
+    let map = map_arg("remove", args, 0)?;
+    let removed = map.borrow_mut().remove(&args[1])?;
+    return Ok(removed.unwrap_or(Value::Nil));
+}
+
+// a new map, entries of the second win
+pub fn merge(args: &[Value]) -> Result<Value, InterpreterError> {
+    let mut merged = map_arg("merge", args, 0)?.borrow().clone();
+    let other = map_arg("merge", args, 1)?;
+    for (key, value) in other.borrow().iter() {
+        merged.insert(key.clone(), value.clone())?;
+    }
+    return Ok(Value::Map(Rc::new(RefCell::new(merged))));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::stdlib::map::{has, keys, merge, remove, values};
+    use crate::types::map::LoxMap;
+    use crate::types::val::Value;
+
+    fn map(entries: Vec<(&str, Value)>) -> Value {
+        let mut map = LoxMap::new();
+        for (key, value) in entries {
+            map.insert(Value::String(key.to_string()), value).unwrap();
+        }
+        return Value::Map(Rc::new(RefCell::new(map)));
+    }
+
+    fn key(name: &str) -> Value {
+        return Value::String(name.to_string());
+    }
+
+    #[test]
+    fn keys_keep_insertion_order() {
+        let m = map(vec![("b", Value::Int(1)), ("a", Value::Int(2)), ("c", Value::Int(3))]);
+        assert_eq!(keys(std::slice::from_ref(&m)).unwrap().to_string(), "[b, a, c]");
+        remove(&[m.clone(), key("a")]).unwrap();
+        assert_eq!(keys(std::slice::from_ref(&m)).unwrap().to_string(), "[b, c]");
+        assert_eq!(values(std::slice::from_ref(&m)).unwrap().to_string(), "[1, 3]");
+        assert_eq!(m.to_string(), "{b: 1, c: 3}");
+    }
+
+    #[test]
+    fn remove_missing_key_is_nil() {
+        let m = map(vec![("a", Value::Int(1))]);
+        assert_eq!(remove(&[m.clone(), key("z")]).unwrap(), Value::Nil);
+        assert_eq!(remove(&[m.clone(), key("a")]).unwrap(), Value::Int(1));
+        assert_eq!(has(&[m, key("a")]).unwrap(), Value::Bool(false));
+    }
+
+    #[test]
+    fn merge_prefers_second_map() {
+        let a = map(vec![("x", Value::Int(1)), ("y", Value::Int(2))]);
+        let b = map(vec![("y", Value::Int(20)), ("z", Value::Int(30))]);
+        let merged = merge(&[a.clone(), b]).unwrap();
+        assert_eq!(merged.to_string(), "{x: 1, y: 20, z: 30}");
+        assert_eq!(a.to_string(), "{x: 1, y: 2}");
+    }
+
+    #[test]
+    fn rejects_non_maps() {
+        let err = keys(&[Value::Int(1)]).unwrap_err();
+        assert!(err.to_string().contains("keys() expects a map as argument 1, found int"));
+        let err = merge(&[map(vec![]), Value::Nil]).unwrap_err();
+        assert!(err.to_string().contains("merge() expects a map as argument 2, found nil"));
+    }
+}
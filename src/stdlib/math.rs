@@ -0,0 +1,126 @@
+use crate::stdlib::{int_arg, number_arg};
+use crate::types::val::{InterpreterError, Value};
+
+// tolerance when approxEq() is given none
+const DEFAULT_EPSILON: f64 = 1e-9;
+
+// an f64 holds about this many significant digits, rounding to more is a no-op
+const MAX_ROUND_DIGITS: i64 = 17;
+
+// `==` stays exact, this is the opt-in for float math
+pub fn approx_eq(args: &[Value]) -> Result<Value, InterpreterError> {
+    let a = number_arg("approxEq", args, 0)?;
+    let b = number_arg("approxEq", args, 1)?;
+    let epsilon = match args.get(2) {
+        None => DEFAULT_EPSILON,
+        Some(_) => number_arg("approxEq", args, 2)?,
+    };
+    if epsilon.is_nan() || epsilon < 0.0 {
+        return Err(InterpreterError::SimpleError(
+            format!("approxEq() expects a non-negative tolerance, found {}.", epsilon)));
+    }
+    return Ok(Value::Bool((a - b).abs() <= epsilon));
+}
+
+// ints are never either, `0 / 0` and `1 / 0` give numbers
+pub fn is_nan(args: &[Value]) -> Result<Value, InterpreterError> {
+    return Ok(Value::Bool(number_arg("isNaN", args, 0)?.is_nan()));
+}
+
+pub fn is_infinite(args: &[Value]) -> Result<Value, InterpreterError> {
+    return Ok(Value::Bool(number_arg("isInfinite", args, 0)?.is_infinite()));
+}
+
+// half to even, decided on the exact stored value: round(2.5) is 2 and
+// round(2.675, 2) is 2.67 since 2.675 is really 2.67499.... ints come back as is
+pub fn round(args: &[Value]) -> Result<Value, InterpreterError> {
+    let digits = match args.get(1) {
+        None => 0,
+        Some(_) => int_arg("round", args, 1)?,
+    };
+    if digits < 0 {
+        return Err(InterpreterError::SimpleError(format!("round() expects a non-negative number of digits, found {}.", digits)));
+    }
+    let x = match &args[0] {
+        Value::Int(int) => return Ok(Value::Int(*int)),
+        _ => number_arg("round", args, 0)?,
+    };
+    if !x.is_finite() || digits > MAX_ROUND_DIGITS {
+        return Ok(Value::Number(x));
+    }
+    // float formatting already rounds the exact decimal expansion
+    let rounded = format!("{:.*}", digits as usize, x).parse()
+        .map_err(|e| InterpreterError::SimpleError(format!("round() can't round {}: {}.", x, e)))?;
+    return Ok(Value::Number(rounded));
+}
+
+// toward zero, ints come back as is
+pub fn trunc(args: &[Value]) -> Result<Value, InterpreterError> {
+    return match &args[0] {
+        Value::Int(int) => Ok(Value::Int(*int)),
+        _ => Ok(Value::Number(number_arg("trunc", args, 0)?.trunc())),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::runtime::{Runtime, VMRuntime};
+    use crate::stdlib::math::{approx_eq, is_infinite, is_nan, round, trunc};
+    use crate::types::val::Value;
+
+    #[test]
+    fn approx_eq_compares_within_tolerance() {
+        let sum = Value::Number(0.1 + 0.2);
+        assert_eq!(approx_eq(&[sum.clone(), Value::Number(0.3), Value::Number(1e-9)]).unwrap(), Value::Bool(true));
+        assert_eq!(approx_eq(&[sum.clone(), Value::Number(0.3), Value::Int(0)]).unwrap(), Value::Bool(false));
+        assert_eq!(approx_eq(&[sum.clone(), Value::Number(0.3)]).unwrap(), Value::Bool(true));
+        assert_eq!(approx_eq(&[Value::Int(1), Value::Number(1.5), Value::Number(0.5)]).unwrap(), Value::Bool(true));
+        assert!(approx_eq(&[sum.clone(), Value::Number(0.3), Value::Number(-1.0)]).is_err());
+        assert!(approx_eq(&[sum, Value::String("0.3".to_string())]).is_err());
+    }
+
+    #[test]
+    fn nan_and_infinity_are_told_apart() {
+        let nan = [Value::Number(f64::NAN)];
+        let inf = [Value::Number(f64::NEG_INFINITY)];
+        assert_eq!(is_nan(&nan).unwrap(), Value::Bool(true));
+        assert_eq!(is_infinite(&nan).unwrap(), Value::Bool(false));
+        assert_eq!(is_infinite(&inf).unwrap(), Value::Bool(true));
+        assert_eq!(is_nan(&inf).unwrap(), Value::Bool(false));
+        assert_eq!(is_nan(&[Value::Int(1)]).unwrap(), Value::Bool(false));
+        assert_eq!(is_infinite(&[Value::Number(1e308)]).unwrap(), Value::Bool(false));
+        assert!(is_nan(&[Value::Nil]).unwrap_err().to_string().contains("isNaN() expects a number"));
+        assert!(is_infinite(&[Value::String("inf".to_string())]).is_err());
+    }
+
+    #[test]
+    fn round_ties_to_even_at_any_precision() {
+        let round_to = |x: f64, digits: i64| round(&[Value::Number(x), Value::Int(digits)]).unwrap();
+        assert_eq!(round_to(1.23456, 3), Value::Number(1.235));
+        assert_eq!(round_to(2.675, 2), Value::Number(2.67));
+        assert_eq!(round_to(0.125, 2), Value::Number(0.12));
+        assert_eq!(round(&[Value::Number(2.5)]).unwrap(), Value::Number(2.0));
+        assert_eq!(round(&[Value::Number(3.5)]).unwrap(), Value::Number(4.0));
+        assert_eq!(round(&[Value::Number(-1.7)]).unwrap(), Value::Number(-2.0));
+        assert_eq!(round(&[Value::Int(7), Value::Int(3)]).unwrap(), Value::Int(7));
+        assert!(round(&[Value::Number(1.0), Value::Int(-1)]).unwrap_err().to_string().contains("non-negative"));
+        assert!(round(&[Value::Number(1.0), Value::Int(i64::MIN)]).is_err());
+        // past what an f64 holds, without building a string that long
+        assert_eq!(round(&[Value::Number(1.5), Value::Int(1_000_000_000)]).unwrap(), Value::Number(1.5));
+        assert_eq!(round(&[Value::Number(0.1), Value::Int(18)]).unwrap(), Value::Number(0.1));
+        assert!(round(&[Value::Number(1.0), Value::Number(1.0)]).is_err());
+        assert_eq!(round(&[Value::Number(f64::INFINITY)]).unwrap(), Value::Number(f64::INFINITY));
+
+        assert_eq!(trunc(&[Value::Number(-2.7)]).unwrap(), Value::Number(-2.0));
+        assert_eq!(trunc(&[Value::Number(2.7)]).unwrap(), Value::Number(2.0));
+        assert_eq!(trunc(&[Value::Int(-3)]).unwrap(), Value::Int(-3));
+        assert!(trunc(&[Value::Nil]).is_err());
+    }
+
+    #[test]
+    fn round_takes_a_precision() {
+        let source = "assert round(3.14159, 2) == 3.14; assert round(2.5) == 2; assert trunc(-2.5) == -2; assert round(7, 2) == 7;";
+        Runtime::default().run_source(source.to_string()).unwrap();
+        VMRuntime::default().run_source(source.to_string()).unwrap();
+    }
+}
@@ -0,0 +1,188 @@
+use std::cell::RefCell;
+use std::fmt::{Debug, Formatter};
+use std::rc::Rc;
+
+use crate::types::map::LoxMap;
+use crate::types::output::Output;
+use crate::types::val::{InterpreterError, Value};
+
+pub mod array;
+pub mod debug;
+pub mod determinism;
+pub mod heap;
+pub mod iter;
+pub mod map;
+pub mod math;
+pub mod os;
+pub mod random;
+pub mod reflect;
+pub mod string;
+pub mod testing;
+pub mod time;
+
+// lets a native call back into whichever engine is running it, or write to its output
+pub trait Caller {
+    fn call(&mut self, callee: Value, args: Vec<Value>) -> Result<Value, InterpreterError>;
+
+    // a call whose error the native handles, the engine is left as it was before the call
+    fn try_call(&mut self, callee: Value, args: Vec<Value>) -> Result<Value, InterpreterError>;
+
+    fn output(&self) -> Output;
+
+    // natives that change the world outside the script refuse to run when set
+    fn sandboxed(&self) -> bool;
+
+    fn rng(&mut self) -> &mut random::Rng;
+
+    // what test() has counted
+    fn tally(&mut self) -> &mut testing::Tally;
+
+    // the fake clock when running with --deterministic
+    fn determinism(&mut self) -> Option<&mut determinism::Determinism>;
+
+    fn heap_stats(&self) -> heap::HeapStats;
+
+    // may free later rather than right away, each engine says when
+    fn collect_garbage(&mut self);
+
+    // the defined globals, in no particular order
+    fn globals(&self) -> Vec<(String, Value)>;
+
+    // a look inside the instances, functions and classes of this engine, None for
+    // any other value
+    fn shape(&self, value: &Value) -> Option<debug::Shape>;
+
+    // drops a field from an instance of this engine, returns what it held
+    fn remove_field(&mut self, instance: &Value, name: &str) -> Option<Value>;
+}
+
+#[derive(Clone, Copy)]
+pub enum NativeFn {
+    // only needs its arguments
+    Args(fn(&[Value]) -> Result<Value, InterpreterError>),
+    // calls functions it was given, like map()
+    Calls(fn(&mut dyn Caller, &[Value]) -> Result<Value, InterpreterError>),
+}
+
+// a native both engines share
+#[derive(Clone)]
+pub struct Native {
+    pub name: &'static str,
+    pub arity: usize,
+    // how many more arguments may follow the required ones
+    pub optional: usize,
+    pub func: NativeFn,
+}
+
+impl Debug for Native {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Native({})", self.name)
+    }
+}
+
+impl Native {
+    pub fn call(&self, caller: &mut dyn Caller, args: &[Value]) -> Result<Value, InterpreterError> {
+        if args.len() < self.arity || args.len() > self.arity + self.optional {
+            let expected = match self.optional {
+                0 => self.arity.to_string(),
+                optional => format!("{} to {}", self.arity, self.arity + optional),
+            };
+            return Err(InterpreterError::SimpleError(format!("Expected {} arguments but got {}.", expected, args.len())));
+        }
+        return match self.func {
+            NativeFn::Args(func) => func(args),
+            NativeFn::Calls(func) => func(caller, args),
+        };
+    }
+}
+
+// everything defined as a global in both engines
+pub fn natives() -> Vec<Native> {
+    return vec![
+        Native { name: "push", arity: 2, optional: 0, func: NativeFn::Args(array::push) },
+        Native { name: "pop", arity: 1, optional: 0, func: NativeFn::Args(array::pop) },
+        Native { name: "insert", arity: 3, optional: 0, func: NativeFn::Args(array::insert) },
+        Native { name: "removeAt", arity: 2, optional: 0, func: NativeFn::Args(array::remove_at) },
+        Native { name: "indexOf", arity: 2, optional: 0, func: NativeFn::Args(array::index_of) },
+        Native { name: "sort", arity: 1, optional: 0, func: NativeFn::Args(array::sort) },
+        Native { name: "slice", arity: 3, optional: 0, func: NativeFn::Args(array::slice) },
+        Native { name: "map", arity: 2, optional: 0, func: NativeFn::Calls(array::map) },
+        Native { name: "filter", arity: 2, optional: 0, func: NativeFn::Calls(array::filter) },
+        Native { name: "reduce", arity: 3, optional: 0, func: NativeFn::Calls(array::reduce) },
+        Native { name: "forEach", arity: 2, optional: 0, func: NativeFn::Calls(array::for_each) },
+        Native { name: "keys", arity: 1, optional: 0, func: NativeFn::Args(map::keys) },
+        Native { name: "values", arity: 1, optional: 0, func: NativeFn::Args(map::values) },
+        Native { name: "has", arity: 2, optional: 0, func: NativeFn::Args(map::has) },
+        Native { name: "remove", arity: 2, optional: 0, func: NativeFn::Args(map::remove) },
+        Native { name: "merge", arity: 2, optional: 0, func: NativeFn::Args(map::merge) },
+        Native { name: "range", arity: 1, optional: 2, func: NativeFn::Args(iter::range) },
+        Native { name: "len", arity: 1, optional: 0, func: NativeFn::Args(iter::len) },
+        Native { name: "format", arity: 1, optional: 255, func: NativeFn::Args(string::format) },
+        Native { name: "printf", arity: 1, optional: 255, func: NativeFn::Calls(string::printf) },
+        Native { name: "eprint", arity: 1, optional: 0, func: NativeFn::Calls(string::eprint) },
+        Native { name: "ord", arity: 1, optional: 0, func: NativeFn::Args(string::ord) },
+        Native { name: "chr", arity: 1, optional: 0, func: NativeFn::Args(string::chr) },
+        Native { name: "equalsIgnoreCase", arity: 2, optional: 0, func: NativeFn::Args(string::equals_ignore_case) },
+        Native { name: "approxEq", arity: 2, optional: 1, func: NativeFn::Args(math::approx_eq) },
+        Native { name: "isNaN", arity: 1, optional: 0, func: NativeFn::Args(math::is_nan) },
+        Native { name: "isInfinite", arity: 1, optional: 0, func: NativeFn::Args(math::is_infinite) },
+        Native { name: "round", arity: 1, optional: 1, func: NativeFn::Args(math::round) },
+        Native { name: "trunc", arity: 1, optional: 0, func: NativeFn::Args(math::trunc) },
+        Native { name: "getenv", arity: 1, optional: 0, func: NativeFn::Args(os::getenv) },
+        Native { name: "setenv", arity: 2, optional: 0, func: NativeFn::Calls(os::setenv) },
+        Native { name: "now", arity: 0, optional: 0, func: NativeFn::Calls(time::now) },
+        Native { name: "formatTime", arity: 2, optional: 0, func: NativeFn::Args(time::format_time) },
+        Native { name: "parseTime", arity: 2, optional: 0, func: NativeFn::Args(time::parse_time) },
+        Native { name: "random", arity: 0, optional: 0, func: NativeFn::Calls(random::random) },
+        Native { name: "randomInt", arity: 2, optional: 0, func: NativeFn::Calls(random::random_int) },
+        Native { name: "seedRandom", arity: 1, optional: 0, func: NativeFn::Calls(random::seed_random) },
+        Native { name: "heapStats", arity: 0, optional: 0, func: NativeFn::Calls(heap::heap_stats) },
+        Native { name: "collectGarbage", arity: 0, optional: 0, func: NativeFn::Calls(heap::collect_garbage) },
+        Native { name: "globals", arity: 0, optional: 0, func: NativeFn::Calls(reflect::globals) },
+        Native { name: "removeField", arity: 2, optional: 0, func: NativeFn::Calls(reflect::remove_field) },
+        Native { name: "debug", arity: 1, optional: 1, func: NativeFn::Calls(debug::debug) },
+        Native { name: "test", arity: 2, optional: 0, func: NativeFn::Calls(testing::test) },
+        Native { name: "testSummary", arity: 0, optional: 0, func: NativeFn::Calls(testing::test_summary) },
+    ];
+}
+
+pub fn array_arg(name: &str, args: &[Value], i: usize) -> Result<Rc<RefCell<Vec<Value>>>, InterpreterError> {
+    return match &args[i] {
+        Value::Array(array) => Ok(array.clone()),
+        other => Err(InterpreterError::SimpleError(
+            format!("{}() expects an array as argument {}, found {}.", name, i + 1, other.type_name()))),
+    };
+}
+
+pub fn map_arg(name: &str, args: &[Value], i: usize) -> Result<Rc<RefCell<LoxMap>>, InterpreterError> {
+    return match &args[i] {
+        Value::Map(map) => Ok(map.clone()),
+        other => Err(InterpreterError::SimpleError(
+            format!("{}() expects a map as argument {}, found {}.", name, i + 1, other.type_name()))),
+    };
+}
+
+pub fn string_arg<'a>(name: &str, args: &'a [Value], i: usize) -> Result<&'a str, InterpreterError> {
+    return match &args[i] {
+        Value::String(string) => Ok(string),
+        other => Err(InterpreterError::SimpleError(
+            format!("{}() expects a string as argument {}, found {}.", name, i + 1, other.type_name()))),
+    };
+}
+
+// ints widen to floats
+pub fn number_arg(name: &str, args: &[Value], i: usize) -> Result<f64, InterpreterError> {
+    return match args[i].as_f64() {
+        Some(number) => Ok(number),
+        None => Err(InterpreterError::SimpleError(
+            format!("{}() expects a number as argument {}, found {}.", name, i + 1, args[i].type_name()))),
+    };
+}
+
+pub fn int_arg(name: &str, args: &[Value], i: usize) -> Result<i64, InterpreterError> {
+    return match &args[i] {
+        Value::Int(int) => Ok(*int),
+        other => Err(InterpreterError::SimpleError(
+            format!("{}() expects an int as argument {}, found {}.", name, i + 1, other.type_name()))),
+    };
+}
@@ -0,0 +1,61 @@
+use std::env;
+
+use crate::stdlib::{string_arg, Caller};
+use crate::types::val::{InterpreterError, Value};
+
+// nil when the variable is unset or isn't valid unicode
+pub fn getenv(args: &[Value]) -> Result<Value, InterpreterError> {
+    let name = string_arg("getenv", args, 0)?;
+    return match env::var(name) {
+        Ok(value) => Ok(Value::String(value)),
+        Err(_) => Ok(Value::Nil),
+    };
+}
+
+pub fn setenv(caller: &mut dyn Caller, args: &[Value]) -> Result<Value, InterpreterError> {
+    let name = string_arg("setenv", args, 0)?;
+    let value = string_arg("setenv", args, 1)?;
+    if caller.sandboxed() {
+        return Err(InterpreterError::SimpleError("setenv() is disabled in the sandbox.".to_string()));
+    }
+    // set_var panics on these instead of failing
+    if name.is_empty() || name.contains('=') || name.contains('\0') || value.contains('\0') {
+        return Err(InterpreterError::SimpleError(format!("setenv() can't set a variable named {:?}.", name)));
+    }
+    env::set_var(name, value);
+    return Ok(Value::Nil);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::runtime::support::{both_engines, runtime_error};
+    use crate::runtime::{Runtime, VMRuntime};
+
+    #[test]
+    fn scripts_read_and_set_environment_variables() {
+        std::env::set_var("LOX_TEST_GETENV", "from the host");
+        let source = r#"
+            print getenv("LOX_TEST_GETENV"), getenv("LOX_TEST_NEVER_SET");
+            setenv("LOX_TEST_SETENV", "from lox");
+            print getenv("LOX_TEST_SETENV");
+        "#;
+        let (out, vm_out) = both_engines(source);
+        let expected = "from the host nil\nfrom lox\n";
+        assert_eq!(out, expected);
+        assert_eq!(vm_out, expected);
+
+        for source in ["getenv(1);", "setenv(\"LOX_TEST_SETENV\", 1);"] {
+            assert!(runtime_error(Runtime::default().run_source(source.to_string())).contains("expects a string"));
+            assert!(runtime_error(VMRuntime::default().run_source(source.to_string())).contains("expects a string"));
+        }
+
+        let blocked = "setenv(\"LOX_TEST_SANDBOXED\", \"x\");";
+        let expected = "setenv() is disabled in the sandbox.";
+        let mut runtime = Runtime { sandbox: true, ..Runtime::default() };
+        assert!(runtime_error(runtime.run_source(blocked.to_string())).contains(expected));
+        let mut vm_runtime = VMRuntime { sandbox: true, ..VMRuntime::default() };
+        assert!(runtime_error(vm_runtime.run_source(blocked.to_string())).contains(expected));
+        assert!(std::env::var("LOX_TEST_SANDBOXED").is_err());
+        vm_runtime.run_source("var home = getenv(\"LOX_TEST_GETENV\");".to_string()).unwrap();
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+use crate::stdlib::{int_arg, Caller};
+use crate::types::val::{InterpreterError, Value};
+
+// xoshiro256**, small and good enough for scripts, not for secrets
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: [u64; 4],
+}
+
+impl Default for Rng {
+    // seeded from the randomness std keys its hash maps with
+    fn default() -> Self {
+        return Rng::seeded(RandomState::new().build_hasher().finish());
+    }
+}
+
+impl Rng {
+    // the same seed always gives the same sequence
+    pub fn seeded(seed: u64) -> Rng {
+        // splitmix64 spreads the seed over the whole state, which must not be all zero
+        let mut x = seed;
+        let mut next = || {
+            x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = x;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ (z >> 31)
+        };
+        return Rng { state: [next(), next(), next(), next()] };
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let s = &mut self.state;
+        let result = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = s[1] << 17;
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+        return result;
+    }
+
+    // in [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        return (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+    }
+
+    // in [lo, hi], rejecting the values that would bias the low end
+    pub fn between(&mut self, lo: i64, hi: i64) -> i64 {
+        let span = hi.wrapping_sub(lo) as u64;
+        if span == u64::MAX {
+            return self.next_u64() as i64;
+        }
+        let count = span + 1;
+        let zone = u64::MAX - u64::MAX % count;
+        loop {
+            let x = self.next_u64();
+            if x < zone {
+                return lo.wrapping_add((x % count) as i64);
+            }
+        }
+    }
+}
+
+pub fn random(caller: &mut dyn Caller, _args: &[Value]) -> Result<Value, InterpreterError> {
+    return Ok(Value::Number(caller.rng().next_f64()));
+}
+
+pub fn random_int(caller: &mut dyn Caller, args: &[Value]) -> Result<Value, InterpreterError> {
+    let lo = int_arg("randomInt", args, 0)?;
+    let hi = int_arg("randomInt", args, 1)?;
+    if lo > hi {
+        return Err(InterpreterError::SimpleError(format!("randomInt() expects lo <= hi, found {} > {}.", lo, hi)));
+    }
+    return Ok(Value::Int(caller.rng().between(lo, hi)));
+}
+
+pub fn seed_random(caller: &mut dyn Caller, args: &[Value]) -> Result<Value, InterpreterError> {
+    let seed = int_arg("seedRandom", args, 0)?;
+    *caller.rng() = Rng::seeded(seed as u64);
+    return Ok(Value::Nil);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::runtime::support::{runtime_error, text};
+    use crate::runtime::{Runtime, VMRuntime};
+    use crate::stdlib::random::Rng;
+    use crate::types::output::Output;
+
+    #[test]
+    fn same_seed_same_sequence() {
+        let mut a = Rng::seeded(7);
+        let mut b = Rng::seeded(7);
+        let mut c = Rng::seeded(8);
+        let from_a: Vec<u64> = (0..8).map(|_| a.next_u64()).collect();
+        let from_b: Vec<u64> = (0..8).map(|_| b.next_u64()).collect();
+        let from_c: Vec<u64> = (0..8).map(|_| c.next_u64()).collect();
+        assert_eq!(from_a, from_b);
+        assert_ne!(from_a, from_c);
+        let x = Rng::seeded(0).next_f64();
+        assert!((0.0..1.0).contains(&x));
+    }
+
+    #[test]
+    fn between_is_inclusive() {
+        let mut rng = Rng::seeded(1);
+        let mut seen = [false; 4];
+        for _ in 0..1000 {
+            let x = rng.between(-1, 2);
+            assert!((-1..=2).contains(&x));
+            seen[(x + 1) as usize] = true;
+        }
+        assert_eq!(seen, [true; 4]);
+        assert_eq!(rng.between(5, 5), 5);
+        let _ = rng.between(i64::MIN, i64::MAX);
+    }
+
+    fn seeded_output(seed: u64, source: &str) -> (String, String) {
+        let (output, out, _) = Output::capture();
+        let mut runtime = Runtime { output, ..Runtime::default() };
+        runtime.seed(seed);
+        runtime.run_source(source.to_string()).unwrap();
+        let (vm_output, vm_out, _) = Output::capture();
+        let mut vm_runtime = VMRuntime { output: vm_output, ..VMRuntime::default() };
+        vm_runtime.seed(seed);
+        vm_runtime.run_source(source.to_string()).unwrap();
+        return (text(&out), text(&vm_out));
+    }
+
+    #[test]
+    fn seeded_random_repeats_across_runs_and_engines() {
+        let source = "print random(), random(), randomInt(1, 1000000);";
+        let (first, vm_first) = seeded_output(42, source);
+        let (again, vm_again) = seeded_output(42, source);
+        assert_eq!(first, again);
+        assert_eq!(first, vm_first);
+        assert_eq!(first, vm_again);
+        let (other, _) = seeded_output(43, source);
+        assert_ne!(first, other);
+
+        // seedRandom() restarts the sequence from inside a script
+        let (out, vm_out) = seeded_output(1, "seedRandom(42); print random(), random(), randomInt(1, 1000000);");
+        assert_eq!(out, first);
+        assert_eq!(vm_out, first);
+
+        let bounds = r#"
+            var low = false;
+            var high = false;
+            for (var i = 0; i < 500; i = i + 1) {
+                var x = randomInt(-2, 2);
+                if (x < -2 or x > 2) print "out of bounds";
+                if (x == -2) low = true;
+                if (x == 2) high = true;
+            }
+            print low, high;
+        "#;
+        let (out, vm_out) = seeded_output(7, bounds);
+        assert_eq!(out, "true true\n");
+        assert_eq!(vm_out, "true true\n");
+        assert!(runtime_error(Runtime::default().run_source("randomInt(2, 1);".to_string())).contains("lo <= hi"));
+    }
+}
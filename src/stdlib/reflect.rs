@@ -0,0 +1,71 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::stdlib::{string_arg, Caller};
+use crate::types::map::LoxMap;
+use crate::types::val::{InterpreterError, Value};
+
+// every global, natives too, sorted by name. arrays, maps and instances come
+// back by reference, changing them changes the global
+pub fn globals(caller: &mut dyn Caller, _args: &[Value]) -> Result<Value, InterpreterError> {
+    let mut bindings = caller.globals();
+    bindings.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut map = LoxMap::new();
+    for (name, value) in bindings {
+        map.insert(Value::String(name), value)?;
+    }
+    return Ok(Value::Map(Rc::new(RefCell::new(map))));
+}
+
+// the removed value, nil if there was no such field. methods of the same name
+// show through again afterwards
+pub fn remove_field(caller: &mut dyn Caller, args: &[Value]) -> Result<Value, InterpreterError> {
+    let name = string_arg("removeField", args, 1)?;
+    return match &args[0] {
+        instance @ (Value::LoxInstance { .. } | Value::Instance(_)) => Ok(caller.remove_field(instance, name).unwrap_or(Value::Nil)),
+        other => Err(InterpreterError::SimpleError(
+            format!("removeField() expects an instance as argument 1, found {}.", other.type_name()))),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::runtime::support::{both_engines, runtime_error};
+    use crate::runtime::{Runtime, VMRuntime};
+
+    #[test]
+    fn globals_lists_every_global_binding() {
+        let source = r#"
+            class Box {}
+            var box = Box();
+            box.value = 1;
+            var count = 3;
+            var name = "lox";
+            fun test_one() { return "ran"; }
+            var all = globals();
+            print all["count"], all["name"], all["test_one"](), has(all, "len"), has(all, "all");
+            all["box"].value = 2;
+            print box.value;
+        "#;
+        let (out, vm_out) = both_engines(source);
+        // all is only defined once globals() has returned
+        let expected = "3 lox ran true false\n2\n";
+        assert_eq!(out, expected);
+        assert_eq!(vm_out, expected);
+    }
+
+    #[test]
+    fn removed_fields_are_gone_through_every_reference() {
+        let class = "class Box { init() { this.a = 1; this.b = 2; this.size = 3; } size() { return \"method\"; } } var box = Box(); var alias = box;";
+        let source = format!("{} fun b(x) {{ return x.b; }} print b(box); print removeField(box, \"a\"), removeField(box, \"a\"), removeField(alias, \"missing\"); print b(alias), removeField(box, \"size\"), alias.size();", class);
+        let (out, vm_out) = both_engines(&source);
+        assert_eq!(out, "2\n1 nil nil\n2 3 method\n");
+        assert_eq!(vm_out, "2\n1 nil nil\n2 3 method\n");
+
+        for (rest, expected) in [("removeField(box, \"a\"); print alias.a;", "a"), ("removeField(1, \"a\");", "expects an instance"), ("removeField(box, 1);", "expects a string")] {
+            let source = format!("{} {}", class, rest);
+            assert!(runtime_error(Runtime::default().run_source(source.clone())).contains(expected), "{}", rest);
+            assert!(runtime_error(VMRuntime::default().run_source(source)).contains(expected), "{}", rest);
+        }
+    }
+}
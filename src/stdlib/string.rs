@@ -0,0 +1,183 @@
+use crate::stdlib::{int_arg, string_arg, Caller};
+use crate::types::output;
+use crate::types::val::{InterpreterError, Value};
+
+enum Piece<'a> {
+    Text(&'a str),
+    Placeholder,
+}
+
+// `{}` takes the next argument, `{{` and `}}` stand for a single brace
+fn pieces(fmt: &str) -> Vec<Piece<'_>> {
+    let mut pieces = vec![];
+    let mut start = 0;
+    let mut chars = fmt.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let placeholder = match (c, chars.peek()) {
+            ('{', Some((_, '}'))) => true,
+            ('{', Some((_, '{'))) | ('}', Some((_, '}'))) => false,
+            _ => continue,
+        };
+        pieces.push(Piece::Text(&fmt[start..i]));
+        chars.next();
+        start = i + 2;
+        match placeholder {
+            true => pieces.push(Piece::Placeholder),
+            false => pieces.push(Piece::Text(&fmt[i..i + 1])),
+        }
+    }
+    pieces.push(Piece::Text(&fmt[start..]));
+    return pieces;
+}
+
+pub fn format_values(name: &str, args: &[Value]) -> Result<String, InterpreterError> {
+    let fmt = match &args[0] {
+        Value::String(s) => s,
+        other => {
+            return Err(InterpreterError::SimpleError(
+                format!("{}() expects a string as argument 1, found {}.", name, other.type_name())));
+        }
+    };
+    let pieces = pieces(fmt);
+    let placeholders = pieces.iter().filter(|p| matches!(p, Piece::Placeholder)).count();
+    let values = &args[1..];
+    if placeholders != values.len() {
+        return Err(InterpreterError::SimpleError(
+            format!("{}() has {} placeholders but got {} arguments.", name, placeholders, values.len())));
+    }
+
+    let mut result = String::new();
+    let mut values = values.iter();
+    for piece in pieces {
+        match piece {
+            Piece::Text(text) => result.push_str(text),
+            Piece::Placeholder => result.push_str(&values.next().unwrap().to_string()),
+        }
+    }
+    return Ok(result);
+}
+
+pub fn format(args: &[Value]) -> Result<Value, InterpreterError> {
+    return Ok(Value::String(format_values("format", args)?));
+}
+
+// no newline, printf output is flushed as it is written
+pub fn printf(caller: &mut dyn Caller, args: &[Value]) -> Result<Value, InterpreterError> {
+    let text = format_values("printf", args)?;
+    output::write_to(&caller.output().out, text.as_bytes())?;
+    return Ok(Value::Nil);
+}
+
+pub fn eprint(caller: &mut dyn Caller, args: &[Value]) -> Result<Value, InterpreterError> {
+    caller.output().eprint(&args[0].to_string())?;
+    return Ok(Value::Nil);
+}
+
+// the unicode scalar value of a one-character string
+pub fn ord(args: &[Value]) -> Result<Value, InterpreterError> {
+    let s = string_arg("ord", args, 0)?;
+    let mut chars = s.chars();
+    return match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(Value::Int(c as i64)),
+        _ => Err(InterpreterError::SimpleError(
+            format!("ord() expects a single character, found {} characters.", s.chars().count()))),
+    };
+}
+
+pub fn chr(args: &[Value]) -> Result<Value, InterpreterError> {
+    let n = int_arg("chr", args, 0)?;
+    // surrogates and anything past 0x10FFFF aren't characters
+    return match u32::try_from(n).ok().and_then(char::from_u32) {
+        Some(c) => Ok(Value::String(c.to_string())),
+        None => Err(InterpreterError::SimpleError(format!("chr() expects a Unicode scalar value, found {}.", n))),
+    };
+}
+
+// compares the unicode lowercase of both, so "ÉCOLE" matches "école"
+pub fn equals_ignore_case(args: &[Value]) -> Result<Value, InterpreterError> {
+    let a = string_arg("equalsIgnoreCase", args, 0)?;
+    let b = string_arg("equalsIgnoreCase", args, 1)?;
+    return Ok(Value::Bool(a.to_lowercase() == b.to_lowercase()));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::runtime::support::{both_engines, both_engines_streams};
+    use crate::stdlib::string::{chr, equals_ignore_case, format, ord};
+    use crate::types::val::Value;
+
+    fn text(s: &str) -> Value {
+        return Value::String(s.to_string());
+    }
+
+    #[test]
+    fn format_fills_placeholders_in_order() {
+        let args = [text("{} + {} = {}"), Value::Int(1), Value::Number(2.5), text("3.5")];
+        assert_eq!(format(&args).unwrap(), text("1 + 2.5 = 3.5"));
+        assert_eq!(format(&[text("{{}} is {}"), Value::Nil]).unwrap(), text("{} is nil"));
+        assert_eq!(format(&[text("{{{}}}"), Value::Bool(true)]).unwrap(), text("{true}"));
+        assert_eq!(format(&[text("héllo {}"), text("wörld")]).unwrap(), text("héllo wörld"));
+    }
+
+    #[test]
+    fn format_counts_must_match() {
+        let err = format(&[text("{} {}"), Value::Int(1)]).unwrap_err();
+        assert!(err.to_string().contains("has 2 placeholders but got 1 arguments"), "{}", err);
+        let err = format(&[text("{}"), Value::Int(1), Value::Int(2)]).unwrap_err();
+        assert!(err.to_string().contains("has 1 placeholders but got 2 arguments"), "{}", err);
+        assert!(format(&[Value::Int(1)]).is_err());
+    }
+
+    #[test]
+    fn ord_and_chr_convert_characters() {
+        assert_eq!(ord(&[text("A")]).unwrap(), Value::Int(65));
+        assert_eq!(chr(&[Value::Int(97)]).unwrap(), text("a"));
+        for c in ["é", "€", "😀"] {
+            let code = ord(&[text(c)]).unwrap();
+            assert_eq!(chr(&[code]).unwrap(), text(c));
+        }
+        assert_eq!(ord(&[text("😀")]).unwrap(), Value::Int(0x1F600));
+    }
+
+    #[test]
+    fn ord_and_chr_reject_bad_input() {
+        for s in ["", "ab", "é!"] {
+            let err = ord(&[text(s)]).unwrap_err();
+            assert!(err.to_string().contains("expects a single character"), "{}", err);
+        }
+        for n in [-1, 0xD800, 0xDFFF, 0x110000, i64::MAX] {
+            let err = chr(&[Value::Int(n)]).unwrap_err();
+            assert!(err.to_string().contains("expects a Unicode scalar value"), "{}", err);
+        }
+        assert!(ord(&[Value::Int(1)]).is_err());
+        assert!(chr(&[text("a")]).is_err());
+    }
+
+    #[test]
+    fn strings_compare_ignoring_case() {
+        assert_eq!(equals_ignore_case(&[text("Hello"), text("hello")]).unwrap(), Value::Bool(true));
+        assert_eq!(equals_ignore_case(&[text("Hello"), text("world")]).unwrap(), Value::Bool(false));
+        assert_eq!(equals_ignore_case(&[text("ÉCOLE"), text("école")]).unwrap(), Value::Bool(true));
+        assert_eq!(equals_ignore_case(&[text("Hello"), text("Hello!")]).unwrap(), Value::Bool(false));
+        let err = equals_ignore_case(&[text("1"), Value::Int(1)]).unwrap_err();
+        assert!(err.to_string().contains("equalsIgnoreCase() expects a string as argument 2"), "{}", err);
+    }
+
+    #[test]
+    fn eprint_writes_to_its_own_sink() {
+        let source = "print 1; eprint(\"oops\"); printf(\"{}-{}\", 2, 3); printf(\"!\"); print [4];";
+        let ((out, err), (vm_out, vm_err)) = both_engines_streams(source);
+        for (out, err) in [(out, err), (vm_out, vm_err)] {
+            assert_eq!(out, "1\n2-3![4]\n");
+            assert_eq!(err, "oops\n");
+        }
+    }
+
+    #[test]
+    fn ord_and_chr_work_in_both_engines() {
+        let source = "print ord(\"€\"), chr(ord(\"a\") + 1);";
+        let (out, vm_out) = both_engines(source);
+        assert_eq!(out, "8364 b\n");
+        assert_eq!(vm_out, "8364 b\n");
+    }
+}
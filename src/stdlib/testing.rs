@@ -0,0 +1,71 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::stdlib::{string_arg, Caller};
+use crate::types::map::LoxMap;
+use crate::types::val::{InterpreterError, Value};
+
+// how the test() calls of a run went so far
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Tally {
+    pub passed: usize,
+    pub failed: usize,
+}
+
+// runs a zero argument function, a runtime error fails the test instead of the script
+pub fn test(caller: &mut dyn Caller, args: &[Value]) -> Result<Value, InterpreterError> {
+    let name = string_arg("test", args, 0)?;
+    let line = match caller.try_call(args[1].clone(), vec![]) {
+        Ok(_) => {
+            caller.tally().passed += 1;
+            format!("PASS {}", name)
+        }
+        // running out of time stops the whole script, not just this test
+        Err(e) if e.is_timeout() => return Err(e),
+        Err(e) => {
+            caller.tally().failed += 1;
+            format!("FAIL {}: {}", name, e)
+        }
+    };
+    caller.output().print(&line)?;
+    return Ok(Value::Bool(line.starts_with("PASS")));
+}
+
+pub fn test_summary(caller: &mut dyn Caller, _args: &[Value]) -> Result<Value, InterpreterError> {
+    let tally = caller.tally().clone();
+    let mut map = LoxMap::new();
+    map.insert(Value::String("passed".to_string()), Value::Int(tally.passed as i64))?;
+    map.insert(Value::String("failed".to_string()), Value::Int(tally.failed as i64))?;
+    return Ok(Value::Map(Rc::new(RefCell::new(map))));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::runtime::support::{both_engines, text, timed_out};
+    use crate::runtime::{Runtime, VMRuntime};
+    use crate::types::output::Output;
+
+    #[test]
+    fn test_suites_run_in_lox() {
+        let source = include_str!("../../example/tests.lox");
+        let expected = "PASS arithmetic\nPASS closures\nPASS stack\n\
+            FAIL wrong length: Simple Error: Assertion failed: len(\"abc\") == 4: abc has four letters [line 32]\n\
+            FAIL broken: Left Int(1) Right Nil Operator Plus, not match [line 9]\n\
+            PASS stack again\nkept\n{passed: 4, failed: 2}\n";
+        let (out, vm_out) = both_engines(source);
+        assert_eq!(out, expected);
+        assert_eq!(vm_out, expected);
+
+        // a timeout still stops the script
+        let spin = "fun spin() { while (true) {} } test(\"spin\", spin); print \"after\";";
+        let timeout = Some(Duration::from_millis(200));
+        let (output, out, _) = Output::capture();
+        assert!(timed_out(Runtime { output, timeout, ..Runtime::default() }.run_source(spin.to_string())));
+        let (vm_output, vm_out, _) = Output::capture();
+        assert!(timed_out(VMRuntime { output: vm_output, timeout, ..VMRuntime::default() }.run_source(spin.to_string())));
+        assert!(text(&out).is_empty());
+        assert!(text(&vm_out).is_empty());
+    }
+}
@@ -0,0 +1,223 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::stdlib::{number_arg, string_arg, Caller};
+use crate::types::val::{InterpreterError, Value};
+
+const SECS_PER_DAY: i64 = 86_400;
+
+// a moment in utc, the only zone these natives know about
+#[derive(Debug, PartialEq)]
+struct DateTime {
+    year: i64,
+    month: i64,
+    day: i64,
+    hour: i64,
+    minute: i64,
+    second: i64,
+}
+
+fn is_leap(year: i64) -> bool {
+    return (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    return match month {
+        2 if is_leap(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+}
+
+// days since 1970-01-01 in the proleptic gregorian calendar, years start in march
+// so the leap day comes last
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_from_march = (month + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    return era * 146_097 + day_of_era - 719_468;
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 { month_from_march + 3 } else { month_from_march - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    return (year, month, day);
+}
+
+impl DateTime {
+    fn from_epoch(secs: i64) -> DateTime {
+        let (year, month, day) = civil_from_days(secs.div_euclid(SECS_PER_DAY));
+        let time = secs.rem_euclid(SECS_PER_DAY);
+        return DateTime { year, month, day, hour: time / 3600, minute: time / 60 % 60, second: time % 60 };
+    }
+
+    fn to_epoch(&self) -> i64 {
+        return days_from_civil(self.year, self.month, self.day) * SECS_PER_DAY
+            + self.hour * 3600 + self.minute * 60 + self.second;
+    }
+}
+
+pub fn now(caller: &mut dyn Caller, _args: &[Value]) -> Result<Value, InterpreterError> {
+    match caller.determinism() {
+        None => {}
+        Some(determinism) => return Ok(Value::Number(determinism.now().as_secs_f64())),
+    }
+    let since = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map_err(|e| InterpreterError::SimpleError(format!("now() can't read the clock: {}.", e)))?;
+    return Ok(Value::Number(since.as_secs_f64()));
+}
+
+// %Y %m %d %H %M %S and %% for a literal percent sign
+pub fn format_time(args: &[Value]) -> Result<Value, InterpreterError> {
+    let secs = number_arg("formatTime", args, 0)?;
+    let fmt = string_arg("formatTime", args, 1)?;
+    if !secs.is_finite() {
+        return Err(InterpreterError::SimpleError(format!("formatTime() expects a finite time, found {}.", secs)));
+    }
+    let time = DateTime::from_epoch(secs.floor() as i64);
+
+    let mut result = String::new();
+    let mut chars = fmt.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('Y') => result.push_str(&format!("{:04}", time.year)),
+            Some('m') => result.push_str(&format!("{:02}", time.month)),
+            Some('d') => result.push_str(&format!("{:02}", time.day)),
+            Some('H') => result.push_str(&format!("{:02}", time.hour)),
+            Some('M') => result.push_str(&format!("{:02}", time.minute)),
+            Some('S') => result.push_str(&format!("{:02}", time.second)),
+            Some('%') => result.push('%'),
+            other => return Err(unknown_directive("formatTime", other)),
+        }
+    }
+    return Ok(Value::String(result));
+}
+
+// the same directives as formatTime(), each reads a fixed number of digits
+pub fn parse_time(args: &[Value]) -> Result<Value, InterpreterError> {
+    let text = string_arg("parseTime", args, 0)?;
+    let fmt = string_arg("parseTime", args, 1)?;
+    let mismatch = || InterpreterError::SimpleError(format!("parseTime() can't read {:?} as {:?}.", text, fmt));
+
+    let mut time = DateTime { year: 1970, month: 1, day: 1, hour: 0, minute: 0, second: 0 };
+    let mut rest = text;
+    let mut chars = fmt.chars();
+    while let Some(c) = chars.next() {
+        let (field, width) = match c {
+            '%' => match chars.next() {
+                Some('Y') => (&mut time.year, 4),
+                Some('m') => (&mut time.month, 2),
+                Some('d') => (&mut time.day, 2),
+                Some('H') => (&mut time.hour, 2),
+                Some('M') => (&mut time.minute, 2),
+                Some('S') => (&mut time.second, 2),
+                Some('%') => {
+                    rest = rest.strip_prefix('%').ok_or_else(mismatch)?;
+                    continue;
+                }
+                other => return Err(unknown_directive("parseTime", other)),
+            },
+            literal => {
+                rest = rest.strip_prefix(literal).ok_or_else(mismatch)?;
+                continue;
+            }
+        };
+        let digits = rest.get(..width).filter(|d| d.chars().all(|c| c.is_ascii_digit())).ok_or_else(mismatch)?;
+        *field = digits.parse().map_err(|_| mismatch())?;
+        rest = &rest[width..];
+    }
+    if !rest.is_empty() {
+        return Err(mismatch());
+    }
+
+    let valid = (1..=12).contains(&time.month)
+        && (1..=days_in_month(time.year, time.month)).contains(&time.day)
+        && time.hour < 24 && time.minute < 60 && time.second < 60;
+    if !valid {
+        return Err(InterpreterError::SimpleError(format!("parseTime() read an invalid date from {:?}.", text)));
+    }
+    return Ok(Value::Int(time.to_epoch()));
+}
+
+fn unknown_directive(name: &str, directive: Option<char>) -> InterpreterError {
+    return match directive {
+        None => InterpreterError::SimpleError(format!("{}() format ends with a lone '%'.", name)),
+        Some(c) => InterpreterError::SimpleError(format!("{}() doesn't support '%{}'.", name, c)),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::runtime::support::both_engines;
+    use crate::stdlib::time::{format_time, parse_time, DateTime};
+    use crate::types::val::Value;
+
+    fn text(s: &str) -> Value {
+        return Value::String(s.to_string());
+    }
+
+    #[test]
+    fn epoch_converts_both_ways() {
+        for secs in [0, 951_782_400, 1_709_164_800, 4_107_542_399, -86_401, -12_219_292_800] {
+            assert_eq!(DateTime::from_epoch(secs).to_epoch(), secs);
+        }
+        assert_eq!(DateTime::from_epoch(-1), DateTime { year: 1969, month: 12, day: 31, hour: 23, minute: 59, second: 59 });
+    }
+
+    #[test]
+    fn format_and_parse_round_trip() {
+        let fmt = text("%Y-%m-%d %H:%M:%S");
+        let formatted = format_time(&[Value::Int(1_700_000_000), fmt.clone()]).unwrap();
+        assert_eq!(formatted, text("2023-11-14 22:13:20"));
+        assert_eq!(parse_time(&[formatted, fmt.clone()]).unwrap(), Value::Int(1_700_000_000));
+        assert_eq!(format_time(&[Value::Number(0.9), text("%d/%m/%Y 100%%")]).unwrap(), text("01/01/1970 100%"));
+    }
+
+    #[test]
+    fn leap_days_only_exist_in_leap_years() {
+        let fmt = text("%Y-%m-%d");
+        assert_eq!(parse_time(&[text("2024-02-29"), fmt.clone()]).unwrap(), Value::Int(1_709_164_800));
+        assert_eq!(format_time(&[Value::Int(1_709_164_800), fmt.clone()]).unwrap(), text("2024-02-29"));
+        assert_eq!(parse_time(&[text("2000-02-29"), fmt.clone()]).unwrap(), Value::Int(951_782_400));
+        assert!(parse_time(&[text("2023-02-29"), fmt.clone()]).is_err());
+        assert!(parse_time(&[text("1900-02-29"), fmt]).is_err());
+    }
+
+    #[test]
+    fn mismatched_input_is_an_error() {
+        let fmt = text("%Y-%m-%d");
+        for input in ["2024/01/01", "2024-1-01", "2024-01-01 ", "2024-13-01", "24-01-01"] {
+            let err = parse_time(&[text(input), fmt.clone()]).unwrap_err();
+            assert!(err.to_string().contains("parseTime()"), "{}", err);
+        }
+        assert!(format_time(&[Value::Int(0), text("%Q")]).unwrap_err().to_string().contains("doesn't support '%Q'"));
+        assert!(parse_time(&[text("2024"), text("%Y%")]).is_err());
+        assert!(format_time(&[Value::Number(f64::NAN), fmt]).is_err());
+    }
+
+    #[test]
+    fn time_natives_work_in_both_engines() {
+        let source = r#"
+            var leap = parseTime("2024-02-29 12:30", "%Y-%m-%d %H:%M");
+            print leap, formatTime(leap + 86400, "%d.%m.%Y %H:%M:%S"), now() > leap;
+        "#;
+        let (out, vm_out) = both_engines(source);
+        let expected = "1709209800 01.03.2024 12:30:00 true\n";
+        assert_eq!(out, expected);
+        assert_eq!(vm_out, expected);
+    }
+}
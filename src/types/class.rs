@@ -1,25 +1,26 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
-use crate::process::environment;
+use std::rc::Rc;
 use crate::process::interpreter::Interpreter;
-use crate::types::{expr, func, val};
+use crate::types::{func, val};
 
 #[derive(Clone, Debug, Default)]
 pub struct LoxClass {
     pub name: String,
     pub methods: Vec<val::Value>,
     pub super_class: Option<Box<LoxClass>>,
+    // read as `Class.name`, an enum's members and `class var`s live here. shared by
+    // every copy of the class, so instances and subclasses see writes
+    pub statics: Rc<RefCell<HashMap<String, val::Value>>>,
 }
 
 impl LoxClass {
     pub fn find_method(&self, name: String) -> Option<val::Value> {
         for method in &self.methods {
-            match method {
-                val::Value::LoxFunc(func_name, _) => {
-                    if func_name.as_str() == name.as_str() {
-                        return Some(method.clone());
-                    }
+            if let val::Value::LoxFunc(func_name, _) = method {
+                if func_name.as_str() == name.as_str() {
+                    return Some(method.clone());
                 }
-                _ => {}
             }
         }
 
@@ -32,6 +33,34 @@ impl LoxClass {
 
         None
     }
+
+    // one declaration, running once, makes one class however many copies of it there are
+    pub fn same(&self, other: &LoxClass) -> bool {
+        return Rc::ptr_eq(&self.statics, &other.statics);
+    }
+
+    // `class` is this class or one of its superclasses
+    pub fn inherits(&self, class: &LoxClass) -> bool {
+        if self.same(class) {
+            return true;
+        }
+        return match &self.super_class {
+            None => false,
+            Some(sc) => sc.inherits(class),
+        };
+    }
+
+    // a subclass reads the statics of its superclasses it doesn't define itself
+    pub fn find_static(&self, name: &str) -> Option<val::Value> {
+        match self.statics.borrow().get(name) {
+            None => {}
+            Some(value) => return Some(value.clone()),
+        }
+        return match &self.super_class {
+            None => None,
+            Some(sc) => sc.find_static(name),
+        };
+    }
 }
 
 impl func::Callable for LoxClass {
@@ -55,47 +84,36 @@ impl func::Callable for LoxClass {
     }
 
     fn call(&self, interpreter: &mut Interpreter, arguments: Vec<val::Value>) -> Result<val::Value, val::InterpreterError> {
-        let lox_instance = LoxInstance::new(&self);
+        let lox_instance = LoxInstance::new(self);
         let id = interpreter.next_id();
         interpreter.lox_instances.insert(id, lox_instance);
 
 
         let mut parent_id = None;
-        if self.super_class.is_some() {
-            let parent = self.super_class.as_ref().unwrap().call(interpreter, arguments.clone())?;
-            match parent {
-                val::Value::LoxInstance {
-                    id, ..
-                } => {
-                    parent_id = Some(id)
-                }
-                _ => {}
+        if let Some(super_class) = &self.super_class {
+            let parent = super_class.call(interpreter, arguments.clone())?;
+            if let val::Value::LoxInstance { id, .. } = parent {
+                parent_id = Some(id)
             }
         }
+        interpreter.lox_instances.get_mut(&id).expect("just inserted").parent = parent_id;
 
         let func = self.find_method("init".to_string());
-        match func {
-            None => {}
-            Some(func) => {
-                match func {
-                    val::Value::LoxFunc(_, ref func_id) => {
-                        let mut func = interpreter.lox_functions.get_mut(func_id).unwrap().clone();
-                        func.bind = Some(val::Value::LoxInstance {
-                            id,
-                            parent: parent_id,
-                        });
-                        func.call(interpreter, arguments)?;
-                        func.is_initializer = true;
-                    }
-                    _ => {}
-                }
-            }
+        if let Some(val::Value::LoxFunc(_, ref func_id)) = func {
+            let mut func = interpreter.lox_functions.get_mut(func_id).unwrap().clone();
+            func.bind = Some(val::Value::LoxInstance {
+                id,
+                parent: parent_id,
+                class: self.name.clone(),
+            });
+            func.call(interpreter, arguments)?;
         }
 
 
         return Ok(val::Value::LoxInstance {
             id,
             parent: parent_id,
+            class: self.name.clone(),
         });
     }
 }
@@ -104,6 +122,8 @@ impl func::Callable for LoxClass {
 pub struct LoxInstance {
     pub class: LoxClass,
     fields: HashMap<String, val::Value>,
+    // the instance made for the superclass, it holds the fields its init set
+    pub parent: Option<usize>,
 }
 
 
@@ -112,6 +132,7 @@ impl LoxInstance {
         return Self {
             class: class.clone(),
             fields: HashMap::default(),
+            parent: None,
         };
     }
 
@@ -131,9 +152,21 @@ impl LoxInstance {
         return lox_class.find_method(name.to_string());
     }
 
+    pub fn has_field(&self, name: &str) -> bool {
+        return self.fields.contains_key(name);
+    }
+
+    pub fn fields(&self) -> impl Iterator<Item=(&String, &val::Value)> {
+        return self.fields.iter();
+    }
+
     pub fn set(&mut self, name: &str, val: val::Value) {
         self.fields.insert(name.to_string(), val);
     }
+
+    pub fn remove(&mut self, name: &str) -> Option<val::Value> {
+        return self.fields.remove(name);
+    }
 }
 
 
@@ -1,23 +1,99 @@
 use std::error::Error;
 use std::fmt::{Display, Formatter};
+use std::io;
 
-pub type RunResult<T> = Result<T, Box<dyn Error>>;
+use crate::types::expr::ExpError;
+use crate::types::span::Span;
+use crate::types::val::InterpreterError;
+
+pub type RunResult<T> = Result<T, LoxError>;
 
 
 #[derive(Debug)]
-pub struct RunError {
-    line: usize,
-    message: String,
+pub struct ScanError {
+    pub span: Span,
+    pub message: String,
 }
 
-impl Display for RunError {
+impl Display for ScanError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "at: {}, case: {}", &self.line, &self.message)
+        write!(f, "at: {}, case: {}", &self.span.line, &self.message)
     }
 }
 
-impl Error for RunError {}
+impl Error for ScanError {}
+
+pub fn new_error(span: Span, message: String) -> ScanError {
+    return ScanError { span, message };
+}
+
+// every way running a lox program can fail, tagged with the phase that failed
+#[derive(Debug)]
+pub enum LoxError {
+    Scan(ScanError),
+    Parse(Vec<ExpError>),
+    Compile(Vec<ExpError>),
+    Runtime(InterpreterError),
+    Io(io::Error),
+}
+
+impl LoxError {
+    // sysexits style codes, same as clox/jlox
+    pub fn exit_code(&self) -> i32 {
+        return match self {
+            LoxError::Scan(_) | LoxError::Parse(_) | LoxError::Compile(_) => 65,
+            LoxError::Runtime(_) => 70,
+            LoxError::Io(_) => 74,
+        };
+    }
+}
 
-pub fn new_error(line: usize, message: String) -> Box<dyn Error> {
-    return Box::new(RunError { line, message });
+impl Display for LoxError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoxError::Scan(err) => write!(f, "{}", err),
+            LoxError::Parse(errors) | LoxError::Compile(errors) => {
+                for (i, err) in errors.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}", err)?;
+                }
+                Ok(())
+            }
+            LoxError::Runtime(err) => write!(f, "{}", err),
+            LoxError::Io(err) => write!(f, "io error: {}", err),
+        }
+    }
+}
+
+impl Error for LoxError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LoxError::Scan(err) => Some(err),
+            LoxError::Parse(errors) | LoxError::Compile(errors) => {
+                errors.first().map(|err| err as &(dyn Error + 'static))
+            }
+            LoxError::Runtime(err) => Some(err),
+            LoxError::Io(err) => Some(err),
+        }
+    }
+}
+
+impl From<ScanError> for LoxError {
+    fn from(err: ScanError) -> Self {
+        LoxError::Scan(err)
+    }
+}
+
+impl From<InterpreterError> for LoxError {
+    fn from(err: InterpreterError) -> Self {
+        LoxError::Runtime(err)
+    }
+}
+
+impl From<io::Error> for LoxError {
+    fn from(err: io::Error) -> Self {
+        LoxError::Io(err)
+    }
 }
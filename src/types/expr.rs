@@ -4,43 +4,52 @@ use std::fmt::{Debug, Display, Formatter};
 
 use crate::process::ast;
 use crate::process::ast::Printer;
+use crate::types::span::Span;
 use crate::types::token;
 
+// most parameters a function declares and arguments a call passes, in both engines
+pub const MAX_ARGS: usize = 255;
+
 #[derive(Debug)]
 pub enum ExpError {
     Common(String),
-    VariableRepeatDef(String),
-    UnexpectedToken(token::Token),
+    // `first` is where the name was declared before
+    VariableRepeatDef { name: String, span: Span, first: Span },
+    DuplicateParameter(String, Span),
+    // a keyword, `this` included, where a parameter name should be
+    ReservedParameter(String, Span),
+    UnexpectedToken(Box<token::Token>),
     TokenMismatch {
         expected: token::TokenType,
-        found: token::Token,
+        found: Box<token::Token>,
         err_string: Option<String>,
     },
     ConvertFailed {
         expected: Vec<token::TokenType>,
-        found: token::Token,
+        found: Box<token::Token>,
     },
     ExpectedExpression {
         token_type: token::TokenType,
-        line: usize,
+        span: Span,
     },
     AssignmentFailed {
         name: String
     },
-    TooManyArgs,
+    // "parameters" or "arguments", at the first one past MAX_ARGS
+    TooManyArgs(&'static str, Span),
 }
 
 impl Display for ExpError {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match &self {
-            ExpError::TooManyArgs => write!(
+            ExpError::TooManyArgs(what, span) => write!(
                 f,
-                "too many args, can't more than 255"
+                "Can't have more than {} {} at line={}.", MAX_ARGS, what, span.line
             ),
             ExpError::UnexpectedToken(tok) => write!(
                 f,
                 "Unexpected token {:?} at line={}",
-                tok.token_type, tok.line
+                tok.token_type, tok.span.line
             ),
             ExpError::TokenMismatch {
                 expected,
@@ -61,14 +70,25 @@ impl Display for ExpError {
                 f,
                 "Cannot ConvertFailed, expected {:?}, found {:?}", expected, found
             ),
-            ExpError::ExpectedExpression { token_type, line } => write!(
+            ExpError::ExpectedExpression { token_type, span } => write!(
                 f,
                 "ExpectedExpression line={},token_type={:?}",
-                line, token_type
+                span.line, token_type
             ),
             ExpError::AssignmentFailed { name } => write!(f, "{}, Invalid assignment target.", name),
 
-            ExpError::VariableRepeatDef(name) => write!(f, "{}, Variable repeat def.", name),
+            ExpError::VariableRepeatDef { name, first, .. } => write!(
+                f,
+                "{}, Variable repeat def, first declared at line={}.", name, first.line
+            ),
+            ExpError::DuplicateParameter(name, span) => write!(
+                f,
+                "Duplicate parameter '{}' at line={}.", name, span.line
+            ),
+            ExpError::ReservedParameter(name, span) => write!(
+                f,
+                "Can't use reserved word '{}' as a parameter name at line={}.", name, span.line
+            ),
             ExpError::Common(str) => write!(f, "{}", str),
         }
     }
@@ -76,6 +96,23 @@ impl Display for ExpError {
 
 impl Error for ExpError {}
 
+impl ExpError {
+    // where in the source the error points, when known
+    pub fn span(&self) -> Option<Span> {
+        return match self {
+            ExpError::UnexpectedToken(tok) => Some(tok.span),
+            ExpError::TokenMismatch { found, .. } => Some(found.span),
+            ExpError::ConvertFailed { found, .. } => Some(found.span),
+            ExpError::ExpectedExpression { span, .. } => Some(*span),
+            ExpError::VariableRepeatDef { span, .. } => Some(*span),
+            ExpError::DuplicateParameter(_, span) => Some(*span),
+            ExpError::ReservedParameter(_, span) => Some(*span),
+            ExpError::TooManyArgs(_, span) => Some(*span),
+            _ => None,
+        };
+    }
+}
+
 
 impl ast::Accept for Expression {
     fn accept(&self, printer: &dyn Printer) -> String {
@@ -86,6 +123,7 @@ impl ast::Accept for Expression {
 
 #[derive(Debug, Clone)]
 pub enum Literal {
+    Int(i64),
     Number(f64),
     String(String),
     True,
@@ -97,15 +135,13 @@ pub enum Literal {
 #[derive(Debug, Copy, Clone)]
 pub struct UnaryOp {
     pub token_type: UnaryOperatorType,
-    // pub line: usize,
-    // pub col: i64,
+    pub span: Span,
 }
 
 #[derive(Debug, Copy, Clone)]
 pub struct BinaryOp {
     pub token_type: BinaryOperatorType,
-    // pub line: usize,
-    // pub col: i64,
+    pub span: Span,
 }
 
 
@@ -135,6 +171,9 @@ pub enum BinaryOperatorType {
     LessEqual,
     Greater,
     GreaterEqual,
+    In,
+    // an instance of the class on the right, or of one of its subclasses
+    Is,
     Plus,
     Minus,
     Star,
@@ -147,8 +186,21 @@ impl Display for BinaryOperatorType {
     }
 }
 
+// a node and the source it was parsed from
+#[derive(Clone, Debug)]
+pub struct Expression {
+    pub kind: ExpressionKind,
+    pub span: Span,
+}
+
+impl Expression {
+    pub fn new(kind: ExpressionKind, span: Span) -> Self {
+        return Expression { kind, span };
+    }
+}
+
 #[derive(Clone, Debug)]
-pub enum Expression {
+pub enum ExpressionKind {
     Literal(Literal),
     Unary(UnaryOp, Box<Expression>),
     Binary(Box<Expression>, BinaryOp, Box<Expression>),
@@ -156,6 +208,8 @@ pub enum Expression {
     Get {
         object: Box<Expression>,
         variable: String,
+        // `?.`, a nil object ends the whole chain with nil
+        optional: bool,
     },
     Set {
         object: Box<Expression>,
@@ -171,13 +225,40 @@ pub enum Expression {
     Variable(String),
     Assign(String, Box<Expression>),
     Logical(Box<Expression>, LogicalOperatorType, Box<Expression>),
+    Array(Vec<Expression>),
+    // key value pairs in source order
+    Map(Vec<(Expression, Expression)>),
+    Index {
+        object: Box<Expression>,
+        index: Box<Expression>,
+    },
+    SetIndex {
+        object: Box<Expression>,
+        index: Box<Expression>,
+        value: Box<Expression>,
+    },
+    // `...array` among a call's arguments, each element becomes an argument
+    Spread(Box<Expression>),
 }
 
 #[derive(Clone, Debug)]
-pub enum Statement {
+pub struct Statement {
+    pub kind: StatementKind,
+    pub span: Span,
+}
+
+impl Statement {
+    pub fn new(kind: StatementKind, span: Span) -> Self {
+        return Statement { kind, span };
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum StatementKind {
     Expression(Expression),
     Function(String, Vec<String>, Box<Statement>),
-    Print(Expression),
+    // `print a, b;` prints the values separated by spaces
+    Print(Vec<Expression>),
     Return(String, Option<Expression>),
     Var(String, Expression),
     Block(Vec<Statement>),
@@ -185,7 +266,33 @@ pub enum Statement {
         name: String,
         methods: Vec<Statement>,
         super_class: Option<String>,
+        // `class var count = 0;`, evaluated in order once the class exists
+        statics: Vec<(String, Expression)>,
     },
+    // `enum Color { Red, Green }`, a class whose statics count up from 0
+    Enum(String, Vec<String>),
     If(Expression, Box<Statement>, Option<Box<Statement>>),
-    While(Expression, Box<Statement>),
+    // a `for` loop is a while with an increment, which `continue` doesn't skip
+    While {
+        condition: Expression,
+        body: Box<Statement>,
+        increment: Option<Expression>,
+        label: Option<String>,
+    },
+    // for (var name in iterable) body
+    ForIn {
+        name: String,
+        iterable: Expression,
+        body: Box<Statement>,
+        label: Option<String>,
+    },
+    // `text` is the condition as written, for the error message
+    Assert {
+        condition: Expression,
+        text: String,
+        message: Option<Expression>,
+    },
+    // with no label these go to the innermost loop
+    Break(Option<String>),
+    Continue(Option<String>),
 }
@@ -1,8 +1,11 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::rc::Rc;
 
 use crate::process::{environment, interpreter};
+use crate::stdlib;
 use crate::types::{expr, val};
-use crate::types::class::LoxClass;
 
 pub trait Callable {
     fn arity(&self, interpreter: &interpreter::Interpreter) -> usize;
@@ -10,13 +13,50 @@ pub trait Callable {
 }
 
 
+// a function implemented in rust, see process::builtins
+#[derive(Clone)]
+pub struct LoxNative {
+    pub name: String,
+    pub arity: usize,
+    pub func: fn(&mut interpreter::Interpreter, &[val::Value]) -> Result<val::Value, val::InterpreterError>,
+}
+
+impl Debug for LoxNative {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "LoxNative({})", self.name)
+    }
+}
+
+impl Callable for LoxNative {
+    fn arity(&self, _interpreter: &interpreter::Interpreter) -> usize {
+        return self.arity;
+    }
+
+    fn call(&self, interpreter: &mut interpreter::Interpreter, args: Vec<val::Value>) -> Result<val::Value, val::InterpreterError> {
+        if args.len() != self.arity {
+            return Err(val::InterpreterError::SimpleError(format!("Expected {} arguments but got {}.", self.arity, args.len())));
+        }
+        return (self.func)(interpreter, args.as_slice());
+    }
+}
+
+impl Callable for stdlib::Native {
+    fn arity(&self, _interpreter: &interpreter::Interpreter) -> usize {
+        return self.arity;
+    }
+
+    fn call(&self, interpreter: &mut interpreter::Interpreter, args: Vec<val::Value>) -> Result<val::Value, val::InterpreterError> {
+        return stdlib::Native::call(self, interpreter, args.as_slice());
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct LoxFunction {
     pub id: usize,
     pub name: String,
     pub parameters: Vec<String>,
     pub body: expr::Statement,
-    pub closure: environment::Environment,
+    pub closure: Rc<RefCell<environment::Environment>>,
     pub bind: Option<val::Value>,
     pub is_initializer: bool,
 }
@@ -38,11 +78,53 @@ impl LoxFunction {
 }
 
 impl Callable for LoxFunction {
-    fn arity(&self, interpreter: &interpreter::Interpreter) -> usize {
+    fn arity(&self, _interpreter: &interpreter::Interpreter) -> usize {
         return self.parameters.len();
     }
 
     fn call(&self, interpreter: &mut interpreter::Interpreter, args: Vec<val::Value>) -> Result<val::Value, val::InterpreterError> {
+        if interpreter.depth >= interpreter::MAX_CALL_DEPTH {
+            return Err(val::InterpreterError::SimpleError("Stack overflow.".to_string()));
+        }
+        interpreter.depth += 1;
+        let result = stacker::maybe_grow(interpreter::STACK_RED_ZONE, interpreter::STACK_SEGMENT, || {
+            self.call_in_place(interpreter, args)
+        });
+        interpreter.depth -= 1;
+        return result;
+    }
+}
+
+impl LoxFunction {
+    // runs the body, then each function it tail calls in its place, so a chain of
+    // tail calls takes one host stack frame however long it gets
+    fn call_in_place(&self, interpreter: &mut interpreter::Interpreter, args: Vec<val::Value>) -> Result<val::Value, val::InterpreterError> {
+        let mut flow = self.run_body(interpreter, args)?;
+        loop {
+            let (callee, args) = match flow {
+                interpreter::ControlFlow::TailCall(callee, args) => (callee, args),
+                interpreter::ControlFlow::Return(value) => return Ok(value),
+                // ran off the end of the body
+                _ => return Ok(val::Value::Nil),
+            };
+            let next = match &callee {
+                val::Value::LoxFunc(_, id) => interpreter.lox_functions.get(id).cloned(),
+                val::Value::LoxBoundMethod { receiver, id, .. } => interpreter.lox_functions.get(id).cloned().map(|mut method| {
+                    method.bind = Some(*receiver.clone());
+                    method
+                }),
+                _ => None,
+            };
+            flow = match next {
+                Some(next) if next.parameters.len() == args.len() => next.run_body(interpreter, args)?,
+                // natives, classes and wrong argument counts take the usual way
+                _ => interpreter::ControlFlow::Return(interpreter.call_with(callee, args)?),
+            };
+        }
+    }
+
+    // how the body finished, a return or a tail call or running off its end
+    fn run_body(&self, interpreter: &mut interpreter::Interpreter, args: Vec<val::Value>) -> Result<interpreter::ControlFlow, val::InterpreterError> {
         let args_env: HashMap<_, _> = self
             .parameters
             .iter()
@@ -61,49 +143,22 @@ impl Callable for LoxFunction {
         let mut new_env = environment::Environment::with_enclosing(self.closure.clone());
         new_env.values.extend(args_env);
 
-        match &self.bind {
-            None => {}
-            Some(bind) => {
-                match bind {
-                    val::Value::LoxInstance {
-                        id, parent
-                    } => {
-                        new_env.values.insert("this".to_string(), val::Value::LoxInstance {
-                            id: *id,
-                            parent: parent.clone(),
-                        });
-
-                        match parent {
-                            Some(p) => {
-                                new_env.values.insert("super".to_string(), val::Value::LoxInstance {
-                                    id: *p,
-                                    parent: None,
-                                });
-                            }
-                            _ => {}
-                        }
-                    }
-                    _ => {}
-                }
-            }
+        if let Some(this @ val::Value::LoxInstance { .. }) = &self.bind {
+            new_env.values.insert("this".to_string(), this.clone());
         }
 
-        interpreter.environment = new_env;
-        interpreter.execute(&self.body)?;
+        interpreter.environment = Rc::new(RefCell::new(new_env));
+        let flow = interpreter.execute(&self.body);
         interpreter.environment = saved_env;
+        let flow = flow?;
 
+        // init hands back `this` however its body returned
         if self.is_initializer {
-            return Ok(self.bind.as_ref().unwrap().clone());
-        }
-
-        return match interpreter.ret.clone() {
-            None => {
-                Ok(val::Value::Nil)
-            }
-            Some(ret) => {
-                interpreter.ret = None;
-                Ok(ret)
+            if let interpreter::ControlFlow::TailCall(callee, args) = flow {
+                interpreter.call_with(callee, args)?;
             }
-        };
+            return Ok(interpreter::ControlFlow::Return(self.bind.as_ref().unwrap().clone()));
+        }
+        return Ok(flow);
     }
 }
\ No newline at end of file
@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+
+use crate::types::val::{InterpreterError, Value};
+
+// the hashable part of a value, only these can be map keys
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum MapKey {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Number(u64),
+    String(String),
+}
+
+impl MapKey {
+    pub fn from_value(value: &Value) -> Result<MapKey, InterpreterError> {
+        return match value {
+            Value::Nil => Ok(MapKey::Nil),
+            Value::Bool(b) => Ok(MapKey::Bool(*b)),
+            Value::Int(i) => Ok(MapKey::Int(*i)),
+            // NaN isn't equal to itself, an entry under it could never be found again
+            Value::Number(n) if n.is_nan() => Err(InterpreterError::SimpleError("Can't use NaN as a map key.".to_string())),
+            // 3.0 == 3, so both have to land on the same entry. -0.0 == 0.0 lands on 0 too
+            Value::Number(n) if n.fract() == 0.0 && *n >= i64::MIN as f64 && *n < i64::MAX as f64 => {
+                Ok(MapKey::Int(*n as i64))
+            }
+            Value::Number(n) => Ok(MapKey::Number(n.to_bits())),
+            Value::String(s) => Ok(MapKey::String(s.clone())),
+            other => Err(InterpreterError::SimpleError(
+                format!("Can't use {} as a map key.", other.type_name()))),
+        };
+    }
+}
+
+// keeps entries in insertion order, the index points into `entries`
+#[derive(Debug, Clone, Default)]
+pub struct LoxMap {
+    entries: Vec<(Value, Value)>,
+    index: HashMap<MapKey, usize>,
+}
+
+impl LoxMap {
+    pub fn new() -> Self {
+        return LoxMap::default();
+    }
+
+    pub fn len(&self) -> usize {
+        return self.entries.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.entries.is_empty();
+    }
+
+    pub fn get(&self, key: &Value) -> Result<Option<Value>, InterpreterError> {
+        let key = MapKey::from_value(key)?;
+        return Ok(self.index.get(&key).map(|i| self.entries[*i].1.clone()));
+    }
+
+    pub fn contains_key(&self, key: &Value) -> Result<bool, InterpreterError> {
+        return Ok(self.index.contains_key(&MapKey::from_value(key)?));
+    }
+
+    // overwriting a key keeps its original position
+    pub fn insert(&mut self, key: Value, value: Value) -> Result<(), InterpreterError> {
+        let map_key = MapKey::from_value(&key)?;
+        match self.index.get(&map_key) {
+            None => {}
+            Some(i) => {
+                self.entries[*i].1 = value;
+                return Ok(());
+            }
+        }
+        // keep the canonical form, so `{3.0: x}` prints and iterates as `{3: x}`
+        let key = match map_key {
+            MapKey::Int(i) => Value::Int(i),
+            _ => key,
+        };
+        self.index.insert(map_key, self.entries.len());
+        self.entries.push((key, value));
+        return Ok(());
+    }
+
+    pub fn remove(&mut self, key: &Value) -> Result<Option<Value>, InterpreterError> {
+        let removed = match self.index.remove(&MapKey::from_value(key)?) {
+            None => return Ok(None),
+            Some(i) => i,
+        };
+        let (_, value) = self.entries.remove(removed);
+        // everything after the removed entry moved down by one
+        for i in self.index.values_mut() {
+            if *i > removed {
+                *i -= 1;
+            }
+        }
+        return Ok(Some(value));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=&(Value, Value)> {
+        return self.entries.iter();
+    }
+
+    pub fn keys(&self) -> Vec<Value> {
+        return self.entries.iter().map(|(k, _)| k.clone()).collect();
+    }
+
+    pub fn values(&self) -> Vec<Value> {
+        return self.entries.iter().map(|(_, v)| v.clone()).collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::types::map::LoxMap;
+    use crate::types::val::Value;
+
+    #[test]
+    fn integral_numbers_share_int_keys() {
+        let mut map = LoxMap::new();
+        map.insert(Value::Int(3), Value::Int(1)).unwrap();
+        assert_eq!(map.get(&Value::Number(3.0)).unwrap(), Some(Value::Int(1)));
+        map.insert(Value::Number(-0.0), Value::Int(2)).unwrap();
+        map.insert(Value::Number(3.5), Value::Int(3)).unwrap();
+        assert_eq!(map.get(&Value::Int(0)).unwrap(), Some(Value::Int(2)));
+        assert_eq!(map.get(&Value::Int(3)).unwrap(), Some(Value::Int(1)));
+        assert_eq!(map.len(), 3);
+        assert_eq!(format!("{:?}", map.keys()), "[Int(3), Int(0), Number(3.5)]");
+    }
+
+    #[test]
+    fn signed_zeros_share_a_key_and_nan_is_no_key() {
+        let mut map = LoxMap::new();
+        map.insert(Value::Number(0.0), Value::Int(1)).unwrap();
+        assert_eq!(map.get(&Value::Number(-0.0)).unwrap(), Some(Value::Int(1)));
+        map.insert(Value::Number(-0.0), Value::Int(2)).unwrap();
+        assert_eq!(map.get(&Value::Number(0.0)).unwrap(), Some(Value::Int(2)));
+        assert_eq!(map.len(), 1);
+
+        let nan = Value::Number(f64::NAN);
+        assert!(map.insert(nan.clone(), Value::Int(3)).unwrap_err().to_string().contains("Can't use NaN as a map key."));
+        assert!(map.get(&nan).is_err());
+        assert!(map.contains_key(&Value::Number(-f64::NAN)).is_err());
+        map.insert(Value::Number(f64::INFINITY), Value::Int(4)).unwrap();
+        assert_eq!(map.get(&Value::Number(f64::INFINITY)).unwrap(), Some(Value::Int(4)));
+        assert_eq!(map.get(&Value::Number(f64::NEG_INFINITY)).unwrap(), None);
+    }
+}
@@ -1,7 +1,10 @@
 pub mod err;
+pub mod span;
 pub mod token;
 pub mod expr;
 pub mod val;
+pub mod map;
+pub mod output;
 pub mod env;
 pub mod func;
 pub mod class;
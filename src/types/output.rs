@@ -0,0 +1,57 @@
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+
+use crate::types::val::InterpreterError;
+
+pub type Sink = Rc<RefCell<dyn Write>>;
+pub type Buffer = Rc<RefCell<Vec<u8>>>;
+
+// where a script's output goes, stdout and stderr unless swapped out, e.g. to
+// capture it in tests
+#[derive(Clone)]
+pub struct Output {
+    pub out: Sink,
+    pub err: Sink,
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        return Output {
+            out: Rc::new(RefCell::new(std::io::stdout())),
+            err: Rc::new(RefCell::new(std::io::stderr())),
+        };
+    }
+}
+
+impl Output {
+    // collects everything written, returns the buffers for stdout and stderr
+    pub fn capture() -> (Output, Buffer, Buffer) {
+        let out: Buffer = Rc::new(RefCell::new(vec![]));
+        let err: Buffer = Rc::new(RefCell::new(vec![]));
+        return (Output { out: out.clone(), err: err.clone() }, out, err);
+    }
+
+    // throws everything away, for benchmarks that only care how long a script takes
+    pub fn discard() -> Output {
+        return Output {
+            out: Rc::new(RefCell::new(std::io::sink())),
+            err: Rc::new(RefCell::new(std::io::sink())),
+        };
+    }
+
+    pub fn print(&self, text: &str) -> Result<(), InterpreterError> {
+        return write_to(&self.out, format!("{}\n", text).as_bytes());
+    }
+
+    pub fn eprint(&self, text: &str) -> Result<(), InterpreterError> {
+        return write_to(&self.err, format!("{}\n", text).as_bytes());
+    }
+}
+
+pub fn write_to(sink: &Sink, bytes: &[u8]) -> Result<(), InterpreterError> {
+    let mut sink = sink.borrow_mut();
+    return sink.write_all(bytes)
+        .and_then(|_| sink.flush())
+        .map_err(|e| InterpreterError::SimpleError(format!("Failed to write output: {}.", e)));
+}
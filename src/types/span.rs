@@ -0,0 +1,31 @@
+// where a token or node sits in the source: 1-based line and column, length in
+// bytes on the first line, and the line and column just past its last character
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+    pub len: usize,
+    pub end_line: usize,
+    pub end_col: usize,
+}
+
+impl Span {
+    pub fn new(line: usize, col: usize, len: usize) -> Self {
+        return Span { line, col, len, end_line: line, end_col: col + len };
+    }
+
+    // only the line is known, e.g. from a vm instruction
+    pub fn line(line: usize) -> Self {
+        return Span { line, col: 0, len: 0, end_line: line, end_col: 0 };
+    }
+
+    // from the start of self to the end of `end`
+    pub fn to(self, end: Span) -> Span {
+        let len = if end.end_line == self.line {
+            end.end_col.saturating_sub(self.col)
+        } else {
+            self.len
+        };
+        return Span { line: self.line, col: self.col, len, end_line: end.end_line, end_col: end.end_col };
+    }
+}
@@ -1,15 +1,23 @@
-use std::error::Error;
-
 use phf::phf_map;
 
+use crate::types::err::ScanError;
+use crate::types::span::Span;
+
 static KEYWORDS: phf::Map<&'static str, TokenType> = phf_map! {
     "and" => TokenType::And,
+    "assert" => TokenType::Assert,
+    "break" => TokenType::Break,
     "class"=> TokenType::Class,
+    "continue" => TokenType::Continue,
     "else" => TokenType::Else,
+    "enum" => TokenType::Enum,
     "false" => TokenType::False,
     "for" => TokenType::For,
     "fun" => TokenType::Fun,
     "if" => TokenType::If,
+    "in" => TokenType::In,
+    "is" => TokenType::Is,
+    "loop" => TokenType::Loop,
     "nil" => TokenType::Nil,
     "or" => TokenType::Or,
     "print" => TokenType::Print,
@@ -25,6 +33,24 @@ pub fn parse_keyword(keyword: &str) -> Option<TokenType> {
     KEYWORDS.get(keyword).cloned()
 }
 
+// the source the tokens were scanned from, spacing within a line is kept
+pub fn source_text(tokens: &[Token]) -> String {
+    let mut text = String::new();
+    let mut previous: Option<&Token> = None;
+    for token in tokens {
+        match previous {
+            None => {}
+            Some(previous) if previous.span.end_line == token.span.line => {
+                text.push_str(&" ".repeat(token.span.col.saturating_sub(previous.span.end_col)));
+            }
+            Some(_) => text.push(' '),
+        }
+        text.push_str(&token.lexeme);
+        previous = Some(token);
+    }
+    return text;
+}
+
 #[derive(Debug, Clone, PartialEq, Copy)]
 pub enum TokenType {
     // Single-character tokens.
@@ -32,6 +58,8 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
     Dot,
     Minus,
@@ -39,6 +67,11 @@ pub enum TokenType {
     Semicolon,
     Slash,
     Star,
+    Question,
+    QuestionDot,
+    Colon,
+    // `...`, spreads an array into call arguments
+    Ellipsis,
 
     // One or two character tokens.
     Bang,
@@ -54,15 +87,26 @@ pub enum TokenType {
     Identifier,
     String,
     Number,
+    // only produced when the scanner keeps comments
+    Comment,
+    // only produced when the scanner treats newlines as significant
+    Newline,
 
     // Keywords.
     And,
+    Assert,
+    Break,
     Class,
+    Continue,
     Else,
+    Enum,
     False,
     Fun,
     For,
     If,
+    In,
+    Is,
+    Loop,
     Nil,
     Or,
     Print,
@@ -81,14 +125,44 @@ pub struct Token {
     pub token_type: TokenType,
     pub lexeme: String,
     pub literal: Option<Literal>,
-    pub line: usize,
+    pub span: Span,
 }
 
-#[derive(Debug, Clone)]
+impl Token {
+    // a token with no position, the literal is derived from the lexeme like the scanner does
+    pub fn simple(token_type: TokenType, lexeme: &str) -> Self {
+        let literal = match token_type {
+            TokenType::Number => match lexeme.parse() {
+                Ok(int) => Some(Literal::Int(int)),
+                Err(_) => Some(Literal::Number(lexeme.parse().expect("not a number"))),
+            },
+            TokenType::String => Some(Literal::Str(lexeme.trim_matches('"').to_string())),
+            _ => None,
+        };
+        return Token {
+            token_type,
+            lexeme: lexeme.to_string(),
+            literal,
+            span: Span::default(),
+        };
+    }
+}
+
+// position is ignored, two tokens are equal when they read the same
+impl PartialEq for Token {
+    fn eq(&self, other: &Self) -> bool {
+        return self.token_type == other.token_type
+            && self.lexeme == other.lexeme
+            && self.literal == other.literal;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Literal {
     Identifier(String),
     Str(String),
+    Int(i64),
     Number(f64),
 }
 
-pub type TokenResult = Result<Vec<Token>, Box<dyn Error>>;
+pub type TokenResult = Result<Vec<Token>, ScanError>;
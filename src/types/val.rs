@@ -1,35 +1,63 @@
+use std::cell::RefCell;
 use std::cmp::Ordering;
 use std::error::Error;
 use std::fmt::{Display, Formatter};
+use std::rc::Rc;
+use std::time::Duration;
 
-use crate::types::{class, expr};
-use crate::vm::chunk::{BoundMethod, Class, Constant, Function, Instance, NativeFunction};
+use crate::stdlib::iter::{LoxIter, Range};
+use crate::stdlib::Native;
+use crate::types::{class, expr, func};
+use crate::types::map::LoxMap;
+use crate::types::span::Span;
+use crate::vm::chunk::{BoundMethod, Class, Closure, Constant, Function, Instance, NativeFunction};
 
 #[derive(Debug, Clone)]
 pub enum Value {
+    Int(i64),
     Number(f64),
     String(String),
     Bool(bool),
     Nil,
+    // shared by reference, like instances
+    Array(Rc<RefCell<Vec<Value>>>),
+    Map(Rc<RefCell<LoxMap>>),
+    Range(Range),
+    // a for-in loop in progress, only ever lives on the VM stack
+    Iterator(Rc<RefCell<LoxIter>>),
+    // stdlib natives both engines call the same way
+    Native(Native),
     LoxFunc(String, usize),
+    // a method read off an instance, `class` is the instance's class
+    LoxBoundMethod {
+        receiver: Box<Value>,
+        class: String,
+        name: String,
+        id: usize,
+    },
+    LoxNative(func::LoxNative),
     LoxClass(class::LoxClass),
+    // `class` is the name it prints with
     LoxInstance {
         id: usize,
         parent: Option<usize>,
+        class: String,
     },
 
-    // for fast return
-    Ret(Box<Value>),
-    Function(Function),
+    Function(Rc<Function>),
+    Closure(Closure),
     NativeFunc(NativeFunction),
     Class(Class),
-    Instance(Instance),
+    Instance(Rc<RefCell<Instance>>),
     BoundMethod(Box<BoundMethod>),
 }
 
 impl From<Constant> for Value {
     fn from(c: Constant) -> Self {
         return match c {
+            Constant::Int(i) => {
+                Value::Int(i)
+            }
             Constant::Number(f) => {
                 Value::Number(f)
             }
@@ -42,9 +70,86 @@ impl From<Constant> for Value {
             Constant::String(s) => {
                 Value::String(s)
             }
-            Constant::Function(func) => {
-                Value::Function(func)
-            }
+        };
+    }
+}
+
+// for embedders building arguments and reading results without matching on Value
+impl From<f64> for Value {
+    fn from(n: f64) -> Self {
+        return Value::Number(n);
+    }
+}
+
+impl From<i64> for Value {
+    fn from(i: i64) -> Self {
+        return Value::Int(i);
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        return Value::Bool(b);
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        return Value::String(s.to_string());
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        return Value::String(s);
+    }
+}
+
+fn mismatch(expected: &str, found: Value) -> InterpreterError {
+    return InterpreterError::TypeNotMatch { expected: expected.to_string(), found: Box::new(found) };
+}
+
+// ints widen, like the natives taking a number
+impl TryFrom<Value> for f64 {
+    type Error = InterpreterError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        return value.as_f64().ok_or_else(|| mismatch("number", value));
+    }
+}
+
+// a float never narrows, even a whole one
+impl TryFrom<Value> for i64 {
+    type Error = InterpreterError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        return match value {
+            Value::Int(i) => Ok(i),
+            other => Err(mismatch("int", other)),
+        };
+    }
+}
+
+// only a bool, use is_truthy() for lox truthiness
+impl TryFrom<Value> for bool {
+    type Error = InterpreterError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        return match value {
+            Value::Bool(b) => Ok(b),
+            other => Err(mismatch("bool", other)),
+        };
+    }
+}
+
+// only a string, to_string() prints any value
+impl TryFrom<Value> for String {
+    type Error = InterpreterError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        return match value {
+            Value::String(s) => Ok(s),
+            other => Err(mismatch("string", other)),
         };
     }
 }
@@ -53,11 +158,25 @@ impl From<Constant> for Value {
 impl PartialOrd for Value {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         return match self {
+            Value::Int(x) => {
+                match other {
+                    Value::Int(y) => {
+                        x.partial_cmp(y)
+                    }
+                    Value::Number(y) => {
+                        (*x as f64).partial_cmp(y)
+                    }
+                    _ => { None }
+                }
+            }
             Value::Number(x) => {
                 match other {
                     Value::Number(y) => {
                         x.partial_cmp(y)
                     }
+                    Value::Int(y) => {
+                        x.partial_cmp(&(*y as f64))
+                    }
                     _ => { None }
                 }
             }
@@ -70,14 +189,10 @@ impl PartialOrd for Value {
                 }
             }
             Value::Bool(_) => {
-                match other {
-                    _ => { None }
-                }
+                None
             }
             Value::Nil => {
-                match other {
-                    _ => { None }
-                }
+                None
             }
             _ => {
                 None
@@ -86,14 +201,30 @@ impl PartialOrd for Value {
     }
 }
 
+// what `==`, `indexOf` and `in` go by. numbers compare as f64, so NaN equals
+// nothing and -0.0 equals 0
 impl PartialEq for Value {
     fn eq(&self, other: &Self) -> bool {
         return match self {
+            Value::Int(x) => {
+                match other {
+                    Value::Int(y) => {
+                        x == y
+                    }
+                    Value::Number(y) => {
+                        *x as f64 == *y
+                    }
+                    _ => { false }
+                }
+            }
             Value::Number(x) => {
                 match other {
                     Value::Number(y) => {
                         x == y
                     }
+                    Value::Int(y) => {
+                        *x == *y as f64
+                    }
                     _ => { false }
                 }
             }
@@ -114,13 +245,82 @@ impl PartialEq for Value {
                 }
             }
             Value::Nil => {
+                matches!(other, Value::Nil)
+            }
+            Value::Array(x) => {
+                match other {
+                    Value::Array(y) => {
+                        arrays_equal(x, y, &mut vec![])
+                    }
+                    _ => { false }
+                }
+            }
+            Value::Map(x) => {
+                match other {
+                    Value::Map(y) => {
+                        Rc::ptr_eq(x, y)
+                    }
+                    _ => { false }
+                }
+            }
+            Value::Range(x) => {
                 match other {
-                    Value::Nil => {
-                        true
+                    Value::Range(y) => {
+                        x == y
                     }
                     _ => { false }
                 }
             }
+            // instances are equal only to themselves
+            Value::Instance(x) => {
+                match other {
+                    Value::Instance(y) => {
+                        Rc::ptr_eq(x, y)
+                    }
+                    _ => { false }
+                }
+            }
+            Value::LoxInstance { id: x, .. } => {
+                match other {
+                    Value::LoxInstance { id: y, .. } => {
+                        x == y
+                    }
+                    _ => { false }
+                }
+            }
+            // a class is itself whichever copy of it this is, like `is` sees it
+            Value::LoxClass(x) => {
+                matches!(other, Value::LoxClass(y) if x.same(y))
+            }
+            Value::Class(x) => {
+                matches!(other, Value::Class(y) if x.id == y.id)
+            }
+            // functions are equal only to themselves as well, natives go by name
+            // since each one is registered once
+            Value::LoxFunc(_, x) => {
+                matches!(other, Value::LoxFunc(_, y) if x == y)
+            }
+            Value::LoxBoundMethod { receiver: x, id: x_id, .. } => {
+                matches!(other, Value::LoxBoundMethod { receiver: y, id: y_id, .. } if x_id == y_id && x == y)
+            }
+            Value::Native(x) => {
+                matches!(other, Value::Native(y) if x.name == y.name)
+            }
+            Value::LoxNative(x) => {
+                matches!(other, Value::LoxNative(y) if x.name == y.name)
+            }
+            Value::NativeFunc(x) => {
+                matches!(other, Value::NativeFunc(y) if x.name == y.name)
+            }
+            Value::Function(x) => {
+                matches!(other, Value::Function(y) if Rc::ptr_eq(x, y))
+            }
+            Value::Closure(x) => {
+                matches!(other, Value::Closure(y) if closures_equal(x, y))
+            }
+            Value::BoundMethod(x) => {
+                matches!(other, Value::BoundMethod(y) if closures_equal(&x.closure, &y.closure) && x.receiver == y.receiver)
+            }
             _ => {
                 false
             }
@@ -128,23 +328,371 @@ impl PartialEq for Value {
     }
 }
 
+// copies of one closure share its function and the variables it captured
+fn closures_equal(x: &Closure, y: &Closure) -> bool {
+    return Rc::ptr_eq(&x.function, &y.function)
+        && x.upvalues.len() == y.upvalues.len()
+        && x.upvalues.iter().zip(y.upvalues.iter()).all(|(a, b)| Rc::ptr_eq(a, b));
+}
+
+type ArrayRef = Rc<RefCell<Vec<Value>>>;
+type ArrayPtr = *const RefCell<Vec<Value>>;
+
+// element by element, `seen` holds the pairs already being compared so cyclic arrays end
+fn arrays_equal(x: &ArrayRef, y: &ArrayRef, seen: &mut Vec<(ArrayPtr, ArrayPtr)>) -> bool {
+    if Rc::ptr_eq(x, y) {
+        return true;
+    }
+    let pair = (Rc::as_ptr(x), Rc::as_ptr(y));
+    if seen.contains(&pair) {
+        return true;
+    }
+    let (xs, ys) = (x.borrow(), y.borrow());
+    if xs.len() != ys.len() {
+        return false;
+    }
+    seen.push(pair);
+    let equal = xs.iter().zip(ys.iter()).all(|(a, b)| match (a, b) {
+        (Value::Array(a), Value::Array(b)) => arrays_equal(a, b, seen),
+        _ => a.eq(b),
+    });
+    seen.pop();
+    return equal;
+}
+
+// what a failed `assert` statement raises, a nil message adds nothing
+pub fn assertion_failed(text: &str, message: &Value) -> InterpreterError {
+    let message = match message {
+        Value::Nil => "".to_string(),
+        message => format!(": {}", message),
+    };
+    return InterpreterError::SimpleError(format!("Assertion failed: {}{}", text, message));
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Int(i) => write!(f, "{}", i),
+            // keep the decimal point so floats and ints read differently
+            Value::Number(n) => write!(f, "{:?}", n),
+            Value::String(s) => write!(f, "{}", s),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Nil => write!(f, "nil"),
+            Value::Array(values) => {
+                write!(f, "[")?;
+                for (i, value) in values.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            }
+            Value::Map(map) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in map.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {}", key, value)?;
+                }
+                write!(f, "}}")
+            }
+            Value::Range(range) => write!(f, "range({}, {}, {})", range.start, range.end, range.step),
+            Value::LoxFunc(name, _) => write!(f, "<fn {}>", name),
+            Value::Function(function) => write_function(f, &function.name),
+            Value::Closure(closure) => write_function(f, &closure.function.name),
+            Value::Native(_) | Value::LoxNative(_) | Value::NativeFunc(_) => write!(f, "<native fn>"),
+            Value::LoxBoundMethod { class, name, .. } => write!(f, "<bound method {}.{}>", class, name),
+            Value::BoundMethod(bound) => match &bound.receiver {
+                Value::Instance(instance) => {
+                    write!(f, "<bound method {}.{}>", instance.borrow().class.name, bound.closure.function.name)
+                }
+                _ => write_function(f, &bound.closure.function.name),
+            },
+            // like jlox, a class prints as its name
+            Value::LoxClass(class) => write!(f, "{}", class.name),
+            Value::Class(class) => write!(f, "{}", class.name),
+            Value::LoxInstance { class, .. } => write!(f, "{} instance", class),
+            Value::Instance(instance) => write!(f, "{} instance", instance.borrow().class.name),
+            other => write!(f, "{:?}", other),
+        }
+    }
+}
+
+// the top level script is a function without a name
+fn write_function(f: &mut Formatter<'_>, name: &str) -> std::fmt::Result {
+    if name.is_empty() {
+        return write!(f, "<script>");
+    }
+    return write!(f, "<fn {}>", name);
+}
+
+impl Value {
+    // the name used in error messages
+    pub fn type_name(&self) -> &'static str {
+        return match self {
+            Value::Int(_) => "int",
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Bool(_) => "bool",
+            Value::Nil => "nil",
+            Value::Array(_) => "array",
+            Value::Map(_) => "map",
+            Value::Range(_) => "range",
+            Value::Iterator(_) => "iterator",
+            Value::Native(_) | Value::LoxFunc(..) | Value::LoxBoundMethod { .. } | Value::LoxNative(_) | Value::Function(_) | Value::Closure(_)
+            | Value::NativeFunc(_) | Value::BoundMethod(_) => "function",
+            Value::LoxClass(_) | Value::Class(_) => "class",
+            Value::LoxInstance { .. } | Value::Instance(_) => "instance",
+        };
+    }
+
+    // lox truthiness, only nil and false are false
+    pub fn is_truthy(&self) -> bool {
+        return !matches!(self, Value::Nil | Value::Bool(false));
+    }
+
+    // either kind of number as a float
+    pub fn as_f64(&self) -> Option<f64> {
+        return match self {
+            Value::Int(i) => Some(*i as f64),
+            Value::Number(n) => Some(*n),
+            _ => None,
+        };
+    }
+}
+
+// the right side of `is` in either engine
+pub fn is_not_a_class(value: &Value) -> InterpreterError {
+    return InterpreterError::SimpleError(format!("Right operand of 'is' must be a class, found {}.", value.type_name()));
+}
+
+// the numeric tower shared by both engines:
+// int op int stays an int, anything mixed with a float becomes a float,
+// `/` always gives a float like the book does,
+// and int overflow is a runtime error instead of wrapping silently.
+pub fn arithmetic(opt: expr::BinaryOperatorType, left: Value, right: Value) -> Result<Value, InterpreterError> {
+    match (&left, &right) {
+        (Value::Int(x), Value::Int(y)) => {
+            let result = match opt {
+                expr::BinaryOperatorType::Plus => x.checked_add(*y),
+                expr::BinaryOperatorType::Minus => x.checked_sub(*y),
+                expr::BinaryOperatorType::Star => x.checked_mul(*y),
+                expr::BinaryOperatorType::Slash => {
+                    return Ok(Value::Number(*x as f64 / *y as f64));
+                }
+                _ => {
+                    return Err(InterpreterError::OperatorNotMatch { left: Box::new(left), right: Box::new(right), opt });
+                }
+            };
+            return match result {
+                Some(i) => Ok(Value::Int(i)),
+                None => Err(InterpreterError::SimpleError(format!("Integer overflow: {} {} {}", x, opt, y))),
+            };
+        }
+        (Value::String(x), Value::String(y)) => {
+            return match opt {
+                expr::BinaryOperatorType::Plus => Ok(Value::String(x.to_owned() + y.as_str())),
+                _ => Err(InterpreterError::OperatorNotMatch { left: Box::new(left), right: Box::new(right), opt }),
+            };
+        }
+        // "ab" * 3 and 3 * "ab" both repeat
+        (Value::String(s), count) | (count, Value::String(s))
+        if matches!(opt, expr::BinaryOperatorType::Star) && count.as_f64().is_some() => {
+            return Ok(Value::String(s.repeat(repeat_count(count)?)));
+        }
+        // always a new array, neither side changes
+        (Value::Array(x), Value::Array(y)) => {
+            return match opt {
+                expr::BinaryOperatorType::Plus => {
+                    let mut values = x.borrow().clone();
+                    values.extend(y.borrow().iter().cloned());
+                    Ok(Value::Array(Rc::new(RefCell::new(values))))
+                }
+                _ => Err(InterpreterError::OperatorNotMatch { left: Box::new(left), right: Box::new(right), opt }),
+            };
+        }
+        _ => {}
+    }
+
+    return match (left.as_f64(), right.as_f64()) {
+        (Some(x), Some(y)) => {
+            match opt {
+                expr::BinaryOperatorType::Plus => Ok(Value::Number(x + y)),
+                expr::BinaryOperatorType::Minus => Ok(Value::Number(x - y)),
+                expr::BinaryOperatorType::Star => Ok(Value::Number(x * y)),
+                expr::BinaryOperatorType::Slash => Ok(Value::Number(x / y)),
+                _ => Err(InterpreterError::OperatorNotMatch { left: Box::new(left), right: Box::new(right), opt }),
+            }
+        }
+        _ => Err(InterpreterError::OperatorNotMatch { left: Box::new(left), right: Box::new(right), opt }),
+    };
+}
+
+fn repeat_count(count: &Value) -> Result<usize, InterpreterError> {
+    return match count.as_f64() {
+        Some(n) if n >= 0.0 && n.fract() == 0.0 => Ok(n as usize),
+        _ => Err(InterpreterError::SimpleError(
+            format!("Can only repeat a string a non-negative whole number of times, found {}.", count))),
+    };
+}
+
+// `<`, `<=`, `>` and `>=` only order numbers with numbers and strings with strings
+pub fn check_comparable(opt: expr::BinaryOperatorType, left: &Value, right: &Value) -> Result<(), InterpreterError> {
+    let numbers = left.as_f64().is_some() && right.as_f64().is_some();
+    let strings = matches!((left, right), (Value::String(_), Value::String(_)));
+    if numbers || strings {
+        return Ok(());
+    }
+    return Err(InterpreterError::OperatorNotMatch { left: Box::new(left.clone()), right: Box::new(right.clone()), opt });
+}
+
+// `item in container`, element equality for arrays, key presence for maps,
+// substring search for strings
+pub fn contains(container: &Value, item: &Value) -> Result<bool, InterpreterError> {
+    return match (container, item) {
+        (Value::Array(values), item) => Ok(values.borrow().iter().any(|v| v.eq(item))),
+        (Value::Map(map), key) => map.borrow().contains_key(key),
+        (Value::String(haystack), Value::String(needle)) => Ok(haystack.contains(needle.as_str())),
+        (Value::String(_), other) => Err(InterpreterError::SimpleError(
+            format!("Can only look for a string in a string, found {}.", other.type_name()))),
+        (other, _) => Err(InterpreterError::SimpleError(
+            format!("Right operand of 'in' must be a container, found {}.", other.type_name()))),
+    };
+}
+
+// position of `index` in a sequence of `len` elements, negative indexes count from the end
+fn array_index(index: &Value, len: usize) -> Result<usize, InterpreterError> {
+    return match index {
+        Value::Int(i) if *i >= 0 && (*i as usize) < len => Ok(*i as usize),
+        Value::Int(i) if *i < 0 && i.unsigned_abs() as usize <= len => Ok(len - i.unsigned_abs() as usize),
+        Value::Int(i) => Err(InterpreterError::SimpleError(
+            format!("Index {} out of bounds for length {}.", i, len))),
+        other => Err(InterpreterError::SimpleError(
+            format!("Array index must be an int, found {}.", other.type_name()))),
+    };
+}
+
+// the field an instance subscript names, `obj["name"]` is `obj.name`
+pub fn property_name(index: &Value) -> Result<&str, InterpreterError> {
+    return match index {
+        Value::String(name) => Ok(name.as_str()),
+        other => Err(InterpreterError::SimpleError(
+            format!("Instance keys must be strings, found {}.", other.type_name()))),
+    };
+}
+
+// `object[index]`
+pub fn index_get(object: &Value, index: &Value) -> Result<Value, InterpreterError> {
+    return match object {
+        Value::Array(values) => {
+            let values = values.borrow();
+            let i = array_index(index, values.len())?;
+            Ok(values[i].clone())
+        }
+        // a missing key reads as nil
+        Value::Map(map) => Ok(map.borrow().get(index)?.unwrap_or(Value::Nil)),
+        // by character, not byte
+        Value::String(s) => {
+            let i = array_index(index, s.chars().count())?;
+            Ok(Value::String(s.chars().nth(i).expect("index checked").to_string()))
+        }
+        other => Err(InterpreterError::SimpleError(
+            format!("Can only index arrays, maps and strings, found {}.", other.type_name()))),
+    };
+}
+
+// `object[index] = value`
+pub fn index_set(object: &Value, index: &Value, value: Value) -> Result<(), InterpreterError> {
+    return match object {
+        Value::Array(values) => {
+            let mut values = values.borrow_mut();
+            let i = array_index(index, values.len())?;
+            values[i] = value;
+            Ok(())
+        }
+        Value::Map(map) => map.borrow_mut().insert(index.clone(), value),
+        other => Err(InterpreterError::SimpleError(
+            format!("Can only index arrays and maps, found {}.", other.type_name()))),
+    };
+}
+
+pub fn negate(value: Value) -> Result<Value, InterpreterError> {
+    return match value {
+        Value::Int(i) => {
+            match i.checked_neg() {
+                Some(i) => Ok(Value::Int(i)),
+                None => Err(InterpreterError::SimpleError(format!("Integer overflow: -{}", i))),
+            }
+        }
+        Value::Number(n) => Ok(Value::Number(-n)),
+        other => Err(InterpreterError::TypeNotMatch {
+            expected: "want val::Value::Number".to_string(),
+            found: Box::new(other),
+        }),
+    };
+}
+
 
 #[derive(Debug)]
 pub enum InterpreterError {
     TypeNotMatch {
         expected: String,
-        found: Value,
+        found: Box<Value>,
     },
     OperatorNotMatch {
-        left: Value,
-        right: Value,
+        left: Box<Value>,
+        right: Box<Value>,
         opt: expr::BinaryOperatorType,
     },
     MissVariable {
         name: String
     },
+    // assignment never creates a variable, only `var` does
+    UndeclaredAssign {
+        name: String
+    },
     ExecuteError(Box<InterpreterError>),
     SimpleError(String),
+    // ran past the --timeout deadline, trace lists the innermost call first
+    Timeout {
+        after: Duration,
+        trace: Vec<String>,
+    },
+    // an error raised at a known place in the source
+    Spanned {
+        span: Span,
+        error: Box<InterpreterError>,
+    },
+}
+
+impl InterpreterError {
+    pub fn at(self, span: Span) -> Self {
+        return match self {
+            // keep the innermost location
+            InterpreterError::Spanned { .. } => self,
+            _ => InterpreterError::Spanned { span, error: Box::new(self) },
+        };
+    }
+
+    // however deep in blocks and located errors it is wrapped
+    pub fn is_timeout(&self) -> bool {
+        return match self {
+            InterpreterError::Timeout { .. } => true,
+            InterpreterError::ExecuteError(inner) => inner.is_timeout(),
+            InterpreterError::Spanned { error, .. } => error.is_timeout(),
+            _ => false,
+        };
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        return match self {
+            InterpreterError::Spanned { span, .. } => Some(*span),
+            InterpreterError::ExecuteError(inner) => inner.span(),
+            _ => None,
+        };
+    }
 }
 
 impl Display for InterpreterError {
@@ -167,15 +715,189 @@ impl Display for InterpreterError {
             ),
             InterpreterError::MissVariable { name } => write!(
                 f,
-                "miss param name {}",
+                "Undefined variable '{}'.",
                 name),
-            InterpreterError::ExecuteError(inner) => write!(
+            InterpreterError::UndeclaredAssign { name } => write!(
                 f,
-                "ExecuteError, case {:?}",
-                inner
-            )
+                "Undefined variable '{}', declare it with 'var' before assigning to it.",
+                name),
+            // raised inside a block, the inner error says what went wrong
+            InterpreterError::ExecuteError(inner) => write!(f, "{}", inner),
+            InterpreterError::Timeout { after, trace } => {
+                write!(f, "execution timed out after {}s", after.as_secs_f64())?;
+                for call in trace {
+                    write!(f, "\n    in {}", call)?;
+                }
+                Ok(())
+            }
+            InterpreterError::Spanned { span, error } => write!(
+                f,
+                "{} [line {}]",
+                error, span.line
+            ),
         }
     }
 }
 
-impl Error for InterpreterError {}
\ No newline at end of file
+impl Error for InterpreterError {}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::runtime::Runtime;
+    use crate::runtime::support::{both_engines, text};
+    use crate::types::expr::BinaryOperatorType;
+    use crate::types::output::Output;
+    use crate::types::val::{arithmetic, negate, Value};
+
+    #[test]
+    fn ints_and_floats_mix_as_floats() {
+        assert_eq!(format!("{:?}", arithmetic(BinaryOperatorType::Plus, Value::Int(1), Value::Int(2)).unwrap()), "Int(3)");
+        assert_eq!(format!("{:?}", arithmetic(BinaryOperatorType::Slash, Value::Int(1), Value::Int(2)).unwrap()), "Number(0.5)");
+        assert_eq!(format!("{:?}", arithmetic(BinaryOperatorType::Star, Value::Int(2), Value::Number(0.5)).unwrap()), "Number(1.0)");
+        assert_eq!(format!("{:?}", negate(Value::Int(3)).unwrap()), "Int(-3)");
+        assert!(arithmetic(BinaryOperatorType::Star, Value::Int(i64::MAX), Value::Int(2)).is_err());
+        assert!(negate(Value::Int(i64::MIN)).is_err());
+        assert!(arithmetic(BinaryOperatorType::Minus, Value::String("a".to_string()), Value::Int(1)).is_err());
+    }
+
+    #[test]
+    fn strings_repeat_by_whole_counts() {
+        let ab = Value::String("ab".to_string());
+        let x = Value::String("x".to_string());
+        assert_eq!(arithmetic(BinaryOperatorType::Star, ab.clone(), Value::Int(3)).unwrap().to_string(), "ababab");
+        assert_eq!(arithmetic(BinaryOperatorType::Star, Value::Int(3), x.clone()).unwrap().to_string(), "xxx");
+        assert_eq!(arithmetic(BinaryOperatorType::Star, x.clone(), Value::Number(2.0)).unwrap().to_string(), "xx");
+        assert_eq!(arithmetic(BinaryOperatorType::Star, x.clone(), Value::Int(0)).unwrap().to_string(), "");
+        assert!(arithmetic(BinaryOperatorType::Star, x.clone(), Value::Int(-1)).is_err());
+        assert!(arithmetic(BinaryOperatorType::Star, x.clone(), Value::Number(1.5)).is_err());
+        assert!(arithmetic(BinaryOperatorType::Plus, x, Value::Int(1)).is_err());
+    }
+
+    #[test]
+    fn ints_compare_with_floats() {
+        assert_eq!(Value::Int(3), Value::Number(3.0));
+        assert!(Value::Int(2) < Value::Number(2.5));
+        assert!(Value::Number(3.5) > Value::Int(3));
+        assert_eq!(Value::Int(3).to_string(), "3");
+        assert_eq!(Value::Number(3.0).to_string(), "3.0");
+    }
+
+    fn array(values: Vec<Value>) -> Value {
+        return Value::Array(Rc::new(RefCell::new(values)));
+    }
+
+    #[test]
+    fn arrays_compare_by_elements() {
+        let nested = array(vec![Value::Int(1), array(vec![Value::String("a".to_string())])]);
+        assert_eq!(nested, array(vec![Value::Number(1.0), array(vec![Value::String("a".to_string())])]));
+        assert_ne!(nested, array(vec![Value::Int(1), array(vec![])]));
+        assert!(nested.partial_cmp(&nested).is_none());
+
+        // each array holds itself, comparing them must still end
+        let (a, b) = (array(vec![Value::Int(1)]), array(vec![Value::Int(1)]));
+        for x in [&a, &b] {
+            if let Value::Array(values) = x {
+                values.borrow_mut().push(x.clone());
+            }
+        }
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn rust_values_round_trip() {
+        assert_eq!(f64::try_from(Value::from(1.5)).unwrap(), 1.5);
+        assert_eq!(f64::try_from(Value::from(2i64)).unwrap(), 2.0);
+        assert_eq!(i64::try_from(Value::from(-7i64)).unwrap(), -7);
+        assert!(!bool::try_from(Value::from(false)).unwrap());
+        assert_eq!(String::try_from(Value::from("lox")).unwrap(), "lox");
+        assert_eq!(String::try_from(Value::from("owned".to_string())).unwrap(), "owned");
+
+        assert!(i64::try_from(Value::from(2.0)).is_err());
+        assert!(bool::try_from(Value::Nil).is_err());
+        let err = f64::try_from(Value::from("1")).unwrap_err();
+        assert!(err.to_string().contains("number"), "{}", err);
+        assert!(String::try_from(Value::from(1i64)).is_err());
+    }
+
+    #[test]
+    fn callables_print_by_name() {
+        let source = r#"
+            fun add(a, b) { return a + b; }
+            class Foo { bar() { return this; } }
+            class Sub < Foo {}
+            var f = Foo();
+            var length = len;
+            fun maker() { fun inner() {} return inner; }
+            print add;
+            print len;
+            print length;
+            print f.bar;
+            print Sub().bar;
+            print Foo;
+            print [add, Foo];
+            print maker();
+            var bar = f.bar;
+            print bar() == f;
+        "#;
+        let expected = "<fn add>\n<native fn>\n<native fn>\n<bound method Foo.bar>\n<bound method Sub.bar>\nFoo\n[<fn add>, Foo]\n<fn inner>\ntrue\n";
+        let (out, vm_out) = both_engines(source);
+        assert_eq!(out, expected);
+        assert_eq!(vm_out, expected);
+    }
+
+    #[test]
+    fn instances_print_with_their_class() {
+        let source = r#"
+            class Foo { me() { return this; } }
+            class Sub < Foo { init() { print this; } }
+            var f = Foo();
+            print f;
+            print f.me();
+            print Sub();
+            print [f, clone(f)];
+        "#;
+        let expected = "Foo instance\nFoo instance\nSub instance\nSub instance\n[Foo instance, Foo instance]\n";
+        let (out, vm_out) = both_engines(source);
+        assert_eq!(out, expected);
+        assert_eq!(vm_out, expected);
+    }
+
+    #[test]
+    fn callables_equal_themselves() {
+        let source = r#"
+            fun f() {}
+            fun h() {}
+            var g = f;
+            class Foo { bar() {} }
+            var foo = Foo();
+            var m = foo.bar;
+            fun counter() { var n = 0; fun inc() { n = n + 1; } return inc; }
+            var c = counter();
+            var d = c;
+            print f == f, g == f, f == h;
+            print push == push, push == len;
+            print m == m, c == d, c == counter();
+        "#;
+        let expected = "true true false\ntrue false\ntrue true false\n";
+        let (out, vm_out) = both_engines(source);
+        assert_eq!(out, expected);
+        assert_eq!(vm_out, expected);
+    }
+
+    #[test]
+    fn number_edges_match_across_engines() {
+        let source = include_str!("../../example/ieee.lox");
+        let expected = "false true\nfalse false false false\nfalse false false\n-1 false false\n\
+            -0.0 0 true false true\n-inf\nsame key\ninf -inf true false\ntrue true\n";
+        let (out, vm_out) = both_engines(source);
+        assert_eq!(out, expected);
+        assert_eq!(vm_out, expected);
+        // folding constants first mustn't change any of it
+        let (output, out, _) = Output::capture();
+        Runtime { output, optimize: true, ..Runtime::default() }.run_source(source.to_string()).unwrap();
+        assert_eq!(text(&out), expected);
+    }
+}
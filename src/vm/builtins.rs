@@ -1,26 +1,72 @@
+use std::cell::RefCell;
+use std::rc::Rc;
 use std::thread;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::cast;
-use crate::types::expr::ExpError;
 use crate::types::val::{InterpreterError, Value};
 use crate::vm::vm::VirtualMachine;
 
 pub fn clock(
-    _vm: &mut VirtualMachine,
+    vm: &mut VirtualMachine,
     _args: &[Value],
 ) -> Result<Value, InterpreterError> {
+    match &mut vm.determinism {
+        None => {}
+        Some(determinism) => return Ok(Value::Number(determinism.now().as_millis() as f64)),
+    }
     let start = SystemTime::now();
     let since_the_epoch = start.duration_since(UNIX_EPOCH).unwrap();
     Ok(Value::Number(since_the_epoch.as_millis() as f64))
 }
 
+// sleeps in slices so a --timeout deadline can interrupt it
+const SLEEP_SLICE: Duration = Duration::from_millis(10);
+
 pub fn sleep(
-    _vm: &mut VirtualMachine,
-    _args: &[Value],
+    vm: &mut VirtualMachine,
+    args: &[Value],
 ) -> Result<Value, InterpreterError> {
-    let secs = cast!(_args[0], Value::Number);
+    let secs = match args[0].as_f64() {
+        None => return Err(InterpreterError::TypeNotMatch { expected: "number".to_string(), found: Box::new(args[0].clone()) }),
+        Some(secs) => secs,
+    };
+    // from_secs_f64 panics on these rather than erroring
+    if !secs.is_finite() || secs < 0.0 {
+        return Err(InterpreterError::SimpleError(
+            format!("sleep() expects a non-negative number of seconds, found {}.", args[0])));
+    }
 
-    thread::sleep(Duration::from_secs(secs as u64));
+    let mut remaining = Duration::from_secs_f64(secs);
+    match &mut vm.determinism {
+        None => {}
+        Some(determinism) => {
+            determinism.sleep(remaining);
+            return Ok(Value::Nil);
+        }
+    }
+    while !remaining.is_zero() {
+        vm.check_deadline()?;
+        let slice = remaining.min(SLEEP_SLICE);
+        thread::sleep(slice);
+        remaining -= slice;
+    }
+    vm.check_deadline()?;
     Ok(Value::Nil)
-}
\ No newline at end of file
+}
+
+// shallow copy: a new instance whose fields still point at the same values
+pub fn clone(
+    vm: &mut VirtualMachine,
+    args: &[Value],
+) -> Result<Value, InterpreterError> {
+    return match &args[0] {
+        Value::Instance(instance) => {
+            let mut copy = instance.borrow().clone();
+            copy.id = vm.next_id();
+            Ok(Value::Instance(Rc::new(RefCell::new(copy))))
+        }
+        Value::Array(values) => Ok(Value::Array(Rc::new(RefCell::new(values.borrow().clone())))),
+        Value::Map(map) => Ok(Value::Map(Rc::new(RefCell::new(map.borrow().clone())))),
+        other => Ok(other.clone()),
+    };
+}
@@ -1,26 +1,82 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fmt::{Debug, Formatter};
+use std::rc::Rc;
 
 use crate::types::val::{InterpreterError, Value};
 use crate::vm::vm::VirtualMachine;
 
 #[derive(Clone, Debug)]
 pub struct BoundMethod {
-    pub function: Function,
+    pub closure: Closure,
     pub receiver: Value,
 }
 
 #[derive(Default, Clone, Debug)]
 pub struct Class {
+    // given when the class declaration runs, tells apart the classes one
+    // declaration makes each time it runs
+    pub id: usize,
     pub name: String,
-    pub methods: HashMap<String, Function>,
+    pub methods: HashMap<String, Closure>,
+    pub superclass: Option<Rc<Class>>,
+}
+
+impl Class {
+    pub fn inherits(&self, class: &Class) -> bool {
+        if self.id == class.id {
+            return true;
+        }
+        return match &self.superclass {
+            None => false,
+            Some(superclass) => superclass.inherits(class),
+        };
+    }
 }
 
 #[derive(Default, Clone, Debug)]
 pub struct Instance {
     pub id: usize,
     pub class: Class,
-    pub fields: HashMap<String, Value>,
+    // in the order they were first set, instances of a class mostly agree on it
+    // so a property site can remember the slot
+    pub fields: Vec<(String, Value)>,
+    // set once a field takes the name of a method, until then a cached method
+    // holds without looking through the fields
+    pub shadows_method: bool,
+}
+
+impl Instance {
+    pub fn field_slot(&self, name: &str) -> Option<usize> {
+        return self.fields.iter().position(|(field, _)| field == name);
+    }
+
+    pub fn field(&self, name: &str) -> Option<&Value> {
+        return self.field_slot(name).map(|slot| &self.fields[slot].1);
+    }
+
+    pub fn slot_for(&mut self, name: &str) -> usize {
+        match self.field_slot(name) {
+            None => {}
+            Some(slot) => return slot,
+        }
+        if self.class.methods.contains_key(name) {
+            self.shadows_method = true;
+        }
+        self.fields.push((name.to_string(), Value::Nil));
+        return self.fields.len() - 1;
+    }
+
+    pub fn set_field(&mut self, name: &str, value: Value) {
+        let slot = self.slot_for(name);
+        self.fields[slot].1 = value;
+    }
+
+    // the later fields move down a slot, caches check the name before trusting one
+    pub fn remove_field(&mut self, name: &str) -> Option<Value> {
+        let slot = self.field_slot(name)?;
+        return Some(self.fields.remove(slot).1);
+    }
 }
 
 
@@ -29,6 +85,28 @@ pub struct Function {
     pub arity: usize,
     pub chunk: Chunk,
     pub name: String,
+    pub upvalue_count: usize,
+}
+
+#[derive(Debug)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+#[derive(Clone, Debug)]
+pub struct Closure {
+    pub function: Rc<Function>,
+    pub upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    // what `super` refers to: set on a method when it joins its class, and
+    // passed on to the closures created while that method runs
+    pub superclass: Option<Rc<Class>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct UpvalueLocation {
+    pub is_local: bool,
+    pub index: usize,
 }
 
 #[derive(Clone)]
@@ -58,9 +136,16 @@ pub enum OpCode {
     OpFalse,
     OpNot,
     OpEqual,
+    OpNotEqual,
     OpGreater,
+    OpGreaterEqual,
     OpLess,
+    OpLessEqual,
+    OpIn,
+    OpIs,
     OpPrint,
+    OpPrintN(usize),
+    OpPrintExpr,
     OpPop,
     OpDefineGlobal(usize),
     OpGetGlobal(usize),
@@ -68,29 +153,102 @@ pub enum OpCode {
     OpGetLocal(usize),
     OpSetLocal(usize),
     JumpIfFalse(usize),
+    JumpIfTrue(usize),
+    JumpIfNil(usize),
     Jump(usize),
     Loop(usize),
-    Call(usize),
+    // argument count, and the constant holding the callee's source for errors
+    Call(usize, usize),
     OpClass(Class),
-    OpSetProperty(String),
-    OpGetProperty(String),
+    // the second operand is the site's inline cache, handed out when the script is linked
+    OpSetProperty(String, usize),
+    OpGetProperty(String, usize),
+    // `obj.name(args)` in one step, so a miss can hand the arguments to methodMissing.
+    // argument count, the callee's source constant and the cache, as above
+    OpInvoke(String, usize, usize, usize),
     OpMethod(String),
+    OpInherit,
+    OpGetSuper(String),
+    OpClosure(usize, Vec<UpvalueLocation>),
+    OpGetUpvalue(usize),
+    OpSetUpvalue(usize),
+    OpCloseUpvalue,
+    OpBuildList(usize),
+    OpIter,
+    // pushes the next value of the iterator in the local slot, jumps when there's none left
+    OpIterNext(usize, usize),
+    OpBuildMap(usize),
+    OpIndexGet,
+    OpIndexSet,
+    OpAssert(usize),
+    OpAssertFail(usize),
 }
 
 #[derive(Debug, Clone)]
 pub enum Constant {
+    Int(i64),
     Number(f64),
     Bool(bool),
     String(String),
-    Function(Function),
     Nil,
 }
 
+// what makes two constants the same one, numbers go by their bits so -0.0 and
+// 0.0 stay apart
+#[derive(PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Int(i64),
+    Number(u64),
+    Bool(bool),
+    String(String),
+    Nil,
+}
+
+impl From<&Constant> for ConstantKey {
+    fn from(c: &Constant) -> Self {
+        return match c {
+            Constant::Int(i) => ConstantKey::Int(*i),
+            Constant::Number(f) => ConstantKey::Number(f.to_bits()),
+            Constant::Bool(b) => ConstantKey::Bool(*b),
+            Constant::String(s) => ConstantKey::String(s.clone()),
+            Constant::Nil => ConstantKey::Nil,
+        };
+    }
+}
+
+#[derive(Default)]
+pub struct ConstantPool {
+    constants: Vec<Constant>,
+    indexes: HashMap<ConstantKey, usize>,
+}
+
+impl ConstantPool {
+    pub fn add(&mut self, val: Constant) -> usize {
+        let key = ConstantKey::from(&val);
+        match self.indexes.get(&key) {
+            None => {}
+            Some(index) => return *index,
+        }
+        let index = self.constants.len();
+        self.constants.push(val);
+        self.indexes.insert(key, index);
+        return index;
+    }
+
+    pub fn into_shared(self) -> Rc<Vec<Constant>> {
+        return Rc::new(self.constants);
+    }
+}
+
 
 #[derive(Clone, Default, Debug)]
 pub struct Chunk {
-    pub code: Vec<(OpCode, usize)>,
-    pub constants: Vec<Constant>,
+    pub code: Vec<OpCode>,
+    // source lines run length encoded as (line, count), most instructions share
+    // the line of the one before them
+    lines: Vec<(usize, usize)>,
+    pub constants: Rc<Vec<Constant>>,
+    pub functions: Vec<Rc<Function>>,
 }
 
 impl Chunk {
@@ -99,39 +257,112 @@ impl Chunk {
         return constant;
     }
 
+    pub fn write(&mut self, op: OpCode, line: usize) {
+        self.code.push(op);
+        match self.lines.last_mut() {
+            Some((last, count)) if *last == line => *count += 1,
+            _ => self.lines.push((line, 1)),
+        }
+    }
+
+    pub fn line_for(&self, offset: usize) -> Option<usize> {
+        let mut start = 0;
+        for (line, count) in &self.lines {
+            start += count;
+            if offset < start {
+                return Some(*line);
+            }
+        }
+        None
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        self.code.truncate(len);
+        let mut kept = 0;
+        let mut runs = 0;
+        for (_, count) in self.lines.iter_mut() {
+            if kept >= len {
+                break;
+            }
+            *count = (*count).min(len - kept);
+            kept += *count;
+            runs += 1;
+        }
+        self.lines.truncate(runs);
+    }
+
     pub fn add_constant(&mut self, val: Constant) -> usize {
         let constants_index = self.constants.len();
-        self.constants.push(val);
+        Rc::make_mut(&mut self.constants).push(val);
         return constants_index;
     }
 
+    pub fn add_function(&mut self, function: Rc<Function>) -> usize {
+        self.functions.push(function);
+        return self.functions.len() - 1;
+    }
+
+    pub fn share_constants(&mut self, constants: &Rc<Vec<Constant>>) {
+        self.constants = constants.clone();
+        for function in self.functions.iter_mut() {
+            Rc::make_mut(function).chunk.share_constants(constants);
+        }
+    }
+
+    pub fn check_jumps(&self) -> Result<(), String> {
+        for (at, op) in self.code.iter().enumerate() {
+            // the vm has already stepped past the jump when it applies the offset
+            let target = match op {
+                OpCode::Jump(offset) | OpCode::JumpIfFalse(offset) | OpCode::JumpIfTrue(offset)
+                | OpCode::JumpIfNil(offset) | OpCode::OpIterNext(_, offset) | OpCode::OpAssert(offset) => {
+                    Some(at + 1 + offset)
+                }
+                OpCode::Loop(offset) => (at + 1).checked_sub(*offset),
+                _ => continue,
+            };
+            match target {
+                Some(target) if target < self.code.len() => {}
+                _ => return Err(format!("{:?} at {} jumps outside the {} instructions of the chunk", op, at, self.code.len())),
+            }
+        }
+        Ok(())
+    }
+
     pub fn disassemble(&self, name: &str) {
-        println!("== {} ==", name);
+        print!("{}", self.disassembly(name));
+    }
+
+    pub fn disassembly(&self, name: &str) -> String {
+        let mut out = format!("== {} ==\n", name);
         for i in 0..self.code.len() {
-            self.disassemble_instruction(i)
+            self.disassemble_instruction(i, &mut out)
         }
+        out.push_str(&format!("-- constants of {} --\n", name));
+        for index in self.used_constants() {
+            out.push_str(&format!("[{:04}] {:?}\n", index, self.constants[index]));
+        }
+        return out;
+    }
+
+    // the pool is shared by the whole compilation, only list what this chunk reads
+    fn used_constants(&self) -> Vec<usize> {
+        let mut used: Vec<usize> = self.code.iter().filter_map(|op| match op {
+            OpCode::OpConstant(index) | OpCode::OpDefineGlobal(index) | OpCode::OpGetGlobal(index)
+            | OpCode::OpSetGlobal(index) | OpCode::Call(_, index) | OpCode::OpInvoke(_, _, index, _) | OpCode::OpAssertFail(index) => Some(*index),
+            _ => None,
+        }).collect();
+        used.sort();
+        used.dedup();
+        return used;
     }
 
-    pub fn disassemble_instruction(&self, index: usize) {
-        let (opt, lineno) = self.code.get(index).expect("want instruction");
+    fn disassemble_instruction(&self, index: usize, out: &mut String) {
+        let opt = self.code.get(index).expect("want instruction");
+        let lineno = self.line_for(index).expect("every instruction has a line");
         let formatted_op = match opt {
             OpCode::OpReturn => "OP_RETURN".to_string(),
             OpCode::OpConstant(const_idx) => {
-                let constant = self.constants[*const_idx].clone();
-
-                return match constant {
-                    Constant::Function(func) => {
-                        func.chunk.disassemble(func.name.as_str());
-                        println!("== {} ==", func.name.as_str());
-                        "".to_string();
-                    }
-                    _ => {
-                        format!(
-                            "OP_CONSTANT {:?} (idx={})",
-                            constant.clone(), *const_idx
-                        );
-                    }
-                };
+                format!("OP_CONSTANT {:?} (idx={})", self.constants[*const_idx], *const_idx)
             }
             OpCode::OpNil => "OP_NIL".to_string(),
             OpCode::OpTrue => "OP_TRUE".to_string(),
@@ -143,9 +374,24 @@ impl Chunk {
             OpCode::OpMultiply => "OP_MUL".to_string(),
             OpCode::OpDivide => "OP_DIV".to_string(),
             OpCode::OpEqual => "OP_EQUAL".to_string(),
+            OpCode::OpNotEqual => "OP_NOT_EQUAL".to_string(),
             OpCode::OpGreater => "OP_GREATER".to_string(),
+            OpCode::OpGreaterEqual => "OP_GREATER_EQUAL".to_string(),
             OpCode::OpLess => "OP_LESS".to_string(),
+            OpCode::OpLessEqual => "OP_LESS_EQUAL".to_string(),
+            OpCode::OpIn => "OP_IN".to_string(),
+            OpCode::OpIs => "OP_IS".to_string(),
+            OpCode::OpBuildList(count) => format!("OP_BUILD_LIST: {}", count),
+            OpCode::OpIter => "OP_ITER".to_string(),
+            OpCode::OpIterNext(slot, offset) => format!("OP_ITER_NEXT: {} {}", slot, offset),
+            OpCode::OpBuildMap(count) => format!("OP_BUILD_MAP: {}", count),
+            OpCode::OpIndexGet => "OP_INDEX_GET".to_string(),
+            OpCode::OpIndexSet => "OP_INDEX_SET".to_string(),
+            OpCode::OpAssert(offset) => format!("OP_ASSERT: {}", offset),
+            OpCode::OpAssertFail(index) => format!("OP_ASSERT_FAIL: {:?}", self.constants[*index]),
             OpCode::OpPrint => "OP_PRINT".to_string(),
+            OpCode::OpPrintN(count) => format!("OP_PRINT_N: {}", count),
+            OpCode::OpPrintExpr => "OP_PRINT_EXPR".to_string(),
             OpCode::OpPop => "OP_POP".to_string(),
             OpCode::OpDefineGlobal(index) => format!("OP_DEF_GLOBAL: {}", index),
             OpCode::OpGetGlobal(index) => format!("OP_GET_GLOBAL: {:?}", self.constants[*index]),
@@ -153,14 +399,27 @@ impl Chunk {
             OpCode::OpGetLocal(index) => format!("OP_GET_LOCAL: {}", index),
             OpCode::OpSetLocal(index) => format!("OP_SET_LOCAL: {}", index),
             OpCode::JumpIfFalse(offset) => format!("JUMP_IF_FALSE: {}", offset),
+            OpCode::JumpIfTrue(offset) => format!("JUMP_IF_TRUE: {}", offset),
+            OpCode::JumpIfNil(offset) => format!("JUMP_IF_NIL: {}", offset),
             OpCode::Jump(offset) => format!("JUMP: {}", offset),
             OpCode::Loop(offset) => format!("LOOP: {}", offset),
-            OpCode::Call(count) => format!("CALL: ARGS_SIZE {}", count),
+            OpCode::Call(count, index) => format!("CALL: ARGS_SIZE {} {:?}", count, self.constants[*index]),
             OpCode::OpClass(name) => format!("CLASS: {:?}", name),
-            OpCode::OpSetProperty(name) => format!("OP_GET_PROPERTY: {:?}", name),
-            OpCode::OpGetProperty(name) => format!("OP_SET_PROPERTY: {:?}", name),
+            OpCode::OpSetProperty(name, _) => format!("OP_SET_PROPERTY: {:?}", name),
+            OpCode::OpGetProperty(name, _) => format!("OP_GET_PROPERTY: {:?}", name),
+            OpCode::OpInvoke(name, count, _, _) => format!("OP_INVOKE: {:?} ARGS_SIZE {}", name, count),
             OpCode::OpMethod(name) => format!("OP_METHOD: {:?}", name),
+            OpCode::OpInherit => "OP_INHERIT".to_string(),
+            OpCode::OpGetSuper(name) => format!("OP_GET_SUPER: {:?}", name),
+            OpCode::OpClosure(index, upvalues) => {
+                let func = &self.functions[*index];
+                out.push_str(&func.chunk.disassembly(func.name.as_str()));
+                format!("OP_CLOSURE: {} upvalues {:?}", func.name, upvalues)
+            }
+            OpCode::OpGetUpvalue(index) => format!("OP_GET_UPVALUE: {}", index),
+            OpCode::OpSetUpvalue(index) => format!("OP_SET_UPVALUE: {}", index),
+            OpCode::OpCloseUpvalue => "OP_CLOSE_UPVALUE".to_string(),
         };
-        println!("{0: <04}   {1: <50} line {2: <50}", index, formatted_op, lineno)
+        out.push_str(&format!("{0: <04}   {1: <50} line {2: <50}\n", index, formatted_op, lineno));
     }
 }
\ No newline at end of file
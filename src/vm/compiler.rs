@@ -1,14 +1,11 @@
-use std::any::Any;
+use std::rc::Rc;
 
-use crate::cast;
-use crate::process::parser::Parser;
-use crate::types::{expr, token, val};
-use crate::types::expr::{ExpError, Literal};
+use crate::types::span::Span;
+use crate::types::token;
+use crate::types::expr::{ExpError, MAX_ARGS};
 use crate::types::token::{Token, TokenType};
-use crate::types::val::Value;
 use crate::vm::chunk;
-use crate::vm::chunk::{Chunk, Class, Constant, Function, OpCode};
-use crate::vm::chunk::OpCode::OpPop;
+use crate::vm::chunk::{Chunk, Class, Constant, ConstantPool, Function, OpCode, UpvalueLocation};
 use crate::vm::vm::FunctionType;
 
 type ConstantIndex = usize;
@@ -27,9 +24,11 @@ enum ParseFn {
     Or,
     Call,
     Dot,
+    OptionalDot,
     This,
     Super,
     List,
+    Map,
     Subscript,
 }
 
@@ -76,41 +75,106 @@ struct ParseRule {
 #[derive(Clone)]
 pub struct Local {
     name: String,
+    span: Span,
     depth: i32,
+    is_captured: bool,
 }
 
-pub struct Compiler {
-    tokens: Vec<Token>,
-    current: usize,
+struct LoopState {
+    label: Option<String>,
     scope_depth: usize,
-    locals: Vec<Local>,
+    start: usize,
+    breaks: Vec<usize>,
+}
+
+struct ClassState {
+    has_superclass: bool,
+}
+
+struct FunctionState {
     function: Function,
     function_type: FunctionType,
+    locals: Vec<Local>,
+    upvalues: Vec<UpvalueLocation>,
+    scope_depth: usize,
+    chain_jumps: Vec<usize>,
+    loops: Vec<LoopState>,
+}
+
+impl FunctionState {
+    fn new(function_type: FunctionType, name: String) -> Self {
+        // slot 0 holds the callee, methods see it as `this`
+        let slot_zero = match function_type {
+            FunctionType::Method | FunctionType::Initializer => "this",
+            _ => "",
+        };
+        return Self {
+            function: Function {
+                name,
+                ..Default::default()
+            },
+            function_type,
+            locals: vec![Local {
+                name: slot_zero.to_string(),
+                span: Span::default(),
+                depth: 0,
+                is_captured: false,
+            }],
+            upvalues: vec![],
+            scope_depth: 0,
+            chain_jumps: vec![],
+            loops: vec![],
+        };
+    }
+}
+
+pub struct Compiler {
+    tokens: Vec<Token>,
+    current: usize,
+    states: Vec<FunctionState>,
+    classes: Vec<ClassState>,
+    constants: ConstantPool,
+    pub repl: bool,
+    pub strip_asserts: bool,
+    operand_start: usize,
 }
 
 impl Compiler {
     pub fn new(tokens: Vec<Token>, function_type: FunctionType) -> Self {
-        let mut compiler = Self {
+        // comments only matter to tools working on the tokens themselves, newlines to the interpreter's parser
+        let tokens = tokens.into_iter()
+            .filter(|t| t.token_type != TokenType::Comment && t.token_type != TokenType::Newline)
+            .collect();
+        return Self {
             tokens,
             current: 0,
-            scope_depth: 0,
-            locals: vec![],
-            function: Default::default(),
-            function_type,
+            states: vec![FunctionState::new(function_type, "".to_string())],
+            classes: vec![],
+            constants: ConstantPool::default(),
+            repl: false,
+            strip_asserts: false,
+            operand_start: 0,
         };
-        return compiler;
+    }
+
+    fn state(&self) -> &FunctionState {
+        return self.states.last().expect("should exist");
+    }
+
+    fn state_mut(&mut self) -> &mut FunctionState {
+        return self.states.last_mut().expect("should exist");
     }
 
     pub fn current_chunk(&mut self) -> &mut Chunk {
-        return &mut self.function.chunk;
+        return &mut self.state_mut().function.chunk;
     }
 
     pub fn current_line(&self) -> usize {
-        return self.current;
+        return self.tokens[self.current.saturating_sub(1)].span.line;
     }
 
     pub fn current_function_mut(&mut self) -> &mut Function {
-        return &mut self.function;
+        return &mut self.state_mut().function;
     }
 
     pub fn compile(&mut self) -> Result<Function, ExpError> {
@@ -118,7 +182,10 @@ impl Compiler {
             self.declaration()?;
         }
         self.end();
-        Ok(self.function.clone())
+        let mut function = self.state().function.clone();
+        let constants = std::mem::take(&mut self.constants).into_shared();
+        function.chunk.share_constants(&constants);
+        Ok(function)
     }
 
     fn declaration(&mut self) -> Result<(), ExpError> {
@@ -128,6 +195,8 @@ impl Compiler {
             self.fun_declaration()?;
         } else if self._match(TokenType::Var) {
             self.var_declaration()?;
+        } else if self._match(TokenType::Enum) {
+            return Err(ExpError::Common("Enums are only supported by the interpreter.".to_string()));
         } else {
             self.statement()?;
         }
@@ -143,32 +212,56 @@ impl Compiler {
         self.declare_variable()?;
 
         self.emit_opt(OpCode::OpClass(Class {
+            id: 0,
             name: class_name.clone(),
             methods: Default::default(),
+            superclass: None,
         }));
-        self.define_variable(constant_index)?;
-        self.named_variable(class_name, false)?;
+        self.mark_initialized()?;
+
+        let mut class_state = ClassState { has_superclass: false };
+        if self._match(TokenType::Less) {
+            self.consume(TokenType::Identifier, "Expect superclass name.")?;
+            let super_name = self.previous().lexeme.clone();
+            if super_name == class_name {
+                return Err(ExpError::Common("A class can't inherit from itself.".to_string()));
+            }
+            self.named_variable(super_name, false)?;
+            // inherited methods go in first so the class's own override them
+            self.emit_opt(OpCode::OpInherit);
+            class_state.has_superclass = true;
+        }
+        self.classes.push(class_state);
 
+        // methods are attached while the class is still on top of the stack,
+        // the variable is only defined once the class is complete
         self.consume(TokenType::LeftBrace, "Expect '{' before class body.")?;
         while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            if self.check(TokenType::Class) {
+                return Err(ExpError::Common("Static fields are only supported by the interpreter.".to_string()));
+            }
             self.method()?;
         }
 
         self.consume(TokenType::RightBrace, "Expect '}' after class body.")?;
-        self.emit_opt(OpCode::OpPop);
-        Ok(())
+        self.classes.pop();
+        self.define_variable(constant_index)
     }
 
     fn method(&mut self) -> Result<(), ExpError> {
         self.consume(TokenType::Identifier, "Expect method name.")?;
-        self.function(FunctionType::Function)?;
         let method_name = self.previous().lexeme.clone();
+        let function_type = match method_name.as_str() {
+            "init" => FunctionType::Initializer,
+            _ => FunctionType::Method,
+        };
+        self.function(function_type)?;
         self.emit_opt(OpCode::OpMethod(method_name));
         Ok(())
     }
 
     fn identifier_constant(&mut self, name: String) -> usize {
-        self.current_chunk().add_constant(Constant::String(name))
+        self.constants.add(Constant::String(name))
     }
 
     fn fun_declaration(&mut self) -> Result<(), ExpError> {
@@ -179,43 +272,53 @@ impl Compiler {
     }
 
     fn function(&mut self, fun_type: FunctionType) -> Result<(), ExpError> {
-        let mut compiler = Self {
-            tokens: self.tokens.clone(),
-            current: self.current,
-            scope_depth: 0,
-            locals: vec![],
-            function: Default::default(),
-            function_type: fun_type,
-        };
-        compiler.function.name = self.previous().lexeme.clone();
-        compiler.begin_scope()?;
+        let name = self.previous().lexeme.clone();
+        self.states.push(FunctionState::new(fun_type, name));
+        self.begin_scope()?;
 
-        compiler.consume(TokenType::LeftParen, "Expect '(' after function name.")?;
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.")?;
 
-        if !compiler.check(TokenType::RightParen) {
+        if !self.check(TokenType::RightParen) {
             loop {
-                let func = compiler.current_function_mut();
+                if self.current_function_mut().arity >= MAX_ARGS {
+                    return Err(ExpError::TooManyArgs("parameters", self.peek().span));
+                }
+                let func = self.current_function_mut();
                 func.arity += 1;
-                let parameter_name = compiler.parse_variable("Expected parameter name")?;
-                compiler.define_variable(parameter_name)?;
-                if !compiler._match(TokenType::Comma) {
+                self.check_parameter()?;
+                let parameter_name = self.parse_variable("Expected parameter name")?;
+                self.define_variable(parameter_name)?;
+                if !self._match(TokenType::Comma) || self.check(TokenType::RightParen) {
                     break;
                 }
             }
         }
 
-        compiler.consume(TokenType::RightParen, "Expect ')' after parameters.")?;
-        compiler.consume(TokenType::LeftBrace, "Expect '{' before function body.")?;
-        compiler.block()?;
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.")?;
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.")?;
+        self.block()?;
 
-        compiler.emit_return();
-        let func = compiler.function;
-        self.emit_constant(Constant::Function(func));
-        self.current = compiler.current;
+        self.emit_return();
+        let state = self.states.pop().expect("should exist");
+        let mut func = state.function;
+        func.upvalue_count = state.upvalues.len();
+        let index = self.current_chunk().add_function(Rc::new(func));
+        self.emit_opt(OpCode::OpClosure(index, state.upvalues));
 
         Ok(())
     }
 
+    fn check_parameter(&mut self) -> Result<(), ExpError> {
+        let token = self.peek().clone();
+        if token::parse_keyword(&token.lexeme).is_some() {
+            return Err(ExpError::ReservedParameter(token.lexeme, token.span));
+        }
+        if self.state().locals.iter().any(|local| local.name == token.lexeme) {
+            return Err(ExpError::DuplicateParameter(token.lexeme, token.span));
+        }
+        Ok(())
+    }
+
     fn var_declaration(&mut self) -> Result<(), ExpError> {
         let global = self.parse_variable("Expect variable name.")?;
         if self._match(TokenType::Equal) {
@@ -231,19 +334,18 @@ impl Compiler {
     }
 
     fn mark_initialized(&mut self) -> Result<(), ExpError> {
-        if self.scope_depth == 0 {
+        let state = self.state_mut();
+        if state.scope_depth == 0 {
             return Ok(());
         }
 
-        let mut last = (*self.locals.last().expect("should exist")).clone();
-        last.depth = self.scope_depth as i32;
-        let last_index = self.locals.len() - 1;
-        self.locals[last_index] = last;
+        let last = state.locals.last_mut().expect("should exist");
+        last.depth = state.scope_depth as i32;
         Ok(())
     }
 
     fn define_variable(&mut self, val: ConstantIndex) -> Result<(), ExpError> {
-        if self.scope_depth > 0 {
+        if self.state().scope_depth > 0 {
             self.mark_initialized()?;
             return Ok(());
         }
@@ -253,18 +355,27 @@ impl Compiler {
     }
 
     fn call(&mut self, _: bool) -> Result<(), ExpError> {
+        let callee = token::source_text(&self.tokens[self.operand_start..self.current - 1]);
+        let index = self.constants.add(Constant::String(callee));
         let args = self.argument_list()?;
-        self.emit_opt(OpCode::Call(args));
+        self.emit_opt(OpCode::Call(args, index));
         Ok(())
     }
 
     fn argument_list(&mut self) -> Result<usize, ExpError> {
-        let mut count = 0 as usize;
+        let mut count: usize = 0;
         if !self.check(TokenType::RightParen) {
             loop {
+                if count >= MAX_ARGS {
+                    return Err(ExpError::TooManyArgs("arguments", self.peek().span));
+                }
+                if self.check(TokenType::Ellipsis) {
+                    return Err(ExpError::Common("Spread arguments are only supported by the interpreter.".to_string()));
+                }
                 self.expression()?;
                 count += 1;
-                if !self._match(TokenType::Comma) {
+                // a trailing comma is fine, a missing argument after it isn't
+                if !self._match(TokenType::Comma) || self.check(TokenType::RightParen) {
                     break;
                 }
             }
@@ -282,10 +393,7 @@ impl Compiler {
     }
 
     fn or(&mut self, _: bool) -> Result<(), ExpError> {
-        let else_jump = self.emit_jump(OpCode::JumpIfFalse(0));
-        let end_jump = self.emit_jump(OpCode::Jump(0));
-
-        self.patch_jump(else_jump);
+        let end_jump = self.emit_jump(OpCode::JumpIfTrue(0));
         self.emit_opt(OpCode::OpPop);
         self.parse_precedence(Precedence::Or)?;
         self.patch_jump(end_jump);
@@ -296,28 +404,30 @@ impl Compiler {
         self.consume(TokenType::Identifier, err_msg)?;
 
         self.declare_variable()?;
-        if self.scope_depth > 0 {
+        if self.state().scope_depth > 0 {
             return Ok(0);
         }
 
 
         let previous = self.previous().clone();
-        let i = self.current_chunk().add_constant(Constant::String(previous.lexeme));
+        let i = self.constants.add(Constant::String(previous.lexeme));
         return Ok(i);
     }
 
     fn declare_variable(&mut self) -> Result<(), ExpError> {
-        if self.scope_depth == 0 {
+        let scope_depth = self.state().scope_depth;
+        if scope_depth == 0 {
             return Ok(());
         }
 
         let name = self.previous().lexeme.clone();
-        for l in &self.locals {
-            if l.depth != -1 && l.depth < self.scope_depth as i32 {
+        let span = self.previous().span;
+        for l in self.state().locals.iter().rev() {
+            if l.depth != -1 && l.depth < scope_depth as i32 {
                 break;
             }
             if l.name.eq(name.as_str()) {
-                return Err(ExpError::VariableRepeatDef(name.clone()));
+                return Err(ExpError::VariableRepeatDef { name: name.clone(), span, first: l.span });
             }
         }
 
@@ -327,9 +437,12 @@ impl Compiler {
     }
 
     fn add_local(&mut self, name: String) -> Result<(), ExpError> {
-        self.locals.push(Local {
+        let span = self.previous().span;
+        self.state_mut().locals.push(Local {
             name,
+            span,
             depth: -1,
+            is_captured: false,
         });
         Ok(())
     }
@@ -337,16 +450,32 @@ impl Compiler {
     fn statement(&mut self) -> Result<(), ExpError> {
         if self._match(TokenType::Print) {
             self.expression()?;
+            let mut count = 1;
+            while self._match(TokenType::Comma) {
+                self.expression()?;
+                count += 1;
+            }
             self.consume(TokenType::Semicolon, "Expect ';' after value.")?;
-            self.emit_opt(OpCode::OpPrint)
+            match count {
+                1 => self.emit_opt(OpCode::OpPrint),
+                _ => self.emit_opt(OpCode::OpPrintN(count)),
+            }
         } else if self._match(TokenType::For) {
-            self.for_statement()?;
+            self.for_statement(None)?;
         } else if self._match(TokenType::If) {
             self.if_statement()?;
         } else if self._match(TokenType::Return) {
             self.return_statement()?;
+        } else if self._match(TokenType::Assert) {
+            self.assert_statement()?;
         } else if self._match(TokenType::While) {
-            self.while_statement()?;
+            self.while_statement(None)?;
+        } else if self._match(TokenType::Loop) {
+            self.loop_statement(None)?;
+        } else if self._match(TokenType::Break) || self._match(TokenType::Continue) {
+            self.jump_statement()?;
+        } else if self.check(TokenType::Identifier) && self.check_ahead(1, TokenType::Colon) {
+            self.labeled_statement()?;
         } else if self._match(TokenType::LeftBrace) {
             self.begin_scope()?;
             self.block()?;
@@ -357,10 +486,38 @@ impl Compiler {
         Ok(())
     }
 
+    fn assert_statement(&mut self) -> Result<(), ExpError> {
+        let code_start = self.current_chunk().code.len();
+        let first = self.current;
+        let line = self.peek().span.line;
+        self.expression()?;
+        let text = token::source_text(&self.tokens[first..self.current]);
+        let pass_jump = self.emit_jump(OpCode::OpAssert(0));
+        if self._match(TokenType::Comma) {
+            self.expression()?;
+        } else {
+            self.emit_opt(OpCode::OpNil);
+        }
+        self.consume(TokenType::Semicolon, "Expect ';' after assertion.")?;
+        let index = self.constants.add(Constant::String(text));
+        // the failure points at the condition, not the message
+        self.current_chunk().write(OpCode::OpAssertFail(index), line);
+        self.patch_jump(pass_jump);
+
+        if self.strip_asserts {
+            // still compiled above so a broken assert is an error either way
+            self.current_chunk().truncate(code_start);
+        }
+        Ok(())
+    }
+
     fn return_statement(&mut self) -> Result<(), ExpError> {
         if self._match(TokenType::Semicolon) {
             self.emit_return();
         } else {
+            if self.state().function_type == FunctionType::Initializer {
+                return Err(ExpError::Common("Can't return a value from an initializer.".to_string()));
+            }
             self.expression()?;
             self.consume(TokenType::Semicolon, "Expect ';' after return value.")?;
             self.emit_opt(OpCode::OpReturn)
@@ -368,9 +525,13 @@ impl Compiler {
         Ok(())
     }
 
-    fn for_statement(&mut self) -> Result<(), ExpError> {
+    fn for_statement(&mut self, label: Option<String>) -> Result<(), ExpError> {
         self.begin_scope()?;
         self.consume(TokenType::LeftParen, "Expect '(' after 'for'.")?;
+        if self.check(TokenType::Var) && self.check_ahead(1, TokenType::Identifier) && self.check_ahead(2, TokenType::In) {
+            self.for_in_statement(label)?;
+            return self.end_scope();
+        }
         if self._match(TokenType::Semicolon) {} else if self._match(TokenType::Var) {
             self.var_declaration()?;
         } else {
@@ -398,9 +559,11 @@ impl Compiler {
         }
 
 
+        self.begin_loop(label, loop_start);
         self.statement()?;
         self.emit_loop(loop_start);
 
+        // `for (;;)` pushed no condition, breaks and returns leave nothing to pop
         match exit_jump {
             None => {}
             Some(index) => {
@@ -408,11 +571,132 @@ impl Compiler {
                 self.emit_opt(OpCode::OpPop);
             }
         }
+        self.end_loop();
+        self.end_scope()?;
+        Ok(())
+    }
+
+    // the iterator sits in a hidden local of the for scope, the loop variable
+    // lives in a scope of its own so every pass gets a fresh one
+    fn for_in_statement(&mut self, label: Option<String>) -> Result<(), ExpError> {
+        self.consume(TokenType::Var, "Expect 'var' in for-in loop.")?;
+        self.consume(TokenType::Identifier, "Expect variable name.")?;
+        let name = self.previous().lexeme.clone();
+        self.consume(TokenType::In, "Expect 'in' after loop variable.")?;
+        self.expression()?;
+        self.consume(TokenType::RightParen, "Expect ')' after for-in expression.")?;
+
+        self.emit_opt(OpCode::OpIter);
+        // the space keeps it out of reach of user code
+        self.add_local(" iterator".to_string())?;
+        self.mark_initialized()?;
+        let slot = self.state().locals.len() - 1;
+
+        let loop_start = self.current_chunk().code.len();
+        let exit_jump = self.emit_jump(OpCode::OpIterNext(slot, 0));
+        self.begin_loop(label, loop_start);
+        self.begin_scope()?;
+        self.add_local(name)?;
+        self.mark_initialized()?;
+        self.statement()?;
+        self.end_scope()?;
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.end_loop();
+        Ok(())
+    }
+
+    fn jump_statement(&mut self) -> Result<(), ExpError> {
+        let keyword = self.previous().clone();
+        if self.state().loops.is_empty() {
+            return Err(ExpError::Common(format!("Can't use '{}' outside of a loop.", keyword.lexeme)));
+        }
+        let target = if self._match(TokenType::Identifier) {
+            let label = self.previous().lexeme.clone();
+            match self.state().loops.iter().rposition(|l| l.label.as_ref() == Some(&label)) {
+                None => {
+                    return Err(ExpError::Common(format!("No enclosing loop labeled '{}'.", label)));
+                }
+                Some(i) => i,
+            }
+        } else {
+            self.state().loops.len() - 1
+        };
+        self.consume(TokenType::Semicolon, format!("Expect ';' after '{}'.", keyword.lexeme).as_str())?;
+
+        let (scope_depth, start) = {
+            let state = &self.state().loops[target];
+            (state.scope_depth, state.start)
+        };
+        // every scope between here and the target loop is left behind
+        self.discard_locals(scope_depth);
+        if keyword.token_type == TokenType::Break {
+            let jump = self.emit_jump(OpCode::Jump(0));
+            self.state_mut().loops[target].breaks.push(jump);
+        } else {
+            self.emit_loop(start);
+        }
+        Ok(())
+    }
+
+    fn labeled_statement(&mut self) -> Result<(), ExpError> {
+        let label = self.advance().lexeme.clone();
+        self.advance();
+        if self.state().loops.iter().any(|l| l.label.as_ref() == Some(&label)) {
+            return Err(ExpError::Common(format!("Label '{}' is already used by an enclosing loop.", label)));
+        }
+        if self._match(TokenType::While) {
+            return self.while_statement(Some(label));
+        }
+        if self._match(TokenType::For) {
+            return self.for_statement(Some(label));
+        }
+        if self._match(TokenType::Loop) {
+            return self.loop_statement(Some(label));
+        }
+        return Err(ExpError::Common(format!("Expect a loop after label '{}'.", label)));
+    }
+
+    fn begin_loop(&mut self, label: Option<String>, start: usize) {
+        let scope_depth = self.state().scope_depth;
+        self.state_mut().loops.push(LoopState { label, scope_depth, start, breaks: vec![] });
+    }
+
+    fn end_loop(&mut self) {
+        let state = self.state_mut().loops.pop().expect("should exist");
+        for jump in state.breaks {
+            self.patch_jump(jump);
+        }
+    }
+
+    fn discard_locals(&mut self, scope_depth: usize) {
+        let captured: Vec<bool> = self.state().locals.iter().rev()
+            .take_while(|local| local.depth > scope_depth as i32)
+            .map(|local| local.is_captured)
+            .collect();
+        for is_captured in captured {
+            if is_captured {
+                self.emit_opt(OpCode::OpCloseUpvalue);
+            } else {
+                self.emit_opt(OpCode::OpPop);
+            }
+        }
+    }
+
+    fn loop_statement(&mut self, label: Option<String>) -> Result<(), ExpError> {
+        self.consume(TokenType::LeftBrace, "Expect '{' after 'loop'.")?;
+        let loop_start = self.current_chunk().code.len();
+        self.begin_loop(label, loop_start);
+        self.begin_scope()?;
+        self.block()?;
         self.end_scope()?;
+        self.emit_loop(loop_start);
+        self.end_loop();
         Ok(())
     }
 
-    fn while_statement(&mut self) -> Result<(), ExpError> {
+    fn while_statement(&mut self, label: Option<String>) -> Result<(), ExpError> {
         let loop_start = self.current_chunk().code.len();
         self.consume(TokenType::LeftParen, "Expect '(' after 'while'.")?;
         self.expression()?;
@@ -420,15 +704,18 @@ impl Compiler {
 
         let exit_jump = self.emit_jump(OpCode::JumpIfFalse(0));
         self.emit_opt(OpCode::OpPop);
+        self.begin_loop(label, loop_start);
         self.statement()?;
 
         self.emit_loop(loop_start);
         self.patch_jump(exit_jump);
         self.emit_opt(OpCode::OpPop);
+        self.end_loop();
         Ok(())
     }
 
     fn emit_loop(&mut self, loop_start: usize) {
+        debug_assert!(loop_start <= self.current_chunk().code.len(), "loop start {} is past the end", loop_start);
         let i = self.current_chunk().code.len() - loop_start + 1;
         self.emit_opt(OpCode::Loop(i))
     }
@@ -459,13 +746,25 @@ impl Compiler {
 
     fn patch_jump(&mut self, jump_location: usize) {
         let true_jump = self.current_chunk().code.len() - jump_location - 1;
-        let (jump, line) = &self.current_chunk().code[jump_location];
+        let jump = &self.current_chunk().code[jump_location];
         match jump {
             OpCode::JumpIfFalse(_) => {
-                self.current_chunk().code[jump_location] = (OpCode::JumpIfFalse(true_jump), *line)
+                self.current_chunk().code[jump_location] = OpCode::JumpIfFalse(true_jump)
+            }
+            OpCode::JumpIfTrue(_) => {
+                self.current_chunk().code[jump_location] = OpCode::JumpIfTrue(true_jump)
+            }
+            OpCode::JumpIfNil(_) => {
+                self.current_chunk().code[jump_location] = OpCode::JumpIfNil(true_jump)
             }
             OpCode::Jump(_) => {
-                self.current_chunk().code[jump_location] = (OpCode::Jump(true_jump), *line)
+                self.current_chunk().code[jump_location] = OpCode::Jump(true_jump)
+            }
+            OpCode::OpIterNext(slot, _) => {
+                self.current_chunk().code[jump_location] = OpCode::OpIterNext(*slot, true_jump)
+            }
+            OpCode::OpAssert(_) => {
+                self.current_chunk().code[jump_location] = OpCode::OpAssert(true_jump)
             }
             _ => panic!("not here")
         }
@@ -482,21 +781,37 @@ impl Compiler {
     fn expression_statement(&mut self) -> Result<(), ExpError> {
         self.expression()?;
         self.consume(TokenType::Semicolon, "Expect ';' after value.")?;
-        self.emit_opt(OpCode::OpPop);
+        let trailing = self.states.len() == 1 && self.state().scope_depth == 0 && self.at_end();
+        if self.repl && trailing {
+            self.emit_opt(OpCode::OpPrintExpr);
+            let name = self.identifier_constant("_".to_string());
+            self.emit_opt(OpCode::OpDefineGlobal(name));
+        } else {
+            self.emit_opt(OpCode::OpPop);
+        }
 
         Ok(())
     }
 
     fn begin_scope(&mut self) -> Result<(), ExpError> {
-        self.scope_depth += 1;
+        self.state_mut().scope_depth += 1;
         Ok(())
     }
 
     fn end_scope(&mut self) -> Result<(), ExpError> {
-        self.scope_depth -= 1;
-        while self.locals.len() > 0 && self.locals.last().expect("exist").depth > self.scope_depth as i32 {
-            self.emit_opt(OpCode::OpPop);
-            self.locals.pop();
+        self.state_mut().scope_depth -= 1;
+        loop {
+            let state = self.state();
+            let local = match state.locals.last() {
+                Some(local) if local.depth > state.scope_depth as i32 => local.clone(),
+                _ => break,
+            };
+            if local.is_captured {
+                self.emit_opt(OpCode::OpCloseUpvalue);
+            } else {
+                self.emit_opt(OpCode::OpPop);
+            }
+            self.state_mut().locals.pop();
         }
         Ok(())
     }
@@ -506,14 +821,84 @@ impl Compiler {
         let property_name = self.previous().lexeme.clone();
         if can_assign && self._match(TokenType::Equal) {
             self.expression()?;
-            self.emit_opt(OpCode::OpSetProperty(property_name))
+            self.emit_opt(OpCode::OpSetProperty(property_name, 0))
+        } else if self._match(TokenType::LeftParen) {
+            let callee = token::source_text(&self.tokens[self.operand_start..self.current - 1]);
+            let index = self.constants.add(Constant::String(callee));
+            let args = self.argument_list()?;
+            self.emit_opt(OpCode::OpInvoke(property_name, args, index, 0))
+        } else {
+            self.emit_opt(OpCode::OpGetProperty(property_name, 0))
+        }
+
+        Ok(())
+    }
+
+    fn list(&mut self) -> Result<(), ExpError> {
+        let mut count = 0;
+        if !self.check(TokenType::RightBracket) {
+            loop {
+                self.expression()?;
+                count += 1;
+                if !self._match(TokenType::Comma) || self.check(TokenType::RightBracket) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightBracket, "Expect ']' after array elements.")?;
+        self.emit_opt(OpCode::OpBuildList(count));
+        Ok(())
+    }
+
+    fn map(&mut self) -> Result<(), ExpError> {
+        let mut count = 0;
+        if !self.check(TokenType::RightBrace) {
+            loop {
+                self.expression()?;
+                self.consume(TokenType::Colon, "Expect ':' after map key.")?;
+                self.expression()?;
+                count += 1;
+                if !self._match(TokenType::Comma) || self.check(TokenType::RightBrace) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after map entries.")?;
+        self.emit_opt(OpCode::OpBuildMap(count));
+        Ok(())
+    }
+
+    fn subscr(&mut self, can_assign: bool) -> Result<(), ExpError> {
+        self.expression()?;
+        self.consume(TokenType::RightBracket, "Expect ']' after index.")?;
+        if can_assign && self._match(TokenType::Equal) {
+            self.expression()?;
+            self.emit_opt(OpCode::OpIndexSet);
         } else {
-            self.emit_opt(OpCode::OpGetProperty(property_name))
+            self.emit_opt(OpCode::OpIndexGet);
         }
+        Ok(())
+    }
 
+    fn optional_dot(&mut self, can_assign: bool) -> Result<(), ExpError> {
+        let jump = self.emit_jump(OpCode::JumpIfNil(0));
+        self.state_mut().chain_jumps.push(jump);
+        self.consume(TokenType::Identifier, "Expect property name after '?.'.")?;
+        let property_name = self.previous().lexeme.clone();
+        if can_assign && self.check(TokenType::Equal) {
+            return Err(ExpError::AssignmentFailed { name: property_name });
+        }
+        self.emit_opt(OpCode::OpGetProperty(property_name, 0));
         Ok(())
     }
 
+    fn end_chain(&mut self, chain_start: usize) {
+        while self.state().chain_jumps.len() > chain_start {
+            let jump = self.state_mut().chain_jumps.pop().expect("should exist");
+            self.patch_jump(jump);
+        }
+    }
+
     fn apply_parse_fn(&mut self, parse_fn: ParseFn, can_assign: bool) -> Result<(), ExpError> {
         match parse_fn {
             ParseFn::Grouping => self.grouping(),
@@ -527,12 +912,12 @@ impl Compiler {
             ParseFn::Or => self.or(can_assign),
             ParseFn::Call => self.call(can_assign),
             ParseFn::Dot => self.dot(can_assign),
-            _ => panic!("not here"),
-            // ParseFn::Dot => self.dot(can_assign),
-            // ParseFn::This => self.this(can_assign),
-            // ParseFn::Super => self.super_(can_assign),
-            // ParseFn::List => self.list(can_assign),
-            // ParseFn::Subscript => self.subscr(can_assign),
+            ParseFn::OptionalDot => self.optional_dot(can_assign),
+            ParseFn::This => self.this(),
+            ParseFn::Super => self.super_(),
+            ParseFn::List => self.list(),
+            ParseFn::Map => self.map(),
+            ParseFn::Subscript => self.subscr(can_assign),
         }
     }
 
@@ -545,21 +930,13 @@ impl Compiler {
 
     fn prev_string(&mut self) -> Result<String, ExpError> {
         let prev = self.previous().clone();
-        match prev.token_type {
-            TokenType::String => {
-                match prev.literal {
-                    None => panic!("not here"),
-                    Some(s) => {
-                        match s {
-                            token::Literal::Str(s) => {
-                                return Ok(s);
-                            }
-                            _ => panic!("not here")
-                        }
-                    }
+        if prev.token_type == TokenType::String {
+            match prev.literal {
+                Some(token::Literal::Str(s)) => {
+                    return Ok(s);
                 }
+                _ => panic!("not here")
             }
-            _ => {}
         }
         Err(ExpError::Common("not string".to_string()))
     }
@@ -569,34 +946,64 @@ impl Compiler {
         self.named_variable(name, can_assign)
     }
 
+    fn this(&mut self) -> Result<(), ExpError> {
+        let in_method = self.states.iter()
+            .any(|state| matches!(state.function_type, FunctionType::Method | FunctionType::Initializer));
+        if !in_method {
+            return Err(ExpError::Common("Can't use 'this' outside of a class.".to_string()));
+        }
+        self.variable(false)
+    }
+
+    fn super_(&mut self) -> Result<(), ExpError> {
+        match self.classes.last() {
+            None => return Err(ExpError::Common("Can't use 'super' outside of a class.".to_string())),
+            Some(class) if !class.has_superclass => {
+                return Err(ExpError::Common("Can't use 'super' in a class with no superclass.".to_string()));
+            }
+            Some(_) => {}
+        }
+        self.consume(TokenType::Dot, "Expect '.' after 'super'.")?;
+        self.consume(TokenType::Identifier, "Expect superclass method name.")?;
+        let name = self.previous().lexeme.clone();
+        // the method is looked up on the superclass but bound to `this`
+        self.named_variable("this".to_string(), false)?;
+        self.emit_opt(OpCode::OpGetSuper(name));
+        Ok(())
+    }
+
     fn named_variable(&mut self, name: String, can_assign: bool) -> Result<(), ExpError> {
-        match self.resolve_local(name.clone())? {
+        let (get_op, set_op) = match self.resolve_local(name.clone())? {
+            Some(index) => (OpCode::OpGetLocal(index), OpCode::OpSetLocal(index)),
             None => {
-                let index = self.current_chunk().add_constant(Constant::String(name.clone()));
-                if can_assign && self._match(TokenType::Equal) {
-                    self.expression()?;
-                    self.emit_opt(OpCode::OpSetGlobal(index));
-                } else {
-                    self.emit_opt(OpCode::OpGetGlobal(index));
-                }
-            }
-            Some(index) => {
-                if can_assign && self._match(TokenType::Equal) {
-                    self.expression()?;
-                    self.emit_opt(OpCode::OpSetLocal(index));
-                } else {
-                    self.emit_opt(OpCode::OpGetLocal(index));
+                match self.resolve_upvalue(name.as_str())? {
+                    Some(index) => (OpCode::OpGetUpvalue(index), OpCode::OpSetUpvalue(index)),
+                    None => {
+                        let index = self.constants.add(Constant::String(name.clone()));
+                        (OpCode::OpGetGlobal(index), OpCode::OpSetGlobal(index))
+                    }
                 }
             }
+        };
+
+        if can_assign && self._match(TokenType::Equal) {
+            self.expression()?;
+            self.emit_opt(set_op);
+        } else {
+            self.emit_opt(get_op);
         }
 
         Ok(())
     }
 
     fn resolve_local(&mut self, name: String) -> Result<Option<LocalIndex>, ExpError> {
-        for i in (0..self.locals.len()).rev() {
-            let local = &self.locals[i];
-            if local.name.eq(name.as_str()) {
+        return Self::resolve_local_in(self.state(), name.as_str());
+    }
+
+    fn resolve_local_in(state: &FunctionState, name: &str) -> Result<Option<LocalIndex>, ExpError> {
+        for i in (0..state.locals.len()).rev() {
+            let local = &state.locals[i];
+            if local.name.eq(name) {
                 if local.depth == -1 {
                     return Err(ExpError::Common("Can't read local variable in its own initializer.".to_string()));
                 }
@@ -606,14 +1013,50 @@ impl Compiler {
         return Ok(None);
     }
 
+    fn resolve_upvalue(&mut self, name: &str) -> Result<Option<usize>, ExpError> {
+        let depth = self.states.len() - 1;
+        return self.resolve_upvalue_at(depth, name);
+    }
+
+    fn resolve_upvalue_at(&mut self, depth: usize, name: &str) -> Result<Option<usize>, ExpError> {
+        if depth == 0 {
+            return Ok(None);
+        }
+
+        if let Some(index) = Self::resolve_local_in(&self.states[depth - 1], name)? {
+            self.states[depth - 1].locals[index].is_captured = true;
+            return Ok(Some(self.add_upvalue(depth, index, true)));
+        }
+
+        if let Some(index) = self.resolve_upvalue_at(depth - 1, name)? {
+            return Ok(Some(self.add_upvalue(depth, index, false)));
+        }
+
+        return Ok(None);
+    }
+
+    fn add_upvalue(&mut self, depth: usize, index: usize, is_local: bool) -> usize {
+        let upvalues = &mut self.states[depth].upvalues;
+        for (i, upvalue) in upvalues.iter().enumerate() {
+            if upvalue.index == index && upvalue.is_local == is_local {
+                return i;
+            }
+        }
+
+        upvalues.push(UpvalueLocation { is_local, index });
+        return upvalues.len() - 1;
+    }
+
     fn parse_precedence(&mut self, precedence: Precedence) -> Result<(), ExpError> {
+        let chain_start = self.state().chain_jumps.len();
+        let first = self.current;
         let token = self.advance();
         let can_assign = precedence <= Precedence::Assignment;
         let rule = Self::get_rule(token.token_type);
 
         match rule.prefix {
             None => {
-                return Err(ExpError::UnexpectedToken(token.clone()));
+                return Err(ExpError::UnexpectedToken(Box::new(token.clone())));
             }
             Some(parse_fn) => {
                 self.apply_parse_fn(parse_fn, can_assign)?;
@@ -622,11 +1065,17 @@ impl Compiler {
 
         while precedence <= Compiler::get_rule(self.peek().token_type).precedence {
             self.advance();
-            match Self::get_rule(self.previous().token_type).infix {
+            let rule = Self::get_rule(self.previous().token_type);
+            if rule.precedence < Precedence::Call {
+                self.end_chain(chain_start);
+            }
+            self.operand_start = first;
+            match rule.infix {
                 Some(parse_fn) => self.apply_parse_fn(parse_fn, can_assign)?,
                 None => panic!("could not find infix rule to apply tok = {:?}", self.peek()),
             }
         }
+        self.end_chain(chain_start);
 
         if can_assign && self._match(TokenType::Equal) {
             panic!("Invalid assignment target")
@@ -683,8 +1132,7 @@ impl Compiler {
                 self.emit_opt(OpCode::OpAdd)
             }
             TokenType::BangEqual => {
-                self.emit_opt(OpCode::OpEqual);
-                self.emit_opt(OpCode::OpNot);
+                self.emit_opt(OpCode::OpNotEqual);
             }
             TokenType::EqualEqual => {
                 self.emit_opt(OpCode::OpEqual);
@@ -693,15 +1141,19 @@ impl Compiler {
                 self.emit_opt(OpCode::OpGreater);
             }
             TokenType::GreaterEqual => {
-                self.emit_opt(OpCode::OpLess);
-                self.emit_opt(OpCode::OpNot);
+                self.emit_opt(OpCode::OpGreaterEqual);
             }
             TokenType::Less => {
                 self.emit_opt(OpCode::OpLess);
             }
             TokenType::LessEqual => {
-                self.emit_opt(OpCode::OpGreater);
-                self.emit_opt(OpCode::OpNot);
+                self.emit_opt(OpCode::OpLessEqual);
+            }
+            TokenType::In => {
+                self.emit_opt(OpCode::OpIn);
+            }
+            TokenType::Is => {
+                self.emit_opt(OpCode::OpIs);
             }
             _ => {
                 panic!("not binary opt")
@@ -711,9 +1163,9 @@ impl Compiler {
     }
 
     fn unary(&mut self) -> Result<(), ExpError> {
-        // self.parse_precedence(Precedence::Unary)?;
         let token_type = self.previous().token_type;
-        self.expression()?;
+        // only the operand, so -1 + 2 negates 1 and not 1 + 2
+        self.parse_precedence(Precedence::Unary)?;
         match token_type {
             TokenType::Minus => {
                 self.emit_opt(OpCode::OpNegate);
@@ -721,27 +1173,6 @@ impl Compiler {
             TokenType::Bang => {
                 self.emit_opt(OpCode::OpNot);
             }
-            TokenType::BangEqual => {
-                self.emit_opt(OpCode::OpEqual);
-                self.emit_opt(OpCode::OpNot);
-            }
-            TokenType::EqualEqual => {
-                self.emit_opt(OpCode::OpEqual);
-            }
-            TokenType::Greater => {
-                self.emit_opt(OpCode::OpGreater);
-            }
-            TokenType::GreaterEqual => {
-                self.emit_opt(OpCode::OpLess);
-                self.emit_opt(OpCode::OpNot);
-            }
-            TokenType::Less => {
-                self.emit_opt(OpCode::OpLess);
-            }
-            TokenType::LessEqual => {
-                self.emit_opt(OpCode::OpGreater);
-                self.emit_opt(OpCode::OpNot);
-            }
             _ => {
                 // Err(ExpError::TokenMismatch {
                 //     expected: token_type.clone(),
@@ -756,6 +1187,9 @@ impl Compiler {
 
     fn number(&mut self) -> Result<(), ExpError> {
         match self.previous().literal {
+            Some(token::Literal::Int(n)) => {
+                self.emit_constant(chunk::Constant::Int(n))
+            }
             Some(token::Literal::Number(n)) => {
                 self.emit_constant(chunk::Constant::Number(n))
             }
@@ -770,8 +1204,8 @@ impl Compiler {
             return Ok(self.advance());
         }
         return Err(ExpError::TokenMismatch {
-            expected: ty.clone(),
-            found: self.previous().clone(),
+            expected: ty,
+            found: Box::new(self.previous().clone()),
             err_string: Some(message.to_string()),
         });
     }
@@ -791,30 +1225,41 @@ impl Compiler {
         return self.peek().token_type.eq(&token_type);
     }
 
+    fn check_ahead(&self, n: usize, token_type: TokenType) -> bool {
+        return match self.tokens.get(self.current + n) {
+            None => false,
+            Some(token) => token.token_type == token_type,
+        };
+    }
+
     fn peek(&self) -> &token::Token {
         return &self.tokens[self.current];
     }
 
 
     fn emit_constant(&mut self, val: Constant) {
-        let line = self.current;
-        let compiling = self.current_chunk();
-        let index = compiling.add_constant(val);
-        compiling.code.push((OpCode::OpConstant(index), line))
+        let line = self.current_line();
+        let index = self.constants.add(val);
+        self.current_chunk().write(OpCode::OpConstant(index), line)
     }
 
 
     fn emit_opt(&mut self, opt: OpCode) {
-        let line = self.current;
-        self.current_chunk().code.push((opt, line))
+        let line = self.current_line();
+        self.current_chunk().write(opt, line)
     }
 
     fn end(&mut self) {
-        // self.emit_opt(OpCode::OpReturn);
+        self.emit_return();
+        debug_assert_eq!(self.current_chunk().check_jumps(), Ok(()));
     }
 
     fn emit_return(&mut self) {
-        self.emit_opt(OpCode::OpNil);
+        if self.state().function_type == FunctionType::Initializer {
+            self.emit_opt(OpCode::OpGetLocal(0));
+        } else {
+            self.emit_opt(OpCode::OpNil);
+        }
         self.emit_opt(OpCode::OpReturn);
     }
 
@@ -846,7 +1291,7 @@ impl Compiler {
                 precedence: Precedence::None,
             },
             TokenType::LeftBrace => ParseRule {
-                prefix: None,
+                prefix: Some(ParseFn::Map),
                 infix: None,
                 precedence: Precedence::None,
             },
@@ -855,26 +1300,31 @@ impl Compiler {
                 infix: None,
                 precedence: Precedence::None,
             },
-            // TokenType::LeftParen => ParseRule {
-            //     prefix: Some(ParseFn::List),
-            //     infix: Some(ParseFn::Subscript),
-            //     precedence: Precedence::Call,
-            // },
-            // TokenType::RightParen => ParseRule {
-            //     prefix: None,
-            //     infix: None,
-            //     precedence: Precedence::None,
-            // },
             TokenType::Comma => ParseRule {
                 prefix: None,
                 infix: None,
                 precedence: Precedence::None,
             },
+            TokenType::LeftBracket => ParseRule {
+                prefix: Some(ParseFn::List),
+                infix: Some(ParseFn::Subscript),
+                precedence: Precedence::Call,
+            },
+            TokenType::RightBracket => ParseRule {
+                prefix: None,
+                infix: None,
+                precedence: Precedence::None,
+            },
             TokenType::Dot => ParseRule {
                 prefix: None,
                 infix: Some(ParseFn::Dot),
                 precedence: Precedence::Call,
             },
+            TokenType::QuestionDot => ParseRule {
+                prefix: None,
+                infix: Some(ParseFn::OptionalDot),
+                precedence: Precedence::Call,
+            },
             TokenType::Minus => ParseRule {
                 prefix: Some(ParseFn::Unary),
                 infix: Some(ParseFn::Binary),
@@ -890,6 +1340,21 @@ impl Compiler {
                 infix: None,
                 precedence: Precedence::None,
             },
+            TokenType::Question => ParseRule {
+                prefix: None,
+                infix: None,
+                precedence: Precedence::None,
+            },
+            TokenType::Colon => ParseRule {
+                prefix: None,
+                infix: None,
+                precedence: Precedence::None,
+            },
+            TokenType::Ellipsis => ParseRule {
+                prefix: None,
+                infix: None,
+                precedence: Precedence::None,
+            },
             TokenType::Slash => ParseRule {
                 prefix: None,
                 infix: Some(ParseFn::Binary),
@@ -935,6 +1400,16 @@ impl Compiler {
                 infix: Some(ParseFn::Binary),
                 precedence: Precedence::Comparison,
             },
+            TokenType::In => ParseRule {
+                prefix: None,
+                infix: Some(ParseFn::Binary),
+                precedence: Precedence::Comparison,
+            },
+            TokenType::Is => ParseRule {
+                prefix: None,
+                infix: Some(ParseFn::Binary),
+                precedence: Precedence::Comparison,
+            },
             TokenType::LessEqual => ParseRule {
                 prefix: None,
                 infix: Some(ParseFn::Binary),
@@ -955,11 +1430,36 @@ impl Compiler {
                 infix: None,
                 precedence: Precedence::None,
             },
+            TokenType::Comment | TokenType::Newline => ParseRule {
+                prefix: None,
+                infix: None,
+                precedence: Precedence::None,
+            },
             TokenType::And => ParseRule {
                 prefix: None,
                 infix: Some(ParseFn::And),
                 precedence: Precedence::And,
             },
+            TokenType::Assert => ParseRule {
+                prefix: None,
+                infix: None,
+                precedence: Precedence::None,
+            },
+            TokenType::Break => ParseRule {
+                prefix: None,
+                infix: None,
+                precedence: Precedence::None,
+            },
+            TokenType::Continue => ParseRule {
+                prefix: None,
+                infix: None,
+                precedence: Precedence::None,
+            },
+            TokenType::Loop => ParseRule {
+                prefix: None,
+                infix: None,
+                precedence: Precedence::None,
+            },
             TokenType::Class => ParseRule {
                 prefix: None,
                 infix: None,
@@ -970,6 +1470,11 @@ impl Compiler {
                 infix: None,
                 precedence: Precedence::None,
             },
+            TokenType::Enum => ParseRule {
+                prefix: None,
+                infix: None,
+                precedence: Precedence::None,
+            },
             TokenType::False => ParseRule {
                 prefix: Some(ParseFn::Literal),
                 infix: None,
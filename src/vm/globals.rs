@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+use std::ops::Index;
+
+use crate::types::val::Value;
+
+// global variables by slot. a name is hashed once, when a script is linked,
+// after that the ops reach their variable by index
+#[derive(Default, Debug)]
+pub struct Globals {
+    slots: HashMap<String, usize>,
+    names: Vec<String>,
+    // None until the variable is defined, a script may mention it before that
+    values: Vec<Option<Value>>,
+}
+
+impl Globals {
+    pub fn slot(&mut self, name: &str) -> usize {
+        match self.slots.get(name) {
+            None => {}
+            Some(slot) => return *slot,
+        }
+        let slot = self.names.len();
+        self.slots.insert(name.to_string(), slot);
+        self.names.push(name.to_string());
+        self.values.push(None);
+        return slot;
+    }
+
+    pub fn name(&self, slot: usize) -> &str {
+        return &self.names[slot];
+    }
+
+    pub fn get_slot(&self, slot: usize) -> Option<&Value> {
+        return self.values[slot].as_ref();
+    }
+
+    pub fn set_slot(&mut self, slot: usize, value: Value) {
+        self.values[slot] = Some(value);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        return match self.slots.get(name) {
+            None => None,
+            Some(slot) => self.get_slot(*slot),
+        };
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=(&str, &Value)> {
+        return self.names.iter().zip(self.values.iter())
+            .filter_map(|(name, value)| value.as_ref().map(|value| (name.as_str(), value)));
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        return self.get(name).is_some();
+    }
+
+    pub fn insert(&mut self, name: &str, value: Value) {
+        let slot = self.slot(name);
+        self.set_slot(slot, value);
+    }
+}
+
+impl Index<&str> for Globals {
+    type Output = Value;
+
+    fn index(&self, name: &str) -> &Value {
+        return self.get(name).unwrap_or_else(|| panic!("no global named {}", name));
+    }
+}
@@ -1,5 +1,6 @@
 pub mod chunk;
+#[allow(clippy::module_inception)]
 pub mod vm;
 pub mod compiler;
 pub mod builtins;
-
+pub mod globals;
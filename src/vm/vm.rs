@@ -1,31 +1,55 @@
-use std::borrow::{Borrow, BorrowMut};
-use std::collections::HashMap;
-use std::f32::consts::E;
+use std::cell::RefCell;
+use std::mem::size_of;
+use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant};
 
 use log::debug;
 
-use crate::{cast, types};
-use crate::types::class::LoxClass;
-use crate::types::expr::ExpError;
+use crate::cast;
+use crate::stdlib;
+use crate::stdlib::iter::LoxIter;
+use crate::stdlib::debug::Shape;
+use crate::stdlib::determinism::Determinism;
+use crate::stdlib::heap::HeapStats;
+use crate::stdlib::random::Rng;
+use crate::stdlib::testing::Tally;
+use crate::types::err::LoxError;
+use crate::types::map::LoxMap;
+use crate::types::output::Output;
+use crate::types::span::Span;
+use crate::types::expr::BinaryOperatorType;
+use crate::types::val;
 use crate::types::val::{InterpreterError, Value};
 use crate::vm::builtins;
-use crate::vm::chunk::{BoundMethod, Chunk, Class, Constant, Function, Instance, NativeFunction, OpCode};
+use crate::vm::chunk::{BoundMethod, Closure, Constant, Function, Instance, NativeFunction, OpCode, Upvalue};
+use crate::vm::globals::Globals;
 
-#[derive(Default, Clone)]
+// what a property site found the last time it ran, good for instances of
+// that same class
+#[derive(Clone, Debug)]
+enum PropertyCache {
+    Field { class: usize, slot: usize },
+    Method { class: usize, method: Closure },
+}
+
+#[derive(Clone)]
 pub struct CallFrame {
-    function: Function,
+    closure: Closure,
     ip: usize,
     slots_offset: usize,
 }
 
 impl CallFrame {
     fn read_constant(&self, idx: usize) -> Constant {
-        self.function.chunk.constants[idx].clone()
+        self.closure.function.chunk.constants[idx].clone()
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum FunctionType {
     Function,
+    Method,
+    Initializer,
     Script,
 }
 
@@ -33,46 +57,97 @@ pub enum FunctionType {
 pub struct VirtualMachine {
     pub call_frames: Vec<CallFrame>,
     pub stack: Vec<Value>,
-    pub globals: HashMap<String, Value>,
+    pub globals: Globals,
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
     id: usize,
+    pub timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    pub output: Output,
+    pub sandbox: bool,
+    pub rng: Rng,
+    pub determinism: Option<Determinism>,
+    pub tally: Tally,
+    // every instance made so far, reference counting frees them as soon as the
+    // last reference goes, this only lets heapStats() see it
+    instances: Vec<Weak<RefCell<Instance>>>,
+    collections: usize,
+    property_caches: Vec<Option<PropertyCache>>,
 }
 
 impl VirtualMachine {
     pub fn init(&mut self) {
-        self.globals.insert("clock".to_string(), Value::NativeFunc(NativeFunction {
+        self.globals.insert("clock", Value::NativeFunc(NativeFunction {
             arity: 0,
             name: "clock".to_string(),
             func: builtins::clock,
         }));
-        self.globals.insert("sleep".to_string(), Value::NativeFunc(NativeFunction {
+        self.globals.insert("sleep", Value::NativeFunc(NativeFunction {
             arity: 1,
             name: "sleep".to_string(),
             func: builtins::sleep,
         }));
+        self.globals.insert("clone", Value::NativeFunc(NativeFunction {
+            arity: 1,
+            name: "clone".to_string(),
+            func: builtins::clone,
+        }));
+        for native in stdlib::natives() {
+            self.globals.insert(native.name, Value::Native(native));
+        }
     }
     pub fn destroy() {}
 
-    fn prepare_interpret(&mut self, func: Function) {
+    fn link(&mut self, function: &mut Function) {
+        let chunk = &mut function.chunk;
+        for op in chunk.code.iter_mut() {
+            match op {
+                OpCode::OpDefineGlobal(index) | OpCode::OpGetGlobal(index) | OpCode::OpSetGlobal(index) => {
+                    *index = self.globals.slot(cast!(&chunk.constants[*index], Constant::String));
+                }
+                OpCode::OpGetProperty(_, cache) | OpCode::OpSetProperty(_, cache) | OpCode::OpInvoke(_, _, _, cache) => {
+                    *cache = self.property_caches.len();
+                    self.property_caches.push(None);
+                }
+                _ => {}
+            }
+        }
+        for function in chunk.functions.iter_mut() {
+            self.link(Rc::make_mut(function));
+        }
+    }
+
+    fn prepare_interpret(&mut self, mut func: Function) {
+        self.link(&mut func);
+        // slot 0 of every frame holds the callee, the script is no exception
+        let closure = Closure {
+            function: Rc::new(func),
+            upvalues: vec![],
+            superclass: None,
+        };
+        self.push(Value::Closure(closure.clone()));
         self.call_frames.push(CallFrame {
-            function: func,
+            closure,
             ip: 0,
-            slots_offset: 1,
+            slots_offset: 0,
         });
     }
 
-    pub fn interpret(&mut self, function: Function) -> Result<(), InterpreterError> {
+    pub fn interpret(&mut self, function: Function) -> Result<(), LoxError> {
+        self.deadline = self.timeout.map(|timeout| Instant::now() + timeout);
         self.prepare_interpret(function);
-        self.run()?;
+        match self.run() {
+            Ok(_) => {}
+            Err(e) => {
+                self.call_frames.clear();
+                self.stack.clear();
+                self.open_upvalues.clear();
+                return Err(LoxError::Runtime(e));
+            }
+        }
 
         Ok(())
     }
 
-    fn pop_stack_n_times(&mut self, num_to_pop: usize) {
-        for _ in 0..num_to_pop {
-            self.pop();
-        }
-    }
-
     fn frame_mut(&mut self) -> &mut CallFrame {
         let last = self.call_frames.len() - 1;
         return &mut self.call_frames[last];
@@ -81,27 +156,61 @@ impl VirtualMachine {
         return self.call_frames.last().expect("should exist");
     }
 
-    fn current_chuck(&self) -> Chunk {
-        return self.frame().clone().function.chunk;
-    }
-
     fn run(&mut self) -> Result<(), InterpreterError> {
         loop {
             if self.is_done() {
                 return Ok(());
             }
-            self.step()?;
+            self.check_deadline()?;
+            match self.step() {
+                Ok(_) => {}
+                Err(e) => {
+                    return Err(self.locate(e));
+                }
+            }
         }
     }
 
+    pub fn check_deadline(&self) -> Result<(), InterpreterError> {
+        match (self.timeout, self.deadline) {
+            (Some(after), Some(deadline)) if Instant::now() >= deadline => {
+                return Err(InterpreterError::Timeout { after, trace: self.stack_trace() });
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn stack_trace(&self) -> Vec<String> {
+        return self.call_frames.iter().rev().map(|frame| {
+            let function = &frame.closure.function;
+            let line = function.chunk.line_for(frame.ip.saturating_sub(1)).unwrap_or(0);
+            if function.name.is_empty() {
+                format!("script [line {}]", line)
+            } else {
+                format!("{}() [line {}]", function.name, line)
+            }
+        }).collect();
+    }
+
+    fn locate(&self, err: InterpreterError) -> InterpreterError {
+        if self.call_frames.is_empty() {
+            return err;
+        }
+        let frame = self.frame();
+        return match frame.closure.function.chunk.line_for(frame.ip.saturating_sub(1)) {
+            None => err,
+            Some(line) => err.at(Span::line(line)),
+        };
+    }
+
     fn is_done(&self) -> bool {
-        self.call_frames.is_empty() || self.frame().ip >= self.frame().function.chunk.code.len()
+        self.call_frames.is_empty() || self.frame().ip >= self.frame().closure.function.chunk.code.len()
     }
 
-    fn next_op_and_advance(&mut self) -> (OpCode, usize) {
+    fn next_op_and_advance(&mut self) -> OpCode {
         let frame = self.frame_mut();
-        let chuck = frame.function.chunk.clone();
-        let result = chuck.code.get(frame.ip).expect("never here").clone();
+        let result = frame.closure.function.chunk.code.get(frame.ip).expect("never here").clone();
         frame.ip += 1;
         return result;
     }
@@ -109,49 +218,41 @@ impl VirtualMachine {
     fn step(&mut self) -> Result<(), InterpreterError> {
         let opt = self.next_op_and_advance();
         match opt {
-            (OpCode::OpReturn, _) => {
+            OpCode::OpReturn => {
                 let result = self.pop();
+                let slots_offset = self.frame().slots_offset;
+                self.close_upvalues(slots_offset);
+                self.call_frames.pop();
+                self.stack.truncate(slots_offset);
 
-                if self.call_frames.len() <= 1 {
-                    self.call_frames.pop();
+                if self.call_frames.is_empty() {
                     return Ok(());
                 }
 
-                let num_to_pop = self.stack.len() - self.frame().slots_offset + self.frame().function.arity;
-                self.call_frames.pop();
-                self.pop_stack_n_times(num_to_pop);
-
                 self.stack.push(result.clone());
                 debug!("return value: {:?}", result.clone())
             }
-            (OpCode::OpNegate, _) => {
-                let new_value = match self.pop() {
-                    Value::Number(val) => {
-                        Value::Number(-val)
-                    }
-                    _ => {
-                        panic!("can't negate")
-                    }
-                };
-                self.push(new_value);
+            OpCode::OpNegate => {
+                let value = self.pop();
+                self.push(val::negate(value)?);
             }
-            (OpCode::OpConstant(index), _) => {
+            OpCode::OpConstant(index) => {
                 let val: Value = self.frame().read_constant(index).into();
                 self.push(val);
             }
-            (OpCode::OpAdd, _) | (OpCode::OpSubtract, _) | (OpCode::OpMultiply, _) | (OpCode::OpDivide, _) => {
-                self.binary_opt(opt.0.clone())
+            OpCode::OpAdd | OpCode::OpSubtract | OpCode::OpMultiply | OpCode::OpDivide => {
+                self.binary_opt(opt.clone())?
             }
-            (OpCode::OpNil, _) => {
+            OpCode::OpNil => {
                 self.push(Value::Nil)
             }
-            (OpCode::OpTrue, _) => {
+            OpCode::OpTrue => {
                 self.push(Value::Bool(true))
             }
-            (OpCode::OpFalse, _) => {
+            OpCode::OpFalse => {
                 self.push(Value::Bool(false))
             }
-            (OpCode::OpNot, _) => {
+            OpCode::OpNot => {
                 match self.pop() {
                     Value::Bool(b) => {
                         self.push(Value::Bool(!b))
@@ -162,118 +263,339 @@ impl VirtualMachine {
                     _ => panic!("not execute opt not")
                 }
             }
-            (OpCode::OpEqual, _) => {
+            OpCode::OpEqual => {
                 let a = self.pop();
                 let b = self.pop();
                 self.push(Value::Bool(a.eq(&b)));
             }
-            (OpCode::OpGreater, _) => {
+            OpCode::OpNotEqual => {
                 let a = self.pop();
                 let b = self.pop();
+                self.push(Value::Bool(a.ne(&b)));
+            }
+            OpCode::OpGreater => {
+                let a = self.pop();
+                let b = self.pop();
+                val::check_comparable(BinaryOperatorType::Greater, &b, &a)?;
                 self.push(Value::Bool(b > a));
             }
-            (OpCode::OpLess, _) => {
+            OpCode::OpGreaterEqual => {
                 let a = self.pop();
                 let b = self.pop();
+                // not the same as !(b < a), NaN compares false both ways
+                val::check_comparable(BinaryOperatorType::GreaterEqual, &b, &a)?;
+                self.push(Value::Bool(b >= a));
+            }
+            OpCode::OpLess => {
+                let a = self.pop();
+                let b = self.pop();
+                val::check_comparable(BinaryOperatorType::Less, &b, &a)?;
                 self.push(Value::Bool(b < a));
             }
-            (OpCode::OpPrint, _) => {
-                println!("{:?}", self.pop());
+            OpCode::OpLessEqual => {
+                let a = self.pop();
+                let b = self.pop();
+                val::check_comparable(BinaryOperatorType::LessEqual, &b, &a)?;
+                self.push(Value::Bool(b <= a));
+            }
+            OpCode::OpBuildList(count) => {
+                let values = self.stack.split_off(self.stack.len() - count);
+                self.push(Value::Array(Rc::new(RefCell::new(values))));
+            }
+            OpCode::OpIter => {
+                let iterable = self.pop();
+                let iter = LoxIter::new(&iterable, &mut |obj, name| self.call_method(obj, name))?;
+                self.push(Value::Iterator(Rc::new(RefCell::new(iter))));
+            }
+            OpCode::OpIterNext(slot, offset) => {
+                let slots_offset = self.frame().slots_offset;
+                let iterator = cast!(&self.stack[slots_offset + slot], Value::Iterator).clone();
+                let next = iterator.borrow_mut().next_value(&mut |obj, name| self.call_method(obj, name))?;
+                match next {
+                    None => {
+                        self.frame_mut().ip += offset;
+                    }
+                    Some(value) => {
+                        self.push(value);
+                    }
+                }
             }
-            (OpCode::OpPop, _) => {
+            OpCode::OpBuildMap(count) => {
+                let values = self.stack.split_off(self.stack.len() - count * 2);
+                let mut map = LoxMap::new();
+                for pair in values.chunks(2) {
+                    map.insert(pair[0].clone(), pair[1].clone())?;
+                }
+                self.push(Value::Map(Rc::new(RefCell::new(map))));
+            }
+            OpCode::OpIndexGet => {
+                let index = self.pop();
+                let object = self.pop();
+                let value = match &object {
+                    Value::Instance(instance) => {
+                        let name = val::property_name(&index)?;
+                        match self.property(instance, name) {
+                            Some(value) => value,
+                            None => {
+                                self.push(object.clone());
+                                return self.method_missing(instance.clone(), name, 0);
+                            }
+                        }
+                    }
+                    _ => val::index_get(&object, &index)?,
+                };
+                self.push(value);
+            }
+            OpCode::OpIndexSet => {
+                let value = self.pop();
+                let index = self.pop();
+                let object = self.pop();
+                match &object {
+                    Value::Instance(instance) => instance.borrow_mut().set_field(val::property_name(&index)?, value.clone()),
+                    _ => val::index_set(&object, &index, value.clone())?,
+                }
+                self.push(value);
+            }
+            OpCode::OpIn => {
+                let container = self.pop();
+                let item = self.pop();
+                self.push(Value::Bool(val::contains(&container, &item)?));
+            }
+            OpCode::OpIs => {
+                let class = match self.pop() {
+                    Value::Class(class) => class,
+                    other => return Err(val::is_not_a_class(&other)),
+                };
+                let is = match self.pop() {
+                    Value::Instance(instance) => instance.borrow().class.inherits(&class),
+                    _ => false,
+                };
+                self.push(Value::Bool(is));
+            }
+            OpCode::OpPrint => {
+                let value = self.pop();
+                self.output.print(&value.to_string())?;
+            }
+            OpCode::OpPrintN(count) => {
+                let values = self.stack.split_off(self.stack.len() - count);
+                let printed: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+                self.output.print(&printed.join(" "))?;
+            }
+            OpCode::OpPrintExpr => {
+                // a call made for its effect has nothing worth echoing
+                match self.peek(0) {
+                    Value::Nil => {}
+                    value => self.output.print(&format!("> {}", value))?,
+                }
+            }
+            OpCode::OpPop => {
                 self.pop();
             }
-            (OpCode::OpDefineGlobal(index), _) => {
+            OpCode::OpDefineGlobal(slot) => {
                 let value = self.pop();
-                let key = cast!(self.frame().read_constant(index), Constant::String);
-
-                self.globals.insert(key, value);
+                self.globals.set_slot(slot, value);
             }
-            (OpCode::OpGetGlobal(index), _) => {
-                let key = cast!(self.frame().read_constant(index), Constant::String);
-                let val = self.globals.get(key.as_str()).expect("not found in globals").clone();
+            OpCode::OpGetGlobal(slot) => {
+                let val = match self.globals.get_slot(slot) {
+                    None => return Err(InterpreterError::MissVariable { name: self.globals.name(slot).to_string() }),
+                    Some(val) => val.clone(),
+                };
                 self.push(val);
             }
-            (OpCode::OpSetGlobal(index), _) => {
-                let key = cast!(self.frame().read_constant(index), Constant::String);
+            OpCode::OpSetGlobal(slot) => {
+                if self.globals.get_slot(slot).is_none() {
+                    return Err(InterpreterError::UndeclaredAssign { name: self.globals.name(slot).to_string() });
+                }
                 let val = self.stack.last().expect("expect last").clone();
-                self.globals.insert(key, val);
+                self.globals.set_slot(slot, val);
             }
-            (OpCode::OpGetLocal(index), _) => {
+            OpCode::OpGetLocal(index) => {
                 let slots_offset = self.frame().slots_offset;
                 let val = self.stack[slots_offset + index].clone();
                 self.push(val)
             }
-            (OpCode::OpSetLocal(index), _) => {
+            OpCode::OpSetLocal(index) => {
                 let slots_offset = self.frame().slots_offset;
                 let val = self.stack.last().expect("expect last").clone();
                 self.stack[slots_offset + index] = val;
             }
-            (OpCode::JumpIfFalse(jump_location), _) => {
-                let last = self.stack.len() - 1;
-                let condition = cast!(self.stack[last].clone(), Value::Bool);
-                if !condition {
+            OpCode::JumpIfFalse(jump_location) => {
+                if !self.stack.last().expect("expect last").is_truthy() {
+                    self.frame_mut().ip += jump_location;
+                }
+            }
+            OpCode::JumpIfTrue(jump_location) => {
+                if self.stack.last().expect("expect last").is_truthy() {
+                    self.frame_mut().ip += jump_location;
+                }
+            }
+            OpCode::OpAssert(jump_location) => {
+                if self.pop().is_truthy() {
+                    self.frame_mut().ip += jump_location;
+                }
+            }
+            OpCode::OpAssertFail(index) => {
+                let text = cast!(self.frame().read_constant(index), Constant::String);
+                let message = self.pop();
+                return Err(val::assertion_failed(&text, &message));
+            }
+            OpCode::JumpIfNil(jump_location) => {
+                if matches!(self.stack.last(), Some(Value::Nil)) {
                     self.frame_mut().ip += jump_location;
                 }
             }
-            (OpCode::Jump(jump_location), _) => {
+            OpCode::Jump(jump_location) => {
                 self.frame_mut().ip += jump_location;
             }
-            (OpCode::Loop(offset), _) => {
+            OpCode::Loop(offset) => {
                 self.frame_mut().ip -= offset
             }
-            (OpCode::Call(args_count), _) => {
-                self.call(self.stack.get(self.stack.len() - args_count - 1).expect("should exit").clone(), args_count)?;
+            OpCode::Call(args_count, index) => {
+                let callee = self.peek(args_count);
+                if !is_callable(&callee) {
+                    let name = cast!(self.frame().read_constant(index), Constant::String);
+                    return Err(InterpreterError::SimpleError(format!(
+                        "Can only call functions and classes; tried to call {} ({}).", name, callee.type_name())));
+                }
+                self.call(callee, args_count)?;
                 debug!("call function, increment call frame");
             }
-            (OpCode::OpClass(clazz), _) => {
+            OpCode::OpClass(mut clazz) => {
+                clazz.id = self.next_id();
                 self.push(Value::Class(clazz))
             }
-            (OpCode::OpSetProperty(name), _) => {
-                let mut instance = cast!(self.peek(1), Value::Instance);
+            OpCode::OpSetProperty(name, cache) => {
+                let instance = match self.peek(1) {
+                    Value::Instance(instance) => instance,
+                    _ => return Err(InterpreterError::SimpleError("Only instances have fields.".to_string())),
+                };
                 let val = self.peek(0);
                 self.pop();
                 self.pop();
-                instance.fields.insert(name, val.clone());
+                let mut instance = instance.borrow_mut();
+                let slot = match self.cached_slot(&instance, &name, cache) {
+                    Some(slot) => slot,
+                    None => {
+                        let slot = instance.slot_for(&name);
+                        self.property_caches[cache] = Some(PropertyCache::Field { class: instance.class.id, slot });
+                        slot
+                    }
+                };
+                instance.fields[slot].1 = val.clone();
+                drop(instance);
                 self.push(val);
-                self.update_ref(Value::Instance(instance));
             }
-            (OpCode::OpGetProperty(name), _) => {
-                let instance = cast!(self.peek(0), Value::Instance);
-                match instance.fields.get(name.as_str()) {
-                    None => {}
+            OpCode::OpGetProperty(name, cache) => {
+                let instance = match self.peek(0) {
+                    Value::Instance(instance) => instance,
+                    _ => return Err(InterpreterError::SimpleError("Only instances have properties.".to_string())),
+                };
+                match self.cached_property(&instance, &name, cache).or_else(|| self.find_property(&instance, &name, cache)) {
+                    None => self.method_missing(instance, &name, 0)?,
                     Some(val) => {
                         self.pop();
-                        self.push(val.clone());
-                        return Ok(());
+                        self.push(val);
                     }
                 }
-
-                if !self.bind_method(&instance.class, name.as_str()) {
-                    return Err(InterpreterError::SimpleError(format!("not found property {}", name)));
+            }
+            OpCode::OpInvoke(name, args_count, index, cache) => {
+                let instance = match self.peek(args_count) {
+                    Value::Instance(instance) => instance,
+                    _ => return Err(InterpreterError::SimpleError("Only instances have properties.".to_string())),
+                };
+                match self.cached_property(&instance, &name, cache).or_else(|| self.find_property(&instance, &name, cache)) {
+                    None => self.method_missing(instance, &name, args_count)?,
+                    Some(callee) => {
+                        if !is_callable(&callee) {
+                            let name = cast!(self.frame().read_constant(index), Constant::String);
+                            return Err(InterpreterError::SimpleError(format!(
+                                "Can only call functions and classes; tried to call {} ({}).", name, callee.type_name())));
+                        }
+                        let slot = self.stack.len() - 1 - args_count;
+                        self.stack[slot] = callee.clone();
+                        self.call(callee, args_count)?;
+                    }
                 }
             }
 
-            (OpCode::OpMethod(name), _) => {
-                let method = cast!(self.peek(0), Value::Function);
+            OpCode::OpMethod(name) => {
+                let mut method = cast!(self.peek(0), Value::Closure);
                 let mut class = cast!(self.peek(1), Value::Class);
+                method.superclass = class.superclass.clone();
                 class.methods.insert(name, method);
                 self.pop();
 
                 let last_index = self.stack.len() - 1;
                 self.stack[last_index] = Value::Class(class);
             }
+            OpCode::OpInherit => {
+                let super_class = match self.pop() {
+                    Value::Class(super_class) => super_class,
+                    _ => return Err(InterpreterError::SimpleError("Superclass must be a class.".to_string())),
+                };
+                let last_index = self.stack.len() - 1;
+                let mut class = cast!(self.peek(0), Value::Class);
+                class.methods.extend(super_class.methods.clone());
+                class.superclass = Some(Rc::new(super_class));
+                self.stack[last_index] = Value::Class(class);
+            }
+            OpCode::OpGetSuper(name) => {
+                let method = self.frame().closure.superclass.as_ref()
+                    .and_then(|superclass| superclass.methods.get(name.as_str()).cloned());
+                if !self.bind_method(method) {
+                    return Err(InterpreterError::SimpleError(format!("Undefined superclass method '{}'.", name)));
+                }
+            }
+            OpCode::OpClosure(index, upvalues) => {
+                let function = self.frame().closure.function.chunk.functions[index].clone();
+                let slots_offset = self.frame().slots_offset;
+                let mut captured = vec![];
+                for upvalue in upvalues {
+                    if upvalue.is_local {
+                        captured.push(self.capture_upvalue(slots_offset + upvalue.index));
+                    } else {
+                        captured.push(self.frame().closure.upvalues[upvalue.index].clone());
+                    }
+                }
+                let superclass = self.frame().closure.superclass.clone();
+                self.push(Value::Closure(Closure {
+                    function,
+                    upvalues: captured,
+                    superclass,
+                }));
+            }
+            OpCode::OpGetUpvalue(index) => {
+                let upvalue = self.frame().closure.upvalues[index].clone();
+                let val = match &*upvalue.borrow() {
+                    Upvalue::Open(slot) => self.stack[*slot].clone(),
+                    Upvalue::Closed(val) => val.clone(),
+                };
+                self.push(val);
+            }
+            OpCode::OpSetUpvalue(index) => {
+                let upvalue = self.frame().closure.upvalues[index].clone();
+                let val = self.peek(0);
+                match &mut *upvalue.borrow_mut() {
+                    Upvalue::Open(slot) => self.stack[*slot] = val,
+                    Upvalue::Closed(closed) => *closed = val,
+                };
+            }
+            OpCode::OpCloseUpvalue => {
+                self.close_upvalues(self.stack.len() - 1);
+                self.pop();
+            }
         }
         Ok(())
     }
-    fn bind_method(&mut self, class: &Class, name: &str) -> bool {
-        match class.methods.get(name) {
+
+    fn bind_method(&mut self, method: Option<Closure>) -> bool {
+        match method {
             None => {
                 return false;
             }
-            Some(func) => {
+            Some(closure) => {
                 let bound_method = BoundMethod {
-                    function: func.clone(),
+                    closure,
                     receiver: self.peek(0).clone(),
                 };
                 self.pop();
@@ -284,57 +606,140 @@ impl VirtualMachine {
         true
     }
 
-    fn peek(&self, n: usize) -> Value {
-        self.stack[self.stack.len() - 1 - n].clone()
+    fn cached_slot(&self, instance: &Instance, name: &str, cache: usize) -> Option<usize> {
+        return match &self.property_caches[cache] {
+            Some(PropertyCache::Field { class, slot }) if *class == instance.class.id => {
+                match instance.fields.get(*slot) {
+                    Some((field, _)) if field == name => Some(*slot),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
     }
 
-    fn update_ref(&mut self, val: Value) {
-        let instance = cast!(val.clone(), Value::Instance);
-
-        for v in self.globals.values_mut() {
-            match v {
-                Value::Instance(ins) => {
-                    if ins.id == instance.id {
-                        ins.fields = instance.fields.clone();
-                    }
+    fn cached_property(&self, instance: &Rc<RefCell<Instance>>, name: &str, cache: usize) -> Option<Value> {
+        let instance_ref = instance.borrow();
+        match &self.property_caches[cache] {
+            // the method only holds while no field of the same name shadows it
+            Some(PropertyCache::Method { class, method }) if *class == instance_ref.class.id => {
+                if instance_ref.shadows_method && instance_ref.field_slot(name).is_some() {
+                    return None;
                 }
-                _ => {}
+                let bound_method = BoundMethod { closure: method.clone(), receiver: Value::Instance(instance.clone()) };
+                return Some(Value::BoundMethod(Box::new(bound_method)));
             }
+            _ => {}
         }
+        return self.cached_slot(&instance_ref, name, cache).map(|slot| instance_ref.fields[slot].1.clone());
+    }
 
+    fn find_property(&mut self, instance: &Rc<RefCell<Instance>>, name: &str, cache: usize) -> Option<Value> {
+        let instance_ref = instance.borrow();
+        let class = instance_ref.class.id;
+        match instance_ref.field_slot(name) {
+            None => {}
+            Some(slot) => {
+                self.property_caches[cache] = Some(PropertyCache::Field { class, slot });
+                return Some(instance_ref.fields[slot].1.clone());
+            }
+        }
+        let method = instance_ref.class.methods.get(name)?.clone();
+        self.property_caches[cache] = Some(PropertyCache::Method { class, method: method.clone() });
+        let bound_method = BoundMethod { closure: method, receiver: Value::Instance(instance.clone()) };
+        return Some(Value::BoundMethod(Box::new(bound_method)));
+    }
 
-        for i in 0..self.stack.len() {
-            let item = &self.stack[i];
-            match item {
-                Value::Instance(ins) => {
-                    if ins.id == instance.id {
-                        self.stack[i] = val.clone();
-                    }
+    fn method_missing(&mut self, instance: Rc<RefCell<Instance>>, name: &str, args_count: usize) -> Result<(), InterpreterError> {
+        let hook = instance.borrow().class.methods.get("methodMissing").cloned();
+        let hook = match hook {
+            None => return Err(InterpreterError::SimpleError(format!("Undefined property '{}'.", name))),
+            Some(hook) => hook,
+        };
+        // a miss on the instance whose methodMissing is running would go round forever
+        let regress = self.call_frames.iter().any(|frame| {
+            frame.closure.function.name == "methodMissing"
+                && matches!(&self.stack[frame.slots_offset], Value::Instance(this) if Rc::ptr_eq(this, &instance))
+        });
+        if regress {
+            return Err(InterpreterError::SimpleError(format!("Undefined property '{}' inside methodMissing.", name)));
+        }
+        let args = self.stack.split_off(self.stack.len() - args_count);
+        self.push(Value::String(name.to_string()));
+        self.push(Value::Array(Rc::new(RefCell::new(args))));
+        let bound_method = BoundMethod { closure: hook, receiver: Value::Instance(instance) };
+        return self.call(Value::BoundMethod(Box::new(bound_method)), 2);
+    }
+
+    fn property(&self, instance: &Rc<RefCell<Instance>>, name: &str) -> Option<Value> {
+        let instance_ref = instance.borrow();
+        match instance_ref.field(name) {
+            None => {}
+            Some(field) => return Some(field.clone()),
+        }
+        let closure = instance_ref.class.methods.get(name)?;
+        let bound_method = BoundMethod { closure: closure.clone(), receiver: Value::Instance(instance.clone()) };
+        return Some(Value::BoundMethod(Box::new(bound_method)));
+    }
+
+    fn call_method(&mut self, obj: &Value, name: &str) -> Result<Value, InterpreterError> {
+        let callee = match obj {
+            Value::Instance(instance) => self.property(instance, name)
+                .ok_or_else(|| InterpreterError::SimpleError(format!("Undefined property '{}'.", name)))?,
+            _ => return Err(InterpreterError::SimpleError("Only instances have methods.".to_string())),
+        };
+        return self.call_value(callee, vec![]);
+    }
+
+    // runs `callee` until it returns, the frames it pushes are stepped here rather than in run()
+    pub fn call_value(&mut self, callee: Value, args: Vec<Value>) -> Result<Value, InterpreterError> {
+        let depth = self.call_frames.len();
+        let arg_count = args.len();
+        self.push(callee.clone());
+        self.stack.extend(args);
+        self.call(callee, arg_count)?;
+        while self.call_frames.len() > depth {
+            self.check_deadline()?;
+            self.step()?;
+        }
+        return Ok(self.pop());
+    }
+
+    fn capture_upvalue(&mut self, slot: usize) -> Rc<RefCell<Upvalue>> {
+        for upvalue in &self.open_upvalues {
+            match &*upvalue.borrow() {
+                Upvalue::Open(open_slot) if *open_slot == slot => {
+                    return upvalue.clone();
                 }
                 _ => {}
             }
         }
+
+        let upvalue = Rc::new(RefCell::new(Upvalue::Open(slot)));
+        self.open_upvalues.push(upvalue.clone());
+        return upvalue;
     }
 
-    pub fn find_function(&self, name: String) -> Option<Function> {
-        for i in (0..self.call_frames.len()).rev() {
-            let call_frame = &self.call_frames[i];
-            for constant in &call_frame.function.chunk.constants {
-                match constant {
-                    Constant::Function(f) => {
-                        if f.name.eq(&name) {
-                            return Some(f.clone());
-                        }
-                    }
-                    _ => {}
-                }
+    fn close_upvalues(&mut self, last: usize) {
+        let stack = &self.stack;
+        self.open_upvalues.retain(|upvalue| {
+            let slot = match &*upvalue.borrow() {
+                Upvalue::Open(slot) => *slot,
+                Upvalue::Closed(_) => return false,
+            };
+            if slot < last {
+                return true;
             }
-        }
+            *upvalue.borrow_mut() = Upvalue::Closed(stack[slot].clone());
+            false
+        });
+    }
 
-        return None;
+    fn peek(&self, n: usize) -> Value {
+        self.stack[self.stack.len() - 1 - n].clone()
     }
 
-    fn next_id(&mut self) -> usize {
+    pub fn next_id(&mut self) -> usize {
         self.id += 1;
         return self.id;
     }
@@ -342,26 +747,56 @@ impl VirtualMachine {
     fn call(&mut self, callee: Value, arg_count: usize) -> Result<(), InterpreterError> {
         match callee {
             Value::BoundMethod(bound_method) => {
-                return self.call(Value::Function(bound_method.function), arg_count);
+                // the receiver takes the callee slot, so it becomes `this` (local 0) of the method
+                let index = self.stack.len() - 1 - arg_count;
+                self.stack[index] = bound_method.receiver;
+                return self.call_closure(bound_method.closure, arg_count);
             }
             Value::Class(clazz) => {
                 let new_instance = Instance {
                     id: self.next_id(),
                     class: clazz,
                     fields: Default::default(),
+                    shadows_method: false,
                 };
 
+                let init = new_instance.class.methods.get("init").cloned();
+                let instance = Rc::new(RefCell::new(new_instance));
+                self.instances.push(Rc::downgrade(&instance));
+
+                // the instance takes the callee slot, `init` sees it as `this`
                 let index = self.stack.len() - 1 - arg_count;
-                self.stack[index] = Value::Instance(new_instance);
+                self.stack[index] = Value::Instance(instance);
+                match init {
+                    None => {
+                        if arg_count != 0 {
+                            return Err(InterpreterError::SimpleError(format!("Expected 0 arguments but got {}.", arg_count)));
+                        }
+                    }
+                    Some(init) => return self.call_closure(init, arg_count),
+                }
+            }
+            Value::Closure(closure) => {
+                return self.call_closure(closure, arg_count);
             }
-            Value::Function(func) => {
-                self.call_frames.push(CallFrame {
-                    function: func,
-                    ip: 0,
-                    slots_offset: self.stack.len() - arg_count,
-                })
+            Value::Function(function) => {
+                return self.call_closure(Closure {
+                    function,
+                    upvalues: vec![],
+                    superclass: None,
+                }, arg_count);
+            }
+            Value::Native(native) => {
+                let args = self.stack.split_off(self.stack.len() - arg_count);
+                // native function value
+                self.pop();
+                let result = native.call(self, args.as_slice())?;
+                self.push(result);
             }
             Value::NativeFunc(native) => {
+                if arg_count != native.arity {
+                    return Err(InterpreterError::SimpleError(format!("Expected {} arguments but got {}.", native.arity, arg_count)));
+                }
                 let mut values = vec![];
                 for _ in 0..native.arity {
                     values.push(self.pop());
@@ -373,13 +808,30 @@ impl VirtualMachine {
                 let result = (native.func)(self, values.as_slice())?;
                 self.push(result);
             }
-            _ => panic!("can't call")
+            _ => {
+                return Err(InterpreterError::SimpleError("Can only call functions and classes.".to_string()));
+            }
         }
 
         Ok(())
     }
 
 
+    fn call_closure(&mut self, closure: Closure, arg_count: usize) -> Result<(), InterpreterError> {
+        if closure.function.arity != arg_count {
+            return Err(InterpreterError::SimpleError(format!(
+                "Expected {} arguments but got {}.", closure.function.arity, arg_count
+            )));
+        }
+
+        self.call_frames.push(CallFrame {
+            closure,
+            ip: 0,
+            slots_offset: self.stack.len() - arg_count - 1,
+        });
+        Ok(())
+    }
+
     pub fn pop(&mut self) -> Value {
         match self.stack.pop() {
             Some(val) => val,
@@ -390,55 +842,139 @@ impl VirtualMachine {
         self.stack.push(var);
     }
 
-    fn binary_opt(&mut self, opt: OpCode) {
-        let x = self.pop();
-        let y = self.pop();
+    fn binary_opt(&mut self, opt: OpCode) -> Result<(), InterpreterError> {
+        let right = self.pop();
+        let left = self.pop();
 
-        debug!("call binary opt: {:?}, x: {:?} y: {:?}", opt,x, y);
+        debug!("call binary opt: {:?}, left: {:?} right: {:?}", opt, left, right);
 
-        let new_value = match x {
-            Value::Number(x) => {
-                match y {
-                    Value::Number(y) => {
-                        match opt {
-                            OpCode::OpAdd => {
-                                Value::Number(x + y)
-                            }
-                            OpCode::OpSubtract => {
-                                Value::Number(y - x)
-                            }
-                            OpCode::OpMultiply => {
-                                Value::Number(y * x)
-                            }
-                            OpCode::OpDivide => {
-                                Value::Number(y / x)
-                            }
-                            _ => panic!("type not equal")
-                        }
-                    }
-                    _ => panic!("type not equal")
-                }
+        let opt = match opt {
+            OpCode::OpAdd => BinaryOperatorType::Plus,
+            OpCode::OpSubtract => BinaryOperatorType::Minus,
+            OpCode::OpMultiply => BinaryOperatorType::Star,
+            OpCode::OpDivide => BinaryOperatorType::Slash,
+            _ => panic!("not support binary opt")
+        };
+        let new_value = val::arithmetic(opt, left, right)?;
+        self.push(new_value);
+        Ok(())
+    }
+}
+
+fn is_callable(value: &Value) -> bool {
+    return matches!(value, Value::BoundMethod(_) | Value::Class(_) | Value::Closure(_) | Value::Function(_)
+        | Value::Native(_) | Value::NativeFunc(_));
+}
+
+impl stdlib::Caller for VirtualMachine {
+    fn call(&mut self, callee: Value, args: Vec<Value>) -> Result<Value, InterpreterError> {
+        return self.call_value(callee, args);
+    }
+
+    fn try_call(&mut self, callee: Value, args: Vec<Value>) -> Result<Value, InterpreterError> {
+        let depth = self.call_frames.len();
+        let height = self.stack.len();
+        return match self.call_value(callee, args) {
+            Ok(value) => Ok(value),
+            Err(e) => {
+                let e = self.locate(e);
+                self.close_upvalues(height);
+                self.call_frames.truncate(depth);
+                self.stack.truncate(height);
+                Err(e)
             }
-            Value::String(x) => {
-                match y {
-                    Value::String(y) => {
-                        Value::String(y + x.as_str())
-                    }
-                    _ => panic!("type not equal")
-                }
+        };
+    }
+
+    fn output(&self) -> Output {
+        return self.output.clone();
+    }
+
+    fn sandboxed(&self) -> bool {
+        return self.sandbox;
+    }
+
+    fn rng(&mut self) -> &mut Rng {
+        return &mut self.rng;
+    }
+
+    fn tally(&mut self) -> &mut Tally {
+        return &mut self.tally;
+    }
+
+    fn determinism(&mut self) -> Option<&mut Determinism> {
+        return self.determinism.as_mut();
+    }
+
+    fn heap_stats(&self) -> HeapStats {
+        let instances = self.instances.iter().filter(|instance| instance.strong_count() > 0).count();
+        return HeapStats {
+            instances: Some(instances),
+            functions: None,
+            strings: None,
+            bytes: instances * size_of::<Instance>(),
+            collections: self.collections,
+        };
+    }
+
+    // there is nothing left to free, only the bookkeeping of what already was.
+    // instances that point at each other are never freed
+    fn collect_garbage(&mut self) {
+        self.instances.retain(|instance| instance.strong_count() > 0);
+        self.collections += 1;
+    }
+
+    fn globals(&self) -> Vec<(String, Value)> {
+        return self.globals.iter().map(|(name, value)| (name.to_string(), value.clone())).collect();
+    }
+
+    fn shape(&self, value: &Value) -> Option<Shape> {
+        let function = |function: &Function| Shape::Function { name: function.name.clone(), arity: function.arity };
+        return match value {
+            Value::Instance(instance) => {
+                let instance_ref = instance.borrow();
+                let mut fields: Vec<(String, Value)> = instance_ref.fields.iter()
+                    .map(|(name, value)| (name.clone(), value.clone()))
+                    .collect();
+                fields.sort_by(|a, b| a.0.cmp(&b.0));
+                let id = Rc::as_ptr(instance) as *const u8 as usize;
+                Some(Shape::Instance { id, class: instance_ref.class.name.clone(), fields })
             }
-            _ => panic!("not support binary opt")
+            Value::Closure(closure) => Some(function(&closure.function)),
+            Value::Function(f) => Some(function(f)),
+            Value::BoundMethod(bound) => Some(function(&bound.closure.function)),
+            Value::NativeFunc(native) => Some(Shape::Function { name: native.name.clone(), arity: native.arity }),
+            Value::Class(class) => Some(Shape::Class { name: class.name.clone() }),
+            _ => None,
         };
+    }
 
-        self.push(new_value)
+    fn remove_field(&mut self, instance: &Value, name: &str) -> Option<Value> {
+        return match instance {
+            Value::Instance(instance) => instance.borrow_mut().remove_field(name),
+            _ => None,
+        };
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::process::scanner;
     use crate::types::val::Value;
-    use crate::vm::chunk::{Chunk, Constant, OpCode};
-    use crate::vm::vm::VirtualMachine;
+    use crate::vm::chunk::{Chunk, Constant, Function, OpCode};
+    use crate::vm::compiler::Compiler;
+    use crate::vm::vm::{FunctionType, PropertyCache, VirtualMachine};
+
+    fn run(source: &str) -> VirtualMachine {
+        let tokens = scanner::scan_tokens(source.to_string()).unwrap();
+        let function = Compiler::new(tokens, FunctionType::Script).compile().unwrap();
+        let mut machine = VirtualMachine::default();
+        machine.init();
+        machine.interpret(function).unwrap();
+        machine
+    }
 
     #[test]
     fn it_works() {
@@ -446,12 +982,1144 @@ mod tests {
         let mut chuck = Chunk::default();
         let i = chuck.add_constant(Constant::Number(12.0));
         let j = chuck.add_constant(Constant::Number(24.0));
-        chuck.code.push((OpCode::OpConstant(i), 1));
-        chuck.code.push((OpCode::OpConstant(j), 2));
-        chuck.code.push((OpCode::OpAdd, 3));
+        chuck.write(OpCode::OpConstant(i), 1);
+        chuck.write(OpCode::OpConstant(j), 2);
+        chuck.write(OpCode::OpAdd, 3);
+
+        machine.prepare_interpret(Function {
+            chunk: chuck,
+            ..Default::default()
+        });
+        for _ in 0..3 {
+            machine.step().expect("TODO: panic message");
+        }
+        assert_eq!(machine.stack.last().unwrap().clone(), Value::Number(36.0));
+    }
+
+    fn compare(a: f64, b: f64, ops: &[OpCode]) -> Value {
+        let mut machine = VirtualMachine::default();
+        let mut chunk = Chunk::default();
+        let i = chunk.add_constant(Constant::Number(a));
+        let j = chunk.add_constant(Constant::Number(b));
+        chunk.write(OpCode::OpConstant(i), 1);
+        chunk.write(OpCode::OpConstant(j), 1);
+        for op in ops {
+            chunk.write(op.clone(), 1);
+        }
+
+        machine.prepare_interpret(Function {
+            chunk,
+            ..Default::default()
+        });
+        for _ in 0..ops.len() + 2 {
+            machine.step().unwrap();
+        }
+        return machine.stack.last().unwrap().clone();
+    }
+
+    #[test]
+    fn comparison_opcodes_follow_ieee_on_nan() {
+        let nan = f64::NAN;
+        // the old two-op sequences say NaN >= 1 and NaN <= 1
+        assert_eq!(compare(nan, 1.0, &[OpCode::OpLess, OpCode::OpNot]), Value::Bool(true));
+        assert_eq!(compare(nan, 1.0, &[OpCode::OpGreaterEqual]), Value::Bool(false));
+        assert_eq!(compare(nan, 1.0, &[OpCode::OpGreater, OpCode::OpNot]), Value::Bool(true));
+        assert_eq!(compare(nan, 1.0, &[OpCode::OpLessEqual]), Value::Bool(false));
+        assert_eq!(compare(nan, nan, &[OpCode::OpEqual, OpCode::OpNot]), Value::Bool(true));
+        assert_eq!(compare(nan, nan, &[OpCode::OpNotEqual]), Value::Bool(true));
+
+        for (a, b) in [(1.0, 2.0), (2.0, 2.0), (3.0, 2.0)] {
+            assert_eq!(compare(a, b, &[OpCode::OpGreaterEqual]), Value::Bool(a >= b));
+            assert_eq!(compare(a, b, &[OpCode::OpLessEqual]), Value::Bool(a <= b));
+            assert_eq!(compare(a, b, &[OpCode::OpNotEqual]), Value::Bool(a != b));
+        }
+    }
+
+    #[test]
+    fn comparisons_compile_to_single_opcodes() {
+        let tokens = scanner::scan_tokens("1 >= 2; 1 <= 2; 1 != 2;".to_string()).unwrap();
+        let function = Compiler::new(tokens, FunctionType::Script).compile().unwrap();
+        let ops: Vec<String> = function.chunk.code.iter()
+            .map(|op| format!("{:?}", op))
+            .filter(|op| !op.starts_with("OpConstant") && op != "OpPop")
+            .collect();
+        assert_eq!(ops, vec!["OpGreaterEqual", "OpLessEqual", "OpNotEqual", "OpNil", "OpReturn"]);
+    }
+
+    #[test]
+    fn or_jumps_once_on_a_truthy_left_side() {
+        let tokens = scanner::scan_tokens("var a = false; a or true;".to_string()).unwrap();
+        let function = Compiler::new(tokens, FunctionType::Script).compile().unwrap();
+        let ops: Vec<String> = function.chunk.code.iter().map(|op| format!("{:?}", op)).collect();
+        // one jump and one pop between the operands, "a" names the first constant
+        assert_eq!(ops[2..6], ["OpGetGlobal(0)", "JumpIfTrue(2)", "OpPop", "OpTrue"]);
+
+        let machine = run(r#"
+            var first = 1 or 2;
+            var second = false or "right";
+            var neither = nil or false;
+            var calls = 0;
+            fun bump() { calls = calls + 1; return true; }
+            var skipped = true or bump();
+            var chained = nil or false or "last";
+        "#);
+        assert_eq!(machine.globals["first"], Value::Int(1));
+        assert_eq!(machine.globals["second"].to_string(), "right");
+        assert_eq!(machine.globals["neither"], Value::Bool(false));
+        assert_eq!(machine.globals["calls"], Value::Int(0));
+        assert_eq!(machine.globals["skipped"], Value::Bool(true));
+        assert_eq!(machine.globals["chained"].to_string(), "last");
+    }
+
+    #[test]
+    fn closure_captures_this_after_method_returns() {
+        let machine = run(r#"
+            class Point {
+                reader() {
+                    fun read() { return this.x; }
+                    return read;
+                }
+            }
+            var p = Point();
+            p.x = 1;
+            var read = p.reader();
+            p.x = 2;
+            var result = read();
+        "#);
+        assert_eq!(machine.globals["result"], Value::Number(2.0));
+    }
+
+    #[test]
+    fn arithmetic_follows_numeric_tower() {
+        let machine = run(r#"
+            var a = 1 + 2;
+            var b = 7 / 2;
+            var c = 1 + 0.5;
+            var d = 2 * 3 - 10;
+            var e = -1 + 2;
+            var f = 2.0 * 3;
+            var g = 1 == 1.0;
+            var h = 2 < 2.5;
+            var i = 10 / 4 * 2;
+            var j = 9223372036854775807;
+        "#);
+        for (name, expected) in [
+            ("a", "Int(3)"),
+            ("b", "Number(3.5)"),
+            ("c", "Number(1.5)"),
+            ("d", "Int(-4)"),
+            ("e", "Int(1)"),
+            ("f", "Number(6.0)"),
+            ("g", "Bool(true)"),
+            ("h", "Bool(true)"),
+            ("i", "Number(5.0)"),
+            ("j", "Int(9223372036854775807)"),
+        ] {
+            assert_eq!(format!("{:?}", machine.globals[name]), expected, "{}", name);
+        }
+
+        let tokens = scanner::scan_tokens("-9223372036854775807 - 2;".to_string()).unwrap();
+        let function = Compiler::new(tokens, FunctionType::Script).compile().unwrap();
+        let err = VirtualMachine::default().interpret(function).unwrap_err();
+        assert!(err.to_string().starts_with("Simple Error: Integer overflow"));
+    }
 
-        machine.current = chuck;
-        machine.step().expect("TODO: panic message");
-        assert_eq!(machine.stack.get(0).unwrap().clone(), Value::Number(36.0));
+    #[test]
+    fn optional_get_short_circuits_chain() {
+        let machine = run(r#"
+            class Node {
+                get() { return this; }
+            }
+            var n = Node();
+            n.value = 1;
+            n.next = nil;
+            var a = nil;
+            var r1 = a?.b;
+            var r2 = a?.b.c;
+            var r3 = n?.value;
+            var r4 = n.next?.value;
+            var r5 = a?.m();
+            var r6 = n?.get().value;
+            var r7 = n.next?.value == nil;
+        "#);
+        for (name, expected) in [
+            ("r1", Value::Nil),
+            ("r2", Value::Nil),
+            ("r3", Value::Int(1)),
+            ("r4", Value::Nil),
+            ("r5", Value::Nil),
+            ("r6", Value::Int(1)),
+            ("r7", Value::Bool(true)),
+        ] {
+            assert_eq!(machine.globals[name], expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn clone_copies_instance_fields() {
+        let machine = run(r#"
+            class Point {}
+            var a = Point();
+            a.x = 1;
+            var b = clone(a);
+            b.x = 2;
+            b.y = 3;
+            var ax = a.x;
+            var bx = b.x;
+            var by = b.y;
+            var n = clone(5);
+            var s = clone("lox");
+        "#);
+        for (name, expected) in [
+            ("ax", Value::Int(1)),
+            ("bx", Value::Int(2)),
+            ("by", Value::Int(3)),
+            ("n", Value::Int(5)),
+            ("s", Value::String("lox".to_string())),
+        ] {
+            assert_eq!(machine.globals[name], expected, "{}", name);
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn in_checks_membership() {
+        let machine = run(r#"
+            var a = "ell" in "hello";
+            var b = "z" in "hello";
+            var c = !("z" in "hello");
+            var d = "he" + "l" in "hello";
+        "#);
+        for (name, expected) in [
+            ("a", Value::Bool(true)),
+            ("b", Value::Bool(false)),
+            ("c", Value::Bool(true)),
+            ("d", Value::Bool(true)),
+        ] {
+            assert_eq!(machine.globals[name], expected, "{}", name);
+        }
+
+        let tokens = scanner::scan_tokens("1 in 2;".to_string()).unwrap();
+        let function = Compiler::new(tokens, FunctionType::Script).compile().unwrap();
+        let err = VirtualMachine::default().interpret(function).unwrap_err();
+        assert!(err.to_string().contains("must be a container, found int"));
+    }
+
+    #[test]
+    fn arrays_are_shared_and_mutable() {
+        let machine = run(r#"
+            var xs = [3, 1, 2];
+            var ys = xs;
+            push(ys, 0);
+            sort(ys);
+            var first = xs[0];
+            xs[1] = 10;
+            var second = ys[1];
+            var removed = removeAt(xs, 3);
+            var at = indexOf(xs, 10);
+            var has = 2 in xs;
+            var copy = clone(xs);
+            push(copy, 5);
+            var len = push(xs, 4);
+        "#);
+        for (name, expected) in [
+            ("first", Value::Int(0)),
+            ("second", Value::Int(10)),
+            ("removed", Value::Int(3)),
+            ("at", Value::Int(1)),
+            ("has", Value::Bool(true)),
+            ("len", Value::Int(4)),
+        ] {
+            assert_eq!(machine.globals[name], expected, "{}", name);
+        }
+        assert_eq!(machine.globals["xs"].to_string(), "[0, 10, 2, 4]");
+    }
+
+    #[test]
+    fn maps_keep_insertion_order() {
+        let machine = run(r#"
+            var m = {"b": 1, "a": 2};
+            m["c"] = 3;
+            m["b"] = 10;
+            var b = m["b"];
+            var missing = m["z"];
+            var found = "a" in m;
+            var removed = remove(m, "a");
+            var gone = remove(m, "a");
+            var still = has(m, "a");
+            var merged = merge(m, {"c": 30, "d": 4});
+            var order = keys(merged);
+            var vals = values(m);
+        "#);
+        for (name, expected) in [
+            ("b", Value::Int(10)),
+            ("missing", Value::Nil),
+            ("found", Value::Bool(true)),
+            ("removed", Value::Int(2)),
+            ("gone", Value::Nil),
+            ("still", Value::Bool(false)),
+        ] {
+            assert_eq!(machine.globals[name], expected, "{}", name);
+        }
+        assert_eq!(machine.globals["order"].to_string(), "[b, c, d]");
+        assert_eq!(machine.globals["vals"].to_string(), "[10, 3]");
+        assert_eq!(machine.globals["m"].to_string(), "{b: 10, c: 3}");
+    }
+
+    #[test]
+    fn negative_indexes_count_from_the_end() {
+        let machine = run(r#"
+            var xs = [1, 2, 3];
+            var last = xs[-1];
+            xs[-3] = 0;
+            var first = xs[0];
+            var ch = "héllo"[-4];
+            var tail = slice(xs, -2, nil);
+        "#);
+        for (name, expected) in [
+            ("last", Value::Int(3)),
+            ("first", Value::Int(0)),
+            ("ch", Value::String("é".to_string())),
+        ] {
+            assert_eq!(machine.globals[name], expected, "{}", name);
+        }
+        assert_eq!(machine.globals["tail"].to_string(), "[2, 3]");
+    }
+
+    #[test]
+    fn methods_bind_their_receiver() {
+        let machine = run(r#"
+            class Counter {
+                show() {
+                    return this.count;
+                }
+            }
+            var c = Counter();
+            c.count = 1;
+            var show = c.show;
+            c.count = 2;
+            var shown = show();
+        "#);
+        assert_eq!(machine.globals["shown"], Value::Int(2));
+
+        for source in ["class A {} A().missing;", "var x = 1; x.y;", "var x = 1; x.y = 2;"] {
+            let tokens = scanner::scan_tokens(source.to_string()).unwrap();
+            let function = Compiler::new(tokens, FunctionType::Script).compile().unwrap();
+            let mut machine = VirtualMachine::default();
+            machine.init();
+            assert!(machine.interpret(function).is_err(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn arrays_concat_and_compare_by_value() {
+        let machine = run(r#"
+            var a = [1, 2];
+            var b = [3];
+            var c = a + b;
+            var same = [1, [2]] == [1, [2]];
+            var differ = [1, [2]] != [1, [3]];
+        "#);
+        assert_eq!(machine.globals["c"].to_string(), "[1, 2, 3]");
+        assert_eq!(machine.globals["a"].to_string(), "[1, 2]");
+        assert_eq!(machine.globals["same"], Value::Bool(true));
+        assert_eq!(machine.globals["differ"], Value::Bool(true));
+    }
+
+    #[test]
+    fn strings_repeat_with_star() {
+        let machine = run(r#"
+            var a = "ab" * 3;
+            var b = 3 * "x";
+            var c = 2 * 3;
+        "#);
+        assert_eq!(machine.globals["a"], Value::String("ababab".to_string()));
+        assert_eq!(machine.globals["b"], Value::String("xxx".to_string()));
+        assert_eq!(machine.globals["c"], Value::Int(6));
+    }
+
+    #[test]
+    fn for_in_walks_ranges_and_collections() {
+        let machine = run(r#"
+            var sum = 0;
+            for (var i in range(1, 5)) sum = sum + i;
+            var down = [];
+            for (var i in range(5, 0, -2)) push(down, i);
+            var seen = 0;
+            for (var i in range(0, 1000000000000)) {
+                var doubled = i * 2;
+                if (i == 3) break;
+                seen = seen + 1;
+            }
+            var chars = "";
+            for (var c in "héllo") chars = c + chars;
+            var keys = "";
+            for (var k in {"a": 1, "b": 2}) keys = keys + k;
+            var n = 0;
+            while (true) {
+                n = n + 1;
+                if (n == 4) break;
+            }
+            var size = len(range(0, 10, 3));
+        "#);
+        for (name, expected) in [
+            ("sum", Value::Int(10)),
+            ("seen", Value::Int(3)),
+            ("chars", Value::String("olléh".to_string())),
+            ("keys", Value::String("ab".to_string())),
+            ("n", Value::Int(4)),
+            ("size", Value::Int(4)),
+        ] {
+            assert_eq!(machine.globals[name], expected, "{}", name);
+        }
+        assert_eq!(machine.globals["down"].to_string(), "[5, 3, 1]");
+    }
+
+    #[test]
+    fn break_closes_captured_loop_variables() {
+        let machine = run(r#"
+            var fs = [];
+            for (var i in [1, 2, 3]) {
+                var j = i * 10;
+                fun f() { return j; }
+                push(fs, f);
+                if (i == 2) break;
+            }
+            var first = fs[0]();
+            var second = fs[1]();
+            var count = len(fs);
+        "#);
+        assert_eq!(machine.globals["first"], Value::Int(10));
+        assert_eq!(machine.globals["second"], Value::Int(20));
+        assert_eq!(machine.globals["count"], Value::Int(2));
+    }
+
+    #[test]
+    fn optional_chain_stops_at_first_nil_link() {
+        let machine = run(r#"
+            class Node {}
+            var a = Node();
+            a.next = Node();
+            a.next.value = 7;
+            var present = a?.next?.value;
+            a.next.next = nil;
+            var cut = a?.next?.next?.value;
+            var missing = nil;
+            var root = missing?.next?.value;
+        "#);
+        for (name, expected) in [
+            ("present", Value::Int(7)),
+            ("cut", Value::Nil),
+            ("root", Value::Nil),
+        ] {
+            assert_eq!(machine.globals[name], expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn for_in_uses_iterate_and_next() {
+        let machine = run(r#"
+            class Cursor {
+                next() {
+                    if (this.node == nil) return nil;
+                    var value = this.node.value;
+                    this.node = this.node.rest;
+                    return value;
+                }
+            }
+            class LinkedList {
+                add(value) {
+                    var node = Cursor();
+                    node.value = value;
+                    node.rest = this.head;
+                    this.head = node;
+                }
+                iterate() {
+                    var cursor = Cursor();
+                    cursor.node = this.head;
+                    return cursor;
+                }
+            }
+            var list = LinkedList();
+            list.head = nil;
+            list.add(3);
+            list.add(2);
+            list.add(1);
+
+            var all = [];
+            for (var x in list) push(all, x);
+            var firstTwo = [];
+            for (var x in list) {
+                if (x == 3) break;
+                push(firstTwo, x);
+            }
+            var pairs = 0;
+            for (var x in list) for (var y in list) pairs = pairs + 1;
+
+            class Wrapper {
+                iterate() { return [4, 5]; }
+            }
+            var wrapped = [];
+            for (var x in Wrapper()) push(wrapped, x);
+        "#);
+        assert_eq!(machine.globals["all"].to_string(), "[1, 2, 3]");
+        assert_eq!(machine.globals["firstTwo"].to_string(), "[1, 2]");
+        assert_eq!(machine.globals["pairs"], Value::Int(9));
+        assert_eq!(machine.globals["wrapped"].to_string(), "[4, 5]");
+    }
+
+    #[test]
+    fn higher_order_natives_call_back() {
+        let machine = run(r#"
+            fun double(x) { return x * 2; }
+            fun gtOne(x) { return x > 1; }
+            fun add(a, b) { return a + b; }
+            class Scale {
+                apply(x) { return x * this.by; }
+            }
+            var s = Scale();
+            s.by = 10;
+            var xs = [1, 2, 3];
+            var doubled = map(xs, double);
+            var scaled = map(xs, s.apply);
+            var lengths = map([[1], [], [1, 2]], len);
+            var big = filter(xs, gtOne);
+            var total = reduce(xs, add, 0);
+            var seen = [];
+            fun remember(x) { push(seen, x); }
+            var nothing = forEach(xs, remember);
+        "#);
+        assert_eq!(machine.globals["doubled"].to_string(), "[2, 4, 6]");
+        assert_eq!(machine.globals["scaled"].to_string(), "[10, 20, 30]");
+        assert_eq!(machine.globals["lengths"].to_string(), "[1, 0, 2]");
+        assert_eq!(machine.globals["big"].to_string(), "[2, 3]");
+        assert_eq!(machine.globals["total"], Value::Int(6));
+        assert_eq!(machine.globals["seen"].to_string(), "[1, 2, 3]");
+        assert_eq!(machine.globals["nothing"], Value::Nil);
+    }
+
+    #[test]
+    fn format_fills_placeholders() {
+        let machine = run(r#"
+            var s = format("{} of {} is {{{}}}", [1, 2], 3, "x");
+        "#);
+        assert_eq!(machine.globals["s"], Value::String("[1, 2] of 3 is {x}".to_string()));
+    }
+
+    #[test]
+    fn integral_number_keys_match_ints() {
+        let machine = run(r#"
+            var m = {};
+            m[3] = 1;
+            var found = m[3.0];
+            m[2.0] = "two";
+        "#);
+        assert_eq!(machine.globals["found"], Value::Int(1));
+        assert_eq!(machine.globals["m"].to_string(), "{3: 1, 2: two}");
+    }
+
+    #[test]
+    fn labeled_break_and_continue() {
+        let machine = run(r#"
+            var pairs = [];
+            outer: for (var i = 0; i < 3; i = i + 1) {
+                for (var j = 0; j < 3; j = j + 1) {
+                    if (j == 1) continue;
+                    if (i == 1) continue outer;
+                    if (i == 2) break outer;
+                    push(pairs, [i, j]);
+                }
+            }
+            var found = nil;
+            rows: for (var row in [[1, 2], [3, 4], [5, 6]]) {
+                var seen = 0;
+                while (true) {
+                    for (var x in row) {
+                        seen = seen + 1;
+                        if (x == 4) {
+                            found = x;
+                            break rows;
+                        }
+                    }
+                    break;
+                }
+            }
+            var n = 0;
+            var odd = 0;
+            while (n < 6) {
+                n = n + 1;
+                if (n == 2 or n == 4 or n == 6) continue;
+                odd = odd + n;
+            }
+        "#);
+        assert_eq!(machine.globals["pairs"].to_string(), "[[0, 0], [0, 2]]");
+        assert_eq!(machine.globals["found"], Value::Int(4));
+        assert_eq!(machine.globals["odd"], Value::Int(9));
+    }
+
+    #[test]
+    fn frames_keep_their_own_locals() {
+        let machine = run(r#"
+            fun inner(a, b, c) {
+                var x = a * 100;
+                var y = b * 10;
+                { var z = c; x = x + y + z; }
+                return x;
+            }
+            fun middle(p, q) {
+                var before = p + q;
+                var r = 1 + inner(p, q, before);
+                var after = before * 1000;
+                return [before, r, after, p, q];
+            }
+            fun outer(n) {
+                var local = n;
+                var results = [];
+                for (var i = 0; i < 2; i = i + 1) {
+                    var tmp = local + i;
+                    push(results, middle(tmp, inner(i, tmp, 0) - 1));
+                    push(results, tmp);
+                }
+                return results;
+            }
+            fun depth(n) {
+                var mine = n * 2;
+                if (n == 0) return mine;
+                var below = depth(n - 1);
+                return mine + below;
+            }
+            fun scale(x) { var k = 3; return x * k; }
+            var all = outer(1);
+            var deep = depth(4);
+            var mapped = map([1, 2], scale);
+        "#);
+        assert_eq!(machine.globals["all"].to_string(),
+                   "[[10, 201, 10000, 1, 9], 1, [121, 1512, 121000, 2, 119], 2]");
+        assert_eq!(machine.globals["deep"], Value::Int(20));
+        assert_eq!(machine.globals["mapped"].to_string(), "[3, 6]");
+        assert!(machine.stack.is_empty());
+    }
+
+    #[test]
+    fn loop_runs_until_break_or_return() {
+        let machine = run(r#"
+            var count = 0;
+            loop {
+                count = count + 1;
+                if (count == 5) break;
+            }
+            fun firstOver(limit) {
+                var n = 1;
+                loop {
+                    n = n * 2;
+                    if (n > limit) return n;
+                }
+            }
+            var over = firstOver(100);
+            var steps = 0;
+            outer: loop {
+                var i = 0;
+                while (true) {
+                    i = i + 1;
+                    steps = steps + 1;
+                    if (i == 3) break outer;
+                }
+            }
+            var skipped = [];
+            var k = 0;
+            loop {
+                k = k + 1;
+                if (k > 4) break;
+                if (k == 2) continue;
+                push(skipped, k);
+            }
+        "#);
+        assert_eq!(machine.globals["count"], Value::Int(5));
+        assert_eq!(machine.globals["over"], Value::Int(128));
+        assert_eq!(machine.globals["steps"], Value::Int(3));
+        assert_eq!(machine.globals["skipped"].to_string(), "[1, 3, 4]");
+    }
+
+    #[test]
+    fn conditionless_for_leaves_the_stack_balanced() {
+        let machine = run(r#"
+            fun firstSquareOver(limit) {
+                var base = 10;
+                for (var i = 1;; i = i + 1) {
+                    var square = i * i;
+                    if (square > limit) return base + square;
+                }
+            }
+            var sum = 0;
+            for (var round = 0; round < 50; round = round + 1) {
+                sum = sum + firstSquareOver(round);
+            }
+            var kept = "kept";
+            var steps = 0;
+            for (;;) {
+                var step = steps + 1;
+                steps = step;
+                if (step == 3) break;
+            }
+            var last = firstSquareOver(99);
+        "#);
+        assert_eq!(machine.globals["last"], Value::Int(110));
+        assert_eq!(machine.globals["steps"], Value::Int(3));
+        assert_eq!(machine.globals["kept"], Value::String("kept".to_string()));
+        // no condition is pushed, so nothing is left over to pop
+        assert!(machine.stack.is_empty(), "{:?}", machine.stack);
+    }
+
+    #[test]
+    fn sleep_takes_non_negative_seconds() {
+        let started = Instant::now();
+        run("sleep(0.01); sleep(0);");
+        assert!(started.elapsed() >= Duration::from_millis(10));
+
+        for source in ["sleep(-1);", "sleep(1 / 0);", "sleep(\"1\");"] {
+            let tokens = scanner::scan_tokens(source.to_string()).unwrap();
+            let function = Compiler::new(tokens, FunctionType::Script).compile().unwrap();
+            let mut machine = VirtualMachine::default();
+            machine.init();
+            assert!(machine.interpret(function).is_err(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn subclasses_inherit_methods() {
+        let machine = run(r#"
+            class A {
+                name() { return "A"; }
+                greet() { return "hi " + this.name(); }
+            }
+            class B < A {
+                name() { return "B"; }
+            }
+            var a = A().greet();
+            var b = B().greet();
+        "#);
+        assert_eq!(machine.globals["a"].to_string(), "hi A");
+        assert_eq!(machine.globals["b"].to_string(), "hi B");
+    }
+
+    #[test]
+    fn calling_a_non_callable_names_the_callee() {
+        for (source, expected) in [
+            ("var x = 1;\nx();", "tried to call x (int). [line 2]"),
+            ("var p = [1];\n\nvar y = p[0](2);", "tried to call p[0] (int). [line 3]"),
+            ("fun f() { return nil; }\nf()();", "tried to call f() (nil). [line 2]"),
+        ] {
+            let tokens = scanner::scan_tokens(source.to_string()).unwrap();
+            let function = Compiler::new(tokens, FunctionType::Script).compile().unwrap();
+            let mut machine = VirtualMachine::default();
+            machine.init();
+            let err = machine.interpret(function).unwrap_err().to_string();
+            assert!(err.contains(expected), "{}", err);
+        }
+    }
+
+    #[test]
+    fn super_calls_the_superclass_method() {
+        let machine = run(r#"
+            class A {
+                name() { return "A"; }
+            }
+            class B < A {
+                name() { return "B" + super.name(); }
+                later() {
+                    fun inner() { return super.name(); }
+                    return inner;
+                }
+            }
+            class C < B {
+                name() { return "C" + super.name(); }
+            }
+            var c = C().name();
+            var later = C().later()();
+        "#);
+        assert_eq!(machine.globals["c"].to_string(), "CBA");
+        assert_eq!(machine.globals["later"].to_string(), "A");
+
+        let tokens = scanner::scan_tokens("class A {} class B < A { m() { return super.missing(); } } B().m();".to_string()).unwrap();
+        let function = Compiler::new(tokens, FunctionType::Script).compile().unwrap();
+        let mut machine = VirtualMachine::default();
+        machine.init();
+        assert!(machine.interpret(function).is_err());
+    }
+
+    #[test]
+    fn approx_eq_allows_a_tolerance() {
+        let machine = run(r#"
+            var exact = 0.1 + 0.2 == 0.3;
+            var close = approxEq(0.1 + 0.2, 0.3, 0.000000001);
+            var strict = approxEq(0.1 + 0.2, 0.3, 0);
+            var fallback = approxEq(0.1 + 0.2, 0.3);
+        "#);
+        assert_eq!(machine.globals["exact"], Value::Bool(false));
+        assert_eq!(machine.globals["close"], Value::Bool(true));
+        assert_eq!(machine.globals["strict"], Value::Bool(false));
+        assert_eq!(machine.globals["fallback"], Value::Bool(true));
+    }
+
+    #[test]
+    fn functions_can_call_themselves() {
+        let machine = run(r#"
+            fun fib(n) {
+                if (n < 2) return n;
+                return fib(n - 1) + fib(n - 2);
+            }
+            var global = fib(10);
+            var local;
+            {
+                fun fact(n) {
+                    if (n <= 1) return 1;
+                    return n * fact(n - 1);
+                }
+                local = fact(10);
+            }
+            fun outer() {
+                fun count(n) {
+                    if (n == 0) return 0;
+                    return 1 + count(n - 1);
+                }
+                return count(20);
+            }
+            var captured = outer();
+        "#);
+        assert_eq!(machine.globals["global"], Value::Int(55));
+        assert_eq!(machine.globals["local"], Value::Int(3628800));
+        assert_eq!(machine.globals["captured"], Value::Int(20));
+    }
+
+    #[test]
+    fn top_level_functions_can_call_each_other() {
+        let machine = run(r#"
+            fun isEven(n) {
+                if (n == 0) return true;
+                return isOdd(n - 1);
+            }
+            fun isOdd(n) {
+                if (n == 0) return false;
+                return isEven(n - 1);
+            }
+            var even = isEven(10);
+            var odd = isOdd(7);
+            var notEven = isEven(7);
+        "#);
+        assert_eq!(machine.globals["even"], Value::Bool(true));
+        assert_eq!(machine.globals["odd"], Value::Bool(true));
+        assert_eq!(machine.globals["notEven"], Value::Bool(false));
+
+        // globals are looked up when the call runs, not when it is compiled
+        let source = "fun first() { return second(); } first(); fun second() { return 1; }";
+        let tokens = scanner::scan_tokens(source.to_string()).unwrap();
+        let function = Compiler::new(tokens, FunctionType::Script).compile().unwrap();
+        let mut machine = VirtualMachine::default();
+        machine.init();
+        let err = machine.interpret(function).unwrap_err();
+        assert!(err.to_string().contains("Undefined variable 'second'."), "{}", err);
+    }
+
+    fn global_ops(function: &Function) -> Vec<(&'static str, usize)> {
+        let mut ops = vec![];
+        for op in &function.chunk.code {
+            match op {
+                OpCode::OpDefineGlobal(slot) => ops.push(("define", *slot)),
+                OpCode::OpGetGlobal(slot) => ops.push(("get", *slot)),
+                OpCode::OpSetGlobal(slot) => ops.push(("set", *slot)),
+                _ => {}
+            }
+        }
+        for declared in &function.chunk.functions {
+            ops.extend(global_ops(declared));
+        }
+        return ops;
+    }
+
+    #[test]
+    fn globals_link_to_one_slot_per_name() {
+        let compile = |source: &str| {
+            let tokens = scanner::scan_tokens(source.to_string()).unwrap();
+            Compiler::new(tokens, FunctionType::Script).compile().unwrap()
+        };
+        let mut machine = VirtualMachine::default();
+        let mut script = compile("var a = 1; var b = 2; fun f() { return a + b; } a = f();");
+        machine.link(&mut script);
+        assert_eq!(global_ops(&script), [
+            ("define", 0), ("define", 1), ("define", 2), ("get", 2), ("set", 0),
+            ("get", 0), ("get", 1),
+        ]);
+
+        // a later script, like the next line at the prompt, finds the same slots
+        let mut next = compile("var c = b; b = later;");
+        machine.link(&mut next);
+        assert_eq!(global_ops(&next), [("get", 1), ("define", 3), ("get", 4), ("set", 1)]);
+        assert_eq!((machine.globals.name(3), machine.globals.name(4)), ("c", "later"));
+        assert!(machine.globals.get_slot(4).is_none());
+    }
+
+    #[test]
+    fn nested_loops_jump_inside_their_chunk() {
+        let source = r#"
+            var total = 0;
+            outer: for (var i = 0; i < 4; i = i + 1) {
+                var j = 0;
+                while (j < 4) {
+                    j = j + 1;
+                    if (j == 2) continue;
+                    for (var k in range(3)) {
+                        if (k == 1) continue;
+                        if (i == 3) continue outer;
+                        var n = 0;
+                        loop {
+                            n = n + 1;
+                            if (n > 1) break;
+                            fun add() {
+                                for (var m = 0; m < 2; m = m + 1) {
+                                    if (m == 0) continue;
+                                    total = total + m;
+                                }
+                            }
+                            add();
+                        }
+                    }
+                }
+            }
+        "#;
+        let tokens = scanner::scan_tokens(source.to_string()).unwrap();
+        let function = Compiler::new(tokens, FunctionType::Script).compile().unwrap();
+        fn check(chunk: &Chunk) -> usize {
+            assert_eq!(chunk.check_jumps(), Ok(()));
+            let mut loops = chunk.code.iter().filter(|op| matches!(op, OpCode::Loop(_))).count();
+            for function in &chunk.functions {
+                loops += check(&function.chunk);
+            }
+            return loops;
+        }
+        // two for each c style for, one for every other loop and continue
+        assert_eq!(check(&function.chunk), 11);
+
+        let mut machine = VirtualMachine::default();
+        machine.init();
+        machine.interpret(function).unwrap();
+        // i in 0..3, j in {1, 3, 4}, k in {0, 2}, one add() each
+        assert_eq!(machine.globals["total"], Value::Int(18));
+    }
+
+    #[test]
+    fn loops_that_jump_out_of_their_chunk_are_caught() {
+        let mut chunk = Chunk::default();
+        chunk.write(OpCode::OpNil, 1);
+        chunk.write(OpCode::Loop(2), 1);
+        assert_eq!(chunk.check_jumps(), Ok(()));
+        chunk.code[1] = OpCode::Loop(3);
+        assert!(chunk.check_jumps().is_err());
+        chunk.code[1] = OpCode::Jump(0);
+        assert!(chunk.check_jumps().is_err());
+    }
+
+    #[test]
+    fn calling_a_class_runs_init() {
+        let machine = run(r#"
+            class Point {
+                init(x, y) {
+                    this.x = x;
+                    this.y = y;
+                    if (x == 0) return;
+                    this.moved = true;
+                }
+            }
+            class Base { init(name) { this.name = name; } }
+            class Derived < Base { init() { super.init("base"); } }
+            var p = Point(3, 4);
+            var x = p.x;
+            var y = p.y;
+            var moved = p.moved;
+            var again = p.init(0, 1).x;
+            var name = Derived().name;
+        "#);
+        assert_eq!(machine.globals["x"], Value::Int(3));
+        assert_eq!(machine.globals["y"], Value::Int(4));
+        assert_eq!(machine.globals["moved"], Value::Bool(true));
+        assert_eq!(machine.globals["again"], Value::Int(0));
+        assert_eq!(machine.globals["name"].to_string(), "base");
+
+        let sources = [
+            ("class Point { init(x) {} } Point();", "Expected 1 arguments but got 0."),
+            ("class Point { init(x) {} } Point(1, 2);", "Expected 1 arguments but got 2."),
+            ("class Empty {} Empty(1);", "Expected 0 arguments but got 1."),
+        ];
+        for (source, expected) in sources {
+            let tokens = scanner::scan_tokens(source.to_string()).unwrap();
+            let function = Compiler::new(tokens, FunctionType::Script).compile().unwrap();
+            let mut machine = VirtualMachine::default();
+            machine.init();
+            let err = machine.interpret(function).unwrap_err();
+            assert!(err.to_string().contains(expected), "{}: {}", source, err);
+        }
+
+        let tokens = scanner::scan_tokens("class A { init() { return 1; } }".to_string()).unwrap();
+        let err = Compiler::new(tokens, FunctionType::Script).compile().unwrap_err();
+        assert!(err.to_string().contains("Can't return a value from an initializer."), "{}", err);
+    }
+
+    #[test]
+    fn lines_are_stored_in_runs() {
+        let source = "var a = 1;\nvar b = a +\n  2;\nif (b > a) {\n  print b;\n}\n";
+        let tokens = scanner::scan_tokens(source.to_string()).unwrap();
+        let function = Compiler::new(tokens, FunctionType::Script).compile().unwrap();
+        let chunk = &function.chunk;
+        let lines: Vec<usize> = (0..chunk.code.len()).map(|i| chunk.line_for(i).unwrap()).collect();
+        assert_eq!(lines, [1, 1, 2, 3, 3, 3, 4, 4, 4, 4, 4, 5, 5, 6, 6, 6, 6]);
+        assert_eq!(chunk.line_for(chunk.code.len()), None);
+
+        // the same listing as when every instruction carried its own line
+        let listing: Vec<String> = chunk.disassembly("main").lines().map(|l| l.trim_end().to_string()).collect();
+        assert_eq!(listing, [
+            "== main ==",
+            "0000   OP_CONSTANT Int(1) (idx=1)                         line 1",
+            "0001   OP_DEF_GLOBAL: 0                                   line 1",
+            "0002   OP_GET_GLOBAL: String(\"a\")                         line 2",
+            "0003   OP_CONSTANT Int(2) (idx=3)                         line 3",
+            "0004   OP_ADD                                             line 3",
+            "0005   OP_DEF_GLOBAL: 2                                   line 3",
+            "0006   OP_GET_GLOBAL: String(\"b\")                         line 4",
+            "0007   OP_GET_GLOBAL: String(\"a\")                         line 4",
+            "0008   OP_GREATER                                         line 4",
+            "0009   JUMP_IF_FALSE: 4                                   line 4",
+            "0010   OP_POP                                             line 4",
+            "0011   OP_GET_GLOBAL: String(\"b\")                         line 5",
+            "0012   OP_PRINT                                           line 5",
+            "0013   JUMP: 1                                            line 6",
+            "0014   OP_POP                                             line 6",
+            "0015   OP_NIL                                             line 6",
+            "0016   OP_RETURN                                          line 6",
+            "-- constants of main --",
+            "[0000] String(\"a\")",
+            "[0001] Int(1)",
+            "[0002] String(\"b\")",
+            "[0003] Int(2)",
+        ]);
+
+        let mut chunk = Chunk::default();
+        for line in [1, 1, 1, 2, 2, 3] {
+            chunk.write(OpCode::OpNil, line);
+        }
+        chunk.truncate(4);
+        chunk.write(OpCode::OpPop, 2);
+        chunk.write(OpCode::OpPop, 7);
+        let lines: Vec<Option<usize>> = (0..7).map(|i| chunk.line_for(i)).collect();
+        assert_eq!(lines, [Some(1), Some(1), Some(1), Some(2), Some(2), Some(7), None]);
+    }
+
+    #[test]
+    fn instances_equal_only_themselves() {
+        let machine = run(r#"
+            class Point {}
+            var a = Point();
+            var b = a;
+            var aliased = a == b;
+            var separate = Point() == Point();
+            var copied = clone(a) == a;
+            var differ = a != Point();
+            var listed = [a] == [b];
+        "#);
+        assert_eq!(machine.globals["aliased"], Value::Bool(true));
+        assert_eq!(machine.globals["separate"], Value::Bool(false));
+        assert_eq!(machine.globals["copied"], Value::Bool(false));
+        assert_eq!(machine.globals["differ"], Value::Bool(true));
+        assert_eq!(machine.globals["listed"], Value::Bool(true));
+    }
+
+    #[test]
+    fn functions_share_one_constants_table() {
+        let mut source = String::from("var total = 0;\n");
+        for i in 0..10 {
+            source.push_str(&format!("fun f{}(n) {{ total = total + n * 2; print \"total\", total; return n + 1; }}\n", i));
+        }
+        let tokens = scanner::scan_tokens(source).unwrap();
+        let function = Compiler::new(tokens, FunctionType::Script).compile().unwrap();
+        // each function used to hold its own copies, 82 constants in all
+        assert_eq!(function.chunk.constants.len(), 14);
+        assert_eq!(function.chunk.functions.len(), 10);
+        for declared in &function.chunk.functions {
+            assert!(std::rc::Rc::ptr_eq(&declared.chunk.constants, &function.chunk.constants));
+        }
+
+        let machine = run("var total = 0; fun add(n) { total = total + n; return total; } var a = add(2); var b = add(3);");
+        assert_eq!(machine.globals["b"], Value::Int(5));
+    }
+
+    #[test]
+    fn disassembly_lists_the_constants_of_each_function() {
+        let source = "fun greet(name) { print \"hi\", name; }\ngreet(\"bob\");\n";
+        let tokens = scanner::scan_tokens(source.to_string()).unwrap();
+        let function = Compiler::new(tokens, FunctionType::Script).compile().unwrap();
+        let listing = function.chunk.disassembly("main");
+        let sections: Vec<&str> = listing.lines().filter(|l| !l.starts_with(|c: char| c.is_ascii_digit())).collect();
+        assert_eq!(sections, [
+            "== main ==",
+            "== greet ==",
+            "-- constants of greet --",
+            "[0001] String(\"hi\")",
+            "-- constants of main --",
+            "[0000] String(\"greet\")",
+            "[0002] String(\"bob\")",
+        ]);
+    }
+
+    #[test]
+    fn redefined_globals_are_read_fresh() {
+        // reads go straight to the global's slot, there is no cached copy to go stale
+        let machine = run(r#"
+            var n = 1;
+            fun read() { return n; }
+            var total = 0;
+            for (var i = 0; i < 3; i = i + 1) total = total + read();
+            var n = 10;
+            for (var i = 0; i < 3; i = i + 1) total = total + read();
+            fun read() { return n * 2; }
+            var last = read();
+            n = "set";
+        "#);
+        assert_eq!(machine.globals["total"], Value::Int(33));
+        assert_eq!(machine.globals["last"], Value::Int(20));
+        assert_eq!(machine.globals["n"].to_string(), "set");
+    }
+
+    #[test]
+    fn property_sites_follow_the_class_they_see() {
+        let machine = run(r#"
+            class A { init() { this.x = 1; this.y = 2; } name() { return "a"; } }
+            class B { init() { this.y = 20; this.x = 10; } name() { return "b"; } }
+            fun read(o) { return o.x + o.y; }
+            fun call(o) { return o.name(); }
+            var sums = [0, 0, 0, 0];
+            var names = [0, 0, 0, 0];
+            var items = [A(), B(), A(), B()];
+            for (var i = 0; i < 4; i = i + 1) {
+                sums[i] = read(items[i]);
+                names[i] = call(items[i]);
+            }
+            var shadowed = A();
+            fun fromField() { return "field"; }
+            shadowed.name = fromField;
+            var called = call(shadowed);
+            fun make() { class C { get() { return 1; } } return C(); }
+            var first = make();
+            fun getter(o) { return o.get(); }
+            var seen = getter(first) + getter(make());
+        "#);
+        assert_eq!(machine.globals["sums"].to_string(), "[3, 30, 3, 30]");
+        assert_eq!(machine.globals["names"].to_string(), "[a, b, a, b]");
+        assert_eq!(machine.globals["called"].to_string(), "field");
+        let shadows_method = |name: &str| match &machine.globals[name] {
+            Value::Instance(instance) => instance.borrow().shadows_method,
+            _ => false,
+        };
+        assert!(shadows_method("shadowed"));
+        assert!(!shadows_method("first"));
+        assert_eq!(machine.globals["seen"], Value::Int(2));
+        assert!(machine.property_caches.iter().any(|cache| matches!(cache, Some(PropertyCache::Method { .. }))));
+        assert!(machine.property_caches.iter().any(|cache| matches!(cache, Some(PropertyCache::Field { .. }))));
+    }
+}
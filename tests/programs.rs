@@ -0,0 +1,66 @@
+// the benchmark programs as plain tests: each must print what it should under
+// both engines and finish well inside a time limit, so a big slowdown fails
+// `cargo test` even when nobody runs the benchmarks
+use std::time::{Duration, Instant};
+
+use crafting_interpreters::runtime::{Runtime, VMRuntime};
+use crafting_interpreters::types::output::Output;
+
+// loose enough for an unoptimized build on a slow machine
+const LIMIT: Duration = Duration::from_secs(30);
+
+fn check(name: &str, source: &str, expected: &str) {
+    let (output, out, _) = Output::capture();
+    let start = Instant::now();
+    let mut runtime = Runtime::default();
+    runtime.output = output;
+    runtime.run_source(source.to_string()).unwrap();
+    let elapsed = start.elapsed();
+    assert!(elapsed < LIMIT, "{} took {:?} in the interpreter", name, elapsed);
+    assert_eq!(String::from_utf8(out.borrow().clone()).unwrap(), expected, "{} in the interpreter", name);
+
+    let (output, out, _) = Output::capture();
+    let start = Instant::now();
+    let mut vm_runtime = VMRuntime::default();
+    vm_runtime.output = output;
+    vm_runtime.run_source(source.to_string()).unwrap();
+    let elapsed = start.elapsed();
+    assert!(elapsed < LIMIT, "{} took {:?} in the vm", name, elapsed);
+    assert_eq!(String::from_utf8(out.borrow().clone()).unwrap(), expected, "{} in the vm", name);
+}
+
+#[test]
+fn fib() {
+    check("fib", include_str!("../benches/programs/fib.lox"), "75025\n");
+}
+
+#[test]
+fn arithmetic() {
+    check("arithmetic", include_str!("../benches/programs/arithmetic.lox"), "14999750000\n");
+}
+
+#[test]
+fn strings() {
+    check("strings", include_str!("../benches/programs/strings.lox"), "5000 aza\n");
+}
+
+#[test]
+fn methods() {
+    check("methods", include_str!("../benches/programs/methods.lox"), "60000\n");
+}
+
+#[test]
+fn closures() {
+    check("closures", include_str!("../benches/programs/closures.lox"), "10000\n");
+}
+
+#[test]
+fn global_and_local_reads() {
+    check("global_reads", include_str!("../benches/programs/global_reads.lox"), "140000\n");
+    check("local_reads", include_str!("../benches/programs/local_reads.lox"), "140000\n");
+}
+
+#[test]
+fn global_increment() {
+    check("global_increment", include_str!("../benches/programs/global_increment.lox"), "150000\n");
+}